@@ -26,6 +26,16 @@ then
     CARGO_FEATURES=verbose
 fi
 
+# lint and test each feature set once, on the host only, the cross builds below just use one
+if [ "$TARGET" = "x86_64-unknown-linux-gnu" ]
+then
+    for features in async async,obfs async,compress async,obfs,compress async,quic async,http2 obfs compress tls verbose
+    do
+        cargo clippy --all-targets --no-default-features --features $features -- -D warnings
+        cargo test --no-default-features --features $features
+    done
+fi
+
 cross rustc --bin wireguard-proxy --target $TARGET --release --no-default-features --features $CARGO_FEATURES
 cross rustc --bin udp-test --target $TARGET --release --no-default-features --features $CARGO_FEATURES
 
@@ -0,0 +1,361 @@
+// the server's live tunnels, and a local unix socket to list, inspect and kill them or stop accepting new
+// ones, it takes one command per line and answers each with one line of JSON:
+//   list              {"accepting":true,"sessions":[{...},...]}
+//   show <id>         {"id":1,"peer":"10.0.0.2:40000","udp_ports":[30000],"tls":null,"uptime_secs":5,"rx_bytes":0,"tx_bytes":0,
+//                      "compression":null}
+//   kill <id>         {"killed":1}
+//   accept on|off     {"accepting":false}
+// and {"error":"..."} for anything it can't do
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::compress::CompressionStats;
+
+/// bytes through a tunnel's stream, rx from the client and tx to it, shared by all clones of the stream
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub rx: AtomicU64,
+    pub tx: AtomicU64,
+}
+
+impl Counters {
+    pub fn add_rx(&self, len: usize) {
+        self.rx.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_tx(&self, len: usize) {
+        self.tx.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+/// what a TLS tunnel was accepted with
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsInfo {
+    /// the server name the client sent, if any
+    pub sni: Option<String>,
+    /// the server's own key, what clients pin it with
+    pub pinnedpubkey: Option<String>,
+}
+
+type Kill = Box<dyn FnOnce() + Send>;
+
+/// one live tunnel
+pub struct Session {
+    pub id: u64,
+    pub peer: String,
+    pub started: Instant,
+    pub bytes: Arc<Counters>,
+    tls: Mutex<Option<TlsInfo>>,
+    udp_ports: Mutex<Vec<u16>>,
+    compression: Mutex<Option<Arc<CompressionStats>>>,
+    killed: AtomicBool,
+    kill: Mutex<Option<Kill>>,
+}
+
+impl Session {
+    pub fn tls(&self) -> Option<TlsInfo> {
+        self.tls.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_tls(&self, tls: TlsInfo) {
+        *self.tls.lock().unwrap() = Some(tls);
+    }
+
+    /// ports of the udp sockets bound for it, more than one when it carries several peers
+    pub fn udp_ports(&self) -> Vec<u16> {
+        self.udp_ports.lock().unwrap().clone()
+    }
+
+    pub(crate) fn add_udp_port(&self, port: u16) {
+        self.udp_ports.lock().unwrap().push(port);
+    }
+
+    /// None unless the client asked for compression
+    pub fn compression(&self) -> Option<Arc<CompressionStats>> {
+        self.compression.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_compression(&self, compression: Arc<CompressionStats>) {
+        *self.compression.lock().unwrap() = Some(compression);
+    }
+
+    pub fn killed(&self) -> bool {
+        self.killed.load(Ordering::Relaxed)
+    }
+
+    fn kill(&self) {
+        self.killed.store(true, Ordering::Relaxed);
+        if let Some(kill) = self.kill.lock().unwrap().take() {
+            kill();
+        }
+    }
+
+    fn json(&self) -> String {
+        let tls = match self.tls() {
+            Some(tls) => format!(r#"{{"sni":{},"pinnedpubkey":{}}}"#, json_opt(&tls.sni), json_opt(&tls.pinnedpubkey)),
+            None => "null".to_owned(),
+        };
+        let udp_ports: Vec<String> = self.udp_ports().iter().map(u16::to_string).collect();
+        let compression = self.compression().map(|compression| compression.json()).unwrap_or_else(|| "null".to_owned());
+        format!(
+            r#"{{"id":{},"peer":{},"udp_ports":[{}],"tls":{},"uptime_secs":{},"rx_bytes":{},"tx_bytes":{},"compression":{}}}"#,
+            self.id,
+            json_str(&self.peer),
+            udp_ports.join(","),
+            tls,
+            self.started.elapsed().as_secs(),
+            self.bytes.rx.load(Ordering::Relaxed),
+            self.bytes.tx.load(Ordering::Relaxed),
+            compression,
+        )
+    }
+}
+
+/// every live tunnel of a server, and whether it accepts new ones
+pub struct Sessions {
+    next_id: AtomicU64,
+    accepting: AtomicBool,
+    sessions: Mutex<BTreeMap<u64, Arc<Session>>>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions::new()
+    }
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions {
+            next_id: AtomicU64::new(1),
+            accepting: AtomicBool::new(true),
+            sessions: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn accepting(&self) -> bool {
+        self.accepting.load(Ordering::Relaxed)
+    }
+
+    /// off closes new connections as they come in, tunnels already open carry on
+    pub fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::Relaxed);
+    }
+
+    /// registers a tunnel until the returned guard is dropped, kill is how to stop it
+    pub(crate) fn open(self: &Arc<Self>, peer: &str, kill: Kill) -> Open {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let session = Arc::new(Session {
+            id,
+            peer: peer.to_owned(),
+            started: Instant::now(),
+            bytes: Arc::new(Counters::default()),
+            tls: Mutex::new(None),
+            udp_ports: Mutex::new(Vec::new()),
+            compression: Mutex::new(None),
+            killed: AtomicBool::new(false),
+            kill: Mutex::new(Some(kill)),
+        });
+        self.sessions.lock().unwrap().insert(id, session.clone());
+        Open { sessions: self.clone(), session }
+    }
+
+    /// oldest first
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        self.sessions.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Session>> {
+        self.sessions.lock().unwrap().get(&id).cloned()
+    }
+
+    /// closes the tunnel with this id, false if there isn't one
+    pub fn kill(&self, id: u64) -> bool {
+        match self.get(id) {
+            Some(session) => {
+                session.kill();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// runs one line of the admin protocol and returns the answer
+    pub fn command(&self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let id = |word: &str| word.parse::<u64>().map_err(|_| error(&format!("invalid session id {}", word)));
+        let answer = match words.as_slice() {
+            ["list"] => {
+                let sessions: Vec<String> = self.list().iter().map(|session| session.json()).collect();
+                Ok(format!(r#"{{"accepting":{},"sessions":[{}]}}"#, self.accepting(), sessions.join(",")))
+            }
+            ["show", word] => id(word).and_then(|id| self.get(id).map(|session| session.json()).ok_or_else(|| error(&format!("no session {}", id)))),
+            ["kill", word] => id(word).and_then(|id| {
+                if !self.kill(id) {
+                    return Err(error(&format!("no session {}", id)));
+                }
+                println!("session {} killed from the admin socket", id);
+                Ok(format!(r#"{{"killed":{}}}"#, id))
+            }),
+            ["accept", on_off @ ("on" | "off")] => {
+                self.set_accepting(*on_off == "on");
+                println!("accepting new tunnels {} from the admin socket", on_off);
+                Ok(format!(r#"{{"accepting":{}}}"#, self.accepting()))
+            }
+            _ => Err(error(&format!("unknown command {}, must be list, show <id>, kill <id> or accept on|off", line.trim()))),
+        };
+        answer.unwrap_or_else(|e| e)
+    }
+}
+
+/// a registered tunnel, unregistered when dropped
+pub(crate) struct Open {
+    sessions: Arc<Sessions>,
+    pub session: Arc<Session>,
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        self.sessions.sessions.lock().unwrap().remove(&self.session.id);
+    }
+}
+
+fn error(msg: &str) -> String {
+    format!(r#"{{"error":{}}}"#, json_str(msg))
+}
+
+pub(crate) fn json_opt(s: &Option<String>) -> String {
+    s.as_deref().map(json_str).unwrap_or_else(|| "null".to_owned())
+}
+
+pub(crate) fn json_str(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(unix)]
+pub use self::unix::{admin_command, listen};
+
+#[cfg(unix)]
+mod unix {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::Sessions;
+    use crate::error::Result;
+
+    /// answers admin commands on listener from a thread of its own, a thread per connection
+    pub fn listen(listener: UnixListener, sessions: Arc<Sessions>) {
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let sessions = sessions.clone();
+                        thread::spawn(move || serve(stream, &sessions));
+                    }
+                    Err(e) => println!("admin socket accept failed: {}", e),
+                }
+            }
+        });
+    }
+
+    fn serve(stream: UnixStream, sessions: &Sessions) -> Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            writer.write_all(sessions.command(&line).as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// sends one command to a server's admin socket at path and returns its answer, what `wireguard-proxy ctl` does
+    pub fn admin_command(path: &str, command: &str) -> Result<String> {
+        let mut stream = UnixStream::connect(path)?;
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\n")?;
+        let mut answer = String::new();
+        BufReader::new(stream).read_line(&mut answer)?;
+        Ok(answer.trim_end().to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        let sessions = Arc::new(Sessions::new());
+        assert_eq!(sessions.command("list"), r#"{"accepting":true,"sessions":[]}"#);
+
+        let killed = Arc::new(AtomicBool::new(false));
+        let kill = killed.clone();
+        let open = sessions.open("10.0.0.2:40000", Box::new(move || kill.store(true, Ordering::Relaxed)));
+        open.session.add_udp_port(30000);
+        open.session.add_udp_port(30001);
+        open.session.set_tls(TlsInfo { sni: Some("vpn.example.com".to_owned()), pinnedpubkey: None });
+        open.session.bytes.add_rx(100);
+        open.session.bytes.add_tx(42);
+        let compression = Arc::new(CompressionStats::default());
+        compression.tx_raw.store(1000, Ordering::Relaxed);
+        compression.tx_compressed.store(400, Ordering::Relaxed);
+        open.session.set_compression(compression);
+        let other = sessions.open("unix:/run/\"wgp\".sock", Box::new(|| ()));
+        assert_eq!(
+            sessions.command("show 1"),
+            concat!(
+                r#"{"id":1,"peer":"10.0.0.2:40000","udp_ports":[30000,30001],"tls":{"sni":"vpn.example.com","pinnedpubkey":null},"uptime_secs":0,"rx_bytes":100,"tx_bytes":42,"#,
+                r#""compression":{"tx_raw":1000,"tx_compressed":400,"rx_raw":0,"rx_compressed":0}}"#
+            )
+        );
+        assert!(sessions.command("list").ends_with(r#"{"id":2,"peer":"unix:/run/\"wgp\".sock","udp_ports":[],"tls":null,"uptime_secs":0,"rx_bytes":0,"tx_bytes":0,"compression":null}]}"#));
+
+        assert_eq!(sessions.command(" kill  1 "), r#"{"killed":1}"#);
+        assert!(killed.load(Ordering::Relaxed) && open.session.killed());
+        // it stays listed until the tunnel actually ends
+        assert_eq!(sessions.list().len(), 2);
+        drop(open);
+        assert_eq!(sessions.list().len(), 1);
+        assert_eq!(sessions.command("kill 1"), r#"{"error":"no session 1"}"#);
+        assert_eq!(sessions.command("show one"), r#"{"error":"invalid session id one"}"#);
+
+        assert_eq!(sessions.command("accept off"), r#"{"accepting":false}"#);
+        assert!(!sessions.accepting());
+        assert_eq!(sessions.command("accept on"), r#"{"accepting":true}"#);
+        assert!(sessions.command("accept maybe").starts_with(r#"{"error":"unknown command accept maybe"#));
+        drop(other);
+    }
+    #[cfg(unix)]
+    #[test]
+    fn test_admin_socket() {
+        let path = std::env::temp_dir().join(format!("wgp-admin-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let sessions = Arc::new(Sessions::new());
+        listen(std::os::unix::net::UnixListener::bind(path).unwrap(), sessions.clone());
+
+        let _open = sessions.open("127.0.0.1:5555", Box::new(|| ()));
+        assert_eq!(admin_command(path, "accept off").unwrap(), r#"{"accepting":false}"#);
+        assert!(!sessions.accepting());
+        assert!(admin_command(path, "list").unwrap().starts_with(r#"{"accepting":false,"sessions":[{"id":1,"peer":"127.0.0.1:5555","#));
+        std::fs::remove_file(path).unwrap();
+    }
+}
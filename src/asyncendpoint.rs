@@ -0,0 +1,330 @@
+// where the tunnel delivers datagrams, normally a udp socket wireguard talks to, but a library
+// user running wireguard in process can hand packets over directly with no loopback udp hop
+
+use std::io;
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::sync::mpsc;
+
+use crate::error::Result;
+use crate::source::ReplyTo;
+use crate::transport::BoxFuture;
+
+/// receiving half of an endpoint, datagrams headed into the tunnel
+pub trait DatagramRx: Send {
+    /// waits for the next datagram and returns its length, one bigger than buf is truncated like udp would
+    fn recv<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize>>;
+
+    /// grabs more datagrams that are already waiting without waiting, filling in their lengths and
+    /// returning how many, the default of 0 just means one per recv
+    fn try_recv_many(&mut self, _bufs: &mut [&mut [u8]], _lens: &mut [usize]) -> usize {
+        0
+    }
+}
+
+/// sending half of an endpoint, datagrams coming out of the tunnel
+pub trait DatagramTx: Send {
+    fn send<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, Result<()>>;
+
+    /// sends as many of bufs as can go right now without waiting, returning how many,
+    /// the rest go through send
+    fn try_send_many(&mut self, _bufs: &[&[u8]]) -> usize {
+        0
+    }
+}
+
+/// anything that sends and receives whole datagrams, split so both directions run at once
+pub trait DatagramEndpoint: Send {
+    fn split(self: Box<Self>) -> (Box<dyn DatagramRx>, Box<dyn DatagramTx>);
+}
+
+pub type BoxEndpoint = Box<dyn DatagramEndpoint>;
+
+/// a connected udp socket, batching with recvmmsg/sendmmsg on linux
+impl DatagramEndpoint for UdpSocket {
+    fn split(self: Box<Self>) -> (Box<dyn DatagramRx>, Box<dyn DatagramTx>) {
+        #[cfg(target_os = "linux")]
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&*self);
+        let (rx, tx) = UdpSocket::split(*self);
+        (
+            Box::new(UdpRx {
+                rx,
+                #[cfg(target_os = "linux")]
+                fd,
+            }),
+            Box::new(UdpTx {
+                tx,
+                #[cfg(target_os = "linux")]
+                fd,
+            }),
+        )
+    }
+}
+
+struct UdpRx {
+    rx: RecvHalf,
+    #[cfg(target_os = "linux")]
+    fd: std::os::unix::io::RawFd,
+}
+
+impl DatagramRx for UdpRx {
+    fn recv<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move { Ok(self.rx.recv(buf).await?) })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_recv_many(&mut self, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> usize {
+        // WouldBlock just means nothing else is waiting, anything else the next recv will report
+        crate::batch::sys::recvmmsg(self.fd, bufs, lens).unwrap_or(0)
+    }
+}
+
+struct UdpTx {
+    tx: SendHalf,
+    #[cfg(target_os = "linux")]
+    fd: std::os::unix::io::RawFd,
+}
+
+impl DatagramTx for UdpTx {
+    fn send<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.tx.send(buf).await?;
+            Ok(())
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_send_many(&mut self, bufs: &[&[u8]]) -> usize {
+        crate::batch::sys::sendmmsg(self.fd, bufs, libc::MSG_DONTWAIT).unwrap_or(0)
+    }
+}
+
+/// the client's udp socket, left unconnected so wireguard can move to a new source port, replies follow it
+pub(crate) struct LocalUdp {
+    socket: UdpSocket,
+    reply_to: Arc<ReplyTo>,
+}
+
+impl LocalUdp {
+    pub fn new(socket: UdpSocket, reply_to: Arc<ReplyTo>) -> LocalUdp {
+        LocalUdp { socket, reply_to }
+    }
+}
+
+impl DatagramEndpoint for LocalUdp {
+    fn split(self: Box<Self>) -> (Box<dyn DatagramRx>, Box<dyn DatagramTx>) {
+        #[cfg(target_os = "linux")]
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&self.socket);
+        let (rx, tx) = self.socket.split();
+        (
+            Box::new(LocalRx {
+                rx,
+                #[cfg(target_os = "linux")]
+                fd,
+                reply_to: self.reply_to.clone(),
+            }),
+            Box::new(LocalTx {
+                tx,
+                #[cfg(target_os = "linux")]
+                fd,
+                reply_to: self.reply_to,
+            }),
+        )
+    }
+}
+
+struct LocalRx {
+    rx: RecvHalf,
+    #[cfg(target_os = "linux")]
+    fd: std::os::unix::io::RawFd,
+    reply_to: Arc<ReplyTo>,
+}
+
+impl DatagramRx for LocalRx {
+    fn recv<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            loop {
+                let (len, src_addr) = self.rx.recv_from(buf).await?;
+                if self.reply_to.accept(src_addr) {
+                    return Ok(len);
+                }
+            }
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_recv_many(&mut self, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> usize {
+        let mut addrs = [None; crate::batch::BATCH];
+        let count = crate::batch::sys::recvmmsg_from(self.fd, bufs, lens, &mut addrs).unwrap_or(0);
+        for (len, addr) in lens.iter_mut().zip(addrs[..count].iter()) {
+            if !addr.is_some_and(|addr| self.reply_to.accept(addr)) {
+                // empty datagrams are dropped when packed
+                *len = 0;
+            }
+        }
+        count
+    }
+}
+
+struct LocalTx {
+    tx: SendHalf,
+    #[cfg(target_os = "linux")]
+    fd: std::os::unix::io::RawFd,
+    reply_to: Arc<ReplyTo>,
+}
+
+impl DatagramTx for LocalTx {
+    fn send<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let addr = self.reply_to.get();
+            self.tx.send_to(buf, &addr).await?;
+            Ok(())
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_send_many(&mut self, bufs: &[&[u8]]) -> usize {
+        crate::batch::sys::sendmmsg_to(self.fd, bufs, Some(&self.reply_to.get()), libc::MSG_DONTWAIT).unwrap_or(0)
+    }
+}
+
+/// in memory datagrams, for running wireguard in the same process or in tests
+pub struct ChannelEndpoint {
+    pub tx: mpsc::Sender<Vec<u8>>,
+    pub rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl ChannelEndpoint {
+    /// two endpoints back to back, what is sent on one is received on the other, give one to the
+    /// proxy and use the other like wireguard's udp socket, each direction holds up to capacity datagrams
+    pub fn pair(capacity: usize) -> (ChannelEndpoint, ChannelEndpoint) {
+        let (a_tx, a_rx) = mpsc::channel(capacity);
+        let (b_tx, b_rx) = mpsc::channel(capacity);
+        (ChannelEndpoint { tx: a_tx, rx: b_rx }, ChannelEndpoint { tx: b_tx, rx: a_rx })
+    }
+}
+
+impl DatagramEndpoint for ChannelEndpoint {
+    fn split(self: Box<Self>) -> (Box<dyn DatagramRx>, Box<dyn DatagramTx>) {
+        (Box::new(self.rx), Box::new(self.tx))
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "datagram channel closed")
+}
+
+// copies as much as fits, like udp truncating into a short buffer
+fn copy_datagram(datagram: &[u8], buf: &mut [u8]) -> usize {
+    let len = datagram.len().min(buf.len());
+    buf[..len].copy_from_slice(&datagram[..len]);
+    len
+}
+
+impl DatagramRx for mpsc::Receiver<Vec<u8>> {
+    fn recv<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            let datagram = mpsc::Receiver::recv(self).await.ok_or_else(closed)?;
+            Ok(copy_datagram(&datagram, buf))
+        })
+    }
+
+    fn try_recv_many(&mut self, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> usize {
+        let mut count = 0;
+        for (buf, len) in bufs.iter_mut().zip(lens.iter_mut()) {
+            match self.try_recv() {
+                Ok(datagram) => *len = copy_datagram(&datagram, buf),
+                Err(_) => break,
+            }
+            count += 1;
+        }
+        count
+    }
+}
+
+impl DatagramTx for mpsc::Sender<Vec<u8>> {
+    fn send<'a>(&'a mut self, buf: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            mpsc::Sender::send(self, buf.to_vec()).await.map_err(|_| closed())?;
+            Ok(())
+        })
+    }
+
+    fn try_send_many(&mut self, bufs: &[&[u8]]) -> usize {
+        bufs.iter().take_while(|buf| self.try_send(buf.to_vec()).is_ok()).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::DEFAULT_MAX_DATAGRAM;
+    use crate::net::asyncmod::TcpUdpPipe;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_channel_pipe() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+
+            // a tunnel with in memory wireguard on both ends, no udp anywhere
+            let (client_side, mut client_wg) = ChannelEndpoint::pair(64);
+            let (server_side, mut server_wg) = ChannelEndpoint::pair(64);
+            tokio::spawn(TcpUdpPipe::new(client.unwrap(), Box::new(client_side), DEFAULT_MAX_DATAGRAM).shuffle());
+            tokio::spawn(TcpUdpPipe::new(server.unwrap().0, Box::new(server_side), DEFAULT_MAX_DATAGRAM).shuffle());
+
+            let datagrams: Vec<Vec<u8>> = (1..=100u8).map(|x| vec![x; x as usize]).collect();
+            for datagram in datagrams.iter() {
+                client_wg.tx.send(datagram.clone()).await.unwrap();
+            }
+            for datagram in datagrams.iter() {
+                assert_eq!(&server_wg.rx.recv().await.unwrap(), datagram);
+            }
+
+            server_wg.tx.send(b"reply".to_vec()).await.unwrap();
+            assert_eq!(client_wg.rx.recv().await.unwrap(), b"reply");
+        });
+    }
+
+    // a whole tunnel, server and client, over a unix socket with in memory wireguard on both ends
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_tunnel() {
+        use crate::transport::Plain;
+        use crate::{ProxyClientBuilder, ProxyServerBuilder};
+        use std::os::unix::fs::PermissionsExt;
+        use std::sync::Mutex;
+
+        let path = std::env::temp_dir().join(format!("wgp-unix-tunnel-async-{}", std::process::id()));
+        let (client_side, mut client_wg) = ChannelEndpoint::pair(64);
+        let (server_side, mut server_wg) = ChannelEndpoint::pair(64);
+        let server_side = Mutex::new(Some(server_side));
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let ready_tx = Mutex::new(ready_tx);
+        let server = ProxyServerBuilder::new()
+            .unix_host(&path, Some(0o600))
+            .endpoint_factory(move || Ok(Box::new(server_side.lock().unwrap().take().expect("only one tunnel")) as BoxEndpoint))
+            .on_ready(move |_| { ready_tx.lock().unwrap().send(()).ok(); })
+            .build()
+            .unwrap();
+        let client = ProxyClientBuilder::new().unix_target(&path).build().unwrap();
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async move {
+            tokio::spawn(async move { server.start_async().await });
+            ready_rx.recv().unwrap();
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+            tokio::spawn(async move { client.start_with_endpoint_async(&Plain, Box::new(client_side)).await });
+
+            client_wg.tx.send(b"hello".to_vec()).await.unwrap();
+            assert_eq!(server_wg.rx.recv().await.unwrap(), b"hello");
+            server_wg.tx.send(b"reply".to_vec()).await.unwrap();
+            assert_eq!(client_wg.rx.recv().await.unwrap(), b"reply");
+            // the server never stops to remove it itself
+            std::fs::remove_file(&path).unwrap();
+        });
+    }
+}
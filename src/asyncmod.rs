@@ -1,149 +1,657 @@
 
-use tokio::net::UdpSocket;
+use std::collections::hash_map::{Entry, HashMap};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Instant;
+
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::runtime::Runtime;
-use tokio_rustls::webpki::DNSNameRef;
 
-use crate::error;
-use crate::error::Result;
+use crate::batch::{UdpBatch, BATCH};
+use crate::bond::Stripes;
+use crate::endpoint::{BoxEndpoint, DatagramRx, DatagramTx, LocalUdp};
+use crate::control::{self, Hello, PeerFrames, Peers};
+use crate::{eyeballs, failover};
+use crate::frame::FrameReader;
+use crate::source::{self, ReplyTo};
+use crate::error::{Error, Result};
+use crate::transport::{Acceptor, BoxFuture, BoxStream, Counted, Plain, TlsAcceptor, TlsTransport, Transport};
 use crate::*;
 
 pub struct TcpUdpPipe<T: AsyncReadExt + AsyncWriteExt + std::marker::Unpin + std::marker::Send + 'static> {
-    buf: [u8; 2050], // 2048 + 2 for len
     tcp_stream: T,
-    udp_socket: UdpSocket,
+    endpoint: BoxEndpoint,
+    frame_reader: FrameReader,
+    max_datagram: usize,
 }
 
 impl<T: AsyncReadExt + AsyncWriteExt + std::marker::Unpin + std::marker::Send + 'static> TcpUdpPipe<T> {
 
-    pub fn new(tcp_stream: T, udp_socket: UdpSocket) -> TcpUdpPipe<T> {
+    pub fn new(tcp_stream: T, endpoint: BoxEndpoint, max_datagram: usize) -> TcpUdpPipe<T> {
         TcpUdpPipe {
             tcp_stream,
-            udp_socket,
-            buf: [0u8; 2050],
+            endpoint,
+            frame_reader: FrameReader::new(max_datagram),
+            max_datagram,
         }
     }
 
-    pub async fn shuffle_after_first_udp(mut self) -> Result<usize> {
-        let (len, src_addr) = self.udp_socket.recv_from(&mut self.buf[2..]).await?;
+    /// carry on with frames already read from tcp_stream
+    pub fn with_reader(mut self, frame_reader: FrameReader) -> TcpUdpPipe<T> {
+        self.frame_reader = frame_reader;
+        self
+    }
 
-        println!("first packet from {}, connecting to that", src_addr);
-        self.udp_socket.connect(src_addr).await?;
+    pub async fn shuffle(self) -> Result<usize> {
+        // todo: investigate https://docs.rs/tokio/0.2.22/tokio/net/struct.TcpStream.html#method.into_split
+        let (tcp_rd, tcp_wr) = tokio::io::split(self.tcp_stream);
+        let (udp_rd, udp_wr) = self.endpoint.split();
 
-        send_udp(&mut self.buf, &mut self.tcp_stream, len).await?;
+        // whichever direction fails first ends the tunnel, dropping the other closes both sockets
+        tokio::select! {
+            result = udp_to_tcp(udp_rd, tcp_wr, self.max_datagram) => result,
+            result = tcp_to_udp(tcp_rd, udp_wr, self.frame_reader) => result,
+        }
+    }
+}
+
+// the client doesn't know where wireguard is until it sends something, so wait for that from an
+// allowed source, send it on and return where it came from to reply to
+async fn first_udp<W: AsyncWriteExt + std::marker::Unpin>(udp_socket: &mut UdpSocket, tcp_wr: &mut W, allowed: &AllowedSources, max_datagram: usize) -> Result<SocketAddr> {
+    let mut batch = UdpBatch::new(max_datagram);
+    let mut rejected = None;
+    let (len, src_addr) = loop {
+        let (len, src_addr) = udp_socket.recv_from(batch.first_mut()).await?;
+        if source::allowed(allowed, src_addr, &mut rejected) {
+            break (len, src_addr);
+        }
+    };
 
-        self.shuffle().await
+    println!("first packet from {}, replying there", src_addr);
+
+    batch.set_first(len);
+    tcp_wr.write_all(batch.pack()).await?;
+    tcp_wr.flush().await?;
+    Ok(src_addr)
+}
+
+async fn udp_to_tcp<W: AsyncWriteExt + std::marker::Unpin>(mut udp_rd: Box<dyn DatagramRx>, mut tcp_wr: W, max_datagram: usize) -> Result<usize> {
+    let mut batch = UdpBatch::new(max_datagram);
+    loop {
+        udp_recv(&mut udp_rd, &mut batch).await?;
+        tcp_wr.write_all(batch.pack()).await?;
+        tcp_wr.flush().await?;
     }
+}
 
-    pub async fn shuffle(self) -> Result<usize> {
-        // todo: investigate https://docs.rs/tokio/0.2.22/tokio/net/struct.TcpStream.html#method.into_split
-        let (mut tcp_rd, mut tcp_wr) = tokio::io::split(self.tcp_stream);
-        let (mut udp_rd, mut udp_wr) = self.udp_socket.split();
-        let mut recv_buf = self.buf.clone(); // or zeroed or?
+async fn udp_recv(udp_rd: &mut Box<dyn DatagramRx>, batch: &mut UdpBatch) -> Result<()> {
+    // wait for the first one normally, then grab whatever else is waiting
+    let len = udp_rd.recv(batch.first_mut()).await?;
+    batch.set_first(len);
+    batch.recv_more(|bufs, lens| udp_rd.try_recv_many(bufs, lens));
+    Ok(())
+}
 
-        tokio::spawn(async move {
-            loop {
-                let len = udp_rd.recv(&mut recv_buf[2..]).await?;
-                send_udp(&mut recv_buf, &mut tcp_wr, len).await?;
+async fn tcp_to_udp<R: AsyncReadExt + std::marker::Unpin>(mut tcp_rd: R, mut udp_wr: Box<dyn DatagramTx>, mut reader: FrameReader) -> Result<usize> {
+    loop {
+        loop {
+            // nothing is done with control frames in a plain tunnel, a newer client may still send them
+            while reader.control()?.is_some() {}
+            let mut frames = reader.frames(BATCH)?;
+            if frames.is_empty() {
+                break;
+            }
+            // send as many as the endpoint will take right now, the rest wait below
+            let sent = udp_wr.try_send_many(&frames);
+            frames.drain(..sent);
+            for frame in frames {
+                udp_wr.send(frame).await?;
+            }
+        }
+        reader.fill_async(&mut tcp_rd).await?;
+    }
+}
+
+// says hello asking for features, an old server never answers so don't wait forever, or at all once it sends a datagram
+async fn hello_async<T: AsyncReadExt + AsyncWriteExt + std::marker::Unpin>(tcp_stream: &mut T, hello: &Hello, max_datagram: usize) -> Result<(FrameReader, Hello)> {
+    tcp_stream.write_all(&hello.frame()).await?;
+    tcp_stream.flush().await?;
+    let mut reader = FrameReader::new(max_datagram);
+    let answer = tokio::time::timeout(HELLO_TIMEOUT, async {
+        // the server's padding can get here ahead of its answer
+        loop {
+            if let Some(hello) = control::answer(&mut reader, hello.features)? {
+                return Ok(hello);
             }
+            reader.fill_async(tcp_stream).await?;
+        }
+    }).await;
+    match answer {
+        Ok(Ok(answer)) => Ok((reader, control::answered(hello.features, answer)?)),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(control::unsupported(hello.features)),
+    }
+}
+
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Attempt = BoxFuture<'static, io::Result<TcpStream>>;
+
+// connects to whichever of addrs answers first, starting the next attempt as soon as one fails or every
+// ATTEMPT_DELAY, attempts still going are dropped once one connects
+async fn race(addrs: Vec<SocketAddr>) -> Result<TcpStream> {
+    let mut addrs = addrs.into_iter();
+    let mut attempts: Vec<Attempt> = Vec::new();
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "resolved to no addresses");
+    loop {
+        match addrs.next() {
+            Some(addr) => attempts.push(Box::pin(TcpStream::connect(addr))),
+            None if attempts.is_empty() => return Err(last_err.into()),
+            None => {}
+        }
+        let more = addrs.len() > 0;
+        tokio::select! {
+            result = first_done(&mut attempts) => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = e,
+            },
+            _ = tokio::time::delay_for(eyeballs::ATTEMPT_DELAY), if more => {}
+        }
+    }
+}
+
+// whichever attempt finishes first, taken out of attempts
+fn first_done(attempts: &mut Vec<Attempt>) -> impl Future<Output = io::Result<TcpStream>> + '_ {
+    std::future::poll_fn(move |cx| {
+        for i in 0..attempts.len() {
+            if let Poll::Ready(result) = attempts[i].as_mut().poll(cx) {
+                drop(attempts.remove(i));
+                return Poll::Ready(result);
+            }
+        }
+        Poll::Pending
+    })
+}
+
+// connected streams, several when bonded, and the Connected status to report once the tunnel carries packets
+type Connection = (Vec<Link>, Status);
+// a connected stream and what was read past the hello if there was one
+type Link = (BoxStream, Option<FrameReader>);
 
-            // Sometimes, the rust type inferencer needs
-            // a little help
-            #[allow(unreachable_code)]
-                {
-                    unsafe { std::hint::unreachable_unchecked(); }
-                    Ok::<_, error::Error>(())
+/// one endpoint carried over several streams of a bond, datagrams read from any of them go to it and what it
+/// sends back goes out over each stream in turn
+pub(crate) struct Bond {
+    udp_wr: tokio::sync::Mutex<Box<dyn DatagramTx>>,
+    // to each stream's writer
+    links: Mutex<Stripes<mpsc::Sender<Vec<u8>>>>,
+    // dropping it with the bond stops the endpoint's task
+    _stop: oneshot::Sender<()>,
+    // keeps udp_target watched for as long as the bond lasts
+    _upstreams: Option<Arc<resolve::Upstreams>>,
+}
+
+impl Bond {
+    pub fn new(endpoint: BoxEndpoint, upstreams: Option<Arc<resolve::Upstreams>>, max_datagram: usize) -> Result<Arc<Bond>> {
+        let (mut udp_rd, udp_wr) = endpoint.split();
+        let (stop_tx, mut stop_rx) = oneshot::channel::<()>();
+        let bond = Arc::new(Bond {
+            udp_wr: tokio::sync::Mutex::new(udp_wr),
+            links: Mutex::new(Stripes::default()),
+            _stop: stop_tx,
+            _upstreams: upstreams,
+        });
+        let weak = Arc::downgrade(&bond);
+        tokio::spawn(async move {
+            let mut batch = UdpBatch::new(max_datagram);
+            loop {
+                let result = tokio::select! {
+                    result = udp_recv(&mut udp_rd, &mut batch) => result,
+                    _ = &mut stop_rx => break,
+                };
+                let bond = match weak.upgrade() {
+                    Some(bond) => bond,
+                    None => break,
+                };
+                match result {
+                    Ok(()) => bond.send(batch.pack().to_vec()).await,
+                    // like one stream's tunnel, the endpoint failing takes every stream down with it
+                    Err(_) => break bond.links.lock().unwrap().drain().for_each(drop),
                 }
+            }
         });
+        Ok(bond)
+    }
 
-        let mut send_buf = self.buf.clone(); // or zeroed or?
+    /// carries stream until it fails, reader holds what was already read from it
+    pub async fn link(&self, stream: BoxStream, reader: FrameReader) -> Result<usize> {
+        let (tcp_rd, tcp_wr) = tokio::io::split(stream);
+        let (link_tx, link_rx) = mpsc::channel(BATCH);
+        let id = self.links.lock().unwrap().add(link_tx)?;
+        let result = tokio::select! {
+            result = link_to_tcp(link_rx, tcp_wr) => result,
+            result = self.tcp_to_udp(tcp_rd, reader) => result,
+        };
+        self.links.lock().unwrap().remove(id);
+        result
+    }
 
+    /// every link at once, until the last one fails
+    pub async fn run(&self, links: Vec<(BoxStream, FrameReader)>) -> Result<usize> {
+        let mut links: Vec<BoxFuture<'_, Result<usize>>> = links.into_iter()
+            .map(|(stream, reader)| Box::pin(self.link(stream, reader)) as BoxFuture<'_, Result<usize>>)
+            .collect();
+        let mut result = Ok(0);
+        std::future::poll_fn(|cx| {
+            links.retain_mut(|link| match link.as_mut().poll(cx) {
+                Poll::Ready(done) => {
+                    result = done;
+                    false
+                }
+                Poll::Pending => true,
+            });
+            match links.is_empty() {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        }).await;
+        result
+    }
+
+    // over the next stream with room for them, dropping any whose writer is gone, only once they're all full is one
+    // waited for, and once none are left the datagrams are lost
+    async fn send(&self, mut frames: Vec<u8>) {
+        let mut full = 0;
         loop {
-            tcp_rd.read_exact(&mut send_buf[..2]).await?;
-            let len = ((send_buf[0] as usize) << 8) + send_buf[1] as usize;
-            #[cfg(feature = "verbose")]
-            println!("tcp expecting len: {}", len);
-            tcp_rd.read_exact(&mut send_buf[..len]).await?;
-            #[cfg(feature = "verbose")]
-            println!("tcp got len: {}", len);
-            udp_wr.send(&send_buf[..len]).await?;
+            let (id, mut link, len) = {
+                let mut links = self.links.lock().unwrap();
+                let len = links.len();
+                match links.next() {
+                    Some((id, link)) => (id, link.clone(), len),
+                    None => return,
+                }
+            };
+            let result = match full < len {
+                true => link.try_send(frames),
+                false => link.send(frames).await.map_err(TrySendError::from),
+            };
+            frames = match result {
+                Ok(()) => return,
+                Err(TrySendError::Full(frames)) => {
+                    full += 1;
+                    frames
+                }
+                Err(TrySendError::Closed(frames)) => {
+                    self.links.lock().unwrap().remove(id);
+                    frames
+                }
+            };
         }
+    }
 
-        #[allow(unreachable_code)]
-            {
-                unsafe { std::hint::unreachable_unchecked(); }
-                Ok(0)
+    async fn tcp_to_udp<R: AsyncReadExt + std::marker::Unpin>(&self, mut tcp_rd: R, mut reader: FrameReader) -> Result<usize> {
+        loop {
+            loop {
+                while reader.control()?.is_some() {}
+                let frames = reader.frames(BATCH)?;
+                if frames.is_empty() {
+                    break;
+                }
+                let mut udp_wr = self.udp_wr.lock().await;
+                let sent = udp_wr.try_send_many(&frames);
+                for frame in &frames[sent..] {
+                    udp_wr.send(frame).await?;
+                }
             }
+            reader.fill_async(&mut tcp_rd).await?;
+        }
     }
 }
 
-async fn send_udp<T: AsyncWriteExt + std::marker::Unpin + 'static>(buf: &mut [u8; 2050], tcp_stream: &mut T, len: usize) -> Result<()> {
-    #[cfg(feature = "verbose")]
-    println!("udp got len: {}", len);
+// what the bond gives this stream to write, until it drops the stream
+async fn link_to_tcp<W: AsyncWriteExt + std::marker::Unpin>(mut link_rx: mpsc::Receiver<Vec<u8>>, mut tcp_wr: W) -> Result<usize> {
+    while let Some(frames) = link_rx.recv().await {
+        tcp_wr.write_all(&frames).await?;
+        tcp_wr.flush().await?;
+    }
+    Err(Error::protocol("bond endpoint closed"))
+}
+
+// several local wireguards share the tunnel, each source address is a peer and PEER frames say whose datagrams follow
+async fn shuffle_peers<T: AsyncReadExt + AsyncWriteExt + std::marker::Unpin + std::marker::Send + 'static>(tcp_stream: T, udp_socket: UdpSocket, reader: FrameReader, allowed: AllowedSources, max_datagram: usize) -> Result<usize> {
+    let (tcp_rd, tcp_wr) = tokio::io::split(tcp_stream);
+    let (udp_rd, udp_wr) = udp_socket.split();
+    let peers = Arc::new(Mutex::new(Peers::new(allowed)));
+
+    tokio::select! {
+        result = local_to_tcp(udp_rd, tcp_wr, peers.clone(), max_datagram) => result,
+        result = tcp_to_local(tcp_rd, udp_wr, reader, peers) => result,
+    }
+}
 
-    buf[0] = ((len >> 8) & 0xFF) as u8;
-    buf[1] = (len & 0xFF) as u8;
+async fn local_to_tcp<W: AsyncWriteExt + std::marker::Unpin>(mut udp_rd: RecvHalf, mut tcp_wr: W, peers: Arc<Mutex<Peers>>, max_datagram: usize) -> Result<usize> {
+    let mut buf = vec![0u8; max_datagram + 1];
+    let mut frames = PeerFrames::new(max_datagram);
+    loop {
+        let (len, src_addr) = udp_rd.recv_from(&mut buf).await?;
+        if len > max_datagram {
+            println!("dropping truncated udp datagram, larger than max datagram size {}", max_datagram);
+            continue;
+        }
+        let id = match peers.lock().unwrap().id(src_addr) {
+            Some(id) => id,
+            None => continue,
+        };
+        frames.push(id, &buf[..len])?;
+        tcp_wr.write_all(frames.as_bytes()).await?;
+        tcp_wr.flush().await?;
+        frames.clear();
+    }
+}
 
-    // todo: tcp_stream.write_all(&buf[..len + 2]).await
-    Ok(tcp_stream.write_all(&buf[..len + 2]).await?)
-    // todo: do this? self.tcp_stream.flush()
+async fn tcp_to_local<R: AsyncReadExt + std::marker::Unpin>(mut tcp_rd: R, mut udp_wr: SendHalf, mut reader: FrameReader, peers: Arc<Mutex<Peers>>) -> Result<usize> {
+    let mut current = None;
+    loop {
+        while let Some((id, frames)) = control::peer_frames(&mut reader, &mut current)? {
+            let addr = peers.lock().unwrap().addr(id)
+                .ok_or_else(|| Error::protocol(format!("datagram for unknown local peer {}", id)))?;
+            for frame in frames {
+                udp_wr.send_to(frame, &addr).await?;
+            }
+        }
+        reader.fill_async(&mut tcp_rd).await?;
+    }
 }
 
 impl ProxyClient {
 
     pub async fn start_async(&self) -> Result<usize> {
-        let tcp_stream = self.tcp_connect()?;
+        self.start_with_async(&Plain).await
+    }
+
+    pub async fn start_tls_async(&self, hostname: Option<&str>, pinnedpubkey: Option<&str>) -> Result<usize> {
+        let config = TlsClientConfig {
+            hostname: hostname.map(str::to_owned),
+            pinnedpubkey: pinnedpubkey.map(str::to_owned),
+        };
+        self.start_with_async(&TlsTransport::new(&config)?).await
+    }
+
+    /// connects and runs the tunnel over whatever transport wraps the connection
+    pub async fn start_with_async(&self, transport: &dyn Transport) -> Result<usize> {
+        let (links, connected_status) = self.connect_target_async(&self.tcp_target, transport, true).await?;
 
         let udp_socket = self.udp_connect()?;
+        notify_ready(&self.on_ready, &format!("{}, listening on {}", self.connected(&self.tcp_target, transport), &self.udp_host));
+        spawn_watchdog();
 
-        TcpUdpPipe::new(tokio::net::TcpStream::from_std(tcp_stream).expect("how could this tokio tcp fail?"), UdpSocket::from_std(udp_socket).expect("how could this tokio udp fail?"))
-            .shuffle_after_first_udp().await
+        let udp_socket = UdpSocket::from_std(udp_socket).expect("how could this tokio udp fail?");
+        self.tunnel_async(links, udp_socket, &mut None, connected_status).await
     }
 
-    pub fn start(&self) -> Result<usize> {
-        let mut rt = Runtime::new()?;
+    /// like start_with_async() but exchanging datagrams with endpoint instead of listening on udp_host
+    pub async fn start_with_endpoint_async(&self, transport: &dyn Transport, endpoint: BoxEndpoint) -> Result<usize> {
+        let (stream, connected_status) = self.connect_transport_async(&self.tcp_target, transport, true).await?;
+        self.report(connected_status);
 
-        rt.block_on(async {
-            self.start_async().await
-        })
+        notify_ready(&self.on_ready, &self.connected(&self.tcp_target, transport));
+        spawn_watchdog();
+
+        TcpUdpPipe::new(stream, endpoint, self.max_datagram).shuffle().await
     }
 
-    pub async fn start_tls_async(&self, hostname: Option<&str>, pinnedpubkey: Option<&str>) -> Result<usize> {
-        let tcp_stream = self.tcp_connect()?;
-        let tcp_stream = tokio::net::TcpStream::from_std(tcp_stream).expect("how could this tokio tcp fail?");
+    /// run_async() with fallback_targets, connects to the first target in target_order that can be, and while on
+    /// a fallback tries the ones ahead of it every fail_back, moving the tunnel over once one connects
+    pub async fn start_failover_async(&self) -> Result<usize> {
+        let targets = self.targets()?;
+        let (order, mut pos, mut connection) = self.connect_first_async(&targets).await?;
 
-        use tokio_rustls::{ TlsConnector, rustls::ClientConfig };
+        let udp_socket = self.udp_connect()?;
+        spawn_watchdog();
 
-        let mut config = ClientConfig::new();
-        config.dangerous().set_certificate_verifier(match pinnedpubkey {
-            Some(pinnedpubkey) => Arc::new(PinnedpubkeyCertVerifier { pinnedpubkey: pinnedpubkey.to_owned() }),
-            None => Arc::new(DummyCertVerifier{}),
-        });
+        // where the local wireguard is, so a tunnel after failing back doesn't wait for it to send first
+        let mut reply_to = None;
+        loop {
+            let (tcp_target, transport) = &targets[order[pos]];
+            notify_ready(&self.on_ready, &format!("{}, listening on {}", self.connected(tcp_target, transport.as_ref()), &self.udp_host));
 
-        let hostname = match hostname {
-            Some(hostname) => match DNSNameRef::try_from_ascii_str(hostname) {
-                Ok(hostname) => hostname,
-                Err(_) => {
-                    config.enable_sni = false;
-                    DNSNameRef::try_from_ascii_str(&"dummy.hostname").unwrap() // why does rustls ABSOLUTELY REQUIRE this ????
+            let (links, connected_status) = connection;
+            let udp = UdpSocket::from_std(udp_socket.try_clone()?).expect("how could this tokio udp fail?");
+            let tunnel = self.tunnel_async(links, udp, &mut reply_to, connected_status);
+            let fail_back = match self.fail_back {
+                Some(fail_back) if pos > 0 => fail_back,
+                _ => return tunnel.await,
+            };
+            tokio::select! {
+                result = tunnel => return result,
+                (better, better_connection) = self.probe_async(&targets, &order[..pos], fail_back) => {
+                    println!("{} is reachable again, failing back to it", targets[order[better]].0);
+                    pos = better;
+                    connection = better_connection;
                 }
-            },
+            }
+        }
+    }
+
+    // the targets in target_order and the position in it of the first one that connects
+    async fn connect_first_async(&self, targets: &Targets) -> Result<(Vec<usize>, usize, Connection)> {
+        let order = match self.target_order {
+            TargetOrder::Listed => (0..targets.len()).collect(),
+            TargetOrder::Latency => {
+                let mut latencies = Vec::with_capacity(targets.len());
+                for (tcp_target, _) in targets.iter() {
+                    let start = Instant::now();
+                    latencies.push(self.connect_async(tcp_target).await.ok().map(|_| start.elapsed()));
+                }
+                failover::by_latency(&latencies)
+            }
+        };
+        let mut last_err = None;
+        for (pos, i) in order.iter().enumerate() {
+            let (tcp_target, transport) = &targets[*i];
+            match self.connect_target_async(tcp_target, transport.as_ref(), true).await {
+                Ok(connection) => return Ok((order, pos, connection)),
+                Err(e) => {
+                    println!("could not connect to {}: {}", tcp_target, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.expect("there is always tcp_target"))
+    }
+
+    // every interval tries candidates in order, returning the position of the first that connects
+    async fn probe_async(&self, targets: &Targets, candidates: &[usize], interval: Duration) -> (usize, Connection) {
+        loop {
+            tokio::time::delay_for(interval).await;
+            for (pos, i) in candidates.iter().enumerate() {
+                let (tcp_target, transport) = &targets[*i];
+                if let Ok(connection) = self.connect_target_async(tcp_target, transport.as_ref(), false).await {
+                    return (pos, connection);
+                }
+            }
+        }
+    }
+
+    // the tunnel over already connected streams, reply_to is where the local wireguard was last seen
+    async fn tunnel_async(&self, links: Vec<Link>, mut udp_socket: UdpSocket, reply_to: &mut Option<Arc<ReplyTo>>, connected: Status) -> Result<usize> {
+        let mut links: Vec<_> = links.into_iter()
+            .map(|(stream, reader)| (stream, reader.unwrap_or_else(|| FrameReader::new(self.max_datagram))))
+            .collect();
+        if self.multi_peer {
+            let (stream, reader) = links.pop().expect("multi peer tunnels aren't bonded");
+            self.report(connected);
+            return shuffle_peers(stream, udp_socket, reader, self.allowed_sources.clone(), self.max_datagram).await;
+        }
+        let reply_to = match reply_to {
+            Some(reply_to) => reply_to.clone(),
             None => {
-                config.enable_sni = false;
-                DNSNameRef::try_from_ascii_str(&"dummy.hostname").unwrap() // why does rustls ABSOLUTELY REQUIRE this ????
+                // we want to wait for first udp packet from client first, to set the target to respond to
+                self.report(Status::WaitingForWireGuard);
+                let src_addr = first_udp(&mut udp_socket, &mut links[0].0, &self.allowed_sources, self.max_datagram).await?;
+                reply_to.insert(Arc::new(ReplyTo::new(src_addr, self.allowed_sources.clone()))).clone()
             }
         };
-        //println!("hostname: {:?}", hostname);
+        self.report(connected);
+        let endpoint = Box::new(LocalUdp::new(udp_socket, reply_to));
+        if links.len() > 1 {
+            return Bond::new(endpoint, None, self.max_datagram)?.run(links).await;
+        }
+        let (stream, reader) = links.pop().expect("a tunnel has a stream");
+        TcpUdpPipe::new(stream, endpoint, self.max_datagram).with_reader(reader).shuffle().await
+    }
 
-        let connector = TlsConnector::from(Arc::new(config));
+    // connected, wrapped by transport and said hello if need be, reporting how it goes unless just probing,
+    // and when bonding the rest of the bond's streams connected the same way without reporting
+    async fn connect_target_async(&self, tcp_target: &str, transport: &dyn Transport, report: bool) -> Result<Connection> {
+        let hello = self.hello(transport)?;
+        let (stream, connected) = self.connect_transport_async(tcp_target, transport, report).await?;
+        let hello = match hello {
+            Some(hello) => hello,
+            None => return Ok((vec![(stream, None)], connected)),
+        };
+        let stats = Arc::new(CompressionStats::default());
+        let mut links = vec![self.say_hello_async(stream, &hello, &stats).await?];
+        for _ in 1..self.bond {
+            let (stream, _) = self.connect_transport_async(tcp_target, transport, false).await?;
+            links.push(self.say_hello_async(stream, &hello, &stats).await?);
+        }
+        Ok((links, connected))
+    }
 
-        let tcp_stream= connector.connect(hostname, tcp_stream).await?;
+    async fn say_hello_async(&self, mut stream: BoxStream, hello: &Hello, stats: &Arc<CompressionStats>) -> Result<Link> {
+        let (reader, agreed) = hello_async(&mut stream, hello, self.max_datagram).await?;
+        let (stream, reader) = self.compressed(stream, reader, &agreed, stats);
+        Ok((stream, Some(reader)))
+    }
 
-        let udp_socket = self.udp_connect()?;
+    async fn connect_transport_async(&self, tcp_target: &str, transport: &dyn Transport, report: bool) -> Result<(BoxStream, Status)> {
+        if report {
+            self.report(Status::Connecting { target: tcp_target.to_owned() });
+        }
+        let (stream, tls) = match quic_addr(tcp_target) {
+            Some(_) if self.http2.is_some() => return Err(Error::config("http2 can't be used with quic: targets")),
+            Some(addr) => self.quic_connect_async(addr, transport).await?,
+            None => {
+                let stream = self.connect_async(tcp_target).await?;
+                if report && transport.name().is_some() {
+                    self.report(Status::TlsHandshaking { target: tcp_target.to_owned() });
+                }
+                match &self.http2 {
+                    Some(path) => self.http2_connect_async(stream, tcp_target, path, transport).await?,
+                    None => transport.connect_info(stream).await?,
+                }
+            }
+        };
+        let stream = padded(stream, &self.padding, self.max_datagram)?;
+        let server_pubkey = tls.and_then(|tls| tls.pinnedpubkey);
+        Ok((stream, Status::Connected { target: tcp_target.to_owned(), server_pubkey }))
+    }
 
-        // we want to wait for first udp packet from client first, to set the target to respond to
-        TcpUdpPipe::new(tcp_stream, UdpSocket::from_std(udp_socket).expect("how could this tokio udp fail?"))
-            .shuffle_after_first_udp().await
+    async fn connect_async(&self, tcp_target: &str) -> Result<BoxStream> {
+        if let Some(addr) = quic_addr(tcp_target) {
+            return Ok(self.quic_connect_async(addr, &Plain).await?.0);
+        }
+        #[cfg(unix)]
+        {
+            if let Some(path) = unix_path(tcp_target) {
+                return Ok(Box::new(tokio::net::UnixStream::from_std(self.unix_connect(path)?).expect("how could this tokio unix fail?")));
+            }
+        }
+
+        Ok(Box::new(self.tcp_connect_async(tcp_target).await?))
+    }
+
+    // resolves tcp_target afresh every time and races its addresses, not std's blocking connect that tries
+    // them one by one, that stalls on a broken ipv6 network and holds up the tunnel during a fail back probe
+    async fn tcp_connect_async(&self, tcp_target: &str) -> Result<TcpStream> {
+        self.within_connect_timeout(tcp_target, async {
+            let addrs = eyeballs::interleave(tokio::net::lookup_host(tcp_target).await?.collect());
+            race(addrs).await
+        }).await
+    }
+
+    // a quic: target over QUIC's own TLS, with the hostname and pinnedpubkey transport would have used
+    #[cfg(feature = "quic")]
+    async fn quic_connect_async(&self, addr: &str, transport: &dyn Transport) -> Result<(BoxStream, Option<TlsInfo>)> {
+        if let (None, Some(name)) = (transport.tls(), transport.name()) {
+            return Err(Error::Config(format!("quic: targets can't be combined with {}", name)));
+        }
+        self.within_connect_timeout(addr, quic::connect(addr, transport.tls())).await
+    }
+
+    #[cfg(not(feature = "quic"))]
+    async fn quic_connect_async(&self, _addr: &str, _transport: &dyn Transport) -> Result<(BoxStream, Option<TlsInfo>)> {
+        Err(Error::config("quic: targets need the async build with the quic feature"))
+    }
+
+    // HTTP/2 over TLS with the hostname and pinnedpubkey transport would have used, offering h2 in ALPN so front ends
+    // speak it, or over transport as is when it isn't TLS, then the tunnel's POST to path
+    #[cfg(feature = "http2")]
+    async fn http2_connect_async(&self, stream: BoxStream, tcp_target: &str, path: &str, transport: &dyn Transport) -> Result<(BoxStream, Option<TlsInfo>)> {
+        let (stream, tls) = match transport.tls() {
+            Some(config) => TlsTransport::new(config)?.alpn(&[http2::ALPN]).connect_info(stream).await?,
+            None => transport.connect_info(stream).await?,
+        };
+        let authority = match transport.tls().and_then(|config| config.hostname.as_deref()) {
+            Some(hostname) => hostname,
+            None if unix_path(tcp_target).is_some() => "localhost",
+            None => tcp_target,
+        };
+        Ok((http2::connect(stream, authority, path, tls.is_some()).await?, tls))
+    }
+
+    #[cfg(not(feature = "http2"))]
+    async fn http2_connect_async(&self, _stream: BoxStream, _tcp_target: &str, _path: &str, _transport: &dyn Transport) -> Result<(BoxStream, Option<TlsInfo>)> {
+        Err(Error::config("http2 needs the async build with the http2 feature"))
+    }
+
+    async fn within_connect_timeout<T, F: Future<Output = Result<T>>>(&self, target: &str, connect: F) -> Result<T> {
+        match self.connect_timeout {
+            Some(connect_timeout) => tokio::time::timeout(connect_timeout, connect).await.map_err(|_| {
+                Error::Timeout(io::Error::new(io::ErrorKind::TimedOut, format!("connecting to {} took over {:?}", target, connect_timeout)))
+            })?,
+            None => connect.await,
+        }
+    }
+
+    /// start_with_async() using self.transport, else start_tls_async() or start_async() depending on self.tls,
+    /// start_failover_async() if there are fallback_targets, all over again after reconnect when it fails
+    pub async fn run_async(&self) -> Result<usize> {
+        let mut attempt = 0;
+        loop {
+            match self.run_once_async().await {
+                Ok(sent) => return Ok(sent),
+                Err(e) => tokio::time::delay_for(self.reconnect_after(e, &mut attempt)?).await,
+            }
+        }
+    }
+
+    async fn run_once_async(&self) -> Result<usize> {
+        if !self.fallback_targets.is_empty() {
+            return self.start_failover_async().await;
+        }
+        if let Some(transport) = &self.transport {
+            return self.start_with_async(transport.as_ref()).await;
+        }
+        match &self.tls {
+            Some(tls) => self.start_tls_async(tls.hostname.as_deref(), tls.pinnedpubkey.as_deref()).await,
+            None => self.start_async().await,
+        }
+    }
+
+    pub fn start(&self) -> Result<usize> {
+        let mut rt = Runtime::new()?;
+
+        rt.block_on(async {
+            self.start_async().await
+        })
     }
 
     pub fn start_tls(&self, hostname: Option<&str>, pinnedpubkey: Option<&str>) -> Result<usize> {
@@ -153,75 +661,91 @@ impl ProxyClient {
             self.start_tls_async(hostname, pinnedpubkey).await
         })
     }
-}
-
-use tokio_rustls::rustls;
-use tokio_rustls::webpki;
 
-struct DummyCertVerifier;
+    pub fn start_with(&self, transport: &dyn Transport) -> Result<usize> {
+        let mut rt = Runtime::new()?;
 
-impl rustls::ServerCertVerifier for DummyCertVerifier {
-    fn verify_server_cert(&self,
-                          _roots: &rustls::RootCertStore,
-                          _certs: &[rustls::Certificate],
-                          _hostname: webpki::DNSNameRef<'_>,
-                          _ocsp: &[u8]) -> core::result::Result<rustls::ServerCertVerified, rustls::TLSError> {
-        // verify nothing, subject to MITM
-        Ok(rustls::ServerCertVerified::assertion())
+        rt.block_on(async {
+            self.start_with_async(transport).await
+        })
     }
-}
 
-struct PinnedpubkeyCertVerifier {
-    pinnedpubkey: String,
-}
+    pub fn start_with_endpoint(&self, transport: &dyn Transport, endpoint: BoxEndpoint) -> Result<usize> {
+        let mut rt = Runtime::new()?;
 
-impl rustls::ServerCertVerifier for PinnedpubkeyCertVerifier {
-    fn verify_server_cert(&self,
-                          _roots: &rustls::RootCertStore,
-                          certs: &[rustls::Certificate],
-                          _hostname: webpki::DNSNameRef<'_>,
-                          _ocsp: &[u8]) -> core::result::Result<rustls::ServerCertVerified, rustls::TLSError> {
-        if certs.is_empty() {
-            return Err(rustls::TLSError::NoCertificatesPresented);
-        }
-        let cert = webpki::trust_anchor_util::cert_der_as_trust_anchor(&certs[0].0)
-            .map_err(rustls::TLSError::WebPKIError)?;
-
-        //println!("spki.len(): {}", cert.spki.len());
-        //println!("spki: {:?}", cert.spki);
-        // todo: what is wrong with webpki? it returns *almost* the right answer but missing these leading bytes:
-        // guess I'll open an issue... (I assume this is some type of algorithm identifying header or something)
-        let mut pubkey: Vec<u8> = vec![48, 130, 1, 34];
-        pubkey.extend(cert.spki);
-
-        let pubkey = ring::digest::digest(&ring::digest::SHA256, &pubkey);
-        let pubkey = base64::encode(pubkey);
-        let pubkey = ["sha256//", &pubkey].join("");
-
-        for key in self.pinnedpubkey.split(";") {
-            if key == pubkey {
-                return Ok(rustls::ServerCertVerified::assertion());
-            }
-        }
+        rt.block_on(async {
+            self.start_with_endpoint_async(transport, endpoint).await
+        })
+    }
 
-        Err(rustls::TLSError::General(format!("pubkey '{}' not found in allowed list '{}'", pubkey, self.pinnedpubkey)))
+    pub fn start_failover(&self) -> Result<usize> {
+        let mut rt = Runtime::new()?;
+
+        rt.block_on(async {
+            self.start_failover_async().await
+        })
     }
 }
 
 impl ProxyServer {
 
     pub async fn start_async(&self) -> Result<()> {
-        let mut listener = tokio::net::TcpListener::bind(&self.tcp_host).await?;
-        println!("Listening for connections on {}", &self.tcp_host);
+        self.start_with_async(Arc::new(Plain)).await
+    }
+
+    pub async fn start_tls_async(&self, tls_key: &str, tls_cert: &str) -> Result<()> {
+        let acceptor = TlsAcceptor::new(&TlsServerConfig::new(tls_key, tls_cert))?;
+        self.start_with_async(Arc::new(acceptor)).await
+    }
+
+    /// listens and runs a tunnel for each connection, after the acceptor wraps it
+    pub async fn start_with_async(&self, acceptor: Arc<dyn Acceptor>) -> Result<()> {
+        if let Some(addr) = quic_addr(&self.tcp_host) {
+            if self.http2.is_some() {
+                return Err(Error::config("http2 can't be used with a quic: tcp_host"));
+            }
+            if self.proxy_protocol.is_some() {
+                return Err(Error::config("proxy_protocol can't be used with a quic: tcp_host"));
+            }
+            let _admin = self.admin_listen()?;
+            return self.quic_listen_async(addr, acceptor).await;
+        }
+
+        self.check_proxy_protocol()?;
+        let acceptor = match &self.http2 {
+            Some(_) => self.http2_acceptor(acceptor)?,
+            None => acceptor,
+        };
+        let connections = match (&self.http2, acceptor.name()) {
+            (Some(_), Some(name)) => format!("HTTP/2 over {} connections", name),
+            (Some(_), None) => "HTTP/2 connections".to_owned(),
+            (None, Some(name)) => format!("{} connections", name),
+            (None, None) => "connections".to_owned(),
+        };
+        let _admin = self.admin_listen()?;
+
+        #[cfg(unix)]
+        {
+            if let Some(path) = unix_path(&self.tcp_host) {
+                let (listener, _socket_file) = self.unix_listener(path)?;
+                let mut listener = tokio::net::UnixListener::from_std(listener)?;
+                notify_ready(&self.on_ready, &format!("Listening for {} on unix:{}", connections, path));
+                spawn_watchdog();
+
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    self.spawn_client(acceptor.clone(), Box::new(stream), format!("unix:{}", path), None);
+                }
+            }
+        }
+
+        let mut listener = tokio::net::TcpListener::from_std(self.tcp_listener()?)?;
+        notify_ready(&self.on_ready, &format!("Listening for {} on {}", connections, listener.local_addr()?));
+        spawn_watchdog();
 
         loop {
-            let (stream, _) = listener.accept().await?;
-            let client_handler = self.client_handler.clone();
-            tokio::spawn(async move {
-                client_handler
-                    .handle_client_async(stream).await
-                    .expect("error handling connection");
-            });
+            let (stream, addr) = listener.accept().await?;
+            self.spawn_client(acceptor.clone(), Box::new(stream), addr.to_string(), Some(addr));
         }
 
         #[allow(unreachable_code)]
@@ -231,6 +755,66 @@ impl ProxyServer {
             }
     }
 
+    // a quic: tcp_host, with the key and cert acceptor would have used, each tunnel's stream is run like a
+    // connection acceptor took apart from its TLS being done already
+    #[cfg(feature = "quic")]
+    async fn quic_listen_async(&self, addr: &str, acceptor: Arc<dyn Acceptor>) -> Result<()> {
+        let tls = acceptor.tls().ok_or_else(|| Error::config("quic: needs a tls key and cert to listen with"))?;
+        let mut listener = quic::Listener::bind(addr, tls)?;
+        notify_ready(&self.on_ready, &format!("Listening for QUIC connections on {}", listener.local_addr()));
+        spawn_watchdog();
+
+        loop {
+            let (stream, addr, tls) = listener.accept().await?;
+            self.spawn_client(Arc::new(transport::Handshaken(Some(tls))), Box::new(stream), addr.to_string(), Some(addr));
+        }
+    }
+
+    #[cfg(not(feature = "quic"))]
+    async fn quic_listen_async(&self, _addr: &str, _acceptor: Arc<dyn Acceptor>) -> Result<()> {
+        Err(Error::config("quic: needs the async build with the quic feature"))
+    }
+
+    // the key and cert acceptor would have used with h2 accepted in ALPN, so front ends speak it, or acceptor as is
+    // when it isn't TLS
+    #[cfg(feature = "http2")]
+    fn http2_acceptor(&self, acceptor: Arc<dyn Acceptor>) -> Result<Arc<dyn Acceptor>> {
+        Ok(match acceptor.tls() {
+            Some(config) => Arc::new(TlsAcceptor::new(config)?.alpn(&[http2::ALPN])),
+            None => acceptor,
+        })
+    }
+
+    #[cfg(not(feature = "http2"))]
+    fn http2_acceptor(&self, _acceptor: Arc<dyn Acceptor>) -> Result<Arc<dyn Acceptor>> {
+        Err(Error::config("http2 needs the async build with the http2 feature"))
+    }
+
+    fn spawn_client(&self, acceptor: Arc<dyn Acceptor>, stream: BoxStream, peer: String, addr: Option<SocketAddr>) {
+        let spawner = Spawner {
+            sessions: self.sessions.clone(),
+            client_handler: self.client_handler.clone(),
+            padding: self.padding.clone(),
+            http2: self.http2.clone(),
+        };
+        match self.proxy_mode(addr) {
+            Ok(Some(mode)) => spawner.spawn_proxied(mode, acceptor, stream, peer),
+            Ok(None) => spawner.spawn_connection(acceptor, stream, peer),
+            Err(e) => self.client_handler.tunnel_closed(&peer, &e),
+        }
+    }
+
+    /// start_with_async() using self.acceptor, else start_tls_async() or start_async() depending on self.tls
+    pub async fn run_async(&self) -> Result<()> {
+        if let Some(acceptor) = &self.acceptor {
+            return self.start_with_async(acceptor.clone()).await;
+        }
+        match &self.tls {
+            Some(tls) => self.start_tls_async(&tls.key, &tls.cert).await,
+            None => self.start_async().await,
+        }
+    }
+
     pub fn start(&self) -> Result<()> {
         let mut rt = Runtime::new()?;
 
@@ -239,66 +823,360 @@ impl ProxyServer {
         })
     }
 
-    pub async fn start_tls_async(&self, tls_key: &str, tls_cert: &str) -> Result<()> {
+    pub fn start_tls(&self, tls_key: &str, tls_cert: &str) -> Result<()> {
+        let mut rt = Runtime::new()?;
 
-        use std::fs::File;
-        use std::io::BufReader;
-        use std::io;
-        use tokio_rustls::rustls::internal::pemfile::{ certs, pkcs8_private_keys };
+        rt.block_on(async {
+            self.start_tls_async(tls_key, tls_cert).await
+        })
+    }
+
+    pub fn start_with(&self, acceptor: Arc<dyn Acceptor>) -> Result<()> {
+        let mut rt = Runtime::new()?;
+
+        rt.block_on(async {
+            self.start_with_async(acceptor).await
+        })
+    }
+}
 
-        let mut tls_key = pkcs8_private_keys(&mut BufReader::new(File::open(tls_key)?))
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))?;
-        if tls_key.is_empty() {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))?;
+// what spawning a tunnel takes, so an HTTP/2 connection's task can spawn one for each of its streams and a
+// connection's task can spawn its own once its PROXY protocol header is read
+struct Spawner {
+    sessions: Arc<Sessions>,
+    client_handler: Arc<ProxyServerClientHandler>,
+    padding: Option<Padding>,
+    http2: Option<String>,
+}
+
+impl Spawner {
+    // the header is read in a task of its own so a slow one doesn't hold up the listener, the client it names is
+    // the peer of the connection's tunnels from then on
+    fn spawn_proxied(self, mode: ProxyProtocol, acceptor: Arc<dyn Acceptor>, stream: BoxStream, peer: String) {
+        tokio::spawn(async move {
+            let timeout = self.client_handler.socket_timeout.unwrap_or(proxyproto::HEADER_TIMEOUT);
+            match transport::proxied(stream, mode, timeout).await {
+                Ok((stream, source)) => self.spawn_connection(acceptor, stream, source.map_or(peer, |source| source.to_string())),
+                Err(e) => self.client_handler.tunnel_closed(&peer, &e),
+            }
+        });
+    }
+
+    // a tunnel, or with http2 one for each of the connection's streams
+    fn spawn_connection(self, acceptor: Arc<dyn Acceptor>, stream: BoxStream, peer: String) {
+        match self.http2.clone() {
+            #[cfg(feature = "http2")]
+            Some(path) => self.spawn_http2(acceptor, stream, peer, path),
+            _ => self.spawn(acceptor, stream, peer),
         }
-        let tls_key = tls_key.remove(0);
+    }
 
-        let tls_cert = certs(&mut BufReader::new(File::open(tls_cert)?))
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))?;
+    // a failing connection only takes down its own tunnel, the listener carries on
+    fn spawn(&self, acceptor: Arc<dyn Acceptor>, stream: BoxStream, peer: String) {
+        if !self.sessions.accepting() {
+            println!("not accepting new tunnels, closing connection from {}", peer);
+            return;
+        }
+        let client_handler = self.client_handler.clone();
+        let padding = self.padding.clone();
+        let (kill_tx, kill_rx) = oneshot::channel::<()>();
+        let open = self.sessions.open(&peer, Box::new(move || { kill_tx.send(()).ok(); }));
+        tokio::spawn(async move {
+            let session = open.session.clone();
+            let tunnel = async {
+                let (stream, tls) = acceptor.accept_info(stream).await?;
+                if let Some(tls) = tls {
+                    session.set_tls(tls);
+                }
+                let stream = padded(stream, &padding, client_handler.max_datagram)?;
+                let stream = Counted::new(stream, session.bytes.clone());
+                client_handler.handle_tunnel_async(stream, Some(session.clone()), acceptor.protected()).await
+            };
+            let result = tokio::select! {
+                result = tunnel => result,
+                _ = kill_rx => Err(Error::Killed),
+            };
+            if let Err(e) = result {
+                client_handler.tunnel_closed(&peer, &e);
+            }
+            drop(open);
+        });
+    }
 
-        let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
-        config.set_single_cert(tls_cert, tls_key)
-            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
-        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+    // every POST to path over the connection is a tunnel with a session of its own, the connection failing takes
+    // down whichever of them are left
+    #[cfg(feature = "http2")]
+    fn spawn_http2(self, acceptor: Arc<dyn Acceptor>, stream: BoxStream, peer: String, path: String) {
+        tokio::spawn(async move {
+            let connection = async {
+                let (stream, tls) = acceptor.accept_info(stream).await?;
+                let handshaken: Arc<dyn Acceptor> = Arc::new(transport::Handshaken(tls));
+                http2::serve(stream, &path, |stream| self.spawn(handshaken.clone(), stream, peer.clone())).await
+            };
+            if let Err(e) = connection.await {
+                self.client_handler.tunnel_closed(&peer, &e);
+            }
+        });
+    }
+}
 
-        let mut listener = tokio::net::TcpListener::bind(&self.tcp_host).await?;
-        println!("Listening for TLS connections on {}", &self.tcp_host);
+// runs on the same runtime as the listener so it only pings systemd if the runtime is alive
+fn spawn_watchdog() {
+    if let Some(interval) = watchdog_interval() {
+        tokio::spawn(async move {
+            loop {
+                notify_watchdog();
+                tokio::time::delay_for(interval).await;
+            }
+        });
+    }
+}
 
-        loop {
-            let (stream, _) = listener.accept().await?;
-            let client_handler = self.client_handler.clone();
-            let acceptor = acceptor.clone();
+impl ProxyServerClientHandler {
 
-            tokio::spawn(async move {
-                let stream = acceptor.accept(stream).await.expect("failed to wrap with TLS?");
+    /// doesn't offer resuming, as there's no telling whether tcp_stream is protected
+    pub async fn handle_client_async<T: AsyncReadExt + AsyncWriteExt + std::marker::Unpin + std::marker::Send + 'static>(&self, tcp_stream: T) -> Result<usize> {
+        self.handle_tunnel_async(tcp_stream, None, false).await
+    }
 
-                client_handler
-                    .handle_client_async(stream).await
-                    .expect("error handling connection");
+    async fn handle_tunnel_async<T: AsyncReadExt + AsyncWriteExt + std::marker::Unpin + std::marker::Send + 'static>(&self, tcp_stream: T, session: Option<Arc<Session>>, protected: bool) -> Result<usize> {
+        // boxed as compression wraps it in one more layer if the client asks for it
+        let mut tcp_stream: BoxStream = Box::new(tcp_stream);
+        // nothing is bound until the client sends something, a client wanting more than plain datagrams says hello first
+        let mut reader = FrameReader::new(self.max_datagram);
+        while !reader.ready()? {
+            reader.fill_async(&mut tcp_stream).await?;
+        }
+        let tunnel = self.tunnel(session);
+        if let Some(hello) = control::hello(&mut reader)? {
+            let agreed = control::agree(&hello, self.offered(protected));
+            tcp_stream.write_all(&agreed.frame()).await?;
+            tcp_stream.flush().await?;
+            let (compressed, rest) = self.compressed(tcp_stream, reader, &agreed, &tunnel);
+            tcp_stream = compressed;
+            reader = rest;
+            if agreed.has(control::MULTI_PEER) {
+                return self.shuffle_peers_async(tcp_stream, reader, &tunnel).await;
+            }
+            // the first stream of a bond makes its endpoint, the rest and any resuming the tunnel join it
+            if let Some(id) = agreed.id {
+                let bond = self.bonds.join(&id, || Bond::new(self.endpoint(&tunnel)?, tunnel.upstreams.clone(), self.max_datagram))?;
+                let result = bond.link(tcp_stream, reader).await;
+                if agreed.has(control::RESUME) {
+                    self.linger(bond, &tunnel);
+                }
+                return result;
+            }
+        }
+        TcpUdpPipe::new(tcp_stream, self.endpoint(&tunnel)?, self.max_datagram).with_reader(reader).shuffle().await
+    }
+
+    // keeps a tunnel that resumes for the grace period after a stream of it is gone, unless it was killed
+    fn linger(&self, bond: Arc<Bond>, tunnel: &Tunnel) {
+        if let Some(grace) = self.resume.filter(|_| !tunnel.killed()) {
+            tokio::spawn(async move {
+                tokio::time::delay_for(grace).await;
+                drop(bond);
             });
         }
+    }
 
-        #[allow(unreachable_code)]
-            {
-                unsafe { std::hint::unreachable_unchecked(); }
-                Ok(())
+    fn endpoint(&self, tunnel: &Tunnel) -> Result<BoxEndpoint> {
+        Ok(match &self.endpoint_factory {
+            Some(endpoint_factory) => endpoint_factory()?,
+            None => {
+                let udp_socket = self.udp_bind()?;
+                self.register(tunnel, &udp_socket)?;
+                Box::new(UdpSocket::from_std(udp_socket).expect("how could this tokio udp fail?"))
             }
+        })
     }
 
-    pub fn start_tls(&self, tls_key: &str, tls_cert: &str) -> Result<()> {
-        let mut rt = Runtime::new()?;
+    // one upstream endpoint for each of the client's local peers, made when its first datagram shows up
+    async fn shuffle_peers_async<T: AsyncReadExt + AsyncWriteExt + std::marker::Unpin + std::marker::Send + 'static>(&self, tcp_stream: T, reader: FrameReader, tunnel: &Tunnel) -> Result<usize> {
+        let (tcp_rd, tcp_wr) = tokio::io::split(tcp_stream);
+        let (up_tx, up_rx) = mpsc::channel(BATCH);
 
-        rt.block_on(async {
-            self.start_tls_async(tls_key, tls_cert).await
-        })
+        tokio::select! {
+            result = upstream_to_tcp(up_rx, tcp_wr, self.max_datagram) => result,
+            result = self.tcp_to_upstream(tcp_rd, reader, up_tx, tunnel) => result,
+        }
+    }
+
+    async fn tcp_to_upstream<R: AsyncReadExt + std::marker::Unpin>(&self, mut tcp_rd: R, mut reader: FrameReader, up_tx: mpsc::Sender<Result<(u16, Vec<u8>)>>, tunnel: &Tunnel) -> Result<usize> {
+        // dropping these when the tunnel ends stops the upstream tasks
+        let mut upstream: HashMap<u16, (Box<dyn DatagramTx>, oneshot::Sender<()>)> = HashMap::new();
+        let mut current = None;
+        loop {
+            while let Some((id, frames)) = control::peer_frames(&mut reader, &mut current)? {
+                let udp_wr = match upstream.entry(id) {
+                    Entry::Occupied(entry) => &mut entry.into_mut().0,
+                    Entry::Vacant(entry) => &mut entry.insert(self.spawn_upstream(id, up_tx.clone(), tunnel)?).0,
+                };
+                let sent = udp_wr.try_send_many(&frames);
+                for frame in &frames[sent..] {
+                    udp_wr.send(frame).await?;
+                }
+            }
+            reader.fill_async(&mut tcp_rd).await?;
+        }
+    }
+
+    fn spawn_upstream(&self, id: u16, mut up_tx: mpsc::Sender<Result<(u16, Vec<u8>)>>, tunnel: &Tunnel) -> Result<(Box<dyn DatagramTx>, oneshot::Sender<()>)> {
+        let (mut udp_rd, udp_wr) = self.endpoint(tunnel)?.split();
+        let (stop_tx, mut stop_rx) = oneshot::channel::<()>();
+        let max_datagram = self.max_datagram;
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; max_datagram + 1];
+            loop {
+                let result = tokio::select! {
+                    result = udp_rd.recv(&mut buf) => result,
+                    _ = &mut stop_rx => break,
+                };
+                let datagram = match result {
+                    Ok(len) if len > max_datagram => {
+                        println!("dropping truncated udp datagram, larger than max datagram size {}", max_datagram);
+                        continue;
+                    }
+                    Ok(len) => Ok((id, buf[..len].to_vec())),
+                    Err(e) => Err(e),
+                };
+                let failed = datagram.is_err();
+                // an upstream failing takes the tunnel down with it, like it would with one peer
+                if up_tx.send(datagram).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok((udp_wr, stop_tx))
     }
 }
 
-impl ProxyServerClientHandler {
+async fn upstream_to_tcp<W: AsyncWriteExt + std::marker::Unpin>(mut up_rx: mpsc::Receiver<Result<(u16, Vec<u8>)>>, mut tcp_wr: W, max_datagram: usize) -> Result<usize> {
+    let mut frames = PeerFrames::new(max_datagram);
+    loop {
+        // wait for the first one, then take whatever else is waiting
+        let mut next = Some(up_rx.recv().await.ok_or_else(|| Error::protocol("upstream closed"))?);
+        let mut count = 0;
+        while let Some(datagram) = next {
+            let (id, datagram) = datagram?;
+            frames.push(id, &datagram)?;
+            count += 1;
+            next = if count < BATCH { up_rx.try_recv().ok() } else { None };
+        }
+        tcp_wr.write_all(frames.as_bytes()).await?;
+        tcp_wr.flush().await?;
+        frames.clear();
+    }
+}
 
-    pub async fn handle_client_async<T: AsyncReadExt + AsyncWriteExt + std::marker::Unpin + std::marker::Send + 'static>(&self, tcp_stream: T) -> Result<usize> {
-        TcpUdpPipe::new(tcp_stream,
-                                   UdpSocket::from_std(self.udp_bind()?).expect("how could this tokio udp fail?")
-        ).shuffle().await
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{FrameWriter, DEFAULT_MAX_DATAGRAM};
+
+    #[test]
+    fn test_race() {
+        Runtime::new().unwrap().block_on(async {
+            let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let listening = listener.local_addr().unwrap();
+            // nothing listens on this port over ipv6, refused straight away so ipv4 doesn't wait for ATTEMPT_DELAY
+            let refused = SocketAddr::new("::1".parse().unwrap(), listening.port());
+            let start = Instant::now();
+            let (stream, _) = tokio::join!(race(vec![refused, listening]), listener.accept());
+            assert_eq!(stream.unwrap().peer_addr().unwrap(), listening);
+            assert!(start.elapsed() < eyeballs::ATTEMPT_DELAY);
+
+            assert!(matches!(race(vec![refused]).await, Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::ConnectionRefused));
+            assert!(race(Vec::new()).await.is_err());
+        });
     }
-}
\ No newline at end of file
+
+    const PIN: &str = "sha256//BEyQeSjwwUBLXXNuCILHRWyV1gLmY31CdMHNA4VH4dE=";
+
+    // a server resuming tunnels to wg on a unix socket at path, once it's listening
+    #[cfg(unix)]
+    fn resume_server(path: &std::path::Path, wg: &std::net::UdpSocket, acceptor: Arc<dyn Acceptor>) {
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let ready_tx = Mutex::new(ready_tx);
+        let server = ProxyServerBuilder::new()
+            .unix_host(path, None)
+            .udp_target(wg.local_addr().unwrap())
+            .resume(Some(Duration::from_secs(30)))
+            .on_ready(move |_| { ready_tx.lock().unwrap().send(()).ok(); })
+            .build()
+            .unwrap();
+        std::thread::spawn(move || Runtime::new().unwrap().block_on(server.start_with_async(acceptor)));
+        ready_rx.recv().unwrap();
+    }
+
+    async fn send_datagram(stream: &mut BoxStream, datagram: &[u8]) {
+        let mut frames = FrameWriter::new(DEFAULT_MAX_DATAGRAM);
+        frames.push(datagram).unwrap();
+        stream.write_all(frames.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+
+    // reconnecting with the same bond id and token carries on with the same udp port towards wireguard
+    #[cfg(unix)]
+    #[test]
+    fn test_resume() {
+        let path = std::env::temp_dir().join(format!("wgp-resume-{}", std::process::id()));
+        let wg = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        wg.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        resume_server(&path, &wg, Arc::new(TlsAcceptor::new(&TlsServerConfig::new("ci/cert.key", "ci/cert.pem")).unwrap()));
+        let client = ProxyClientBuilder::new().unix_target(&path).resume(true).build().unwrap();
+        let transport = TlsTransport::new(&TlsClientConfig::new().pinnedpubkey(PIN)).unwrap();
+        let mut buf = [0u8; 16];
+
+        Runtime::new().unwrap().block_on(async {
+            let (mut links, _) = client.connect_target_async(&client.tcp_target, &transport, false).await.unwrap();
+            send_datagram(&mut links[0].0, b"first").await;
+            let (len, upstream) = wg.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"first");
+            drop(links);
+            // long enough for the server to see the first connection gone, so the reply can't go out over it
+            tokio::time::delay_for(Duration::from_millis(200)).await;
+
+            let (mut links, _) = client.connect_target_async(&client.tcp_target, &transport, false).await.unwrap();
+            send_datagram(&mut links[0].0, b"resumed").await;
+            let (len, resumed) = wg.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"resumed");
+            assert_eq!(resumed, upstream);
+
+            wg.send_to(b"reply", upstream).unwrap();
+            let (stream, reader) = &mut links[0];
+            let reader = reader.as_mut().unwrap();
+            loop {
+                if let Some(reply) = reader.frames(1).unwrap().first() {
+                    assert_eq!(*reply, b"reply");
+                    break;
+                }
+                reader.fill_async(stream).await.unwrap();
+            }
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // the token is never sent where it could be read along, and a server doesn't offer resuming there either
+    #[cfg(unix)]
+    #[test]
+    fn test_resume_unprotected() {
+        let path = std::env::temp_dir().join(format!("wgp-resume-plain-{}", std::process::id()));
+        let wg = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        resume_server(&path, &wg, Arc::new(Plain));
+        let client = ProxyClientBuilder::new().unix_target(&path).resume(true).build().unwrap();
+
+        Runtime::new().unwrap().block_on(async {
+            let result = client.connect_target_async(&client.tcp_target, &Plain, false).await;
+            assert!(matches!(result, Err(Error::Config(_))));
+
+            // a client asking anyway carries on without it
+            let (mut stream, _) = client.connect_transport_async(&client.tcp_target, &Plain, false).await.unwrap();
+            let hello = Hello::with_id(control::RESUME, client.id().unwrap());
+            let (_, agreed) = hello_async(&mut stream, &hello, DEFAULT_MAX_DATAGRAM).await.unwrap();
+            assert!(!agreed.has(control::RESUME));
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,890 @@
+// what the tunnel runs over, a Transport wraps each stream the client connects and an Acceptor
+// each stream the server accepts, so a library user can swap in their own layer instead of TLS
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls;
+use tokio_rustls::webpki;
+use tokio_rustls::webpki::DNSNameRef;
+
+use crate::compress::{Compressor, Decompressor};
+use crate::error::{Error, Result};
+use crate::frame::FrameReader;
+use crate::obfs::{Opener, Sealer};
+use crate::padding::Padder;
+use crate::proxyproto::{HeaderReader, Parsed, ProxyProtocol};
+use crate::{CompressionStats, Counters, Obfuscation, Padding, TlsClientConfig, TlsInfo, TlsServerConfig};
+
+/// anything the tunnel can run over
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for T {}
+
+pub type BoxStream = Box<dyn Stream>;
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// client side, wraps a freshly connected tcp or unix stream
+pub trait Transport: Send + Sync {
+    fn connect(&self, stream: BoxStream) -> BoxFuture<'_, Result<BoxStream>>;
+
+    /// connect() along with what the server's TLS looked like, None for anything that isn't TLS
+    fn connect_info(&self, stream: BoxStream) -> BoxFuture<'_, Result<(BoxStream, Option<TlsInfo>)>> {
+        Box::pin(async move { Ok((self.connect(stream).await?, None)) })
+    }
+
+    /// shows up in log messages like "Connected with TLS", None for nothing special
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    /// whether what's sent over it can't be read or changed on the way, resuming is only asked for when it is
+    fn protected(&self) -> bool {
+        false
+    }
+
+    /// the hostname and pinnedpubkey a quic: target or HTTP/2 is connected with in place of this, None for anything
+    /// that isn't TLS
+    fn tls(&self) -> Option<&TlsClientConfig> {
+        None
+    }
+}
+
+/// server side, wraps a freshly accepted tcp or unix stream
+pub trait Acceptor: Send + Sync {
+    fn accept(&self, stream: BoxStream) -> BoxFuture<'_, Result<BoxStream>>;
+
+    /// accept() along with what the admin socket shows about TLS, None for anything that isn't TLS
+    fn accept_info(&self, stream: BoxStream) -> BoxFuture<'_, Result<(BoxStream, Option<TlsInfo>)>> {
+        Box::pin(async move { Ok((self.accept(stream).await?, None)) })
+    }
+
+    /// shows up in log messages like "Listening for TLS connections", None for nothing special
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    /// whether what's sent over it can't be read or changed on the way, resuming is only offered when it is
+    fn protected(&self) -> bool {
+        false
+    }
+
+    /// the key and cert a quic: tcp_host or HTTP/2 listens with in place of this, None for anything that isn't TLS
+    fn tls(&self) -> Option<&TlsServerConfig> {
+        None
+    }
+}
+
+/// the stream as is
+pub struct Plain;
+
+impl Transport for Plain {
+    fn connect(&self, stream: BoxStream) -> BoxFuture<'_, Result<BoxStream>> {
+        Box::pin(async move { Ok(stream) })
+    }
+}
+
+impl Acceptor for Plain {
+    fn accept(&self, stream: BoxStream) -> BoxFuture<'_, Result<BoxStream>> {
+        Box::pin(async move { Ok(stream) })
+    }
+}
+
+/// TLS with rustls, verifies nothing unless a pinnedpubkey is set
+pub struct TlsTransport {
+    config: TlsClientConfig,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl TlsTransport {
+    pub fn new(config: &TlsClientConfig) -> Result<TlsTransport> {
+        Ok(TlsTransport {
+            config: config.clone(),
+            alpn: Vec::new(),
+        })
+    }
+
+    /// offer these protocols in ALPN, most preferred first, default none
+    pub fn alpn(mut self, protocols: &[&[u8]]) -> TlsTransport {
+        self.alpn = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        self
+    }
+
+    async fn tls_connect(&self, stream: BoxStream) -> Result<(BoxStream, Option<TlsInfo>)> {
+        use tokio_rustls::{ TlsConnector, rustls::{ ClientConfig, Session } };
+
+        let pins = self.config.pinnedpubkey.as_deref().map(Pins::new);
+        let mut config = ClientConfig::new();
+        config.set_protocols(&self.alpn);
+        config.dangerous().set_certificate_verifier(match &pins {
+            Some(pins) => Arc::new(PinnedpubkeyCertVerifier(pins.clone())),
+            None => Arc::new(DummyCertVerifier{}),
+        });
+
+        let mut sni = self.config.hostname.clone();
+        let hostname = match self.config.hostname.as_deref() {
+            Some(hostname) => match DNSNameRef::try_from_ascii_str(hostname) {
+                Ok(hostname) => hostname,
+                Err(_) => {
+                    sni = None;
+                    config.enable_sni = false;
+                    DNSNameRef::try_from_ascii_str("dummy.hostname").unwrap() // why does rustls ABSOLUTELY REQUIRE this ????
+                }
+            },
+            None => {
+                config.enable_sni = false;
+                DNSNameRef::try_from_ascii_str("dummy.hostname").unwrap() // why does rustls ABSOLUTELY REQUIRE this ????
+            }
+        };
+        //println!("hostname: {:?}", hostname);
+
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let stream = connector.connect(hostname, stream).await
+            .map_err(|e| pins.as_ref().and_then(Pins::mismatch).unwrap_or_else(|| tls_error(e)))?;
+        let pinnedpubkey = stream.get_ref().1.get_peer_certificates()
+            .and_then(|certs| certs.first().and_then(|cert| pubkey_pin(&cert.0).ok()));
+        Ok((Box::new(stream), Some(TlsInfo { sni, pinnedpubkey })))
+    }
+}
+
+impl Transport for TlsTransport {
+    fn connect(&self, stream: BoxStream) -> BoxFuture<'_, Result<BoxStream>> {
+        Box::pin(async move { Ok(self.tls_connect(stream).await?.0) })
+    }
+
+    fn connect_info(&self, stream: BoxStream) -> BoxFuture<'_, Result<(BoxStream, Option<TlsInfo>)>> {
+        Box::pin(self.tls_connect(stream))
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("TLS")
+    }
+
+    fn protected(&self) -> bool {
+        // without a pin it verifies nothing, so anyone in the middle could read along
+        self.config.pinnedpubkey.is_some()
+    }
+
+    fn tls(&self) -> Option<&TlsClientConfig> {
+        Some(&self.config)
+    }
+}
+
+// rustls failures come back as io errors wrapping a TLSError
+fn tls_error(e: std::io::Error) -> Error {
+    if matches!(e.get_ref(), Some(inner) if inner.is::<rustls::TLSError>()) {
+        return Error::Tls(Box::new(e));
+    }
+    e.into()
+}
+
+/// TLS with rustls, key and cert are read once up front
+pub struct TlsAcceptor {
+    server_config: Arc<rustls::ServerConfig>,
+    pinnedpubkey: Option<String>,
+    config: TlsServerConfig,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: &TlsServerConfig) -> Result<TlsAcceptor> {
+        let (tls_key, tls_cert) = key_and_cert(config)?;
+        let pinnedpubkey = tls_cert.first().and_then(|cert| pubkey_pin(&cert.0).ok());
+        let mut server_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        server_config.set_single_cert(tls_cert, tls_key)
+            .map_err(|err| Error::Config(format!("invalid key or cert: {}", err)))?;
+        Ok(TlsAcceptor {
+            server_config: Arc::new(server_config),
+            pinnedpubkey,
+            config: config.clone(),
+        })
+    }
+
+    /// accept these protocols in ALPN, most preferred first, a client offering none of them carries on without,
+    /// default none
+    pub fn alpn(mut self, protocols: &[&[u8]]) -> TlsAcceptor {
+        let protocols: Vec<_> = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        Arc::make_mut(&mut self.server_config).set_protocols(&protocols);
+        self
+    }
+}
+
+// the first pkcs8 key and every cert in their pem files
+pub(crate) fn key_and_cert(config: &TlsServerConfig) -> Result<(rustls::PrivateKey, Vec<rustls::Certificate>)> {
+    use std::fs::File;
+    use std::io::BufReader;
+    use tokio_rustls::rustls::internal::pemfile::{ certs, pkcs8_private_keys };
+
+    let mut tls_key = pkcs8_private_keys(&mut BufReader::new(File::open(&config.key)?))
+        .map_err(|_| Error::config("invalid key"))?;
+    if tls_key.is_empty() {
+        return Err(Error::config("invalid key"));
+    }
+    let tls_key = tls_key.remove(0);
+
+    let tls_cert = certs(&mut BufReader::new(File::open(&config.cert)?))
+        .map_err(|_| Error::config("invalid cert"))?;
+    Ok((tls_key, tls_cert))
+}
+
+impl Acceptor for TlsAcceptor {
+    fn accept(&self, stream: BoxStream) -> BoxFuture<'_, Result<BoxStream>> {
+        Box::pin(async move { Ok(self.accept_info(stream).await?.0) })
+    }
+
+    fn accept_info(&self, stream: BoxStream) -> BoxFuture<'_, Result<(BoxStream, Option<TlsInfo>)>> {
+        Box::pin(async move {
+            let acceptor = tokio_rustls::TlsAcceptor::from(self.server_config.clone());
+            let stream = acceptor.accept(stream).await.map_err(tls_error)?;
+            let tls = TlsInfo {
+                sni: stream.get_ref().1.get_sni_hostname().map(str::to_owned),
+                pinnedpubkey: self.pinnedpubkey.clone(),
+            };
+            Ok((Box::new(stream) as BoxStream, Some(tls)))
+        })
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("TLS")
+    }
+
+    fn protected(&self) -> bool {
+        true
+    }
+
+    fn tls(&self) -> Option<&TlsServerConfig> {
+        Some(&self.config)
+    }
+}
+
+/// what a stream that was already handshaken hands spawn_client, like each tunnel of a QUIC or HTTP/2 connection
+#[cfg(any(feature = "quic", feature = "http2"))]
+pub(crate) struct Handshaken(pub Option<TlsInfo>);
+
+#[cfg(any(feature = "quic", feature = "http2"))]
+impl Acceptor for Handshaken {
+    fn accept(&self, stream: BoxStream) -> BoxFuture<'_, Result<BoxStream>> {
+        Box::pin(async move { Ok(stream) })
+    }
+
+    fn accept_info(&self, stream: BoxStream) -> BoxFuture<'_, Result<(BoxStream, Option<TlsInfo>)>> {
+        Box::pin(async move { Ok((stream, self.0.clone())) })
+    }
+
+    fn protected(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+struct DummyCertVerifier;
+
+impl rustls::ServerCertVerifier for DummyCertVerifier {
+    fn verify_server_cert(&self,
+                          _roots: &rustls::RootCertStore,
+                          _certs: &[rustls::Certificate],
+                          _hostname: webpki::DNSNameRef<'_>,
+                          _ocsp: &[u8]) -> core::result::Result<rustls::ServerCertVerified, rustls::TLSError> {
+        // verify nothing, subject to MITM
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+struct PinnedpubkeyCertVerifier(Pins);
+
+impl rustls::ServerCertVerifier for PinnedpubkeyCertVerifier {
+    fn verify_server_cert(&self,
+                          _roots: &rustls::RootCertStore,
+                          certs: &[rustls::Certificate],
+                          _hostname: webpki::DNSNameRef<'_>,
+                          _ocsp: &[u8]) -> core::result::Result<rustls::ServerCertVerified, rustls::TLSError> {
+        let cert = certs.first().ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        self.0.check(&cert.0).map_err(rustls::TLSError::General)?;
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+/// a pinnedpubkey checked against the server's cert, for TlsTransport and QUIC alike as they're on different rustls
+/// versions, a fresh one per connection so a mismatch can't be mixed up with another connection's
+#[derive(Clone)]
+pub(crate) struct Pins {
+    pinnedpubkey: String,
+    // rustls only passes on our error as a string, so this is how the connection tells a mismatch apart
+    mismatch: Arc<Mutex<Option<String>>>,
+}
+
+impl Pins {
+    pub fn new(pinnedpubkey: &str) -> Pins {
+        Pins {
+            pinnedpubkey: pinnedpubkey.to_owned(),
+            mismatch: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Ok if cert, der encoded, has one of the pinned pubkeys, else the error for rustls to fail the handshake with
+    pub fn check(&self, cert: &[u8]) -> core::result::Result<(), String> {
+        let pubkey = pubkey_pin(cert).map_err(|e| format!("invalid certificate: {:?}", e))?;
+        if self.pinnedpubkey.split(';').any(|key| key == pubkey) {
+            return Ok(());
+        }
+        let err = format!("pubkey '{}' not found in allowed list '{}'", pubkey, self.pinnedpubkey);
+        *self.mismatch.lock().unwrap() = Some(pubkey);
+        Err(err)
+    }
+
+    /// Error::PinMismatch if that's what failed the handshake
+    pub fn mismatch(&self) -> Option<Error> {
+        self.mismatch.lock().unwrap().take().map(Error::PinMismatch)
+    }
+}
+
+// "sha256//" and the base64 sha256 of cert's public key, the same as curl's --pinnedpubkey
+pub(crate) fn pubkey_pin(cert: &[u8]) -> core::result::Result<String, webpki::Error> {
+    let cert = webpki::trust_anchor_util::cert_der_as_trust_anchor(cert)?;
+
+    //println!("spki.len(): {}", cert.spki.len());
+    //println!("spki: {:?}", cert.spki);
+    // todo: what is wrong with webpki? it returns *almost* the right answer but missing these leading bytes:
+    // guess I'll open an issue... (I assume this is some type of algorithm identifying header or something)
+    let mut pubkey: Vec<u8> = vec![48, 130, 1, 34];
+    pubkey.extend(cert.spki);
+
+    let pubkey = ring::digest::digest(&ring::digest::SHA256, &pubkey);
+    let pubkey = base64::encode(pubkey);
+    Ok(["sha256//", &pubkey].join(""))
+}
+
+impl Transport for Obfuscation {
+    fn connect(&self, stream: BoxStream) -> BoxFuture<'_, Result<BoxStream>> {
+        Box::pin(async move { Ok(Box::new(Obfuscated::new(stream, self)?) as BoxStream) })
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("obfs")
+    }
+
+    fn protected(&self) -> bool {
+        true
+    }
+}
+
+impl Acceptor for Obfuscation {
+    fn accept(&self, stream: BoxStream) -> BoxFuture<'_, Result<BoxStream>> {
+        Box::pin(async move { Ok(Box::new(Obfuscated::new(stream, self)?) as BoxStream) })
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("obfs")
+    }
+
+    fn protected(&self) -> bool {
+        true
+    }
+}
+
+/// stream with everything going through it obfuscated
+struct Obfuscated {
+    stream: BoxStream,
+    opener: Opener,
+    sealer: Sealer,
+    // how much of what's sealed is written, the rest goes out before anything new is sealed, or on flush
+    written: usize,
+}
+
+impl Obfuscated {
+    fn new(stream: BoxStream, obfuscation: &Obfuscation) -> Result<Obfuscated> {
+        Ok(Obfuscated {
+            stream,
+            opener: obfuscation.opener(),
+            sealer: obfuscation.sealer()?,
+            written: 0,
+        })
+    }
+
+    // writes out what's sealed, Ready once all of it is
+    fn poll_sealed(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.sealer.sealed().len() {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.sealer.sealed()[self.written..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(len)) => self.written += len,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Obfuscated {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let this = &mut *self;
+        loop {
+            let len = this.opener.open(buf)?;
+            if len > 0 {
+                return Poll::Ready(Ok(len));
+            }
+            match Pin::new(&mut this.stream).poll_read(cx, this.opener.read_mut()) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(len)) => this.opener.filled(len),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Obfuscated {
+    // buf is accepted once it's sealed, whatever of it isn't written yet goes out on the next write or flush
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        match self.poll_sealed(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        self.sealer.seal(buf);
+        self.written = 0;
+        if let Poll::Ready(Err(e)) = self.poll_sealed(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_sealed(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.stream).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_sealed(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.stream).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+/// pads every write with padding frames, cover traffic goes out from reads as it falls due since
+/// the read side is always waiting on the stream, while the write side only runs with something to write
+pub(crate) struct Padded {
+    stream: BoxStream,
+    padder: Padder,
+    cover: Option<tokio::time::Interval>,
+    // like Obfuscated, how much of what's padded is written
+    written: usize,
+    // since cover traffic last looked
+    wrote: bool,
+}
+
+impl Padded {
+    pub fn new(stream: BoxStream, padding: &Padding, max_datagram: usize) -> Result<Padded> {
+        let padder = Padder::new(padding, max_datagram)?;
+        let cover = padder.cover().map(|cover| tokio::time::interval_at(tokio::time::Instant::now() + cover, cover));
+        Ok(Padded {
+            stream,
+            padder,
+            cover,
+            written: 0,
+            wrote: false,
+        })
+    }
+
+    // writes out what's padded, Ready once all of it is
+    fn poll_padded(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.padder.padded().len() {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.padder.padded()[self.written..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(len)) => self.written += len,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // padding alone for each interval nothing was written in, only ever between writes
+    fn poll_cover(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let cover = match &mut self.cover {
+            Some(cover) => cover,
+            None => return Ok(()),
+        };
+        while cover.poll_tick(cx).is_ready() {
+            if !self.wrote && self.written == self.padder.padded().len() {
+                self.padder.pad_cover();
+                self.written = 0;
+            }
+            self.wrote = false;
+        }
+        if let Poll::Ready(Err(e)) = self.poll_padded(cx) {
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for Padded {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.poll_cover(cx)?;
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Padded {
+    // like Obfuscated, buf is accepted once it's padded
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        // the last write or cover traffic still going out
+        match self.poll_padded(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        self.padder.pad(buf);
+        self.written = 0;
+        self.wrote = true;
+        if let Poll::Ready(Err(e)) = self.poll_padded(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_padded(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.stream).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_padded(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.stream).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+/// compresses the datagram frames written and decompresses those read, see compress.rs
+pub(crate) struct Compressed {
+    stream: BoxStream,
+    decompressor: Decompressor,
+    compressor: Compressor,
+    // like Obfuscated, how much of what's compressed is written
+    written: usize,
+}
+
+impl Compressed {
+    /// reader holds what was already read from stream
+    pub fn new(stream: BoxStream, reader: FrameReader, max_datagram: usize, stats: Arc<CompressionStats>) -> Compressed {
+        Compressed {
+            stream,
+            decompressor: Decompressor::new(reader, max_datagram, stats.clone()),
+            compressor: Compressor::new(stats),
+            written: 0,
+        }
+    }
+}
+
+impl AsyncRead for Compressed {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let this = &mut *self;
+        loop {
+            let len = this.decompressor.open(buf)?;
+            if len > 0 {
+                return Poll::Ready(Ok(len));
+            }
+            match Pin::new(&mut this.stream).poll_read(cx, this.decompressor.read_mut()) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(len)) => this.decompressor.filled(len),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Compressed {
+    // writes out what's compressed, Ready once all of it is
+    fn poll_compressed(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.compressor.compressed().len() {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.compressor.compressed()[self.written..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(len)) => self.written += len,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Compressed {
+    // like Obfuscated, buf is accepted once it's compressed
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        match self.poll_compressed(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        self.compressor.compress(buf);
+        self.written = 0;
+        if let Poll::Ready(Err(e)) = self.poll_compressed(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_compressed(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.stream).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_compressed(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.stream).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+/// counts what goes through stream into counters
+pub(crate) struct Counted<S> {
+    stream: S,
+    counters: Arc<Counters>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(stream: S, counters: Arc<Counters>) -> Counted<S> {
+        Counted { stream, counters }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(len)) = poll {
+            self.counters.add_rx(len);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = poll {
+            self.counters.add_tx(len);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// stream with what was read off its start to look for a PROXY protocol header put back in front
+pub(crate) struct Rewound {
+    stream: BoxStream,
+    rewound: Vec<u8>,
+}
+
+impl AsyncRead for Rewound {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if self.rewound.is_empty() {
+            return Pin::new(&mut self.stream).poll_read(cx, buf);
+        }
+        let len = buf.len().min(self.rewound.len());
+        buf[..len].copy_from_slice(&self.rewound[..len]);
+        self.rewound.drain(..len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for Rewound {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// reads a PROXY protocol header off the start of stream, returning the client it names if any and the stream
+/// carrying on after it, or after nothing when there's no header and that's allowed, failing if it takes over timeout
+pub(crate) async fn proxied(mut stream: BoxStream, mode: ProxyProtocol, timeout: Duration) -> Result<(BoxStream, Option<SocketAddr>)> {
+    use tokio::io::AsyncReadExt;
+    let mut reader = HeaderReader::new();
+    let header = async {
+        loop {
+            match reader.parse()? {
+                Parsed::Incomplete => {
+                    let len = stream.read(reader.read_mut()).await?;
+                    if len == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    reader.filled(len);
+                }
+                Parsed::Absent if mode == ProxyProtocol::Strict => return Err(Error::protocol("no PROXY protocol header")),
+                Parsed::Absent => return Ok((0, None)),
+                Parsed::Header { len, source } => return Ok((len, source)),
+            }
+        }
+    };
+    let (len, source) = tokio::time::timeout(timeout, header).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("no PROXY protocol header within {:?}", timeout)))??;
+    Ok((Box::new(Rewound { stream, rewound: reader.rest(len) }), source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const PIN: &str = "sha256//BEyQeSjwwUBLXXNuCILHRWyV1gLmY31CdMHNA4VH4dE=";
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn pair() -> (BoxStream, BoxStream) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (Box::new(client.unwrap()), Box::new(server.unwrap().0))
+    }
+
+    #[test]
+    fn test_tls() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let acceptor = TlsAcceptor::new(&TlsServerConfig::new("ci/cert.key", "ci/cert.pem")).unwrap();
+
+            let transport = TlsTransport::new(&TlsClientConfig::new().pinnedpubkey(PIN)).unwrap();
+            let (client, server) = pair().await;
+            let (client, server) = tokio::join!(transport.connect(client), acceptor.accept(server));
+            let (mut client, mut server) = (client.unwrap(), server.unwrap());
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            let wrong = "sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+            let transport = TlsTransport::new(&TlsClientConfig::new().pinnedpubkey(wrong)).unwrap();
+            let (client, server) = pair().await;
+            let (client, _) = tokio::join!(transport.connect(client), acceptor.accept(server));
+            assert!(matches!(client, Err(Error::PinMismatch(ref pubkey)) if pubkey == PIN));
+        });
+    }
+
+    #[test]
+    fn test_pins() {
+        let (_, certs) = key_and_cert(&TlsServerConfig::new("ci/cert.key", "ci/cert.pem")).unwrap();
+        let wrong = "sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        assert!(Pins::new(PIN).check(&certs[0].0).is_ok());
+        assert!(Pins::new(&format!("{};{}", wrong, PIN)).check(&certs[0].0).is_ok());
+
+        let pins = Pins::new(wrong);
+        assert!(pins.check(&certs[0].0).is_err());
+        assert!(matches!(pins.mismatch(), Some(Error::PinMismatch(ref pubkey)) if pubkey == PIN));
+        assert!(pins.mismatch().is_none());
+        // a cert that doesn't parse isn't a mismatch
+        assert!(pins.check(b"not a cert").is_err());
+        assert!(pins.mismatch().is_none());
+    }
+
+    #[cfg(feature = "obfs")]
+    #[test]
+    fn test_obfuscation() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let obfuscation = Obfuscation::new("correct horse").unwrap();
+            let (client, server) = pair().await;
+            let (client, server) = tokio::join!(obfuscation.connect(client), obfuscation.accept(server));
+            let (mut client, mut server) = (client.unwrap(), server.unwrap());
+            let big = vec![7u8; 100 * 1024];
+            let write = async {
+                client.write_all(&big).await?;
+                client.flush().await
+            };
+            let (written, read) = tokio::join!(write, async {
+                let mut buf = vec![0u8; big.len()];
+                server.read_exact(&mut buf).await.map(|_| buf)
+            });
+            written.unwrap();
+            assert_eq!(read.unwrap(), big);
+            server.write_all(b"hello").await.unwrap();
+            server.flush().await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            let wrong = Obfuscation::new("battery staple").unwrap();
+            let (client, server) = pair().await;
+            let (client, server) = tokio::join!(obfuscation.connect(client), wrong.accept(server));
+            let (mut client, mut server) = (client.unwrap(), server.unwrap());
+            client.write_all(b"hello").await.unwrap();
+            client.flush().await.unwrap();
+            let e = server.read_exact(&mut buf).await.unwrap_err();
+            assert!(matches!(Error::from(e), Error::Protocol(_)));
+        });
+    }
+
+    #[test]
+    fn test_proxied() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let (mut client, server) = pair().await;
+            client.write_all(b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\nhello").await.unwrap();
+            let (mut server, source) = proxied(server, ProxyProtocol::Strict, TIMEOUT).await.unwrap();
+            assert_eq!(source, Some("203.0.113.7:51234".parse().unwrap()));
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // without a header what was read to find out is still there
+            for (mode, ok) in [(ProxyProtocol::Optional, true), (ProxyProtocol::Strict, false)].iter() {
+                let (mut client, server) = pair().await;
+                client.write_all(b"hello").await.unwrap();
+                match proxied(server, *mode, TIMEOUT).await {
+                    Ok((mut server, source)) if *ok => {
+                        assert_eq!(source, None);
+                        server.read_exact(&mut buf).await.unwrap();
+                        assert_eq!(&buf, b"hello");
+                    }
+                    Err(Error::Protocol(_)) if !*ok => {}
+                    result => panic!("{:?} got {:?}", mode, result.map(|(_, source)| source)),
+                }
+            }
+
+            // a peer that says nothing doesn't hold on to the task
+            let (_client, server) = pair().await;
+            let result = proxied(server, ProxyProtocol::Optional, Duration::from_millis(50)).await;
+            assert!(matches!(result, Err(Error::Timeout(_))));
+        });
+    }
+}
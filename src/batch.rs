@@ -0,0 +1,304 @@
+// batching udp datagrams so we make fewer syscalls per packet, on linux
+// recvmmsg/sendmmsg move many datagrams per syscall, elsewhere this degrades to one at a time
+
+use crate::frame::{write_header, HEADER, MAX_DATAGRAM};
+
+/// max datagrams handled per syscall
+pub const BATCH: usize = 32;
+
+/// receives datagrams into slots with room for the length prefix in front,
+/// then packs them together so they can be written to tcp with one syscall
+pub struct UdpBatch {
+    buf: Vec<u8>,
+    lens: [usize; BATCH],
+    count: usize,
+    max_datagram: usize,
+    slot: usize,
+}
+
+impl UdpBatch {
+    pub fn new(max_datagram: usize) -> UdpBatch {
+        let max_datagram = max_datagram.min(MAX_DATAGRAM);
+        // one byte more than the max, so a datagram filling it is known to have been truncated
+        let slot = HEADER + max_datagram + 1;
+        UdpBatch {
+            buf: vec![0u8; BATCH * slot],
+            lens: [0; BATCH],
+            count: 0,
+            max_datagram,
+            slot,
+        }
+    }
+
+    /// where the first datagram of a batch should be received, normally with a blocking/async recv
+    pub fn first_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[HEADER..self.slot]
+    }
+
+    pub fn set_first(&mut self, len: usize) {
+        self.lens[0] = len;
+        self.count = 1;
+    }
+
+    /// grab as many more datagrams as are already waiting without blocking, recv fills the
+    /// buffers and their lengths and returns how many it got
+    pub fn recv_more<F: FnOnce(&mut [&mut [u8]], &mut [usize]) -> usize>(&mut self, recv: F) -> usize {
+        let mut bufs: Vec<&mut [u8]> = self.buf.chunks_mut(self.slot)
+            .skip(self.count)
+            .map(|slot| &mut slot[HEADER..])
+            .collect();
+        let count = recv(&mut bufs, &mut self.lens[self.count..]);
+        self.count += count;
+        count
+    }
+
+    /// length prefix each datagram and pack them together, returning what should be written to tcp,
+    /// datagrams that were too big to fit are dropped, the other end could never accept them anyway,
+    /// empty ones too since a zero length marks a control frame
+    pub fn pack(&mut self) -> &[u8] {
+        #[cfg(feature = "verbose")]
+        println!("udp got {} datagrams", self.count);
+
+        let mut end = 0;
+        for x in 0..self.count {
+            let len = self.lens[x];
+            if len > self.max_datagram {
+                println!("dropping truncated udp datagram, larger than max datagram size {}", self.max_datagram);
+                continue;
+            }
+            if len == 0 {
+                continue;
+            }
+            let slot = x * self.slot;
+            write_header(&mut self.buf[slot..], len);
+            if slot != end {
+                self.buf.copy_within(slot..slot + len + HEADER, end);
+            }
+            end += len + HEADER;
+        }
+        self.count = 0;
+        &self.buf[..end]
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub mod sys {
+    use std::io;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::RawFd;
+
+    use super::BATCH;
+
+    /// receive into bufs without blocking, fills in lens and returns how many were received
+    pub fn recvmmsg(fd: RawFd, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> io::Result<usize> {
+        recvmmsg_from(fd, bufs, lens, &mut [])
+    }
+
+    /// recvmmsg for an unconnected socket, also fills in where each came from, as many addrs as there are room for
+    pub fn recvmmsg_from(fd: RawFd, bufs: &mut [&mut [u8]], lens: &mut [usize], addrs: &mut [Option<SocketAddr>]) -> io::Result<usize> {
+        let count = bufs.len().min(lens.len()).min(BATCH);
+        if count == 0 {
+            return Ok(0);
+        }
+        let mut iovecs: Vec<libc::iovec> = bufs[..count].iter_mut().map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }).collect();
+        let mut names: Vec<libc::sockaddr_storage> = vec![unsafe { std::mem::zeroed() }; addrs.len().min(count)];
+        let mut hdrs: Vec<libc::mmsghdr> = iovecs.iter_mut().enumerate().map(|(x, iovec)| {
+            let mut hdr: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            hdr.msg_hdr.msg_iov = iovec;
+            hdr.msg_hdr.msg_iovlen = 1;
+            if let Some(name) = names.get_mut(x) {
+                hdr.msg_hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+                hdr.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as _;
+            }
+            hdr
+        }).collect();
+        let ret = unsafe {
+            libc::recvmmsg(fd, hdrs.as_mut_ptr(), count as _, libc::MSG_DONTWAIT as _, std::ptr::null_mut())
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ret = ret as usize;
+        for (len, hdr) in lens.iter_mut().zip(hdrs[..ret].iter()) {
+            *len = hdr.msg_len as usize;
+        }
+        for (addr, name) in addrs.iter_mut().zip(names[..ret.min(names.len())].iter()) {
+            *addr = from_sockaddr(name);
+        }
+        Ok(ret)
+    }
+
+    /// send bufs as separate datagrams on a connected socket, returns how many were sent which may be fewer than asked
+    pub fn sendmmsg(fd: RawFd, bufs: &[&[u8]], flags: libc::c_int) -> io::Result<usize> {
+        sendmmsg_to(fd, bufs, None, flags)
+    }
+
+    /// sendmmsg with every datagram going to addr, for an unconnected socket
+    pub fn sendmmsg_to(fd: RawFd, bufs: &[&[u8]], addr: Option<&SocketAddr>, flags: libc::c_int) -> io::Result<usize> {
+        let count = bufs.len().min(BATCH);
+        if count == 0 {
+            return Ok(0);
+        }
+        let mut name = addr.map(to_sockaddr);
+        let mut iovecs: Vec<libc::iovec> = bufs[..count].iter().map(|buf| libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }).collect();
+        let mut hdrs: Vec<libc::mmsghdr> = iovecs.iter_mut().map(|iovec| {
+            let mut hdr: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            hdr.msg_hdr.msg_iov = iovec;
+            hdr.msg_hdr.msg_iovlen = 1;
+            if let Some((name, len)) = name.as_mut() {
+                hdr.msg_hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+                hdr.msg_hdr.msg_namelen = *len;
+            }
+            hdr
+        }).collect();
+        let ret = unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), count as _, flags as _) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as _;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                std::mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as _;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                std::mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as _)
+    }
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+                Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{FrameReader, DEFAULT_MAX_DATAGRAM};
+
+    #[test]
+    fn test_pack() {
+        let datagrams: [&[u8]; 3] = [b"first", b"", b"third datagram"];
+
+        let mut batch = UdpBatch::new(DEFAULT_MAX_DATAGRAM);
+        batch.first_mut()[..datagrams[0].len()].copy_from_slice(datagrams[0]);
+        batch.set_first(datagrams[0].len());
+        // pretend recvmmsg filled in the rest
+        for (x, datagram) in datagrams.iter().enumerate().skip(1) {
+            let slot = x * batch.slot + HEADER;
+            batch.buf[slot..slot + datagram.len()].copy_from_slice(datagram);
+            batch.lens[x] = datagram.len();
+        }
+        batch.count = datagrams.len();
+
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        reader.fill(&mut batch.pack()).unwrap();
+        // the empty one is dropped
+        assert_eq!(reader.frames(BATCH).unwrap(), vec![datagrams[0], datagrams[2]]);
+    }
+
+    #[test]
+    fn test_truncated() {
+        use std::net::UdpSocket;
+
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        tx.connect(rx.local_addr().unwrap()).unwrap();
+
+        let mut batch = UdpBatch::new(16);
+        tx.send(&[1u8; 17]).unwrap();
+        let len = rx.recv(batch.first_mut()).unwrap();
+        batch.set_first(len);
+        assert!(batch.pack().is_empty());
+
+        tx.send(&[2u8; 16]).unwrap();
+        let len = rx.recv(batch.first_mut()).unwrap();
+        batch.set_first(len);
+        assert_eq!(batch.pack().len(), HEADER + 16);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_mmsg() {
+        use std::io;
+        use std::net::UdpSocket;
+        use std::os::unix::io::AsRawFd;
+
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        tx.connect(rx.local_addr().unwrap()).unwrap();
+
+        let sent: [&[u8]; 3] = [b"one", b"two two", b"three three three"];
+        assert_eq!(sys::sendmmsg(tx.as_raw_fd(), &sent, 0).unwrap(), sent.len());
+
+        let mut bufs = vec![[0u8; 64]; BATCH];
+        let mut bufs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+        let mut lens = [0usize; BATCH];
+        // loopback delivery is synchronous, so they are all waiting already
+        let count = sys::recvmmsg(rx.as_raw_fd(), &mut bufs, &mut lens).unwrap();
+        assert_eq!(count, sent.len());
+        for x in 0..count {
+            assert_eq!(&bufs[x][..lens[x]], sent[x]);
+        }
+        assert_eq!(sys::recvmmsg(rx.as_raw_fd(), &mut bufs, &mut lens).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_mmsg_unconnected() {
+        use std::net::UdpSocket;
+        use std::os::unix::io::AsRawFd;
+
+        for host in ["127.0.0.1:0", "[::1]:0"].iter() {
+            let rx = UdpSocket::bind(host).unwrap();
+            let tx = UdpSocket::bind(host).unwrap();
+
+            let sent: [&[u8]; 2] = [b"one", b"two two"];
+            assert_eq!(sys::sendmmsg_to(tx.as_raw_fd(), &sent, Some(&rx.local_addr().unwrap()), 0).unwrap(), sent.len());
+
+            let mut bufs = vec![[0u8; 64]; BATCH];
+            let mut bufs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+            let mut lens = [0usize; BATCH];
+            let mut addrs = [None; BATCH];
+            let count = sys::recvmmsg_from(rx.as_raw_fd(), &mut bufs, &mut lens, &mut addrs).unwrap();
+            assert_eq!(count, sent.len());
+            for x in 0..count {
+                assert_eq!(&bufs[x][..lens[x]], sent[x]);
+                assert_eq!(addrs[x], Some(tx.local_addr().unwrap()));
+            }
+        }
+    }
+}
@@ -3,7 +3,7 @@ use std::time::Duration;
 
 use std::process::{exit, Command};
 use std::{env, thread};
-use wireguard_proxy::{Args, ProxyClient, ProxyServer};
+use wireguard_proxy::{Args, ProxyClientBuilder, ProxyServerBuilder, TlsClientConfig, TlsServerConfig};
 
 const PONG: [u8; 246] = [
     0x6A, 0x2, 0x6B, 0xC, 0x6C, 0x3F, 0x6D, 0xC, 0xA2, 0xEA, 0xDA, 0xB6, 0xDC, 0xD6, 0x6E, 0x0,
@@ -57,7 +57,7 @@ impl Server {
                 assert_eq!(&buf[..len], &PONG[..]);
 
                 // now reply back to src_addr to make sure other direction works
-                let sent = udp_socket.send_to(&PONG, &src_addr)?;
+                let sent = udp_socket.send_to(&PONG, src_addr)?;
                 assert_eq!(sent, PONG.len());
             }
             Err(e) => {
@@ -83,6 +83,66 @@ impl Server {
     }
 }
 
+// waits for a spawned wireguard-proxy server to send READY=1 over NOTIFY_SOCKET
+// like systemd would, instead of guessing how long it takes to start
+struct ReadyWaiter {
+    #[cfg(unix)]
+    notify: Option<(std::os::unix::net::UnixDatagram, std::path::PathBuf)>,
+}
+
+impl ReadyWaiter {
+    fn new(command: &mut Command) -> ReadyWaiter {
+        #[cfg(unix)]
+        {
+            let path = env::temp_dir().join(format!("udp-test-notify-{}", std::process::id()));
+            std::fs::remove_file(&path).ok();
+            let notify = match std::os::unix::net::UnixDatagram::bind(&path) {
+                Ok(notify) => {
+                    command.env("NOTIFY_SOCKET", &path);
+                    Some((notify, path))
+                }
+                Err(e) => {
+                    println!("cannot bind notify socket, falling back to sleep: {}", e);
+                    None
+                }
+            };
+            ReadyWaiter { notify }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = command;
+            ReadyWaiter {}
+        }
+    }
+
+    fn wait(self, timeout: Duration) {
+        #[cfg(unix)]
+        {
+            if let Some((notify, path)) = self.notify {
+                notify.set_read_timeout(Some(timeout)).ok();
+                let mut buf = [0u8; 1024];
+                let ready = loop {
+                    match notify.recv(&mut buf) {
+                        Ok(len) => {
+                            let state = String::from_utf8_lossy(&buf[..len]);
+                            if state.lines().any(|line| line == "READY=1") {
+                                break true;
+                            }
+                        }
+                        Err(_) => break false,
+                    }
+                };
+                std::fs::remove_file(&path).ok();
+                if !ready {
+                    println!("never got READY=1, continuing anyway");
+                }
+                return;
+            }
+        }
+        thread::sleep(timeout);
+    }
+}
+
 fn main() {
     let raw_args = env::args().collect();
     let args = Args::new(&raw_args);
@@ -170,30 +230,32 @@ fn main() {
 
         let mut proxyd_args = vec!["-th", tcp_host, "-ut", host];
 
-        let tls_key = tls_key.as_ref().map(String::as_str);
-        let tls_cert = tls_cert.as_ref().map(String::as_str);
+        let tls_key = tls_key.as_deref();
+        let tls_cert = tls_cert.as_deref();
         if tls {
             let tls_key = tls_key.unwrap();
             let tls_cert = tls_cert.unwrap();
-            proxyd_args.extend(["-tk", &tls_key, "-tc", &tls_cert].iter().cloned());
+            proxyd_args.extend(["-tk", tls_key, "-tc", tls_cert].iter().cloned());
         }
 
         println!("executing: {} {}", proxy, proxyd_args.join(" "));
-        let mut proxyd = Command::new(proxy.clone())
-                .args(&proxyd_args)
+        let mut proxyd = Command::new(proxy);
+        proxyd.args(&proxyd_args);
+        let ready = ReadyWaiter::new(&mut proxyd);
+        let mut proxyd = proxyd
                 .spawn()
                 .expect("wireguard-proxy server failed to launch");
-        println!("waiting: {:?} for wireguard-proxy server to come up.....", sleep);
-        thread::sleep(sleep);
+        println!("waiting: up to {:?} for wireguard-proxy server to come up.....", sleep);
+        ready.wait(sleep);
 
         let mut proxy_args = vec!["-tt", tcp_host];
 
-        let pinnedpubkey = pinnedpubkey.as_ref().map(String::as_str);
+        let pinnedpubkey = pinnedpubkey.as_deref();
         if tls {
             proxy_args.push("--tls");
-            if pinnedpubkey.is_some() {
+            if let Some(pinnedpubkey) = pinnedpubkey {
                 proxy_args.push("--pinnedpubkey");
-                proxy_args.push(&pinnedpubkey.unwrap());
+                proxy_args.push(pinnedpubkey);
             }
         }
 
@@ -231,37 +293,48 @@ fn main() {
         let tcp_host = "127.0.0.1:5555";
         let sleep = Duration::from_secs(5);
 
-        let proxy_server = ProxyServer::new(
-            tcp_host.to_owned(),
-            host.to_owned(),
-            "127.0.0.1".to_owned(),
-            30000,
-            30100,
-            0,
-        );
+        let mut proxy_server = ProxyServerBuilder::new()
+            .tcp_host(tcp_host.parse().unwrap())
+            .udp_target(host.parse().unwrap())
+            .udp_bind_range("127.0.0.1".parse().unwrap(), 30000, 30100);
+        if tls {
+            proxy_server = proxy_server.tls(TlsServerConfig::new(tls_key.as_deref().unwrap(), tls_cert.as_deref().unwrap()));
+        }
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let ready = std::sync::Mutex::new(ready_tx.clone());
+        let proxy_server = proxy_server
+            .on_ready(move |_| ready.lock().unwrap().send(()).unwrap())
+            .build()
+            .expect("invalid proxy_server config");
 
         println!(
             "udp_target: {}, udp_bind_host_range: 127.0.0.1:30000-30100, socket_timeout: {:?}",
             proxy_server.client_handler.udp_target, proxy_server.client_handler.socket_timeout,
         );
-
+        match &proxy_server.tls {
+            Some(tls) => println!("executing: wireguard-proxy -th '{}' -ut '{}' -tk '{}' -tc '{}'", tcp_host, host, tls.key, tls.cert),
+            None => println!("executing: wireguard-proxy -th '{}' -ut '{}'", tcp_host, host),
+        }
+        thread::spawn(move || proxy_server.run().expect("error running proxy_server"));
+        println!("waiting: up to {:?} for wireguard-proxy server to come up.....", sleep);
+        ready_rx.recv_timeout(sleep).expect("wireguard-proxy server did not come up");
+
+        let mut proxy_client = ProxyClientBuilder::new()
+            .udp_host("127.0.0.1:51820".parse().unwrap())
+            .tcp_target(tcp_host.parse().unwrap())
+            .socket_timeout(Some(Duration::from_secs(15)));
         if tls {
-            let tls_key = tls_key.unwrap().to_owned();
-            let tls_cert = tls_cert.unwrap().to_owned();
-            println!("executing: wireguard-proxy -th '{}' -ut '{}' -tk '{}' -tc '{}'", tcp_host, host, tls_key, tls_cert);
-            thread::spawn(move || proxy_server.start_tls(&tls_key, &tls_cert).expect("error running TLS proxy_server"));
-        } else {
-            println!("executing: wireguard-proxy -th '{}' -ut '{}'", tcp_host, host);
-            thread::spawn(move || proxy_server.start().expect("error running proxy_server"));
+            let mut tls_config = TlsClientConfig::new();
+            if let Some(pinnedpubkey) = pinnedpubkey.as_deref() {
+                tls_config = tls_config.pinnedpubkey(pinnedpubkey);
+            }
+            proxy_client = proxy_client.tls(tls_config);
         }
-        println!("waiting: {:?} for wireguard-proxy server to come up.....", sleep);
-        thread::sleep(sleep);
-
-        let proxy_client = ProxyClient::new(
-            "127.0.0.1:51820".to_owned(),
-            tcp_host.to_owned(),
-            15,
-        );
+        let ready = std::sync::Mutex::new(ready_tx);
+        let proxy_client = proxy_client
+            .on_ready(move |_| ready.lock().unwrap().send(()).unwrap())
+            .build()
+            .expect("invalid proxy_client config");
 
         println!(
             "udp_host: {}, tcp_target: {}, socket_timeout: {:?}",
@@ -269,26 +342,14 @@ fn main() {
             proxy_client.tcp_target,
             proxy_client.socket_timeout,
         );
-
-        if tls {
-            let hostname = tcp_host.split(":").next();
-            let pinnedpubkey = pinnedpubkey.as_ref().map(String::as_str);
-            match pinnedpubkey {
-                Some(pinnedpubkey) =>
-                    println!("executing: wireguard-proxy -tt {} --tls --pinnedpubkey {}", tcp_host, pinnedpubkey),
-                None =>
-                    println!("executing: wireguard-proxy -tt {} --tls", tcp_host),
-            }
-            // this is a little funky, is this the only way to do it?
-            let pinnedpubkey = pinnedpubkey.map(&str::to_owned);
-            // can use pinnedpubkey.as_deref() below when it's stabilized
-            thread::spawn(move || proxy_client.start_tls(hostname, pinnedpubkey.as_ref().map(String::as_str)).expect("error running proxy_client"));
-        } else {
-            println!("executing: wireguard-proxy -tt {}", tcp_host);
-            thread::spawn(move || proxy_client.start().expect("error running proxy_client"));
+        match proxy_client.tls.as_ref().map(|tls| tls.pinnedpubkey.as_deref()) {
+            Some(Some(pinnedpubkey)) => println!("executing: wireguard-proxy -tt {} --tls --pinnedpubkey {}", tcp_host, pinnedpubkey),
+            Some(None) => println!("executing: wireguard-proxy -tt {} --tls", tcp_host),
+            None => println!("executing: wireguard-proxy -tt {}", tcp_host),
         }
-        println!("waiting: {:?} for wireguard-proxy client to come up.....", sleep);
-        thread::sleep(sleep);
+        thread::spawn(move || proxy_client.run().expect("error running proxy_client"));
+        println!("waiting: up to {:?} for wireguard-proxy client to come up.....", sleep);
+        ready_rx.recv_timeout(sleep).expect("wireguard-proxy client did not come up");
 
         first_arg = host.to_owned();
     }
@@ -1,10 +1,19 @@
 use std::env;
-use wireguard_proxy::{Args, ProxyClient, ProxyServer};
+use wireguard_proxy::frame::DEFAULT_MAX_DATAGRAM;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use wireguard_proxy::admin_command;
+use wireguard_proxy::{parse_udp_bind_range, quic_addr, unix_path, Args, Obfuscation, Padding, ProxyClient, ProxyServer, Target, TlsClientConfig, DEFAULT_MAX_PADDING};
 
 fn main() {
-    let raw_args = env::args().collect();
+    let raw_args: Vec<String> = env::args().collect();
     let args = Args::new(&raw_args);
 
+    if raw_args.get(1).map(String::as_str) == Some("ctl") {
+        return ctl(&raw_args[2..], args);
+    }
+
     if args.flag("-V") || args.flag("--version") {
         print!("wireguard-proxy {} ", env!("CARGO_PKG_VERSION"));
         #[cfg(not(any(feature = "tls", feature = "openssl_vendored", feature = "async")))]
@@ -31,9 +40,14 @@ fn main() {
         (tcp_target.is_some() && tcp_host.is_some())
         {
         println!(r#"usage: wireguard-proxy [options...]
+       wireguard-proxy ctl --admin-socket <path> <command>
  Client Mode (requires --tcp-target):
  -tt, --tcp-target <ip:port>     TCP target to send packets to, where
-                                 wireguard-proxy server is running
+                                 wireguard-proxy server is running,
+                                 unix:/path connects to a unix socket,
+                                 quic:host:port connects over QUIC on
+                                 UDP, always TLS like --tls, requires
+                                 the quic feature
  -uh, --udp-host <ip:port>       UDP host to listen on, point wireguard
                                  client here, default: {}
  --tls                           use TLS when connecting to tcp-target
@@ -47,9 +61,57 @@ fn main() {
  --tls-hostname                  send this in SNI instead of host
                                  from --tcp-target, useful for avoiding
                                  DNS lookup on connect
+ --multi-peer                    carry packets from any number of local
+                                 wireguard instances over one connection,
+                                 requires a server that supports it
+ --compress                      lz4 compress packets both ways where
+                                 that makes them smaller, requires a
+                                 server that answers like --multi-peer,
+                                 requires the compress feature, which is
+                                 compiled in by default; compression is
+                                 off unless --compress is given
+ --bond <connections>            stripe packets across this many parallel
+                                 connections, carrying on while any is
+                                 left, up to 16, not with --multi-peer,
+                                 requires a server that supports it,
+                                 the token that lets a connection join
+                                 the bond is sent in the clear unless
+                                 over TLS or --obfs-secret, default: 1
+ --resume                        ask the server to keep the UDP port
+                                 towards wireguard for a reconnect to
+                                 carry on with, see --reconnect, needs
+                                 --obfs or --tls with --pinnedpubkey
+ --allowed-sources <cidr,...>    only take packets from these local
+                                 sources, loopback means 127.0.0.0/8
+                                 and ::1, default any
+ --fallback-targets <target,...> tried in order when --tcp-target can't
+                                 be connected, ip:port, host:port,
+                                 unix:/path or quic:host:port,
+                                 tls:host:port uses TLS, both TLS and
+                                 QUIC use --pinnedpubkey if given
+ --target-order <listed|latency> try targets as listed or fastest to
+                                 connect first, default: listed
+ --fail-back <seconds>           while on a fallback, how often to check
+                                 if one ahead of it connects again and
+                                 move back, 0 never does, default: 60
+ --connect-timeout <seconds>     give up connecting to a target after
+                                 this long, 0 waits as long as the OS
+                                 does, default: 10
+ --reconnect <seconds>           when the tunnel fails, wait this long
+                                 and start over, 0 exits instead,
+                                 default: 0
+ --status-file <path>            keep the client's status in this file
+                                 as one line of JSON, for tray icons
+                                 and the like
 
  Server Mode (requires --tcp-host):
- -th, --tcp-host <ip:port>                TCP host to listen on
+ -th, --tcp-host <ip:port>                TCP host to listen on,
+                                          unix:/path listens on a unix socket,
+                                          quic:ip:port listens for QUIC on
+                                          that UDP port, requires --tls-key,
+                                          --tls-cert and the quic feature
+ --unix-socket-mode <octal>               permissions for the unix socket
+                                          file, default: from umask
  -ut, --udp-target <ip:port>              UDP target to send packets to, where
                                           wireguard server is running,
                                           default: {}
@@ -57,7 +119,34 @@ fn main() {
                                           one port per TCP connection, to
                                           listen on for UDP packets to send
                                           back over the TCP connection,
+                                          ipv6 in brackets like
+                                          [::1]:30000-40000, loopback follows
+                                          the family of --udp-target,
                                           default: 127.0.0.1:30000-40000
+ --re-resolve <seconds>                   how often open tunnels look up
+                                          --udp-target again and move to its
+                                          new address, 0 only looks it up as
+                                          each tunnel starts, default: 0
+ --resume <seconds>                       keep a tunnel's UDP port this long
+                                          after its connection drops, for a
+                                          client with --resume reconnecting,
+                                          only offered over --obfs or TLS,
+                                          0 doesn't offer it, default: 0
+ --proxy-protocol <optional|strict>       read a PROXY protocol v1 or v2
+                                          header off each connection for the
+                                          client address a load balancer in
+                                          front passes on, strict closes
+                                          connections without one, requires
+                                          --proxy-protocol-from
+ --proxy-protocol-from <cidr,...>         load balancers a header is believed
+                                          from, anything else connecting is
+                                          taken as it is, or closed when
+                                          strict, unix sockets are always
+                                          believed, loopback means
+                                          127.0.0.0/8 and ::1
+ --admin-socket <path>                    unix socket to list, show and kill
+                                          tunnels or stop accepting new ones
+                                          on, only the owner can use it
  -tk, --tls-key <ip:port>                 TLS key to listen with,
                                           requires --tls-cert also
  -tc, --tls-cert <ip:port>                TLS cert to listen with,
@@ -67,11 +156,44 @@ fn main() {
        also the same file can work for both if you combine them into
        one pem file
 
+ Control Mode (ctl, talks to a running server's --admin-socket):
+ list                            live tunnels with peer, udp ports, TLS
+                                 SNI and pin, uptime, bytes and how well
+                                 they compress, as JSON
+ show <id>                       just the one tunnel
+ kill <id>                       close the tunnel
+ accept <on|off>                 whether new tunnels are accepted
+
  Common Options:
  -h, --help                      print this usage text
  -V, --version                   Show version number and TLS support then quit
  -st, --socket-timeout <seconds> Socket timeout (time to wait for data)
                                  before terminating, default: {}
+ --max-datagram <bytes>          largest UDP datagram to carry, up to 65535
+                                 for jumbo frames, must be the same on
+                                 client and server, default: {}
+ --obfs-secret <secret>          obfuscate the tunnel so it can't be
+                                 fingerprinted, the same secret must be
+                                 set on client and server, not with TLS,
+                                 WGP_OBFS_SECRET keeps it out of ps,
+                                 requires the obfs feature, which is
+                                 compiled in by default; obfuscation is
+                                 off unless --obfs-secret is given
+ --obfs-padding <bytes>          each record gets random padding up to
+                                 this long with --obfs-secret, default: 255
+ --padding <policy,...>          pad what's written so TLS records don't
+                                 give away packet sizes, bucket:<bytes>
+                                 rounds each write up to a multiple,
+                                 random:<bytes> adds up to that much,
+                                 cover:<millis> writes padding alone when
+                                 idle that long, the other end only has
+                                 to be new enough to throw it away
+ --http2 <path>                  carry each tunnel in an HTTP/2 POST to
+                                 this path, for CDNs and load balancers
+                                 that only forward HTTP/2, over TLS
+                                 with --tls or --tls-key, h2c without,
+                                 the same path must be set on client and
+                                 server, requires the http2 feature
 
  Environment variable support:
  For every long command line option (starting with --), if you replace the
@@ -84,69 +206,193 @@ fn main() {
    --socket-timeout 5 is WGP_SOCKET_TIMEOUT=5
    --tls is WGP_TLS=1 or WGP_TLS=true
    WGP_TLS=0 or WGP_TLS=false would be like not sending --tls
-        "#, default_udp_host_target, default_udp_host_target, default_socket_timeout);
+        "#, default_udp_host_target, default_udp_host_target, default_socket_timeout, DEFAULT_MAX_DATAGRAM);
         return;
     }
 
     let socket_timeout = args.get(&["-st", "--socket-timeout"], default_socket_timeout);
+    let max_datagram = match args.get_option(&["--max-datagram"]) {
+        Some(max) => match max.parse::<u16>() {
+            Ok(max) if max > 0 => max as usize,
+            _ => {
+                println!("Error: --max-datagram must be between 1 and 65535");
+                return;
+            }
+        },
+        None => DEFAULT_MAX_DATAGRAM,
+    };
 
-    if tcp_target.is_some() {
-        client(&tcp_target.unwrap(), socket_timeout, args);
+    if let Some(tcp_target) = tcp_target {
+        client(&tcp_target, socket_timeout, max_datagram, args);
     } else {
-        server(&tcp_host.unwrap(), socket_timeout, args);
+        server(&tcp_host.unwrap(), socket_timeout, max_datagram, args);
+    }
+}
+
+// sends one command to a running server's admin socket and prints its answer, failing if it is an error
+#[cfg(unix)]
+fn ctl(words: &[String], args: Args) {
+    let path = match args.get_option(&["--admin-socket"]) {
+        Some(path) => path,
+        None => {
+            println!("Error: ctl requires --admin-socket");
+            std::process::exit(1);
+        }
+    };
+    let mut command = Vec::new();
+    let mut words = words.iter();
+    while let Some(word) = words.next() {
+        if word == "--admin-socket" {
+            words.next();
+        } else {
+            command.push(word.as_str());
+        }
     }
+    match admin_command(&path, &command.join(" ")) {
+        Ok(answer) => {
+            println!("{}", answer);
+            if answer.starts_with(r#"{"error""#) {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            println!("Error: cannot talk to admin socket {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn ctl(_words: &[String], _args: Args) {
+    println!("Error: ctl is only supported on unix");
 }
 
-fn client(tcp_target: &str, socket_timeout: u64, args: Args) {
-    let proxy_client = ProxyClient::new(
+fn client(tcp_target: &str, socket_timeout: u64, max_datagram: usize, args: Args) {
+    let mut proxy_client = ProxyClient::new(
         args.get_str(&["-uh", "--udp-host"], "127.0.0.1:51820").to_owned(),
         tcp_target.to_owned(),
         socket_timeout,
     );
+    proxy_client.max_datagram = max_datagram;
+    proxy_client.multi_peer = args.flag("--multi-peer");
+    proxy_client.compress = args.flag("--compress");
+    proxy_client.bond = args.get(&["--bond"], 1);
+    proxy_client.resume = args.flag("--resume");
+    if let Some(allowed_sources) = args.get_option(&["--allowed-sources"]) {
+        proxy_client.allowed_sources = match allowed_sources.parse() {
+            Ok(allowed_sources) => allowed_sources,
+            Err(e) => {
+                println!("Error: invalid --allowed-sources, {}", e);
+                return;
+            }
+        };
+    }
+
+    let pinnedpubkey = args.get_option(&["--pinnedpubkey"]);
+    if let Some(fallback_targets) = args.get_option(&["--fallback-targets"]) {
+        for target in fallback_targets.split(',') {
+            let mut target: Target = match target.parse() {
+                Ok(target) => target,
+                Err(e) => {
+                    println!("Error: invalid --fallback-targets, {}", e);
+                    return;
+                }
+            };
+            if let Some(tls) = &mut target.tls {
+                tls.pinnedpubkey = pinnedpubkey.clone();
+            }
+            proxy_client.fallback_targets.push(target);
+        }
+    }
+    if let Some(target_order) = args.get_option(&["--target-order"]) {
+        proxy_client.target_order = match target_order.parse() {
+            Ok(target_order) => target_order,
+            Err(e) => {
+                println!("Error: invalid --target-order, {}", e);
+                return;
+            }
+        };
+    }
+    proxy_client.fail_back = match args.get(&["--fail-back"], 60) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    proxy_client.connect_timeout = match args.get(&["--connect-timeout"], 10) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    proxy_client.reconnect = match args.get(&["--reconnect"], 0) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    proxy_client.status_file = args.get_option(&["--status-file"]);
+    proxy_client.http2 = args.get_option(&["--http2"]);
+    proxy_client.padding = match padding(&args) {
+        Ok(padding) => padding,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
 
     let tls = args.flag("--tls");
+    let quic = quic_addr(tcp_target).is_some();
+    if quic && proxy_client.http2.is_some() {
+        println!("Error: --http2 can't be used with a quic: target");
+        return;
+    }
+    match obfuscation(&args) {
+        Ok(Some(_)) if tls || quic => {
+            println!("Error: --obfs-secret can't be used with --tls or a quic: target");
+            return;
+        }
+        Ok(obfuscation) => proxy_client.transport = obfuscation.map(|obfuscation| Arc::new(obfuscation) as _),
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    }
 
     println!(
-        "udp_host: {}, tcp_target: {}, socket_timeout: {:?}, tls: {}",
+        "udp_host: {}, tcp_target: {}, socket_timeout: {:?}, tls: {}, http2: {:?}, multi_peer: {}, compress: {}, bond: {}, resume: {}",
         proxy_client.udp_host,
         proxy_client.tcp_target,
         proxy_client.socket_timeout,
         tls,
+        proxy_client.http2,
+        proxy_client.multi_peer,
+        proxy_client.compress,
+        proxy_client.bond,
+        proxy_client.resume,
     );
 
-    if tls {
-        let hostname = args.get_option(&["--tls-hostname"]).or_else(|| tcp_target.split(":").next().map(&str::to_owned));
-        let pinnedpubkey = args.get_option(&["--pinnedpubkey"]);
-        proxy_client.start_tls(hostname.as_ref().map(String::as_str), pinnedpubkey.as_ref().map(String::as_str)).expect("error running tls proxy_client");
+    // QUIC is always TLS, this only says what hostname and pinnedpubkey it uses
+    if tls || quic {
+        let hostname = args.get_option(&["--tls-hostname"]).or_else(|| {
+            if unix_path(tcp_target).is_some() {
+                None
+            } else {
+                quic_addr(tcp_target).unwrap_or(tcp_target).split(':').next().map(&str::to_owned)
+            }
+        });
+        proxy_client.tls = Some(TlsClientConfig { hostname, pinnedpubkey });
+        proxy_client.run().expect("error running tls proxy_client");
     } else {
-        proxy_client.start().expect("error running proxy_client");
+        proxy_client.run().expect("error running proxy_client");
     }
 }
 
-fn server(tcp_host: &str, socket_timeout: u64, args: Args) {
+fn server(tcp_host: &str, socket_timeout: u64, max_datagram: usize, args: Args) {
     let udp_bind_host_range_str = args.get_str(&["-ur", "--udp-bind-host-range"], "127.0.0.1:30000-40000");
-    let mut udp_bind_host_range = udp_bind_host_range_str.split(":");
-    let udp_host = udp_bind_host_range
-        .next()
-        .expect("udp_bind_host_range host invalid");
-    let mut udp_ports = udp_bind_host_range
-        .next()
-        .expect("udp_bind_host_range port range invalid")
-        .split("-");
-    let udp_low_port = udp_ports
-        .next()
-        .expect("udp_bind_host_range low port invalid")
-        .trim()
-        .parse::<u16>()
-        .expect("udp_bind_host_range low port invalid");
-    let udp_high_port = udp_ports
-        .next()
-        .expect("udp_bind_host_range low port invalid")
-        .trim()
-        .parse::<u16>()
-        .expect("udp_bind_host_range low port invalid");
-
-    let proxy_server = ProxyServer::new(
+    let (udp_host, udp_low_port, udp_high_port) = match parse_udp_bind_range(&udp_bind_host_range_str) {
+        Ok(range) => range,
+        Err(e) => {
+            println!("Error: invalid --udp-bind-host-range, {}", e);
+            return;
+        }
+    };
+
+    let mut proxy_server = ProxyServer::new(
         tcp_host.to_owned(),
         args.get_str(&["-ut", "--udp-target"], "127.0.0.1:51820").to_owned(),
         udp_host.to_string(),
@@ -155,23 +401,108 @@ fn server(tcp_host: &str, socket_timeout: u64, args: Args) {
         socket_timeout,
     );
 
+    proxy_server.admin_socket = args.get_option(&["--admin-socket"]);
+    proxy_server.http2 = args.get_option(&["--http2"]);
+    if let Some(proxy_protocol) = args.get_option(&["--proxy-protocol"]) {
+        proxy_server.proxy_protocol = match proxy_protocol.parse() {
+            Ok(proxy_protocol) => Some(proxy_protocol),
+            Err(e) => {
+                println!("Error: invalid --proxy-protocol, {}", e);
+                return;
+            }
+        };
+    }
+    if let Some(proxy_protocol_from) = args.get_option(&["--proxy-protocol-from"]) {
+        proxy_server.proxy_protocol_from = match proxy_protocol_from.parse() {
+            Ok(proxy_protocol_from) => proxy_protocol_from,
+            Err(e) => {
+                println!("Error: invalid --proxy-protocol-from, {}", e);
+                return;
+            }
+        };
+    }
+    if proxy_server.proxy_protocol.is_some() && proxy_server.proxy_protocol_from.0.is_empty() {
+        println!("Error: --proxy-protocol requires --proxy-protocol-from, anyone connecting directly could claim any address");
+        return;
+    }
+    proxy_server.padding = match padding(&args) {
+        Ok(padding) => padding,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+    let re_resolve = match args.get(&["--re-resolve"], 0) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let resume = match args.get(&["--resume"], 0) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let set = proxy_server.set_max_datagram(max_datagram)
+        .and_then(|_| proxy_server.set_re_resolve(re_resolve))
+        .and_then(|_| proxy_server.set_resume(resume));
+    if let Err(e) = set {
+        println!("Error: {}", e);
+        return;
+    }
+    if let Some(mode) = args.get_option(&["--unix-socket-mode"]) {
+        match u32::from_str_radix(&mode, 8) {
+            Ok(mode) => proxy_server.unix_socket_mode = Some(mode),
+            Err(_) => {
+                println!("Error: --unix-socket-mode {} invalid, must be octal like 660", mode);
+                return;
+            }
+        }
+    }
+
     let tls_key = args.get_option(&["-tk", "--tls-key"]);
     let tls_cert = args.get_option(&["-tc", "--tls-cert"]);
+    match obfuscation(&args) {
+        Ok(Some(_)) if tls_key.is_some() || tls_cert.is_some() => {
+            println!("Error: --obfs-secret can't be used with --tls-key or --tls-cert");
+            return;
+        }
+        Ok(obfuscation) => proxy_server.acceptor = obfuscation.map(|obfuscation| Arc::new(obfuscation) as _),
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    }
 
     println!(
-        "udp_target: {}, udp_bind_host_range: {}, socket_timeout: {:?}, tls_key: {:?}, tls_cert: {:?}",
+        "udp_target: {}, udp_bind_host_range: {}, socket_timeout: {:?}, re_resolve: {:?}, resume: {:?}, tls_key: {:?}, tls_cert: {:?}, http2: {:?}, proxy_protocol: {:?}, proxy_protocol_from: {:?}",
         proxy_server.client_handler.udp_target,
         udp_bind_host_range_str,
         proxy_server.client_handler.socket_timeout,
+        proxy_server.client_handler.re_resolve,
+        proxy_server.client_handler.resume,
         tls_key,
         tls_cert,
+        proxy_server.http2,
+        proxy_server.proxy_protocol,
+        proxy_server.proxy_protocol_from,
     );
 
-    if tls_key.is_some() && tls_cert.is_some() {
-        proxy_server.start_tls(&tls_key.unwrap(), &tls_cert.unwrap()).expect("error running TLS proxy_server");
-    } else if tls_key.is_none() && tls_cert.is_none() {
-        proxy_server.start().expect("error running proxy_server");
-    } else {
-        println!("Error: if one of --tls-key or --tls-cert is specified both must be!");
+    match (tls_key, tls_cert) {
+        (Some(tls_key), Some(tls_cert)) => proxy_server.start_tls(&tls_key, &tls_cert).expect("error running TLS proxy_server"),
+        (None, None) => proxy_server.run().expect("error running proxy_server"),
+        _ => println!("Error: if one of --tls-key or --tls-cert is specified both must be!"),
     }
 }
+
+// from --obfs-secret and --obfs-padding, None without a secret
+fn obfuscation(args: &Args) -> wireguard_proxy::error::Result<Option<Obfuscation>> {
+    let secret = match args.get_option(&["--obfs-secret"]) {
+        Some(secret) => secret,
+        None => return Ok(None),
+    };
+    let max_padding = args.get(&["--obfs-padding"], DEFAULT_MAX_PADDING);
+    Ok(Some(Obfuscation::new(&secret)?.max_padding(max_padding)))
+}
+
+// from --padding, None without it
+fn padding(args: &Args) -> wireguard_proxy::error::Result<Option<Padding>> {
+    args.get_option(&["--padding"]).map(|padding| padding.parse()).transpose()
+}
@@ -0,0 +1,203 @@
+// bonding stripes one tunnel across several connections so a lossy or throttled one doesn't hold the rest up,
+// each says hello with the same bond id and token and the server carries all of them to the one endpoint, the id
+// says which bond and the token is what proves a connection belongs to it, as only the client ever knows it, unless
+// its connections are plain tcp which hands the token to anyone watching. what comes back
+// from it goes out over each connection in turn. losing a connection loses what was in flight on it, the bond
+// carries on over the rest until the last one is gone. a tunnel that resumes is a bond the server keeps for a grace
+// period after its last connection is gone, the client reconnecting with the same id carries on with its endpoint
+// so wireguard behind the server sees the same source port
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::error::{Error, Result};
+
+/// most connections one bond takes
+pub const MAX_BOND: usize = 16;
+
+/// a client's connections, 1 doesn't bond, and a bond or a tunnel that resumes carries a single peer
+pub fn validate(connections: usize, multi_peer: bool, resume: bool) -> Result<()> {
+    if connections == 0 || connections > MAX_BOND {
+        return Err(Error::Config(format!("bond must be between 1 and {} connections", MAX_BOND)));
+    }
+    if connections > 1 && multi_peer {
+        return Err(Error::config("bond can't be combined with multi peer"));
+    }
+    if resume && multi_peer {
+        return Err(Error::config("resume can't be combined with multi peer"));
+    }
+    Ok(())
+}
+
+/// random bytes a connection has to know to join a bond, as well as its id
+pub const TOKEN: usize = 16;
+
+/// which bond or tunnel that resumes a connection belongs to, the server tells them apart by id and only lets
+/// connections with the same token join
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BondId {
+    pub id: u64,
+    pub token: [u8; TOKEN],
+}
+
+impl BondId {
+    /// a random one for a new bond or tunnel that resumes
+    pub fn new() -> Result<BondId> {
+        let mut random = [0u8; 8 + TOKEN];
+        crate::random::fill(&mut random)?;
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&random[..8]);
+        let mut token = [0u8; TOKEN];
+        token.copy_from_slice(&random[8..]);
+        Ok(BondId { id: u64::from_be_bytes(id), token })
+    }
+
+    // in constant time, so how long a wrong token takes to turn down says nothing about the right one
+    fn same_token(&self, token: &[u8; TOKEN]) -> bool {
+        self.token.iter().zip(token.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+/// the connections of one bond to send over, each in turn
+pub struct Stripes<T> {
+    links: Vec<(u64, T)>,
+    next: usize,
+    next_id: u64,
+}
+
+impl<T> Default for Stripes<T> {
+    fn default() -> Self {
+        Stripes {
+            links: Vec::new(),
+            next: 0,
+            next_id: 0,
+        }
+    }
+}
+
+impl<T> Stripes<T> {
+    /// an id to remove it by later, an error once MAX_BOND are taken
+    pub fn add(&mut self, link: T) -> Result<u64> {
+        if self.links.len() >= MAX_BOND {
+            return Err(Error::protocol(format!("bond already has {} connections", MAX_BOND)));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.links.push((id, link));
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.links.retain(|(link, _)| *link != id);
+    }
+
+    /// the connection whose turn it is and its id, None with none left
+    pub fn next(&mut self) -> Option<(u64, &mut T)> {
+        if self.links.is_empty() {
+            return None;
+        }
+        self.next = (self.next + 1) % self.links.len();
+        let (id, link) = &mut self.links[self.next];
+        Some((*id, link))
+    }
+
+    /// every connection, leaving none
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.links.drain(..).map(|(_, link)| link)
+    }
+
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+}
+
+/// the server's bonds by id, each kept alive by its connections and forgotten once they're all gone
+pub struct Bonds<B> {
+    bonds: Mutex<HashMap<u64, Joinable<B>>>,
+}
+
+// a bond while it lasts and the token a connection needs to join it
+type Joinable<B> = (Weak<B>, [u8; TOKEN]);
+
+impl<B> Default for Bonds<B> {
+    fn default() -> Self {
+        Bonds { bonds: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<B> Bonds<B> {
+    /// the live bond with this id as long as the token matches, or a new one from new if there isn't one
+    pub fn join<F: FnOnce() -> Result<Arc<B>>>(&self, id: &BondId, new: F) -> Result<Arc<B>> {
+        if let Some(bond) = live(&mut self.bonds.lock().unwrap(), id)? {
+            return Ok(bond);
+        }
+        // made without holding bonds since that binds a socket, another connection of the bond may beat it to it
+        let bond = new()?;
+        let mut bonds = self.bonds.lock().unwrap();
+        if let Some(bond) = live(&mut bonds, id)? {
+            return Ok(bond);
+        }
+        bonds.insert(id.id, (Arc::downgrade(&bond), id.token));
+        Ok(bond)
+    }
+}
+
+fn live<B>(bonds: &mut HashMap<u64, Joinable<B>>, id: &BondId) -> Result<Option<Arc<B>>> {
+    bonds.retain(|_, (bond, _)| bond.strong_count() > 0);
+    match bonds.get(&id.id) {
+        Some((_, token)) if !id.same_token(token) => Err(Error::protocol(format!("wrong token to join bond {:016x}", id.id))),
+        Some((bond, _)) => Ok(bond.upgrade()),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stripes() {
+        let mut stripes = Stripes::default();
+        assert!(stripes.next().is_none());
+        let a = stripes.add('a').unwrap();
+        stripes.add('b').unwrap();
+        stripes.add('c').unwrap();
+        let turns: Vec<char> = (0..6).map(|_| *stripes.next().unwrap().1).collect();
+        assert_eq!(turns, vec!['b', 'c', 'a', 'b', 'c', 'a']);
+        stripes.remove(a);
+        let turns: Vec<char> = (0..4).map(|_| *stripes.next().unwrap().1).collect();
+        assert_eq!(turns, vec!['c', 'b', 'c', 'b']);
+
+        for _ in stripes.len()..MAX_BOND {
+            stripes.add('x').unwrap();
+        }
+        assert!(matches!(stripes.add('y'), Err(Error::Protocol(_))));
+        assert_eq!(stripes.drain().count(), MAX_BOND);
+        assert!(stripes.next().is_none());
+    }
+
+    #[test]
+    fn test_bonds() {
+        let bonds = Bonds::default();
+        let one = BondId::new().unwrap();
+        let first = bonds.join(&one, || Ok(Arc::new("first"))).unwrap();
+        assert_eq!(*bonds.join(&one, || panic!("already made")).unwrap(), "first");
+        assert_eq!(*bonds.join(&BondId::new().unwrap(), || Ok(Arc::new("other"))).unwrap(), "other");
+        // knowing the id isn't enough to join
+        let mut guessed = one;
+        guessed.token[0] ^= 1;
+        assert!(matches!(bonds.join(&guessed, || panic!("not made for a wrong token")), Err(Error::Protocol(_))));
+        // once nothing holds it a bond is made afresh
+        drop(first);
+        assert_eq!(*bonds.join(&one, || Ok(Arc::new("again"))).unwrap(), "again");
+        assert!(bonds.join(&BondId::new().unwrap(), || Err(Error::config("no endpoint"))).is_err());
+        // other bonds can be joined while one is being made
+        bonds.join(&BondId::new().unwrap(), || Ok(Arc::new(bonds.bonds.try_lock().map(|_| "unlocked").unwrap()))).unwrap();
+        assert_ne!(BondId::new().unwrap(), BondId::new().unwrap());
+
+        assert!(validate(1, true, false).is_ok() && validate(MAX_BOND, false, true).is_ok());
+        for (connections, multi_peer, resume) in [(0, false, false), (MAX_BOND + 1, false, false), (2, true, false), (1, true, true)].iter() {
+            assert!(matches!(validate(*connections, *multi_peer, *resume), Err(Error::Config(_))));
+        }
+    }
+}
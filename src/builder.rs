@@ -0,0 +1,687 @@
+// builders for embedding the proxy in other programs, everything is checked in build()
+// so a bad option is a Config error up front rather than a failure once running
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::endpoint::BoxEndpoint;
+use crate::{bond, compress, family};
+use crate::error::{Error, Result};
+use crate::frame::{DEFAULT_MAX_DATAGRAM, MAX_DATAGRAM};
+use crate::transport::{Acceptor, Transport};
+use crate::{AllowedSources, EndpointFactory, Obfuscation, Padding, ProxyProtocol, Target, TargetOrder, DEFAULT_CONNECT_TIMEOUT, DEFAULT_FAIL_BACK, ProxyClient, ProxyServer, ProxyServerClientHandler, ReadyCallback, Sessions, Status, StatusCallback, TunnelClosedCallback};
+
+fn default_wireguard() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 51820)
+}
+
+fn check_max_datagram(max_datagram: usize) -> Result<()> {
+    if max_datagram == 0 || max_datagram > MAX_DATAGRAM {
+        return Err(Error::Config(format!("max_datagram must be between 1 and {}", MAX_DATAGRAM)));
+    }
+    Ok(())
+}
+
+fn check_tls_support() -> Result<()> {
+    #[cfg(not(any(feature = "async", feature = "tls", feature = "openssl_vendored")))]
+    return Err(Error::config("compiled without TLS support"));
+    #[cfg(any(feature = "async", feature = "tls", feature = "openssl_vendored"))]
+    Ok(())
+}
+
+fn check_http2(http2: &Option<String>) -> Result<()> {
+    match http2 {
+        Some(path) if !path.starts_with('/') => Err(Error::Config(format!("http2 path {} must start with /", path))),
+        #[cfg(not(feature = "http2"))]
+        Some(_) => Err(Error::config("compiled without http2 support")),
+        _ => Ok(()),
+    }
+}
+
+/// how the client does TLS, without a pinned pubkey the server is not verified at all
+#[derive(Clone, Debug, Default)]
+pub struct TlsClientConfig {
+    /// sent in SNI, None sends no SNI
+    pub hostname: Option<String>,
+    /// any number of "sha256//" + base64 sha256 hashes separated by ";", like curl's --pinnedpubkey
+    pub pinnedpubkey: Option<String>,
+}
+
+impl TlsClientConfig {
+    pub fn new() -> TlsClientConfig {
+        TlsClientConfig::default()
+    }
+
+    pub fn hostname(mut self, hostname: &str) -> TlsClientConfig {
+        self.hostname = Some(hostname.to_owned());
+        self
+    }
+
+    pub fn pinnedpubkey(mut self, pinnedpubkey: &str) -> TlsClientConfig {
+        self.pinnedpubkey = Some(pinnedpubkey.to_owned());
+        self
+    }
+
+    fn validate(&self) -> Result<()> {
+        check_tls_support()?;
+        if let Some(pinnedpubkey) = &self.pinnedpubkey {
+            for key in pinnedpubkey.split(';') {
+                // base64 of a 32 byte sha256 is always 43 characters and one = of padding
+                let valid = match key.strip_prefix("sha256//") {
+                    Some(hash) => hash.len() == 44 && hash.ends_with('=') && hash[..43].bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/'),
+                    None => false,
+                };
+                if !valid {
+                    return Err(Error::Config(format!("invalid pinnedpubkey '{}', must be sha256// followed by a base64 sha256 hash", key)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// key and cert the server listens with, PEM files, "-" reads it from stdin
+#[derive(Clone, Debug)]
+pub struct TlsServerConfig {
+    pub key: String,
+    pub cert: String,
+}
+
+impl TlsServerConfig {
+    pub fn new(key: &str, cert: &str) -> TlsServerConfig {
+        TlsServerConfig {
+            key: key.to_owned(),
+            cert: cert.to_owned(),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        check_tls_support()?;
+        for (what, path) in [("key", &self.key), ("cert", &self.cert)].iter() {
+            if *path != "-" {
+                std::fs::File::open(path).map_err(|e| Error::Config(format!("cannot read TLS {} {}: {}", what, path, e)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// builds a ProxyClient, at least tcp_target or unix_target is required
+pub struct ProxyClientBuilder {
+    udp_host: SocketAddr,
+    tcp_target: Option<String>,
+    socket_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    max_datagram: usize,
+    multi_peer: bool,
+    compress: bool,
+    bond: usize,
+    resume: bool,
+    allowed_sources: AllowedSources,
+    tls: Option<TlsClientConfig>,
+    transport: Option<Arc<dyn Transport>>,
+    fallback_targets: Vec<Target>,
+    target_order: TargetOrder,
+    fail_back: Option<Duration>,
+    on_ready: Option<ReadyCallback>,
+    reconnect: Option<Duration>,
+    on_status: Option<StatusCallback>,
+    status_file: Option<String>,
+    padding: Option<Padding>,
+    http2: Option<String>,
+}
+
+impl Default for ProxyClientBuilder {
+    fn default() -> Self {
+        ProxyClientBuilder::new()
+    }
+}
+
+impl ProxyClientBuilder {
+    pub fn new() -> ProxyClientBuilder {
+        ProxyClientBuilder {
+            udp_host: default_wireguard(),
+            tcp_target: None,
+            socket_timeout: None,
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            max_datagram: DEFAULT_MAX_DATAGRAM,
+            multi_peer: false,
+            compress: false,
+            bond: 1,
+            resume: false,
+            allowed_sources: AllowedSources::default(),
+            tls: None,
+            transport: None,
+            fallback_targets: Vec::new(),
+            target_order: TargetOrder::Listed,
+            fail_back: Some(DEFAULT_FAIL_BACK),
+            on_ready: None,
+            reconnect: None,
+            on_status: None,
+            status_file: None,
+            padding: None,
+            http2: None,
+        }
+    }
+
+    /// where to listen for the wireguard client, default 127.0.0.1:51820
+    pub fn udp_host(mut self, udp_host: SocketAddr) -> ProxyClientBuilder {
+        self.udp_host = udp_host;
+        self
+    }
+
+    /// where the wireguard-proxy server is listening
+    pub fn tcp_target(mut self, tcp_target: SocketAddr) -> ProxyClientBuilder {
+        self.tcp_target = Some(tcp_target.to_string());
+        self
+    }
+
+    /// connect to the server over a unix socket instead
+    #[cfg(unix)]
+    pub fn unix_target<P: AsRef<std::path::Path>>(mut self, path: P) -> ProxyClientBuilder {
+        self.tcp_target = Some(format!("unix:{}", path.as_ref().display()));
+        self
+    }
+
+    /// give up when nothing is received for this long, default None waits forever
+    pub fn socket_timeout(mut self, socket_timeout: Option<Duration>) -> ProxyClientBuilder {
+        self.socket_timeout = socket_timeout;
+        self
+    }
+
+    /// how long connecting to a target may take, None leaves it up to the os, default 10 seconds
+    pub fn connect_timeout(mut self, connect_timeout: Option<Duration>) -> ProxyClientBuilder {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// largest udp datagram carried, must match the server, default 2048
+    pub fn max_datagram(mut self, max_datagram: usize) -> ProxyClientBuilder {
+        self.max_datagram = max_datagram;
+        self
+    }
+
+    /// carry datagrams from any number of local wireguards over the one connection, the server must support it, default false
+    pub fn multi_peer(mut self, multi_peer: bool) -> ProxyClientBuilder {
+        self.multi_peer = multi_peer;
+        self
+    }
+
+    /// compress datagrams both ways where that makes them smaller, the server must answer the hello but can turn
+    /// compression down, default false
+    pub fn compress(mut self, compress: bool) -> ProxyClientBuilder {
+        self.compress = compress;
+        self
+    }
+
+    /// stripe datagrams across this many parallel connections, carrying on while any is left, up to 16, not with
+    /// multi_peer, the server must support it, default 1 which doesn't bond
+    pub fn bond(mut self, connections: usize) -> ProxyClientBuilder {
+        self.bond = connections;
+        self
+    }
+
+    /// ask the server to keep the tunnel's udp socket while reconnecting, so wireguard behind it sees the same source
+    /// port, not with multi_peer, carries on without if the server doesn't offer it, only over obfuscation or TLS
+    /// with a pinnedpubkey, default false
+    pub fn resume(mut self, resume: bool) -> ProxyClientBuilder {
+        self.resume = resume;
+        self
+    }
+
+    /// only take wireguard packets from these local sources, default any
+    pub fn allowed_sources(mut self, allowed_sources: AllowedSources) -> ProxyClientBuilder {
+        self.allowed_sources = allowed_sources;
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsClientConfig) -> ProxyClientBuilder {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// run the tunnel over this instead of plain or tls
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> ProxyClientBuilder {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// obfuscate with a secret shared with the server, as the transport
+    pub fn obfuscation(self, obfuscation: Obfuscation) -> ProxyClientBuilder {
+        self.transport(Arc::new(obfuscation))
+    }
+
+    /// another target to try when the ones before it can't be connected, any number can be added
+    pub fn fallback_target(mut self, target: Target) -> ProxyClientBuilder {
+        self.fallback_targets.push(target);
+        self
+    }
+
+    /// whether targets are tried as listed or fastest first, default listed
+    pub fn target_order(mut self, target_order: TargetOrder) -> ProxyClientBuilder {
+        self.target_order = target_order;
+        self
+    }
+
+    /// while on a fallback, how often to check whether one ahead of it connects again, None never does, default 60 seconds
+    pub fn fail_back(mut self, fail_back: Option<Duration>) -> ProxyClientBuilder {
+        self.fail_back = fail_back;
+        self
+    }
+
+    /// called with a status message once connected to the server
+    pub fn on_ready<F: Fn(&str) + Send + Sync + 'static>(mut self, on_ready: F) -> ProxyClientBuilder {
+        self.on_ready = Some(Arc::new(on_ready));
+        self
+    }
+
+    /// how long run() waits to start over after the tunnel fails, None returns the error instead, default None
+    pub fn reconnect(mut self, reconnect: Option<Duration>) -> ProxyClientBuilder {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// called with every change of status, from whichever thread made it
+    pub fn on_status<F: Fn(&Status) + Send + Sync + 'static>(mut self, on_status: F) -> ProxyClientBuilder {
+        self.on_status = Some(Arc::new(on_status));
+        self
+    }
+
+    /// also write every change of status to this file as one line of JSON
+    pub fn status_file(mut self, status_file: &str) -> ProxyClientBuilder {
+        self.status_file = Some(status_file.to_owned());
+        self
+    }
+
+    /// pad what's written to the server, which has to be new enough to throw it away
+    pub fn padding(mut self, padding: Padding) -> ProxyClientBuilder {
+        self.padding = Some(padding);
+        self
+    }
+
+    /// carry the tunnel in an HTTP/2 POST to this path, for front ends that only forward HTTP/2, over TLS when tls is
+    /// set, the server must listen for HTTP/2 on the same path
+    pub fn http2(mut self, path: &str) -> ProxyClientBuilder {
+        self.http2 = Some(path.to_owned());
+        self
+    }
+
+    pub fn build(self) -> Result<ProxyClient> {
+        let tcp_target = self.tcp_target.ok_or_else(|| Error::config("tcp_target is required"))?;
+        check_max_datagram(self.max_datagram)?;
+        if self.tls.is_some() && self.transport.is_some() {
+            return Err(Error::config("only one of tls or transport can be set"));
+        }
+        if let Some(tls) = &self.tls {
+            tls.validate()?;
+        }
+        for target in self.fallback_targets.iter() {
+            if let Some(tls) = &target.tls {
+                tls.validate()?;
+            }
+        }
+        if self.fail_back == Some(Duration::from_secs(0)) {
+            return Err(Error::config("fail_back must be more than 0, None never fails back"));
+        }
+        if self.connect_timeout == Some(Duration::from_secs(0)) {
+            return Err(Error::config("connect_timeout must be more than 0, None leaves it up to the os"));
+        }
+        if self.reconnect == Some(Duration::from_secs(0)) {
+            return Err(Error::config("reconnect must be more than 0, None never reconnects"));
+        }
+        if let Some(padding) = &self.padding {
+            padding.validate()?;
+        }
+        bond::validate(self.bond, self.multi_peer, self.resume)?;
+        compress::check(self.compress)?;
+        check_http2(&self.http2)?;
+
+        let mut proxy_client = ProxyClient::new(self.udp_host.to_string(), tcp_target, 0);
+        proxy_client.socket_timeout = self.socket_timeout;
+        proxy_client.connect_timeout = self.connect_timeout;
+        proxy_client.max_datagram = self.max_datagram;
+        proxy_client.multi_peer = self.multi_peer;
+        proxy_client.compress = self.compress;
+        proxy_client.bond = self.bond;
+        proxy_client.resume = self.resume;
+        proxy_client.allowed_sources = self.allowed_sources;
+        proxy_client.tls = self.tls;
+        proxy_client.transport = self.transport;
+        proxy_client.fallback_targets = self.fallback_targets;
+        proxy_client.target_order = self.target_order;
+        proxy_client.fail_back = self.fail_back;
+        proxy_client.on_ready = self.on_ready;
+        proxy_client.reconnect = self.reconnect;
+        proxy_client.on_status = self.on_status;
+        proxy_client.status_file = self.status_file;
+        proxy_client.padding = self.padding;
+        proxy_client.http2 = self.http2;
+        Ok(proxy_client)
+    }
+}
+
+/// builds a ProxyServer, at least tcp_host or unix_host is required
+pub struct ProxyServerBuilder {
+    tcp_host: Option<String>,
+    unix_socket_mode: Option<u32>,
+    udp_target: SocketAddr,
+    udp_bind_ip: IpAddr,
+    udp_low_port: u16,
+    udp_high_port: u16,
+    socket_timeout: Option<Duration>,
+    max_datagram: usize,
+    tls: Option<TlsServerConfig>,
+    acceptor: Option<Arc<dyn Acceptor>>,
+    on_ready: Option<ReadyCallback>,
+    on_tunnel_closed: Option<TunnelClosedCallback>,
+    endpoint_factory: Option<EndpointFactory>,
+    admin_socket: Option<String>,
+    padding: Option<Padding>,
+    resume: Option<Duration>,
+    http2: Option<String>,
+    proxy_protocol: Option<ProxyProtocol>,
+    proxy_protocol_from: AllowedSources,
+}
+
+impl Default for ProxyServerBuilder {
+    fn default() -> Self {
+        ProxyServerBuilder::new()
+    }
+}
+
+impl ProxyServerBuilder {
+    pub fn new() -> ProxyServerBuilder {
+        ProxyServerBuilder {
+            tcp_host: None,
+            unix_socket_mode: None,
+            udp_target: default_wireguard(),
+            udp_bind_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            udp_low_port: 30000,
+            udp_high_port: 40000,
+            socket_timeout: None,
+            max_datagram: DEFAULT_MAX_DATAGRAM,
+            tls: None,
+            acceptor: None,
+            on_ready: None,
+            on_tunnel_closed: None,
+            endpoint_factory: None,
+            admin_socket: None,
+            padding: None,
+            resume: None,
+            http2: None,
+            proxy_protocol: None,
+            proxy_protocol_from: AllowedSources::default(),
+        }
+    }
+
+    /// where to listen for wireguard-proxy clients
+    pub fn tcp_host(mut self, tcp_host: SocketAddr) -> ProxyServerBuilder {
+        self.tcp_host = Some(tcp_host.to_string());
+        self
+    }
+
+    /// listen on a unix socket instead, mode sets its permissions, None leaves it up to umask
+    #[cfg(unix)]
+    pub fn unix_host<P: AsRef<std::path::Path>>(mut self, path: P, mode: Option<u32>) -> ProxyServerBuilder {
+        self.tcp_host = Some(format!("unix:{}", path.as_ref().display()));
+        self.unix_socket_mode = mode;
+        self
+    }
+
+    /// where the wireguard server is listening, default 127.0.0.1:51820
+    pub fn udp_target(mut self, udp_target: SocketAddr) -> ProxyServerBuilder {
+        self.udp_target = udp_target;
+        self
+    }
+
+    /// ip and inclusive port range to bind, one port per tunnel, default 127.0.0.1 30000-40000
+    pub fn udp_bind_range(mut self, ip: IpAddr, low_port: u16, high_port: u16) -> ProxyServerBuilder {
+        self.udp_bind_ip = ip;
+        self.udp_low_port = low_port;
+        self.udp_high_port = high_port;
+        self
+    }
+
+    /// close a tunnel when nothing is received for this long, default None waits forever
+    pub fn socket_timeout(mut self, socket_timeout: Option<Duration>) -> ProxyServerBuilder {
+        self.socket_timeout = socket_timeout;
+        self
+    }
+
+    /// largest udp datagram carried, must match the clients, default 2048
+    pub fn max_datagram(mut self, max_datagram: usize) -> ProxyServerBuilder {
+        self.max_datagram = max_datagram;
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsServerConfig) -> ProxyServerBuilder {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// wrap accepted connections with this instead of plain or tls
+    pub fn acceptor(mut self, acceptor: Arc<dyn Acceptor>) -> ProxyServerBuilder {
+        self.acceptor = Some(acceptor);
+        self
+    }
+
+    /// obfuscate with a secret shared with the clients, as the acceptor
+    pub fn obfuscation(self, obfuscation: Obfuscation) -> ProxyServerBuilder {
+        self.acceptor(Arc::new(obfuscation))
+    }
+
+    /// called with a status message once listening
+    pub fn on_ready<F: Fn(&str) + Send + Sync + 'static>(mut self, on_ready: F) -> ProxyServerBuilder {
+        self.on_ready = Some(Arc::new(on_ready));
+        self
+    }
+
+    /// called with the peer and the reason whenever a tunnel closes
+    pub fn on_tunnel_closed<F: Fn(&str, &Error) + Send + Sync + 'static>(mut self, on_tunnel_closed: F) -> ProxyServerBuilder {
+        self.on_tunnel_closed = Some(Arc::new(on_tunnel_closed));
+        self
+    }
+
+    /// called for each tunnel to get the endpoint its datagrams go to, instead of a udp socket to udp_target
+    pub fn endpoint_factory<F: Fn() -> Result<BoxEndpoint> + Send + Sync + 'static>(mut self, endpoint_factory: F) -> ProxyServerBuilder {
+        self.endpoint_factory = Some(Arc::new(endpoint_factory));
+        self
+    }
+
+    /// serve the admin protocol on a unix socket only the owner can use, to list or kill tunnels, see admin.rs
+    #[cfg(unix)]
+    pub fn admin_socket<P: AsRef<std::path::Path>>(mut self, path: P) -> ProxyServerBuilder {
+        self.admin_socket = Some(path.as_ref().display().to_string());
+        self
+    }
+
+    /// keep a tunnel's udp socket this long after the connection drops for a client that asked to resume it over
+    /// obfuscation or TLS, default None doesn't offer resuming
+    pub fn resume(mut self, grace: Option<Duration>) -> ProxyServerBuilder {
+        self.resume = grace;
+        self
+    }
+
+    /// pad what's written to clients, which have to be new enough to throw it away
+    pub fn padding(mut self, padding: Padding) -> ProxyServerBuilder {
+        self.padding = Some(padding);
+        self
+    }
+
+    /// take each HTTP/2 POST to this path as a tunnel, over TLS when tls is set, anything else gets a 404
+    pub fn http2(mut self, path: &str) -> ProxyServerBuilder {
+        self.http2 = Some(path.to_owned());
+        self
+    }
+
+    /// take the client address from a PROXY protocol header a load balancer puts in front of each connection,
+    /// needs proxy_protocol_from as anyone connecting directly can claim any address
+    pub fn proxy_protocol(mut self, mode: ProxyProtocol) -> ProxyServerBuilder {
+        self.proxy_protocol = Some(mode);
+        self
+    }
+
+    /// the load balancers a PROXY protocol header is believed from, connections from anywhere else are taken as they
+    /// are, or closed when strict
+    pub fn proxy_protocol_from(mut self, sources: AllowedSources) -> ProxyServerBuilder {
+        self.proxy_protocol_from = sources;
+        self
+    }
+
+    pub fn build(self) -> Result<ProxyServer> {
+        let tcp_host = self.tcp_host.ok_or_else(|| Error::config("tcp_host is required"))?;
+        check_max_datagram(self.max_datagram)?;
+        if self.udp_low_port == 0 || self.udp_low_port > self.udp_high_port {
+            return Err(Error::Config(format!("invalid udp port range {}-{}", self.udp_low_port, self.udp_high_port)));
+        }
+        // a loopback or unspecified bind ip follows udp_target's family, anything else has to match it
+        family::pick(&[self.udp_bind_ip], &[self.udp_target])?;
+        if self.tls.is_some() && self.acceptor.is_some() {
+            return Err(Error::config("only one of tls or acceptor can be set"));
+        }
+        if let Some(tls) = &self.tls {
+            tls.validate()?;
+        }
+        if let Some(padding) = &self.padding {
+            padding.validate()?;
+        }
+        if self.resume == Some(Duration::from_secs(0)) {
+            return Err(Error::config("resume must be more than 0, None doesn't offer resuming"));
+        }
+        check_http2(&self.http2)?;
+
+        let proxy_server = ProxyServer {
+            tcp_host,
+            unix_socket_mode: self.unix_socket_mode,
+            tls: self.tls,
+            acceptor: self.acceptor,
+            on_ready: self.on_ready,
+            client_handler: Arc::new(ProxyServerClientHandler {
+                udp_target: self.udp_target.to_string(),
+                udp_host: self.udp_bind_ip.to_string(),
+                udp_low_port: self.udp_low_port,
+                udp_high_port: self.udp_high_port,
+                socket_timeout: self.socket_timeout,
+                max_datagram: self.max_datagram,
+                on_tunnel_closed: self.on_tunnel_closed,
+                endpoint_factory: self.endpoint_factory,
+                re_resolve: None,
+                resolver: None,
+                resume: self.resume,
+                bonds: Default::default(),
+            }),
+            sessions: Arc::new(Sessions::new()),
+            admin_socket: self.admin_socket,
+            padding: self.padding,
+            http2: self.http2,
+            proxy_protocol: self.proxy_protocol,
+            proxy_protocol_from: self.proxy_protocol_from,
+        };
+        proxy_server.check_proxy_protocol()?;
+        Ok(proxy_server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_client_builder() {
+        assert!(matches!(ProxyClientBuilder::new().build(), Err(Error::Config(_))));
+        assert!(matches!(ProxyClientBuilder::new().tcp_target(addr("127.0.0.1:5555")).max_datagram(0).build(), Err(Error::Config(_))));
+        assert!(matches!(ProxyClientBuilder::new().tcp_target(addr("127.0.0.1:5555")).max_datagram(65536).build(), Err(Error::Config(_))));
+
+        let client = ProxyClientBuilder::new()
+            .tcp_target(addr("[::1]:5555"))
+            .udp_host(addr("127.0.0.1:51821"))
+            .socket_timeout(Some(Duration::from_secs(5)))
+            .max_datagram(9000)
+            .multi_peer(true)
+            .allowed_sources(AllowedSources::loopback())
+            .build()
+            .unwrap();
+        assert_eq!(client.tcp_target, "[::1]:5555");
+        assert_eq!(client.udp_host, "127.0.0.1:51821");
+        assert_eq!(client.socket_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(client.max_datagram, 9000);
+        assert!(client.multi_peer);
+        assert_eq!(client.allowed_sources, AllowedSources::loopback());
+        assert!(client.tls.is_none());
+        assert!(client.fallback_targets.is_empty());
+        assert_eq!(client.fail_back, Some(DEFAULT_FAIL_BACK));
+        assert_eq!(client.connect_timeout, Some(DEFAULT_CONNECT_TIMEOUT));
+
+        let builder = || ProxyClientBuilder::new().tcp_target(addr("127.0.0.1:5555"));
+        assert!(matches!(builder().fail_back(Some(Duration::from_secs(0))).build(), Err(Error::Config(_))));
+        assert!(matches!(builder().connect_timeout(Some(Duration::from_secs(0))).build(), Err(Error::Config(_))));
+        assert!(matches!(builder().bond(2).multi_peer(true).build(), Err(Error::Config(_))));
+        assert_eq!(builder().bond(4).build().unwrap().bond, 4);
+        assert!(matches!(builder().resume(true).multi_peer(true).build(), Err(Error::Config(_))));
+        assert!(matches!(builder().http2("tunnel").build(), Err(Error::Config(_))));
+        assert_eq!(builder().compress(true).build().is_ok(), cfg!(feature = "compress"));
+        let client = builder()
+            .fallback_target(Target::new("gw.example.com:443"))
+            .fallback_target(Target::new("unix:/run/wgp.sock"))
+            .target_order(TargetOrder::Latency)
+            .fail_back(None)
+            .build()
+            .unwrap();
+        let fallbacks: Vec<_> = client.fallback_targets.iter().map(|target| target.tcp_target.as_str()).collect();
+        assert_eq!(fallbacks, vec!["gw.example.com:443", "unix:/run/wgp.sock"]);
+        assert_eq!(client.target_order, TargetOrder::Latency);
+        assert_eq!(client.fail_back, None);
+    }
+
+    #[cfg(any(feature = "async", feature = "tls", feature = "openssl_vendored"))]
+    #[test]
+    fn test_client_tls() {
+        let builder = || ProxyClientBuilder::new().tcp_target(addr("127.0.0.1:5555"));
+        let good = "sha256//BEyQeSjwwUBLXXNuCILHRWyV1gLmY31CdMHNA4VH4dE=";
+        for bad in ["BEyQeSjwwUBLXXNuCILHRWyV1gLmY31CdMHNA4VH4dE=", "sha256//short=", &format!("{};sha1//abc", good)].iter() {
+            assert!(matches!(builder().tls(TlsClientConfig::new().pinnedpubkey(bad)).build(), Err(Error::Config(_))), "{}", bad);
+        }
+        assert!(matches!(builder().fallback_target(Target::new("127.0.0.1:443").tls(TlsClientConfig::new().pinnedpubkey("sha256//short="))).build(), Err(Error::Config(_))));
+        let client = builder().tls(TlsClientConfig::new().hostname("example.com").pinnedpubkey(&format!("{};{}", good, good))).build().unwrap();
+        assert_eq!(client.tls.unwrap().hostname.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn test_server_builder() {
+        assert!(matches!(ProxyServerBuilder::new().build(), Err(Error::Config(_))));
+        let builder = || ProxyServerBuilder::new().tcp_host(addr("127.0.0.1:5555"));
+        assert!(matches!(builder().udp_bind_range("127.0.0.1".parse().unwrap(), 40000, 30000).build(), Err(Error::Config(_))));
+        assert!(matches!(builder().udp_bind_range("fd00::1".parse().unwrap(), 30000, 40000).build(), Err(Error::Config(_))));
+        // the default bind ip just follows an ipv6 udp_target
+        assert!(builder().udp_target(addr("[::1]:51820")).build().is_ok());
+        assert!(matches!(builder().tls(TlsServerConfig::new("/nonexistent.key", "/nonexistent.pem")).build(), Err(Error::Config(_))));
+        assert!(matches!(builder().resume(Some(Duration::from_secs(0))).build(), Err(Error::Config(_))));
+        assert!(matches!(builder().http2("").build(), Err(Error::Config(_))));
+        assert!(matches!(builder().proxy_protocol(ProxyProtocol::Optional).build(), Err(Error::Config(_))));
+        #[cfg(feature = "http2")]
+        assert_eq!(builder().http2("/tunnel").build().unwrap().http2.as_deref(), Some("/tunnel"));
+
+        let server = builder()
+            .udp_target(addr("[::1]:51820"))
+            .udp_bind_range("::1".parse().unwrap(), 30000, 30010)
+            .on_tunnel_closed(|_, _| {})
+            .resume(Some(Duration::from_secs(30)))
+            .proxy_protocol("strict".parse().unwrap())
+            .proxy_protocol_from("10.0.0.0/24".parse().unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(server.tcp_host, "127.0.0.1:5555");
+        assert_eq!(server.proxy_protocol, Some(ProxyProtocol::Strict));
+        assert!(matches!("always".parse::<ProxyProtocol>(), Err(Error::Config(_))));
+        assert_eq!(server.client_handler.udp_target, "[::1]:51820");
+        assert_eq!(server.client_handler.udp_host, "::1");
+        assert_eq!(server.client_handler.udp_high_port, 30010);
+        assert!(server.client_handler.on_tunnel_closed.is_some());
+        assert_eq!(server.client_handler.resume, Some(Duration::from_secs(30)));
+    }
+}
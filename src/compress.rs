@@ -0,0 +1,319 @@
+// compression shrinks datagrams that have something to squeeze, plaintext tunnelled inside wireguard in a lab or
+// small handshake packets where the framing is a good part of them. once both ends agreed to COMPRESS in the hello
+// each datagram goes out as a COMPRESSED control frame holding it as an lz4 block whenever that comes out smaller
+// than its datagram frame, and as the datagram frame otherwise, so encrypted packets cost nothing but the attempt.
+// lz4 is only there with the compress feature, without it COMPRESS is never asked for nor agreed to
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::control::COMPRESSED;
+use crate::error::{Error, Result};
+use crate::frame::{read_header, write_header, Frame, FrameReader, CONTROL_HEADER, HEADER};
+
+/// datagram bytes before compression and what they took in their frames, each way, shared by all clones of a stream
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    pub tx_raw: AtomicU64,
+    pub tx_compressed: AtomicU64,
+    pub rx_raw: AtomicU64,
+    pub rx_compressed: AtomicU64,
+}
+
+impl CompressionStats {
+    /// what was sent as a fraction of what it was before, 1.0 before anything was
+    pub fn tx_ratio(&self) -> f64 {
+        ratio(&self.tx_compressed, &self.tx_raw)
+    }
+
+    /// what was received as a fraction of what it was before, 1.0 before anything was
+    pub fn rx_ratio(&self) -> f64 {
+        ratio(&self.rx_compressed, &self.rx_raw)
+    }
+
+    pub(crate) fn json(&self) -> String {
+        format!(
+            r#"{{"tx_raw":{},"tx_compressed":{},"rx_raw":{},"rx_compressed":{}}}"#,
+            self.tx_raw.load(Ordering::Relaxed),
+            self.tx_compressed.load(Ordering::Relaxed),
+            self.rx_raw.load(Ordering::Relaxed),
+            self.rx_compressed.load(Ordering::Relaxed),
+        )
+    }
+}
+
+fn ratio(compressed: &AtomicU64, raw: &AtomicU64) -> f64 {
+    match raw.load(Ordering::Relaxed) {
+        0 => 1.0,
+        raw => compressed.load(Ordering::Relaxed) as f64 / raw as f64,
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} datagram bytes as {} ({:.1}%), received {} as {} ({:.1}%)",
+            self.tx_raw.load(Ordering::Relaxed),
+            self.tx_compressed.load(Ordering::Relaxed),
+            self.tx_ratio() * 100.0,
+            self.rx_raw.load(Ordering::Relaxed),
+            self.rx_compressed.load(Ordering::Relaxed),
+            self.rx_ratio() * 100.0,
+        )
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Error::protocol(msg))
+}
+
+/// asking for compression is a config error when it's compiled out
+pub fn check(compress: bool) -> Result<()> {
+    if compress && cfg!(not(feature = "compress")) {
+        return Err(Error::config("compiled without compression support"));
+    }
+    Ok(())
+}
+
+// datagram as an lz4 block in block, None if that doesn't come out smaller than its datagram frame
+#[cfg(feature = "compress")]
+fn lz4_compress(datagram: &[u8], block: &mut Vec<u8>) -> Option<usize> {
+    block.resize(lz4_flex::block::get_maximum_output_size(datagram.len()), 0);
+    match lz4_flex::block::compress_into(datagram, block) {
+        // the kind and length of a control frame have to be paid for too
+        Ok(len) if CONTROL_HEADER + len < datagram.len() => Some(len),
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "compress"))]
+fn lz4_compress(_datagram: &[u8], _block: &mut Vec<u8>) -> Option<usize> {
+    None
+}
+
+#[cfg(feature = "compress")]
+fn lz4_decompress(block: &[u8], datagram: &mut [u8]) -> io::Result<usize> {
+    match lz4_flex::block::decompress_into(block, datagram) {
+        Ok(0) => Err(invalid("compressed frame holds an empty datagram".to_owned())),
+        Ok(len) => Ok(len),
+        Err(e) => Err(invalid(format!("invalid compressed frame: {}", e))),
+    }
+}
+
+#[cfg(not(feature = "compress"))]
+fn lz4_decompress(_block: &[u8], _datagram: &mut [u8]) -> io::Result<usize> {
+    Err(invalid("compressed frame but compiled without compression support".to_owned()))
+}
+
+/// rewrites the datagram frames written as COMPRESSED frames where that makes them smaller, one direction of one stream
+pub(crate) struct Compressor {
+    stats: Arc<CompressionStats>,
+    // the end of a write that stopped partway through a frame, finished by the next one
+    pending: Vec<u8>,
+    block: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl Compressor {
+    pub fn new(stats: Arc<CompressionStats>) -> Compressor {
+        Compressor {
+            stats,
+            pending: Vec::new(),
+            block: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    /// the frames in buf, compressed, for compressed() to hand out until the next call
+    pub fn compress(&mut self, buf: &[u8]) {
+        self.out.clear();
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend_from_slice(buf);
+        let mut rest = &pending[..];
+        while rest.len() >= HEADER {
+            let len = match read_header(rest) {
+                0 if rest.len() < HEADER + CONTROL_HEADER => break,
+                0 => HEADER + CONTROL_HEADER + read_header(&rest[HEADER + 1..]),
+                len => HEADER + len,
+            };
+            if rest.len() < len {
+                break;
+            }
+            match read_header(rest) {
+                0 => self.out.extend_from_slice(&rest[..len]),
+                _ => self.push_datagram(&rest[HEADER..len]),
+            }
+            rest = &rest[len..];
+        }
+        let left = rest.len();
+        pending.drain(..pending.len() - left);
+        self.pending = pending;
+    }
+
+    pub fn compressed(&self) -> &[u8] {
+        &self.out
+    }
+
+    fn push_datagram(&mut self, datagram: &[u8]) {
+        let start = self.out.len();
+        match lz4_compress(datagram, &mut self.block) {
+            Some(len) => {
+                self.out.extend_from_slice(&[0, 0, COMPRESSED, 0, 0]);
+                write_header(&mut self.out[start + HEADER + 1..], len);
+                self.out.extend_from_slice(&self.block[..len]);
+            }
+            None => {
+                self.out.extend_from_slice(&[0, 0]);
+                write_header(&mut self.out[start..], datagram.len());
+                self.out.extend_from_slice(datagram);
+            }
+        }
+        self.stats.tx_raw.fetch_add(datagram.len() as u64, Ordering::Relaxed);
+        self.stats.tx_compressed.fetch_add((self.out.len() - start - HEADER) as u64, Ordering::Relaxed);
+    }
+}
+
+/// turns COMPRESSED frames read back into the datagram frames they were, one direction of one stream
+pub(crate) struct Decompressor {
+    reader: FrameReader,
+    stats: Arc<CompressionStats>,
+    datagram: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl Decompressor {
+    /// reader holds anything already read from the stream
+    pub fn new(reader: FrameReader, max_datagram: usize, stats: Arc<CompressionStats>) -> Decompressor {
+        Decompressor {
+            reader,
+            stats,
+            datagram: vec![0u8; max_datagram],
+            out: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// free space to read from the stream into, call filled() with how much was
+    pub fn read_mut(&mut self) -> &mut [u8] {
+        self.reader.read_mut()
+    }
+
+    pub fn filled(&mut self, len: usize) {
+        self.reader.filled(len);
+    }
+
+    /// as much of the frames read as fits into buf, 0 means read more
+    pub fn open(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.out.len() {
+            self.out.clear();
+            self.pos = 0;
+            self.decompress()?;
+        }
+        let len = buf.len().min(self.out.len() - self.pos);
+        buf[..len].copy_from_slice(&self.out[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+
+    // every complete frame the reader has into out, decompressed
+    fn decompress(&mut self) -> io::Result<()> {
+        while let Some(frame) = self.reader.next_frame().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))? {
+            let start = self.out.len();
+            match frame {
+                Frame::Control(COMPRESSED, block) => {
+                    let len = lz4_decompress(block, &mut self.datagram)?;
+                    self.out.extend_from_slice(&[0, 0]);
+                    write_header(&mut self.out[start..], len);
+                    self.out.extend_from_slice(&self.datagram[..len]);
+                    self.stats.rx_raw.fetch_add(len as u64, Ordering::Relaxed);
+                    self.stats.rx_compressed.fetch_add(block.len() as u64, Ordering::Relaxed);
+                }
+                Frame::Control(kind, payload) => {
+                    self.out.extend_from_slice(&[0, 0, kind, 0, 0]);
+                    write_header(&mut self.out[start + HEADER + 1..], payload.len());
+                    self.out.extend_from_slice(payload);
+                }
+                Frame::Datagram(datagram) => {
+                    self.out.extend_from_slice(&[0, 0]);
+                    write_header(&mut self.out[start..], datagram.len());
+                    self.out.extend_from_slice(datagram);
+                    self.stats.rx_raw.fetch_add(datagram.len() as u64, Ordering::Relaxed);
+                    self.stats.rx_compressed.fetch_add(datagram.len() as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "compress"))]
+mod tests {
+    use super::*;
+    use crate::frame::{FrameWriter, DEFAULT_MAX_DATAGRAM};
+
+    // feeds compressed bytes to the decompressor a few at a time and reads it all back
+    fn decompress_all(decompressor: &mut Decompressor, mut compressed: &[u8], chunk: usize) -> io::Result<Vec<u8>> {
+        let mut got = Vec::new();
+        let mut buf = [0u8; 100];
+        loop {
+            let len = decompressor.open(&mut buf)?;
+            if len > 0 {
+                got.extend_from_slice(&buf[..len]);
+                continue;
+            }
+            if compressed.is_empty() {
+                return Ok(got);
+            }
+            let take = compressed.len().min(chunk);
+            decompressor.read_mut()[..take].copy_from_slice(&compressed[..take]);
+            decompressor.filled(take);
+            compressed = &compressed[take..];
+        }
+    }
+
+    #[test]
+    fn test_compression() {
+        let plaintext = b"GET / HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\nAccept: */*\r\nAccept: */*\r\n\r\n".repeat(10);
+        let mut random = vec![0u8; 500];
+        crate::random::Rng::new().unwrap().fill(&mut random);
+        let mut writer = FrameWriter::new(DEFAULT_MAX_DATAGRAM);
+        writer.push(&plaintext).unwrap();
+        writer.push_control(9, b"control").unwrap();
+        writer.push(&random).unwrap();
+        writer.push(b"tiny").unwrap();
+        let frames = writer.as_bytes().to_vec();
+
+        let stats = Arc::new(CompressionStats::default());
+        let mut compressor = Compressor::new(stats.clone());
+        // split partway through a frame, the rest of it comes with the next write
+        compressor.compress(&frames[..7]);
+        let mut compressed = compressor.compressed().to_vec();
+        compressor.compress(&frames[7..]);
+        compressed.extend_from_slice(compressor.compressed());
+        assert!(compressed.len() < frames.len() / 2);
+        // only the plaintext shrank, the rest went as it was
+        assert_eq!(stats.tx_raw.load(Ordering::Relaxed), (plaintext.len() + random.len() + 4) as u64);
+        assert!(stats.tx_ratio() < 0.5);
+        assert!(compressed.ends_with(&frames[frames.len() - random.len() - 8..]));
+
+        for chunk in [1, 3, 1000].iter() {
+            let stats = Arc::new(CompressionStats::default());
+            let mut decompressor = Decompressor::new(FrameReader::new(DEFAULT_MAX_DATAGRAM), DEFAULT_MAX_DATAGRAM, stats.clone());
+            assert_eq!(decompress_all(&mut decompressor, &compressed, *chunk).unwrap(), frames);
+            assert_eq!(stats.rx_raw.load(Ordering::Relaxed), (plaintext.len() + random.len() + 4) as u64);
+        }
+        assert_eq!(CompressionStats::default().tx_ratio(), 1.0);
+
+        // a block that doesn't decompress, or to more than max datagram, is a protocol error
+        let mut decompressor = Decompressor::new(FrameReader::new(100), 100, Arc::new(CompressionStats::default()));
+        let e = decompress_all(&mut decompressor, &compressed, 1000).unwrap_err();
+        assert!(matches!(Error::from(e), Error::Protocol(_)));
+        let garbage = crate::frame::control_frame(COMPRESSED, &[0xFF; 10]);
+        let mut decompressor = Decompressor::new(FrameReader::new(DEFAULT_MAX_DATAGRAM), DEFAULT_MAX_DATAGRAM, stats);
+        assert!(matches!(Error::from(decompress_all(&mut decompressor, &garbage, 1000).unwrap_err()), Error::Protocol(_)));
+    }
+}
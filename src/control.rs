@@ -0,0 +1,382 @@
+// control frames share the tcp stream with datagrams (see frame.rs), these are their kinds and what they carry,
+// a client that wants anything beyond plain datagrams says hello first and the server answers with what it agreed to
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use crate::batch::BATCH;
+use crate::bond::{BondId, TOKEN};
+use crate::error::{Error, Result};
+use crate::frame::{control_frame, FrameReader, FrameWriter};
+use crate::source::{self, AllowedSources};
+
+pub const HELLO: u8 = 1;
+/// the datagrams following it, either way, belong to the local peer with this u16 id
+pub const PEER: u8 = 2;
+/// nothing but zeros to hide the size of what's around it, thrown away by whoever reads it
+pub const PADDING: u8 = 3;
+/// a datagram as an lz4 block, only once both ends agreed to COMPRESS, see compress.rs
+pub const COMPRESSED: u8 = 4;
+
+/// hello feature bit, datagrams are tagged with PEER frames so several local wireguards share the tunnel
+pub const MULTI_PEER: u32 = 1;
+/// hello feature bit, datagrams are compressed where that makes them smaller
+pub const COMPRESS: u32 = 2;
+/// hello feature bit, the connection is one of several carrying the same tunnel, the hello's id says which and its
+/// token proves it's one of them
+pub const BOND: u32 = 4;
+/// hello feature bit, the server keeps the tunnel's udp socket for a while after the connection drops and a
+/// reconnect saying hello with the same id and token carries on with it
+pub const RESUME: u32 = 8;
+/// every feature this end knows about, compression only when it's compiled in
+#[cfg(feature = "compress")]
+pub const FEATURES: u32 = MULTI_PEER | COMPRESS | BOND | RESUME;
+#[cfg(not(feature = "compress"))]
+pub const FEATURES: u32 = MULTI_PEER | BOND | RESUME;
+// the features that need the tunnel's id
+const WITH_ID: u32 = BOND | RESUME;
+
+/// most local peers one tunnel carries, the server binds a udp socket for each
+pub const MAX_PEERS: usize = 256;
+
+const VERSION: u8 = 1;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hello {
+    pub features: u32,
+    /// with BOND or RESUME, the same for every connection of the tunnel and every reconnect
+    pub id: Option<BondId>,
+}
+
+impl Hello {
+    pub fn new(features: u32) -> Hello {
+        Hello { features, id: None }
+    }
+
+    /// features has BOND or RESUME
+    pub fn with_id(features: u32, id: BondId) -> Hello {
+        Hello { features, id: Some(id) }
+    }
+
+    pub fn has(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+
+    /// version byte then features, then with BOND or RESUME the id and token, anything after that is from a newer version
+    /// and ignored
+    pub fn decode(payload: &[u8]) -> Result<Hello> {
+        if payload.len() < 5 || payload[0] == 0 {
+            return Err(Error::protocol(format!("invalid hello of {} bytes", payload.len())));
+        }
+        let mut features = [0u8; 4];
+        features.copy_from_slice(&payload[1..5]);
+        let features = u32::from_be_bytes(features);
+        if features & WITH_ID == 0 {
+            return Ok(Hello::new(features));
+        }
+        if payload.len() < 13 + TOKEN {
+            return Err(Error::protocol(format!("invalid hello with an id of {} bytes", payload.len())));
+        }
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&payload[5..13]);
+        let mut token = [0u8; TOKEN];
+        token.copy_from_slice(&payload[13..13 + TOKEN]);
+        Ok(Hello::with_id(features, BondId { id: u64::from_be_bytes(id), token }))
+    }
+
+    pub fn frame(&self) -> Vec<u8> {
+        let mut payload = vec![VERSION];
+        payload.extend_from_slice(&self.features.to_be_bytes());
+        if let Some(id) = &self.id {
+            payload.extend_from_slice(&id.id.to_be_bytes());
+            payload.extend_from_slice(&id.token);
+        }
+        control_frame(HELLO, &payload)
+    }
+}
+
+/// what the server agrees to out of what the client asked for and it offers, a bond or a resumed tunnel carries one peer
+pub fn agree(hello: &Hello, offered: u32) -> Hello {
+    let features = hello.features & offered;
+    match hello.id {
+        Some(id) if features & MULTI_PEER == 0 && features & WITH_ID != 0 => Hello::with_id(features, id),
+        _ => Hello::new(features & !WITH_ID),
+    }
+}
+
+/// the server's answer to a client that asked for features, it can't do without multiple peers or bonding but can
+/// without compression or resuming
+pub fn answered(features: u32, answer: Hello) -> Result<Hello> {
+    for feature in [MULTI_PEER, BOND].iter() {
+        if features & feature != 0 && !answer.has(*feature) {
+            return Err(unsupported(*feature));
+        }
+    }
+    if features & COMPRESS != 0 && !answer.has(COMPRESS) {
+        println!("server does not support compression, carrying on without it");
+    }
+    if features & RESUME != 0 && !answer.has(RESUME) {
+        println!("server does not offer resuming, carrying on without it");
+    }
+    Ok(answer)
+}
+
+/// why a server that never answered the client's hello won't do
+pub fn unsupported(features: u32) -> Error {
+    if features & MULTI_PEER != 0 {
+        return Error::protocol("server does not support multiple peers");
+    }
+    if features & BOND != 0 {
+        return Error::protocol("server does not support bonding");
+    }
+    if features & COMPRESS != 0 {
+        return Error::protocol("server does not support compression");
+    }
+    Error::protocol("server does not offer resuming")
+}
+
+pub fn peer_frame(id: u16) -> [u8; 2] {
+    id.to_be_bytes()
+}
+
+pub fn decode_peer(payload: &[u8]) -> Result<u16> {
+    if payload.len() != 2 {
+        return Err(Error::protocol(format!("invalid peer frame of {} bytes", payload.len())));
+    }
+    let id = u16::from_be_bytes([payload[0], payload[1]]);
+    if id as usize >= MAX_PEERS {
+        return Err(Error::protocol(format!("peer id {} over the limit of {}", id, MAX_PEERS)));
+    }
+    Ok(id)
+}
+
+/// a hello if that's what the client sent first, past any padding, anything else is left in reader for the tunnel
+pub fn hello(reader: &mut FrameReader) -> Result<Option<Hello>> {
+    loop {
+        return match reader.control()? {
+            Some((HELLO, payload)) => Ok(Some(Hello::decode(payload)?)),
+            Some((PADDING, _)) => continue,
+            Some((kind, _)) => Err(Error::protocol(format!("expected hello, got control frame {}", kind))),
+            None => Ok(None),
+        };
+    }
+}
+
+/// the server's answer to the client's hello once it's here, a server too old to answer reads the hello as a datagram,
+/// so a datagram from it ahead of any answer fails right away instead of waiting for one that never comes
+pub fn answer(reader: &mut FrameReader, features: u32) -> Result<Option<Hello>> {
+    match hello(reader)? {
+        None if reader.ready()? => Err(unsupported(features)),
+        answer => Ok(answer),
+    }
+}
+
+/// a peer id and datagrams of theirs
+pub type PeerDatagrams<'a> = (u16, Vec<&'a [u8]>);
+
+/// the datagrams waiting in reader and the peer they belong to, following PEER frames along the way,
+/// None once reader needs filling again
+pub fn peer_frames<'a>(reader: &'a mut FrameReader, current: &mut Option<u16>) -> Result<Option<PeerDatagrams<'a>>> {
+    while let Some((kind, payload)) = reader.control()? {
+        if kind == PEER {
+            *current = Some(decode_peer(payload)?);
+        }
+    }
+    let frames = reader.frames(BATCH)?;
+    if frames.is_empty() {
+        return Ok(None);
+    }
+    let id = current.ok_or_else(|| Error::protocol("datagram before any peer frame"))?;
+    Ok(Some((id, frames)))
+}
+
+/// frames datagrams for the tcp stream, with a PEER frame in front whenever the peer changes
+pub struct PeerFrames {
+    writer: FrameWriter,
+    current: Option<u16>,
+}
+
+impl PeerFrames {
+    pub fn new(max_datagram: usize) -> PeerFrames {
+        PeerFrames {
+            writer: FrameWriter::new(max_datagram),
+            current: None,
+        }
+    }
+
+    pub fn push(&mut self, id: u16, datagram: &[u8]) -> Result<()> {
+        if self.current != Some(id) {
+            self.writer.push_control(PEER, &peer_frame(id))?;
+            self.current = Some(id);
+        }
+        self.writer.push(datagram)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.writer.as_bytes()
+    }
+
+    pub fn clear(&mut self) {
+        self.writer.clear();
+    }
+}
+
+/// the client's local peers by source address, ids are handed out in order and never reused
+#[derive(Default)]
+pub struct Peers {
+    ids: HashMap<SocketAddr, u16>,
+    addrs: Vec<SocketAddr>,
+    allowed: AllowedSources,
+    rejected: Option<SocketAddr>,
+}
+
+impl Peers {
+    pub fn new(allowed: AllowedSources) -> Peers {
+        Peers {
+            allowed,
+            ..Default::default()
+        }
+    }
+
+    /// the id for this source, a new one the first time it's seen, None if it isn't an allowed
+    /// source or MAX_PEERS are taken, its datagram should be dropped
+    pub fn id(&mut self, addr: SocketAddr) -> Option<u16> {
+        if let Some(id) = self.ids.get(&addr) {
+            return Some(*id);
+        }
+        if !source::allowed(&self.allowed, addr, &mut self.rejected) {
+            return None;
+        }
+        if self.addrs.len() >= MAX_PEERS {
+            println!("already carrying {} local peers, dropping datagram from {}", MAX_PEERS, addr);
+            return None;
+        }
+        let id = self.addrs.len() as u16;
+        println!("new local peer {} from {}", id, addr);
+        self.ids.insert(addr, id);
+        self.addrs.push(addr);
+        Some(id)
+    }
+
+    pub fn addr(&self, id: u16) -> Option<SocketAddr> {
+        self.addrs.get(id as usize).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{FrameReader, CONTROL_HEADER, DEFAULT_MAX_DATAGRAM, HEADER};
+
+    // what a server with every feature offers
+    const ALL: u32 = MULTI_PEER | COMPRESS | BOND | RESUME;
+
+    fn id(id: u64) -> BondId {
+        BondId { id, token: [id as u8; TOKEN] }
+    }
+
+    #[test]
+    fn test_hello() {
+        let hello = Hello::new(MULTI_PEER | 0x8000);
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        reader.fill(&mut &hello.frame()[..]).unwrap();
+        let (kind, payload) = reader.control().unwrap().unwrap();
+        assert_eq!(kind, HELLO);
+        let decoded = Hello::decode(payload).unwrap();
+        assert_eq!(decoded, hello);
+        // unknown bits are turned down, and compression when it's compiled out
+        assert_eq!(agree(&decoded, ALL), Hello::new(MULTI_PEER));
+        assert!(agree(&decoded, ALL).has(MULTI_PEER));
+        assert_eq!(agree(&Hello::new(MULTI_PEER | COMPRESS), FEATURES).has(COMPRESS), cfg!(feature = "compress"));
+
+        // compression is nice to have, multiple peers are a must
+        assert_eq!(answered(MULTI_PEER | COMPRESS, Hello::new(MULTI_PEER)).unwrap(), Hello::new(MULTI_PEER));
+        assert!(answered(MULTI_PEER | COMPRESS, Hello::new(COMPRESS)).is_err());
+        assert!(answered(COMPRESS, Hello::new(0)).is_ok());
+
+        // a bonded hello carries its id and token there and back, but not alongside multiple peers
+        let bonded = Hello::with_id(BOND | COMPRESS, id(0x0102030405060708));
+        assert_eq!(bonded.frame().len(), HEADER + CONTROL_HEADER + 13 + TOKEN);
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        reader.fill(&mut &bonded.frame()[..]).unwrap();
+        let decoded = Hello::decode(reader.control().unwrap().unwrap().1).unwrap();
+        assert_eq!(agree(&decoded, ALL), bonded);
+        assert!(answered(BOND, agree(&decoded, ALL)).is_ok());
+        assert_eq!(agree(&Hello::with_id(MULTI_PEER | BOND, id(1)), ALL), Hello::new(MULTI_PEER));
+        assert!(answered(BOND, Hello::new(0)).is_err());
+        assert!(Hello::decode(&[VERSION, 0, 0, 0, BOND as u8, 1, 2]).is_err());
+        // an id without its token is no good either
+        assert!(Hello::decode(&[VERSION, 0, 0, 0, BOND as u8, 1, 2, 3, 4, 5, 6, 7, 8]).is_err());
+
+        // resuming is nice to have too, and needs the server to offer it
+        let resume = Hello::with_id(RESUME, id(7));
+        assert_eq!(agree(&resume, ALL), resume);
+        assert_eq!(agree(&resume, ALL & !RESUME), Hello::new(0));
+        assert_eq!(agree(&Hello::with_id(RESUME | BOND, id(7)), ALL & !RESUME), Hello::with_id(BOND, id(7)));
+        assert!(answered(RESUME, Hello::new(0)).is_ok());
+
+        assert!(Hello::decode(&[VERSION, 0, 0]).is_err());
+        // newer versions can add more on the end
+        assert_eq!(Hello::decode(&[2, 0, 0, 0, 1, 9, 9]).unwrap(), Hello::new(1));
+
+        // the answer can come after padding, but an old server sending a datagram first won't ever answer
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        let mut padded = control_frame(PADDING, &[0; 4]);
+        padded.extend_from_slice(&Hello::new(MULTI_PEER).frame());
+        reader.fill(&mut &padded[..]).unwrap();
+        assert_eq!(answer(&mut reader, MULTI_PEER).unwrap(), Some(Hello::new(MULTI_PEER)));
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        reader.fill(&mut &control_frame(PADDING, &[0; 4])[..]).unwrap();
+        assert_eq!(answer(&mut reader, MULTI_PEER).unwrap(), None);
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        reader.fill(&mut &[0, 3, 1, 2, 3][..]).unwrap();
+        assert!(matches!(answer(&mut reader, MULTI_PEER), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn test_peers() {
+        let mut peers = Peers::new("127.0.0.0/8".parse().unwrap());
+        assert_eq!(peers.id("10.0.0.1:1000".parse().unwrap()), None);
+        let a: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:1001".parse().unwrap();
+        assert_eq!(peers.id(a), Some(0));
+        assert_eq!(peers.id(b), Some(1));
+        assert_eq!(peers.id(a), Some(0));
+        assert_eq!(peers.addr(1), Some(b));
+        assert_eq!(peers.addr(2), None);
+
+        for port in 2000..3000 {
+            peers.id(SocketAddr::new(a.ip(), port));
+        }
+        assert_eq!(peers.id("127.0.0.1:5000".parse().unwrap()), None);
+        assert_eq!(decode_peer(&peer_frame(1)).unwrap(), 1);
+        assert!(decode_peer(&peer_frame(MAX_PEERS as u16)).is_err());
+    }
+
+    #[test]
+    fn test_peer_frames() {
+        let mut frames = PeerFrames::new(DEFAULT_MAX_DATAGRAM);
+        frames.push(3, b"a").unwrap();
+        frames.push(3, b"b").unwrap();
+        frames.push(5, b"c").unwrap();
+        frames.push(3, b"d").unwrap();
+
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        reader.fill(&mut frames.as_bytes()).unwrap();
+        let mut current = None;
+        let mut got = Vec::new();
+        while let Some((id, datagrams)) = peer_frames(&mut reader, &mut current).unwrap() {
+            got.push((id, datagrams.iter().map(|d| d.to_vec()).collect::<Vec<_>>()));
+        }
+        assert_eq!(got, vec![
+            (3, vec![b"a".to_vec(), b"b".to_vec()]),
+            (5, vec![b"c".to_vec()]),
+            (3, vec![b"d".to_vec()]),
+        ]);
+
+        // a datagram with no peer is a protocol error
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        reader.fill(&mut &[0u8, 1, 9][..]).unwrap();
+        assert!(matches!(peer_frames(&mut reader, &mut None), Err(Error::Protocol(_))));
+    }
+}
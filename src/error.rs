@@ -1,44 +1,98 @@
 use core::result;
+use std::io;
 
 #[cfg(not(any(feature = "async")))]
 pub type IoResult<T> = result::Result<T, std::io::Error>;
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// everything that can go wrong, more kinds may be added so match with a wildcard arm
 #[derive(Debug)]
-pub struct Error(String);
+#[non_exhaustive]
+pub enum Error {
+    /// any io failure that isn't one of the more specific kinds below, like a refused connection
+    Io(io::Error),
+    /// TLS setup or handshake failed
+    Tls(Box<dyn std::error::Error + Send + Sync>),
+    /// the server's public key isn't one of the pinned ones, holds the key it presented
+    PinMismatch(String),
+    /// the peer broke the framing protocol, only the tunnel it came over is closed
+    Protocol(String),
+    /// every udp port in the range is already in use
+    PortRangeExhausted { low: u16, high: u16 },
+    /// bad options, key or cert, or something this build doesn't support
+    Config(String),
+    /// nothing was received for socket_timeout, or connecting took longer than connect_timeout
+    Timeout(io::Error),
+    /// the tunnel was killed from the admin socket
+    Killed,
+}
 
 impl Error {
-    pub fn new(msg: &str) -> Error {
-        Error(msg.to_owned())
+    pub fn protocol(msg: impl Into<String>) -> Error {
+        Error::Protocol(msg.into())
     }
-    pub fn new_owned(msg: String) -> Error {
-        Error(msg)
+    pub fn config(msg: impl Into<String>) -> Error {
+        Error::Config(msg.into())
     }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        f.write_str(&self.0)
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Tls(e) => write!(f, "TLS error: {}", e),
+            Error::PinMismatch(pubkey) => write!(f, "pubkey '{}' not found in pinned list", pubkey),
+            Error::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            Error::PortRangeExhausted { low, high } => write!(f, "cannot find free port in {}-{}, increase range?", low, high),
+            Error::Config(msg) => write!(f, "config error: {}", msg),
+            Error::Timeout(e) => write!(f, "timed out: {}", e),
+            Error::Killed => write!(f, "killed from the admin socket"),
+        }
     }
 }
 
 impl std::error::Error for Error {
-    fn description(&self) -> &str {
-        &self.0
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) | Error::Timeout(e) => Some(e),
+            Error::Tls(e) => Some(e.as_ref()),
+            _ => None,
+        }
     }
 }
 
-impl From<std::io::Error> for Error {
-    fn from(value: std::io::Error) -> Self {
-        Error::new(&format!("{}", value))
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        // a stream wrapper can only fail with an io::Error, so it carries ours inside one
+        if matches!(value.get_ref(), Some(inner) if inner.is::<Error>()) {
+            return *value.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        match value.kind() {
+            // a read timeout on a blocking socket is WouldBlock on unix and TimedOut on windows
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout(value),
+            _ => Error::Io(value),
+        }
     }
 }
 
-/*
-impl From<std::option::NoneError> for Error {
-    fn from(value: std::option::NoneError) -> Self {
-        Error::new(value.description())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as StdError;
+
+    #[test]
+    fn test_from_io() {
+        let refused: Error = io::Error::from(io::ErrorKind::ConnectionRefused).into();
+        assert!(matches!(refused, Error::Io(ref e) if e.kind() == io::ErrorKind::ConnectionRefused));
+        assert!(refused.source().is_some());
+
+        let timeout: Error = io::Error::from(io::ErrorKind::WouldBlock).into();
+        assert!(matches!(timeout, Error::Timeout(_)));
+
+        let tls = Error::Tls(Box::new(io::Error::new(io::ErrorKind::InvalidData, "bad cert")));
+        assert_eq!(tls.source().unwrap().to_string(), "bad cert");
+
+        assert!(Error::protocol("bad").source().is_none());
     }
 }
-*/
\ No newline at end of file
@@ -0,0 +1,54 @@
+// happy eyeballs (RFC 8305), a host resolving to both ipv6 and ipv4 is connected over whichever answers
+// first instead of waiting out each address in turn, so a broken ipv6 network doesn't stall the client
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// how long connecting to a target may take before giving up
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how long an attempt gets before the next address is tried alongside it, RFC 8305 recommends 250ms
+#[cfg(feature = "async")]
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// addresses in the order to try them, the resolver's first choice first then alternating families,
+/// so one broken family only ever delays the other by one attempt
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (first, second): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+    let mut second = second.into_iter();
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    for addr in first {
+        interleaved.push(addr);
+        interleaved.extend(second.next());
+    }
+    interleaved.extend(second);
+    interleaved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_interleave() {
+        assert_eq!(
+            interleave(addrs(&["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"])),
+            addrs(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"])
+        );
+        // the resolver's preference is kept, even for ipv4
+        assert_eq!(
+            interleave(addrs(&["10.0.0.1:1", "10.0.0.2:1", "10.0.0.3:1", "[::1]:1"])),
+            addrs(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1", "10.0.0.3:1"])
+        );
+        assert_eq!(interleave(addrs(&["10.0.0.1:1", "10.0.0.2:1"])), addrs(&["10.0.0.1:1", "10.0.0.2:1"]));
+        assert!(interleave(Vec::new()).is_empty());
+    }
+}
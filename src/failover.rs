@@ -0,0 +1,143 @@
+// more than one place the client can connect, for networks where some gateways are blocked, tried in
+// order or fastest first, failing over when one can't be connected and back to a preferred one once it can
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::{quic_addr, unix_path, TlsClientConfig};
+
+/// how often a client on a fallback target checks whether a preferred one is reachable again
+pub const DEFAULT_FAIL_BACK: Duration = Duration::from_secs(60);
+
+/// somewhere else the client can connect, ip:port, host:port, unix:/path or quic:host:port, with or without TLS
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub tcp_target: String,
+    pub tls: Option<TlsClientConfig>,
+}
+
+impl Target {
+    pub fn new(tcp_target: &str) -> Target {
+        Target {
+            tcp_target: tcp_target.to_owned(),
+            tls: None,
+        }
+    }
+
+    pub fn tls(mut self, tls: TlsClientConfig) -> Target {
+        self.tls = Some(tls);
+        self
+    }
+}
+
+/// a target as given on the command line, tls:host:port connects with TLS sending host in SNI, and so does
+/// quic:host:port as QUIC is always TLS
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Target> {
+        let s = s.trim();
+        let tls = |tcp_target| TlsClientConfig {
+            hostname: hostname(tcp_target).map(str::to_owned),
+            pinnedpubkey: None,
+        };
+        let target = match (s.strip_prefix("tls:"), quic_addr(s)) {
+            (Some(tcp_target), _) => Target::new(tcp_target).tls(tls(tcp_target)),
+            (None, Some(addr)) => Target::new(s).tls(tls(addr)),
+            (None, None) => Target::new(s),
+        };
+        let host_port = |addr: &str| addr.rfind(':').is_some_and(|colon| colon > 0 && addr[colon + 1..].parse::<u16>().is_ok());
+        let valid = match quic_addr(&target.tcp_target) {
+            Some(addr) => host_port(addr),
+            None => unix_path(&target.tcp_target).is_some() || host_port(&target.tcp_target),
+        };
+        if !valid {
+            return Err(Error::Config(format!("invalid target {}, must be host:port, unix:/path or quic:host:port", s)));
+        }
+        Ok(target)
+    }
+}
+
+// the host to send in SNI, ip addresses aren't allowed there
+fn hostname(tcp_target: &str) -> Option<&str> {
+    if unix_path(tcp_target).is_some() {
+        return None;
+    }
+    let host = &tcp_target[..tcp_target.rfind(':')?];
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<std::net::IpAddr>() {
+        Ok(_) => None,
+        Err(_) => Some(host),
+    }
+}
+
+/// how the client picks between its targets
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TargetOrder {
+    /// tcp_target first, then the fallbacks as given
+    #[default]
+    Listed,
+    /// fastest to connect first, measured every time the client starts over
+    Latency,
+}
+
+impl FromStr for TargetOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TargetOrder> {
+        match s {
+            "listed" => Ok(TargetOrder::Listed),
+            "latency" => Ok(TargetOrder::Latency),
+            _ => Err(Error::Config(format!("invalid target order {}, must be listed or latency", s))),
+        }
+    }
+}
+
+/// indexes of targets fastest first, ones that couldn't be reached last, ties stay in listed order
+pub fn by_latency(latencies: &[Option<Duration>]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..latencies.len()).collect();
+    order.sort_by_key(|i| latencies[*i].unwrap_or(Duration::MAX));
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target() {
+        let target: Target = "gw.example.com:443".parse().unwrap();
+        assert_eq!(target.tcp_target, "gw.example.com:443");
+        assert!(target.tls.is_none());
+
+        let target: Target = "tls:gw.example.com:443".parse().unwrap();
+        assert_eq!(target.tcp_target, "gw.example.com:443");
+        assert_eq!(target.tls.unwrap().hostname.as_deref(), Some("gw.example.com"));
+
+        // no SNI for ip addresses
+        let target: Target = "tls:[::1]:443".parse().unwrap();
+        assert_eq!(target.tcp_target, "[::1]:443");
+        assert_eq!(target.tls.unwrap().hostname, None);
+        assert_eq!("tls:10.0.0.1:443".parse::<Target>().unwrap().tls.unwrap().hostname, None);
+
+        assert_eq!("unix:/run/wgp.sock".parse::<Target>().unwrap().tcp_target, "unix:/run/wgp.sock");
+
+        // quic keeps its prefix to connect by and is TLS all the same
+        let target: Target = "quic:gw.example.com:443".parse().unwrap();
+        assert_eq!(target.tcp_target, "quic:gw.example.com:443");
+        assert_eq!(target.tls.unwrap().hostname.as_deref(), Some("gw.example.com"));
+
+        for bad in ["gw.example.com", "gw.example.com:https", ":443", "tls:", "quic:443", "quic:unix:/run/wgp.sock", ""].iter() {
+            assert!(matches!(bad.parse::<Target>(), Err(Error::Config(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_by_latency() {
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(by_latency(&[ms(50), None, ms(10), ms(50), ms(20)]), vec![2, 4, 0, 3, 1]);
+        assert_eq!(by_latency(&[None, None]), vec![0, 1]);
+        assert_eq!("latency".parse::<TargetOrder>().unwrap(), TargetOrder::Latency);
+        assert!("fastest".parse::<TargetOrder>().is_err());
+    }
+}
@@ -0,0 +1,122 @@
+// the server binds a udp socket per tunnel and connects it to udp_target, which only works when both
+// are the same address family, so the bind host follows udp_target's family where it can
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::error::{Error, Result};
+
+/// host, low port and high port from host:low-high, an ipv6 host goes in brackets like [::1]:30000-40000
+pub fn parse_udp_bind_range(s: &str) -> Result<(String, u16, u16)> {
+    let invalid = || Error::Config(format!("invalid udp bind range {}, must be host:low-high", s));
+    let colon = s.rfind(':').ok_or_else(invalid)?;
+    let host = s[..colon].trim();
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']').ok_or_else(invalid)?,
+        None => host,
+    };
+    let mut ports = s[colon + 1..].splitn(2, '-');
+    let mut port = || ports.next().and_then(|port| port.trim().parse::<u16>().ok()).ok_or_else(invalid);
+    let (low, high) = (port()?, port()?);
+    if host.is_empty() || low == 0 || low > high {
+        return Err(invalid());
+    }
+    Ok((host.to_owned(), low, high))
+}
+
+/// the first of targets one of binds can reach and the bind address to reach it from, failing that a
+/// loopback or unspecified bind address is swapped for the same in the other family, like 127.0.0.1 for ::1
+pub fn pick(binds: &[IpAddr], targets: &[SocketAddr]) -> Result<(IpAddr, SocketAddr)> {
+    let same_family = |bind: &IpAddr, target: &SocketAddr| bind.is_ipv4() == target.is_ipv4();
+    for target in targets {
+        if let Some(bind) = binds.iter().find(|bind| same_family(bind, target)) {
+            return Ok((*bind, *target));
+        }
+    }
+    for target in targets {
+        if let Some(bind) = binds.iter().filter_map(|bind| other_family(*bind)).find(|bind| same_family(bind, target)) {
+            return Ok((bind, *target));
+        }
+    }
+    Err(Error::Config(format!("udp bind host {:?} and udp_target {:?} are different address families", binds, targets)))
+}
+
+fn other_family(ip: IpAddr) -> Option<IpAddr> {
+    match ip {
+        IpAddr::V4(ip) if ip.is_loopback() => Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        IpAddr::V4(ip) if ip.is_unspecified() => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        IpAddr::V6(ip) if ip.is_loopback() => Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        IpAddr::V6(ip) if ip.is_unspecified() => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProxyServerClientHandler;
+    use std::net::UdpSocket;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_udp_bind_range() {
+        assert_eq!(parse_udp_bind_range("127.0.0.1:30000-40000").unwrap(), ("127.0.0.1".to_owned(), 30000, 40000));
+        assert_eq!(parse_udp_bind_range("[::1]:30000-40000").unwrap(), ("::1".to_owned(), 30000, 40000));
+        assert_eq!(parse_udp_bind_range("[::]:5-5").unwrap(), ("::".to_owned(), 5, 5));
+        assert_eq!(parse_udp_bind_range("localhost: 1 - 2").unwrap(), ("localhost".to_owned(), 1, 2));
+        for bad in ["127.0.0.1", "127.0.0.1:30000", ":1-2", "[::1:1-2", "127.0.0.1:2-1", "127.0.0.1:0-1", "127.0.0.1:1-70000"].iter() {
+            assert!(matches!(parse_udp_bind_range(bad), Err(Error::Config(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_pick() {
+        // a dual stack target is reached from whichever family the bind host has
+        let dual = [addr("[::1]:51820"), addr("127.0.0.1:51820")];
+        assert_eq!(pick(&[ip("127.0.0.1")], &dual).unwrap(), (ip("127.0.0.1"), dual[1]));
+        assert_eq!(pick(&[ip("::1")], &dual).unwrap(), (ip("::1"), dual[0]));
+        // the default bind host follows an ipv6 only target
+        assert_eq!(pick(&[ip("127.0.0.1")], &dual[..1]).unwrap(), (ip("::1"), dual[0]));
+        assert_eq!(pick(&[ip("::")], &dual[1..]).unwrap(), (ip("0.0.0.0"), dual[1]));
+        assert_eq!(pick(&[ip("0.0.0.0")], &dual[..1]).unwrap(), (ip("::"), dual[0]));
+        // but a specific address can't be swapped
+        assert!(matches!(pick(&[ip("10.0.0.1")], &dual[..1]), Err(Error::Config(_))));
+        assert!(pick(&[ip("127.0.0.1")], &[]).is_err());
+    }
+
+    #[test]
+    fn test_udp_bind_ipv6() {
+        // wireguard on ::1 only, the server left on its default ipv4 bind host
+        let wireguard = UdpSocket::bind("[::1]:0").unwrap();
+        let handler = ProxyServerClientHandler {
+            udp_target: wireguard.local_addr().unwrap().to_string(),
+            udp_host: "127.0.0.1".to_owned(),
+            udp_low_port: 30000,
+            udp_high_port: 40000,
+            socket_timeout: None,
+            max_datagram: crate::frame::DEFAULT_MAX_DATAGRAM,
+            on_tunnel_closed: None,
+            endpoint_factory: None,
+            re_resolve: None,
+            resolver: None,
+            resume: None,
+            bonds: Default::default(),
+        };
+        let udp_socket = handler.udp_bind().unwrap();
+        assert_eq!(udp_socket.local_addr().unwrap().ip(), ip("::1"));
+
+        udp_socket.send(b"hello").unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = wireguard.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        wireguard.send_to(b"reply", from).unwrap();
+        let len = udp_socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"reply");
+    }
+}
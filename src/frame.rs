@@ -0,0 +1,390 @@
+// the tcp side of the tunnel is a stream of frames, each a big-endian u16 length followed by that many bytes of one udp datagram,
+// a zero length can't be a datagram wireguard would send, so it marks a control frame instead: a kind byte, a u16 length and
+// that many bytes of payload
+
+use std::io::{self, Read};
+
+use crate::error::{Error, Result};
+
+/// largest datagram carried by default, a frame claiming to be longer than the configured max is a protocol error
+pub const DEFAULT_MAX_DATAGRAM: usize = 2048;
+/// the most a u16 length header can describe, enough for jumbo frames
+pub const MAX_DATAGRAM: usize = u16::MAX as usize;
+pub const HEADER: usize = 2;
+/// kind and length following the zero length header of a control frame
+pub const CONTROL_HEADER: usize = 3;
+// read this much at a time when we can, so many small frames come from one read
+const READ_SIZE: usize = 64 * 1024;
+
+pub fn write_header(buf: &mut [u8], len: usize) {
+    buf[0] = ((len >> 8) & 0xFF) as u8;
+    buf[1] = (len & 0xFF) as u8;
+}
+
+pub fn read_header(buf: &[u8]) -> usize {
+    ((buf[0] as usize) << 8) + buf[1] as usize
+}
+
+/// one control frame, ready to be written, payload is at most max datagram size like any frame
+pub fn control_frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; HEADER + CONTROL_HEADER];
+    buf[HEADER] = kind;
+    write_header(&mut buf[HEADER + 1..], payload.len());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+#[derive(Debug)]
+pub enum Frame<'a> {
+    Datagram(&'a [u8]),
+    Control(u8, &'a [u8]),
+}
+
+// what comes next in the buffer, with the position of its payload
+enum Next {
+    Datagram(usize, usize),
+    Control(u8, usize, usize),
+}
+
+fn eof() -> Error {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+}
+
+/// reads large chunks from a stream and hands out complete frames without copying them,
+/// only a trailing partial frame is ever moved, and only when the space after it runs low
+pub struct FrameReader {
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    max_datagram: usize,
+}
+
+impl FrameReader {
+    pub fn new(max_datagram: usize) -> FrameReader {
+        let max_datagram = max_datagram.min(MAX_DATAGRAM);
+        FrameReader {
+            buf: vec![0u8; READ_SIZE + HEADER + CONTROL_HEADER + max_datagram],
+            start: 0,
+            end: 0,
+            max_datagram,
+        }
+    }
+
+    /// free space to read into, a frame is never bigger than HEADER + CONTROL_HEADER + max_datagram so
+    /// as long as that much is free after start we can always make progress, call filled() with how much was
+    pub(crate) fn read_mut(&mut self) -> &mut [u8] {
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        } else if self.buf.len() - self.start < HEADER + CONTROL_HEADER + self.max_datagram + 1 || self.end == self.buf.len() {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        &mut self.buf[self.end..]
+    }
+
+    pub(crate) fn filled(&mut self, len: usize) {
+        self.end += len;
+    }
+
+    /// one read from the stream into the buffer, an EOF is an error because the tunnel is never supposed to end
+    pub fn fill<R: Read>(&mut self, stream: &mut R) -> Result<()> {
+        let len = stream.read(self.read_mut())?;
+        if len == 0 {
+            return Err(eof());
+        }
+        self.end += len;
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn fill_async<R: tokio::io::AsyncRead + std::marker::Unpin>(&mut self, stream: &mut R) -> Result<()> {
+        use tokio::io::AsyncReadExt;
+        let len = stream.read(self.read_mut()).await?;
+        if len == 0 {
+            return Err(eof());
+        }
+        self.end += len;
+        Ok(())
+    }
+
+    // the next complete frame, if there is one
+    fn next(&self) -> Result<Option<Next>> {
+        let available = self.end - self.start;
+        if available < HEADER {
+            return Ok(None);
+        }
+        let len = read_header(&self.buf[self.start..]);
+        if len == 0 {
+            return self.next_control();
+        }
+        if len > self.max_datagram {
+            return Err(Error::protocol(format!("frame length {} exceeds max datagram size {}", len, self.max_datagram)));
+        }
+        #[cfg(feature = "verbose")]
+        println!("tcp expecting len: {}", len);
+        if available < HEADER + len {
+            return Ok(None);
+        }
+        Ok(Some(Next::Datagram(self.start + HEADER, len)))
+    }
+
+    fn next_control(&self) -> Result<Option<Next>> {
+        if self.end - self.start < HEADER + CONTROL_HEADER {
+            return Ok(None);
+        }
+        let kind = self.buf[self.start + HEADER];
+        let len = read_header(&self.buf[self.start + HEADER + 1..]);
+        if len > self.max_datagram {
+            return Err(Error::protocol(format!("control frame length {} exceeds max datagram size {}", len, self.max_datagram)));
+        }
+        if self.end - self.start < HEADER + CONTROL_HEADER + len {
+            return Ok(None);
+        }
+        Ok(Some(Next::Control(kind, self.start + HEADER + CONTROL_HEADER, len)))
+    }
+
+    /// whether a complete frame of either kind is buffered
+    pub fn ready(&self) -> Result<bool> {
+        Ok(self.next()?.is_some())
+    }
+
+    /// the next complete frame in the buffer, if any
+    pub fn next_frame(&mut self) -> Result<Option<Frame<'_>>> {
+        Ok(match self.next()? {
+            Some(Next::Datagram(frame, len)) => {
+                self.start = frame + len;
+                #[cfg(feature = "verbose")]
+                println!("tcp got len: {}", len);
+                Some(Frame::Datagram(&self.buf[frame..frame + len]))
+            }
+            Some(Next::Control(kind, payload, len)) => {
+                self.start = payload + len;
+                Some(Frame::Control(kind, &self.buf[payload..payload + len]))
+            }
+            None => None,
+        })
+    }
+
+    /// the next frame if it is a complete control frame, datagrams ahead of it are left for frames()
+    pub fn control(&mut self) -> Result<Option<(u8, &[u8])>> {
+        match self.next()? {
+            Some(Next::Control(kind, payload, len)) => {
+                self.start = payload + len;
+                Ok(Some((kind, &self.buf[payload..payload + len])))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// every complete datagram frame in the buffer up to max of them, stopping at a control frame
+    pub fn frames(&mut self, max: usize) -> Result<Vec<&[u8]>> {
+        let mut frames = Vec::with_capacity(max);
+        while frames.len() < max {
+            match self.next()? {
+                Some(Next::Datagram(frame, len)) => {
+                    frames.push((frame, len));
+                    self.start = frame + len;
+                }
+                _ => break,
+            }
+        }
+        let buf = &self.buf;
+        Ok(frames.into_iter().map(|(frame, len)| &buf[frame..frame + len]).collect())
+    }
+}
+
+/// builds frames to be written to a stream in one go
+pub struct FrameWriter {
+    buf: Vec<u8>,
+    max_datagram: usize,
+}
+
+impl FrameWriter {
+    pub fn new(max_datagram: usize) -> FrameWriter {
+        let max_datagram = max_datagram.min(MAX_DATAGRAM);
+        FrameWriter {
+            buf: Vec::with_capacity(HEADER + CONTROL_HEADER + max_datagram),
+            max_datagram,
+        }
+    }
+
+    /// an empty datagram is skipped, it has nothing for wireguard and its zero length would mark a control frame
+    pub fn push(&mut self, frame: &[u8]) -> Result<()> {
+        if frame.is_empty() {
+            return Ok(());
+        }
+        if frame.len() > self.max_datagram {
+            return Err(Error::protocol(format!("datagram length {} exceeds max datagram size {}", frame.len(), self.max_datagram)));
+        }
+        #[cfg(feature = "verbose")]
+        println!("udp got len: {}", frame.len());
+        let start = self.buf.len();
+        self.buf.extend_from_slice(&[0, 0]);
+        write_header(&mut self.buf[start..], frame.len());
+        self.buf.extend_from_slice(frame);
+        Ok(())
+    }
+
+    pub fn push_control(&mut self, kind: u8, payload: &[u8]) -> Result<()> {
+        if payload.len() > self.max_datagram {
+            return Err(Error::protocol(format!("control frame length {} exceeds max datagram size {}", payload.len(), self.max_datagram)));
+        }
+        self.buf.extend_from_slice(&control_frame(kind, payload));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(frame: Frame) -> Vec<u8> {
+        match frame {
+            Frame::Datagram(datagram) => datagram.to_vec(),
+            Frame::Control(kind, _) => panic!("expected datagram, got control frame {}", kind),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let datagrams: [&[u8]; 3] = [b"first", &[7u8; DEFAULT_MAX_DATAGRAM], b"last"];
+
+        let mut writer = FrameWriter::new(DEFAULT_MAX_DATAGRAM);
+        for datagram in datagrams.iter() {
+            writer.push(datagram).unwrap();
+            // skipped, it would look like a control frame
+            writer.push(b"").unwrap();
+        }
+        assert!(writer.push(&[0u8; DEFAULT_MAX_DATAGRAM + 1]).is_err());
+
+        // trickle it in a few bytes at a time so frames are split across reads
+        let mut stream = writer.as_bytes().chunks(3).collect::<Vec<_>>().into_iter();
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        let mut got = Vec::new();
+        while got.len() < datagrams.len() {
+            reader.fill(&mut stream.next().unwrap()).unwrap();
+            while let Some(frame) = reader.next_frame().unwrap() {
+                got.push(datagram(frame));
+            }
+        }
+        assert_eq!(got, datagrams.iter().map(|d| d.to_vec()).collect::<Vec<_>>());
+        assert!(stream.next().is_none());
+        assert!(reader.fill(&mut &b""[..]).is_err());
+    }
+
+    #[test]
+    fn test_control() {
+        let mut writer = FrameWriter::new(DEFAULT_MAX_DATAGRAM);
+        writer.push(b"one").unwrap();
+        writer.push(b"two").unwrap();
+        writer.push_control(7, b"payload").unwrap();
+        writer.push_control(8, b"").unwrap();
+        writer.push(b"three").unwrap();
+        assert!(writer.push_control(9, &[0u8; DEFAULT_MAX_DATAGRAM + 1]).is_err());
+
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        // the control frame split partway through its header isn't ready yet
+        let bytes = writer.as_bytes();
+        reader.fill(&mut &bytes[..12]).unwrap();
+        assert_eq!(reader.frames(32).unwrap(), vec![&b"one"[..], b"two"]);
+        assert!(!reader.ready().unwrap());
+        assert!(reader.control().unwrap().is_none());
+
+        reader.fill(&mut &bytes[12..]).unwrap();
+        assert!(reader.ready().unwrap());
+        // frames stop at a control frame so it can be handled in order
+        assert!(reader.frames(32).unwrap().is_empty());
+        assert_eq!(reader.control().unwrap(), Some((7, &b"payload"[..])));
+        assert_eq!(reader.control().unwrap(), Some((8, &b""[..])));
+        assert!(reader.control().unwrap().is_none());
+        assert_eq!(reader.frames(32).unwrap(), vec![&b"three"[..]]);
+    }
+
+    #[test]
+    fn test_frames_batch() {
+        let mut writer = FrameWriter::new(DEFAULT_MAX_DATAGRAM);
+        for x in 0..10u8 {
+            writer.push(&[x; 100]).unwrap();
+        }
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        reader.fill(&mut writer.as_bytes()).unwrap();
+        let frames = reader.frames(4).unwrap();
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[3], &[3u8; 100][..]);
+        assert_eq!(reader.frames(100).unwrap().len(), 6);
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        reader.fill(&mut &[0x08u8, 0x01, 0, 0][..]).unwrap();
+        match reader.next_frame() {
+            Err(Error::Protocol(_)) => {}
+            other => panic!("expected protocol error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_jumbo() {
+        let jumbo = vec![9u8; MAX_DATAGRAM];
+        let mut writer = FrameWriter::new(MAX_DATAGRAM);
+        writer.push(&jumbo).unwrap();
+        writer.push(b"after").unwrap();
+
+        let mut stream = writer.as_bytes();
+        let mut reader = FrameReader::new(MAX_DATAGRAM);
+        let mut got = Vec::new();
+        while got.len() < 2 {
+            reader.fill(&mut stream).unwrap();
+            while let Some(frame) = reader.next_frame().unwrap() {
+                got.push(datagram(frame));
+            }
+        }
+        assert_eq!(got, vec![jumbo, b"after".to_vec()]);
+
+        // the default reader refuses what the jumbo writer sent
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        reader.fill(&mut writer.as_bytes()).unwrap();
+        assert!(reader.next_frame().is_err());
+    }
+
+    #[test]
+    fn test_wraps_around() {
+        // lots of frames that don't evenly fill the buffer, so partial frames get moved to the front
+        let mut writer = FrameWriter::new(DEFAULT_MAX_DATAGRAM);
+        for x in 0..200usize {
+            writer.push(&vec![x as u8; 1000 + x]).unwrap();
+        }
+        let mut stream = writer.as_bytes();
+        let mut reader = FrameReader::new(DEFAULT_MAX_DATAGRAM);
+        let mut count = 0;
+        while count < 200 {
+            reader.fill(&mut stream).unwrap();
+            loop {
+                let frames = reader.frames(32).unwrap();
+                if frames.is_empty() {
+                    break;
+                }
+                for frame in frames {
+                    assert_eq!(frame, &vec![count as u8; 1000 + count][..]);
+                    count += 1;
+                }
+            }
+        }
+    }
+}
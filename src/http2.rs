@@ -0,0 +1,229 @@
+// HTTP/2 carries the tunnel through CDNs and load balancers that only forward HTTP/2 streams. each tunnel is a POST
+// to a configured path whose request body carries the client's side of the framing and whose response body carries
+// the server's, hello and all, as DATA frames. a front end multiplexes tunnels from many clients over one connection
+// to the server, so the server takes every POST to the path on a connection as a tunnel of its own and answers
+// anything else with a 404. over TLS h2 is offered in ALPN like a browser would, without TLS it's prior knowledge
+// h2c, for a front end that terminates TLS or for testing against a local h2 server
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use h2::{client, server, RecvStream, SendStream};
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, Response, StatusCode, Uri};
+use http2_bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::error::{Error, Result};
+use crate::transport::BoxStream;
+
+/// what HTTP/2 is called in ALPN
+pub(crate) const ALPN: &[u8] = b"h2";
+// flow control windows, the defaults of 64K would hold a tunnel to a handful of datagrams per round trip
+const STREAM_WINDOW: u32 = 1024 * 1024;
+const CONNECTION_WINDOW: u32 = 16 * 1024 * 1024;
+// most of a write handed to h2 at once
+const MAX_WRITE: usize = 64 * 1024;
+const CONTENT: &str = "application/octet-stream";
+
+/// the tunnel's POST to path over an already connected stream, authority is what the request says it's for,
+/// https when tls
+pub(crate) async fn connect(stream: BoxStream, authority: &str, path: &str, tls: bool) -> Result<BoxStream> {
+    let scheme = if tls { "https" } else { "http" };
+    let uri: Uri = format!("{}://{}{}", scheme, authority, path).parse()
+        .map_err(|e| Error::Config(format!("invalid http2 authority {} or path {}: {}", authority, path, e)))?;
+
+    let (client, connection) = client::Builder::new()
+        .initial_window_size(STREAM_WINDOW)
+        .initial_connection_window_size(CONNECTION_WINDOW)
+        .handshake::<_, Bytes>(stream)
+        .await
+        .map_err(h2_error)?;
+    // runs until the tunnel's stream is done with it, nothing else is ever sent on the connection
+    tokio::spawn(async move {
+        connection.await.ok();
+    });
+
+    let mut client = client.ready().await.map_err(h2_error)?;
+    let request = Request::post(uri).header(CONTENT_TYPE, CONTENT).body(()).expect("a valid uri is a valid request");
+    let (response, send) = client.send_request(request, false).map_err(h2_error)?;
+    let response = response.await.map_err(h2_error)?;
+    if response.status() != StatusCode::OK {
+        return Err(Error::protocol(format!("HTTP/2 server answered {} to POST {}", response.status(), path)));
+    }
+    Ok(Box::new(Http2Stream::new(send, response.into_body())))
+}
+
+/// serves an HTTP/2 connection, calling tunnel with each POST to path until the connection closes
+pub(crate) async fn serve<F: FnMut(BoxStream)>(stream: BoxStream, path: &str, mut tunnel: F) -> Result<()> {
+    let mut connection = server::Builder::new()
+        .initial_window_size(STREAM_WINDOW)
+        .initial_connection_window_size(CONNECTION_WINDOW)
+        .handshake::<_, Bytes>(stream)
+        .await
+        .map_err(h2_error)?;
+
+    // accepting is also what drives the connection, the tunnels' streams included
+    while let Some(request) = connection.accept().await {
+        let (request, mut respond) = match request {
+            Ok(request) => request,
+            // the peer going away without a goodbye, which TLS only notices when saying one back
+            Err(e) if e.get_io().map(io::Error::kind) == Some(io::ErrorKind::NotConnected) => break,
+            Err(e) => return Err(h2_error(e)),
+        };
+        if request.method() != Method::POST || request.uri().path() != path {
+            let response = Response::builder().status(StatusCode::NOT_FOUND).body(()).expect("a valid status is a valid response");
+            respond.send_response(response, true).ok();
+            continue;
+        }
+        let response = Response::builder().header(CONTENT_TYPE, CONTENT).body(()).expect("a valid header is a valid response");
+        let send = match respond.send_response(response, false) {
+            Ok(send) => send,
+            // reset by the client already
+            Err(_) => continue,
+        };
+        tunnel(Box::new(Http2Stream::new(send, request.into_body())));
+    }
+    Ok(())
+}
+
+// h2 passes io errors from the connection on as they were
+fn h2_error(e: h2::Error) -> Error {
+    if e.is_io() {
+        return e.into_io().expect("is_io has an io error").into();
+    }
+    Error::protocol(format!("HTTP/2: {}", e))
+}
+
+fn io_error(e: h2::Error) -> io::Error {
+    if e.is_io() {
+        return e.into_io().expect("is_io has an io error");
+    }
+    io::Error::other(e)
+}
+
+/// one HTTP/2 stream both ways, what's written goes out as DATA frames and the DATA frames read come in
+struct Http2Stream {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    // the rest of the last DATA frame read
+    data: Bytes,
+}
+
+impl Http2Stream {
+    fn new(send: SendStream<Bytes>, recv: RecvStream) -> Http2Stream {
+        Http2Stream {
+            send,
+            recv,
+            data: Bytes::new(),
+        }
+    }
+}
+
+impl AsyncRead for Http2Stream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        while this.data.is_empty() {
+            match this.recv.poll_data(cx) {
+                Poll::Ready(Some(Ok(data))) => {
+                    // the window opens up again as soon as a frame is taken, there's never more than one sitting here
+                    this.recv.flow_control().release_capacity(data.len()).map_err(io_error)?;
+                    this.data = data;
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(io_error(e))),
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = buf.len().min(this.data.len());
+        buf[..len].copy_from_slice(&this.data.split_to(len));
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for Http2Stream {
+    // only as much as the stream's window has room for, so nothing piles up inside h2
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.send.reserve_capacity(buf.len().min(MAX_WRITE));
+        loop {
+            let capacity = self.send.capacity();
+            if capacity > 0 {
+                let len = buf.len().min(capacity);
+                self.send.send_data(Bytes::copy_from_slice(&buf[..len]), false).map_err(io_error)?;
+                return Poll::Ready(Ok(len));
+            }
+            match self.send.poll_capacity(cx) {
+                Poll::Ready(Some(Ok(_))) => {}
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(io_error(e))),
+                Poll::Ready(None) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    // the connection's task writes out DATA frames as soon as they're sent
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.send.send_data(Bytes::new(), true).ok();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_http2() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (tx, mut tunnels) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        serve(Box::new(stream), "/tunnel", |stream| { tx.send(stream).ok(); }).await
+                    });
+                }
+            });
+
+            let stream = Box::new(TcpStream::connect(addr).await.unwrap());
+            let e = connect(stream, "localhost", "/elsewhere", false).await.err().unwrap();
+            assert!(matches!(e, Error::Protocol(ref msg) if msg.contains("404")), "{}", e);
+
+            let stream = Box::new(TcpStream::connect(addr).await.unwrap());
+            let mut client = connect(stream, "localhost", "/tunnel", false).await.unwrap();
+            let mut server = tunnels.recv().await.unwrap();
+            // well past the stream's window both ways
+            let big: Vec<u8> = (0..4 * STREAM_WINDOW).map(|i| i as u8).collect();
+            let (written, read) = tokio::join!(client.write_all(&big), async {
+                let mut buf = vec![0u8; big.len()];
+                server.read_exact(&mut buf).await.map(|_| buf)
+            });
+            written.unwrap();
+            assert_eq!(read.unwrap(), big);
+            let (written, read) = tokio::join!(server.write_all(&big), async {
+                let mut buf = vec![0u8; big.len()];
+                client.read_exact(&mut buf).await.map(|_| buf)
+            });
+            written.unwrap();
+            assert_eq!(read.unwrap(), big);
+
+            client.shutdown().await.unwrap();
+            let mut buf = [0u8; 1];
+            assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+        });
+    }
+}
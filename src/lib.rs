@@ -1,11 +1,71 @@
-use std::net::{TcpStream, UdpSocket};
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-mod error;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+mod batch;
+mod builder;
+mod control;
+pub mod error;
+pub mod frame;
+mod source;
+mod failover;
+mod eyeballs;
+mod family;
+mod resolve;
+mod admin;
+mod status;
+#[cfg(feature = "obfs")]
+mod obfs;
+#[cfg(not(feature = "obfs"))]
+#[path = "noobfs.rs"]
+mod obfs;
+mod random;
+mod padding;
+mod compress;
+mod bond;
+#[cfg(feature = "quic")]
+mod quic;
+#[cfg(feature = "http2")]
+mod http2;
+mod proxyproto;
 use error::Result;
 
+#[cfg(unix)]
+mod systemd;
+#[cfg(unix)]
+mod socketfile;
+
+pub use builder::{ProxyClientBuilder, ProxyServerBuilder, TlsClientConfig, TlsServerConfig};
+pub use source::{AllowedSources, Cidr};
+pub use failover::{Target, TargetOrder, DEFAULT_FAIL_BACK};
+pub use eyeballs::DEFAULT_CONNECT_TIMEOUT;
+pub use family::parse_udp_bind_range;
+pub use admin::{Counters, Session, Sessions, TlsInfo};
+pub use status::Status;
+pub use obfs::{Obfuscation, DEFAULT_MAX_PADDING};
+pub use padding::Padding;
+pub use compress::CompressionStats;
+pub use proxyproto::ProxyProtocol;
+#[cfg(unix)]
+pub use admin::admin_command;
+
+/// called with a status message once a client is connected or a server is listening
+pub type ReadyCallback = Arc<dyn Fn(&str) + Send + Sync>;
+/// called with the peer and the reason whenever one of a server's tunnels closes
+pub type TunnelClosedCallback = Arc<dyn Fn(&str, &error::Error) + Send + Sync>;
+/// called by the server for each new tunnel, to get its endpoint instead of binding a udp socket
+pub type EndpointFactory = Arc<dyn Fn() -> Result<endpoint::BoxEndpoint> + Send + Sync>;
+/// called with each change in where a client is at
+pub type StatusCallback = Arc<dyn Fn(&Status) + Send + Sync>;
+/// looks up the server's udp_target in place of the system resolver
+pub type Resolver = Arc<dyn Fn(&str) -> std::io::Result<Vec<SocketAddr>> + Send + Sync>;
+// where a client can connect and the transport for each
+type Targets = Vec<(String, Arc<dyn transport::Transport>)>;
+
 fn arg_to_env(arg: &str) -> Option<String> {
     if !arg.starts_with("--") {
         return None;
@@ -20,6 +80,34 @@ fn env_for_arg(arg: &str) -> Option<String> {
     arg_to_env(arg).and_then(|key| std::env::var(key).ok())
 }
 
+/// returns the path if this tcp_host or tcp_target is a unix socket, in the form unix:/path
+pub fn unix_path(host: &str) -> Option<&str> {
+    host.strip_prefix("unix:")
+}
+
+/// returns host:port if this tcp_host or tcp_target is QUIC over udp, in the form quic:host:port
+pub fn quic_addr(host: &str) -> Option<&str> {
+    host.strip_prefix("quic:")
+}
+
+#[cfg(unix)]
+fn unix_bind(path: &str, mode: Option<u32>) -> Result<(UnixListener, socketfile::SocketFile)> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // a socket file left behind by a previous run prevents binding, only remove it if nothing is listening on it
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            if UnixStream::connect(path).is_ok() {
+                let msg = format!("something is already listening on unix socket {}", path);
+                return Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, msg).into());
+            }
+            println!("removing stale unix socket {}", path);
+            std::fs::remove_file(path)?;
+        }
+    }
+    socketfile::bind(path, mode)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -30,6 +118,64 @@ mod tests {
         assert_eq!(arg_to_env("-h"), None);
         assert_eq!(arg_to_env("-th"), None);
     }
+
+    #[test]
+    fn test_unix_path() {
+        assert_eq!(unix_path("unix:/run/wgp.sock"), Some("/run/wgp.sock"));
+        assert_eq!(unix_path("unix:relative.sock"), Some("relative.sock"));
+        assert_eq!(unix_path("127.0.0.1:5555"), None);
+        assert_eq!(unix_path("[::]:5555"), None);
+        assert_eq!(quic_addr("quic:gw.example.com:443"), Some("gw.example.com:443"));
+        assert_eq!(quic_addr("unix:/run/wgp.sock"), None);
+    }
+
+    #[test]
+    fn test_setters() {
+        let mut proxy_server = ProxyServer::new("127.0.0.1:5555".to_owned(), "127.0.0.1:51820".to_owned(), "127.0.0.1".to_owned(), 0, 0, 0);
+        proxy_server.set_max_datagram(9000).unwrap();
+        proxy_server.set_resume(Some(Duration::from_secs(30))).unwrap();
+        assert_eq!(proxy_server.client_handler.max_datagram, 9000);
+        // once started something else holds on to client_handler
+        let _started = proxy_server.client_handler.clone();
+        assert!(matches!(proxy_server.set_re_resolve(Some(Duration::from_secs(60))), Err(error::Error::Config(_))));
+        assert_eq!(proxy_server.client_handler.re_resolve, None);
+    }
+
+    #[test]
+    fn test_resume_protected() {
+        let mut proxy_client = ProxyClient::new("127.0.0.1:51820".to_owned(), "127.0.0.1:5555".to_owned(), 0);
+        proxy_client.resume = true;
+        let unpinned = transport::TlsTransport::new(&TlsClientConfig::new()).unwrap();
+        let pinned = transport::TlsTransport::new(&TlsClientConfig::new().pinnedpubkey("sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=")).unwrap();
+        assert!(matches!(proxy_client.hello(&transport::Plain), Err(error::Error::Config(_))));
+        assert!(matches!(proxy_client.hello(&unpinned), Err(error::Error::Config(_))));
+        assert!(proxy_client.hello(&pinned).unwrap().unwrap().has(control::RESUME));
+
+        let mut proxy_server = ProxyServer::new("127.0.0.1:5555".to_owned(), "127.0.0.1:51820".to_owned(), "127.0.0.1".to_owned(), 0, 0, 0);
+        proxy_server.set_resume(Some(Duration::from_secs(30))).unwrap();
+        assert_eq!(proxy_server.client_handler.offered(false) & control::RESUME, 0);
+        assert_ne!(proxy_server.client_handler.offered(true) & control::RESUME, 0);
+    }
+
+    #[test]
+    fn test_proxy_mode() {
+        let mut proxy_server = ProxyServer::new("127.0.0.1:5555".to_owned(), "127.0.0.1:51820".to_owned(), "127.0.0.1".to_owned(), 0, 0, 0);
+        let balancer = Some("10.0.0.2:40000".parse().unwrap());
+        let direct = Some("203.0.113.7:40000".parse().unwrap());
+        assert_eq!(proxy_server.proxy_mode(direct).unwrap(), None);
+
+        proxy_server.proxy_protocol = Some(ProxyProtocol::Optional);
+        assert!(matches!(proxy_server.check_proxy_protocol(), Err(error::Error::Config(_))));
+        proxy_server.proxy_protocol_from = "10.0.0.0/24".parse().unwrap();
+        proxy_server.check_proxy_protocol().unwrap();
+        assert_eq!(proxy_server.proxy_mode(balancer).unwrap(), Some(ProxyProtocol::Optional));
+        assert_eq!(proxy_server.proxy_mode(direct).unwrap(), None);
+        assert_eq!(proxy_server.proxy_mode(None).unwrap(), Some(ProxyProtocol::Optional));
+
+        proxy_server.proxy_protocol = Some(ProxyProtocol::Strict);
+        assert_eq!(proxy_server.proxy_mode(balancer).unwrap(), Some(ProxyProtocol::Strict));
+        assert!(matches!(proxy_server.proxy_mode(direct), Err(error::Error::Protocol(_))));
+    }
 }
 
 pub struct Args<'a> {
@@ -37,7 +183,7 @@ pub struct Args<'a> {
 }
 
 impl<'a> Args<'a> {
-    pub fn new(args: &'a Vec<String>) -> Args {
+    pub fn new(args: &'a Vec<String>) -> Args<'a> {
         Args { args }
     }
     pub fn flag(&self, flag: &'a str) -> bool {
@@ -46,7 +192,7 @@ impl<'a> Args<'a> {
         }
         // because env we want slightly special handling of empty/0/false
         match env_for_arg(flag) {
-            Some(env) => &env != "" && &env != "0" && &env != "false",
+            Some(env) => !env.is_empty() && &env != "0" && &env != "false",
             None => false,
         }
     }
@@ -69,7 +215,7 @@ impl<'a> Args<'a> {
                 return env;
             }
         }
-        return None;
+        None
     }
     pub fn get_str(&self, flags: &[&'a str], def: &'a str) -> String {
         match self.get_option(flags) {
@@ -92,11 +238,76 @@ pub struct ProxyClient {
     pub udp_host: String,
     pub tcp_target: String,
     pub socket_timeout: Option<Duration>,
+    /// how long connecting to a target may take, resolving it too in the async build, None leaves it up to the os
+    pub connect_timeout: Option<Duration>,
+    /// largest udp datagram carried, up to frame::MAX_DATAGRAM for jumbo frames, must match the server
+    pub max_datagram: usize,
+    /// accept datagrams from any number of local wireguards over the one connection, the server must support it too
+    pub multi_peer: bool,
+    /// compress datagrams both ways where that makes them smaller, the server has to answer the hello like with multi_peer
+    /// but carries on uncompressed if it doesn't support compression
+    pub compress: bool,
+    /// how many connections to stripe the tunnel across, up to 16, not with multi_peer, the server must support it
+    /// like multi_peer, 1 doesn't bond
+    pub bond: usize,
+    /// ask the server to keep the tunnel's udp socket for a while after the connection drops, so reconnecting carries
+    /// on with the same source port towards udp_target, not with multi_peer, carries on without if it isn't offered,
+    /// only over obfuscation or TLS with a pinnedpubkey
+    pub resume: bool,
+    /// local sources wireguard packets are taken from, empty takes them from anywhere
+    pub allowed_sources: AllowedSources,
+    /// used by run(), start_tls() takes its options directly
+    pub tls: Option<TlsClientConfig>,
+    /// used by run() instead of tls when set
+    pub transport: Option<Arc<dyn transport::Transport>>,
+    /// tried by run() when tcp_target can't be connected
+    pub fallback_targets: Vec<Target>,
+    pub target_order: TargetOrder,
+    /// while on a fallback, how often to check if one ahead of it is reachable again and move back, None stays put
+    pub fail_back: Option<Duration>,
+    /// after the tunnel fails, run() waits this long and starts over, None returns the error instead
+    pub reconnect: Option<Duration>,
+    pub on_ready: Option<ReadyCallback>,
+    pub on_status: Option<StatusCallback>,
+    /// each status is also written here as one line of JSON, replacing the last
+    pub status_file: Option<String>,
+    /// padding added to what the client writes, the server needs to be new enough to throw it away
+    pub padding: Option<Padding>,
+    /// path to POST to, carrying the tunnel in an HTTP/2 stream for front ends that only forward HTTP/2, over TLS
+    /// when the transport is, None doesn't use HTTP/2, needs the http2 feature
+    pub http2: Option<String>,
+    status: Mutex<Option<Status>>,
+    udp_socket: Mutex<Option<UdpSocket>>,
+    compression: Mutex<Option<Arc<CompressionStats>>>,
+    // said in every hello when bonding or resuming, kept across reconnects
+    id: Mutex<Option<bond::BondId>>,
 }
 
 pub struct ProxyServer {
     pub tcp_host: String,
+    /// permissions to set on the socket file when tcp_host is unix:/path, None leaves it up to umask
+    pub unix_socket_mode: Option<u32>,
+    /// used by run(), start_tls() takes its options directly
+    pub tls: Option<TlsServerConfig>,
+    /// used by run() instead of tls when set
+    pub acceptor: Option<Arc<dyn transport::Acceptor>>,
+    pub on_ready: Option<ReadyCallback>,
     pub client_handler: Arc<ProxyServerClientHandler>,
+    /// every live tunnel, to list or kill them or stop accepting new ones
+    pub sessions: Arc<Sessions>,
+    /// unix socket path to serve the admin protocol on, see admin.rs, None doesn't
+    pub admin_socket: Option<String>,
+    /// padding added to what the server writes, clients need to be new enough to throw it away
+    pub padding: Option<Padding>,
+    /// path clients POST to, each such HTTP/2 stream is a tunnel, None doesn't speak HTTP/2, needs the http2 feature
+    pub http2: Option<String>,
+    /// PROXY protocol header read off the start of every connection, before TLS, for the client address a load
+    /// balancer in front passes on, None doesn't look for one
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// the load balancers a PROXY protocol header is believed from, required with proxy_protocol, a connection from
+    /// anywhere else is taken as it is, or closed when strict, one over a unix socket is believed as only what the
+    /// socket's permissions let in can connect
+    pub proxy_protocol_from: AllowedSources,
 }
 
 pub struct ProxyServerClientHandler {
@@ -105,23 +316,49 @@ pub struct ProxyServerClientHandler {
     pub udp_low_port: u16,
     pub udp_high_port: u16,
     pub socket_timeout: Option<Duration>,
+    /// largest udp datagram carried, up to frame::MAX_DATAGRAM for jumbo frames, must match the client
+    pub max_datagram: usize,
+    pub on_tunnel_closed: Option<TunnelClosedCallback>,
+    /// used for each tunnel instead of binding a udp socket to udp_target when set
+    pub endpoint_factory: Option<EndpointFactory>,
+    /// how often open tunnels look up udp_target again and move to a new address, None only as each starts
+    pub re_resolve: Option<Duration>,
+    pub resolver: Option<Resolver>,
+    /// how long a tunnel's udp socket is kept after the connection drops for a client that asked to resume,
+    /// only offered over obfuscation or TLS, None doesn't offer resuming
+    pub resume: Option<Duration>,
+    bonds: bond::Bonds<net::Bond>,
 }
 
-#[cfg(any(feature = "async"))]
+#[cfg(feature = "async")]
 #[path = ""]
 mod net {
     mod asyncmod;
+    pub(crate) use self::asyncmod::Bond;
+    #[cfg(all(test, feature = "quic"))]
+    pub(crate) use self::asyncmod::TcpUdpPipe;
+    #[path = "asynctransport.rs"]
+    pub mod transport;
+    #[path = "asyncendpoint.rs"]
+    pub mod endpoint;
 }
 
-#[cfg(not(any(feature = "async")))]
+#[cfg(not(feature = "async"))]
 #[path = ""]
 mod net {
-
-
-
     mod syncmod;
+    pub(crate) use self::syncmod::Bond;
+    #[path = "synctransport.rs"]
+    pub mod transport;
+    #[path = "syncendpoint.rs"]
+    pub mod endpoint;
 }
 
+/// Transport and Acceptor, what the tunnel runs over, async and sync builds have their own flavor
+pub use net::transport;
+/// DatagramEndpoint, where the tunnel delivers datagrams, a udp socket unless a library user supplies another
+pub use net::endpoint;
+
 
 impl ProxyClient {
     pub fn new(udp_host: String, tcp_target: String, secs: u64) -> ProxyClient {
@@ -132,19 +369,191 @@ impl ProxyClient {
                 0 => None,
                 x => Some(Duration::from_secs(x)),
             },
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            max_datagram: frame::DEFAULT_MAX_DATAGRAM,
+            multi_peer: false,
+            compress: false,
+            bond: 1,
+            resume: false,
+            allowed_sources: AllowedSources::default(),
+            tls: None,
+            transport: None,
+            fallback_targets: Vec::new(),
+            target_order: TargetOrder::Listed,
+            fail_back: Some(DEFAULT_FAIL_BACK),
+            reconnect: None,
+            on_ready: None,
+            on_status: None,
+            status_file: None,
+            padding: None,
+            http2: None,
+            status: Mutex::new(None),
+            udp_socket: Mutex::new(None),
+            compression: Mutex::new(None),
+            id: Mutex::new(None),
         }
     }
 
-    fn tcp_connect(&self) -> Result<TcpStream> {
-        let tcp_stream = TcpStream::connect(&self.tcp_target)?;
-        tcp_stream.set_read_timeout(self.socket_timeout)?;
-        Ok(tcp_stream)
+    /// the last status reported, None before starting
+    pub fn status(&self) -> Option<Status> {
+        self.status.lock().unwrap().clone()
     }
 
+    /// how well the current or last tunnel compressed, None unless the server agreed to compress
+    pub fn compression(&self) -> Option<Arc<CompressionStats>> {
+        self.compression.lock().unwrap().clone()
+    }
+
+    fn report(&self, status: Status) {
+        if let Some(status_file) = &self.status_file {
+            if let Err(e) = status::write_file(status_file, &status) {
+                println!("cannot write status file {}: {}", status_file, e);
+            }
+        }
+        if let Some(on_status) = &self.on_status {
+            on_status(&status);
+        }
+        *self.status.lock().unwrap() = Some(status);
+    }
+
+    // once run()'s tunnel fails, how long to wait before starting over, or the error to give up with
+    fn reconnect_after(&self, e: error::Error, attempt: &mut u32) -> Result<Duration> {
+        if let Some(Status::Connected { .. }) = self.status() {
+            *attempt = 0;
+        }
+        if let Some(compression) = self.compression() {
+            println!("compression {}", compression);
+        }
+        self.report(Status::Failed { error: e.to_string() });
+        // bad options won't get any better
+        let delay = match self.reconnect {
+            Some(delay) if !matches!(e, error::Error::Config(_)) => delay,
+            _ => return Err(e),
+        };
+        *attempt += 1;
+        println!("{}, reconnecting in {:?}, attempt {}", e, delay, attempt);
+        self.report(Status::Reconnecting { attempt: *attempt });
+        Ok(delay)
+    }
+
+    /// start_with() using self.transport, else start_tls() or start() depending on self.tls,
+    /// start_failover() if there are fallback_targets, all over again after reconnect when it fails
+    pub fn run(&self) -> Result<usize> {
+        let mut attempt = 0;
+        loop {
+            match self.run_once() {
+                Ok(sent) => return Ok(sent),
+                Err(e) => std::thread::sleep(self.reconnect_after(e, &mut attempt)?),
+            }
+        }
+    }
+
+    fn run_once(&self) -> Result<usize> {
+        if !self.fallback_targets.is_empty() {
+            return self.start_failover();
+        }
+        if let Some(transport) = &self.transport {
+            return self.start_with(transport.as_ref());
+        }
+        match &self.tls {
+            Some(tls) => self.start_tls(tls.hostname.as_deref(), tls.pinnedpubkey.as_deref()),
+            None => self.start(),
+        }
+    }
+
+    // tcp_target then fallback_targets, each with the transport it's connected over
+    fn targets(&self) -> Result<Targets> {
+        let transport = |tls: &Option<TlsClientConfig>| -> Result<Arc<dyn transport::Transport>> {
+            Ok(match tls {
+                Some(tls) => Arc::new(transport::TlsTransport::new(tls)?),
+                None => Arc::new(transport::Plain),
+            })
+        };
+        let primary = match &self.transport {
+            Some(primary) => primary.clone(),
+            None => transport(&self.tls)?,
+        };
+        let mut targets = vec![(self.tcp_target.clone(), primary)];
+        for target in self.fallback_targets.iter() {
+            targets.push((target.tcp_target.clone(), transport(&target.tls)?));
+        }
+        Ok(targets)
+    }
+
+    #[cfg(unix)]
+    fn unix_connect(&self, path: &str) -> Result<UnixStream> {
+        let unix_stream = UnixStream::connect(path)?;
+        unix_stream.set_read_timeout(self.socket_timeout)?;
+        Ok(unix_stream)
+    }
+
+    // the hello to say on every connection of a tunnel over transport, None doesn't say hello at all
+    fn hello(&self, transport: &dyn transport::Transport) -> Result<Option<control::Hello>> {
+        bond::validate(self.bond, self.multi_peer, self.resume)?;
+        compress::check(self.compress)?;
+        // whoever has the token can take the tunnel over, so it isn't sent where it could be read along
+        if self.resume && !transport.protected() {
+            return Err(error::Error::config("resume needs obfuscation, or TLS with a pinnedpubkey"));
+        }
+        let mut features = 0;
+        if self.multi_peer {
+            features |= control::MULTI_PEER;
+        }
+        if self.compress {
+            features |= control::COMPRESS;
+        }
+        if self.bond > 1 {
+            features |= control::BOND;
+        }
+        if self.resume {
+            features |= control::RESUME;
+        }
+        Ok(match features {
+            0 => None,
+            features if features & (control::BOND | control::RESUME) != 0 => Some(control::Hello::with_id(features, self.id()?)),
+            features => Some(control::Hello::new(features)),
+        })
+    }
+
+    fn id(&self) -> Result<bond::BondId> {
+        let mut id = self.id.lock().unwrap();
+        match *id {
+            Some(id) => Ok(id),
+            None => Ok(*id.insert(bond::BondId::new()?)),
+        }
+    }
+
+    // the stream compressed if the server agreed to, and the reader to carry on with, stats are shared by a bond's connections
+    fn compressed(&self, stream: transport::BoxStream, reader: frame::FrameReader, agreed: &control::Hello, stats: &Arc<CompressionStats>) -> (transport::BoxStream, frame::FrameReader) {
+        if !agreed.has(control::COMPRESS) {
+            return (stream, reader);
+        }
+        *self.compression.lock().unwrap() = Some(stats.clone());
+        compressed(stream, reader, self.max_datagram, stats.clone())
+    }
+
+    // "Connected with TLS to ..." for the ready message
+    fn connected(&self, tcp_target: &str, transport: &dyn transport::Transport) -> String {
+        if quic_addr(tcp_target).is_some() {
+            return format!("Connected with QUIC to {}", tcp_target);
+        }
+        match (&self.http2, transport.name()) {
+            (Some(_), Some(name)) => format!("Connected with HTTP/2 over {} to {}", name, tcp_target),
+            (Some(_), None) => format!("Connected with HTTP/2 to {}", tcp_target),
+            (None, Some(name)) => format!("Connected with {} to {}", name, tcp_target),
+            (None, None) => format!("Connected to {}", tcp_target),
+        }
+    }
+
+    // bound once and shared by every tunnel run() starts, as the last one's thread can still be blocked in recv on it
     fn udp_connect(&self) -> Result<UdpSocket> {
-        let udp_socket = UdpSocket::bind(&self.udp_host)?;
-        udp_socket.set_read_timeout(self.socket_timeout)?;
-        Ok(udp_socket)
+        let mut bound = self.udp_socket.lock().unwrap();
+        if bound.is_none() {
+            let udp_socket = UdpSocket::bind(&self.udp_host)?;
+            udp_socket.set_read_timeout(self.socket_timeout)?;
+            *bound = Some(udp_socket);
+        }
+        Ok(bound.as_ref().unwrap().try_clone()?)
     }
 }
 
@@ -166,30 +575,251 @@ impl ProxyServer {
                 0 => None,
                 x => Some(Duration::from_secs(x)),
             },
+            max_datagram: frame::DEFAULT_MAX_DATAGRAM,
+            on_tunnel_closed: None,
+            endpoint_factory: None,
+            re_resolve: None,
+            resolver: None,
+            resume: None,
+            bonds: Default::default(),
         });
         ProxyServer {
             tcp_host,
+            unix_socket_mode: None,
+            tls: None,
+            acceptor: None,
+            on_ready: None,
             client_handler,
+            sessions: Arc::new(Sessions::new()),
+            admin_socket: None,
+            padding: None,
+            http2: None,
+            proxy_protocol: None,
+            proxy_protocol_from: AllowedSources::default(),
+        }
+    }
+
+    /// start_with() using self.acceptor, else start_tls() or start() depending on self.tls
+    pub fn run(&self) -> Result<()> {
+        if let Some(acceptor) = &self.acceptor {
+            return self.start_with(acceptor.clone());
+        }
+        match &self.tls {
+            Some(tls) => self.start_tls(&tls.key, &tls.cert),
+            None => self.start(),
+        }
+    }
+
+    /// only possible before the server is started, while nothing else holds client_handler
+    pub fn set_max_datagram(&mut self, max_datagram: usize) -> Result<()> {
+        self.client_handler_mut("max datagram size")?.max_datagram = max_datagram;
+        Ok(())
+    }
+
+    /// only possible before the server is started, like set_max_datagram
+    pub fn set_re_resolve(&mut self, re_resolve: Option<Duration>) -> Result<()> {
+        self.client_handler_mut("re-resolve interval")?.re_resolve = re_resolve;
+        Ok(())
+    }
+
+    /// only possible before the server is started, like set_max_datagram
+    pub fn set_resume(&mut self, resume: Option<Duration>) -> Result<()> {
+        self.client_handler_mut("resume grace period")?.resume = resume;
+        Ok(())
+    }
+
+    // anyone connecting directly could claim any address in a header, so there have to be load balancers to trust
+    fn check_proxy_protocol(&self) -> Result<()> {
+        if self.proxy_protocol.is_some() && self.proxy_protocol_from.0.is_empty() {
+            return Err(error::Error::config("proxy_protocol needs proxy_protocol_from, the load balancers trusted to send the header"));
+        }
+        Ok(())
+    }
+
+    // whether to look for a header on a connection from addr, None for a unix socket, an error closes it
+    fn proxy_mode(&self, addr: Option<SocketAddr>) -> Result<Option<ProxyProtocol>> {
+        Ok(match (self.proxy_protocol, addr) {
+            (None, _) => None,
+            (Some(mode), None) => Some(mode),
+            (Some(mode), Some(addr)) if self.proxy_protocol_from.allows(&addr) => Some(mode),
+            (Some(ProxyProtocol::Strict), Some(addr)) => return Err(error::Error::protocol(format!("{} isn't a trusted load balancer", addr))),
+            (Some(ProxyProtocol::Optional), Some(_)) => None,
+        })
+    }
+
+    fn client_handler_mut(&mut self, what: &str) -> Result<&mut ProxyServerClientHandler> {
+        Arc::get_mut(&mut self.client_handler).ok_or_else(|| error::Error::Config(format!("cannot change {} after starting", what)))
+    }
+
+    // prefer a socket passed in by systemd socket activation, if any
+    #[cfg(unix)]
+    fn systemd_listener<T: std::os::unix::io::FromRawFd>(&self) -> Option<T> {
+        let mut fds = systemd::listen_fds().into_iter();
+        let fd = fds.next()?;
+        if fds.next().is_some() {
+            println!("systemd passed more than one socket, only using the first");
+        }
+        println!("using socket passed in by systemd instead of binding {}", &self.tcp_host);
+        Some(unsafe { T::from_raw_fd(fd) })
+    }
+
+    fn tcp_listener(&self) -> Result<TcpListener> {
+        #[cfg(unix)]
+        {
+            if let Some(listener) = self.systemd_listener::<TcpListener>() {
+                // systemd could pass it in non-blocking mode, sync mode relies on blocking and async sets this itself
+                listener.set_nonblocking(false)?;
+                return Ok(listener);
+            }
+        }
+        Ok(TcpListener::bind(&self.tcp_host)?)
+    }
+
+    // with the socket file to keep for as long as it's listened on, None when systemd owns it
+    #[cfg(unix)]
+    fn unix_listener(&self, path: &str) -> Result<(UnixListener, Option<socketfile::SocketFile>)> {
+        if let Some(listener) = self.systemd_listener::<UnixListener>() {
+            listener.set_nonblocking(false)?;
+            return Ok((listener, None));
+        }
+        let (listener, socket_file) = unix_bind(path, self.unix_socket_mode)?;
+        Ok((listener, Some(socket_file)))
+    }
+
+    // only the owner may use it, it can kill tunnels, what's returned removes the socket file when dropped
+    fn admin_listen(&self) -> Result<Option<AdminSocket>> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = &self.admin_socket {
+                let (listener, socket_file) = unix_bind(path, Some(0o600))?;
+                admin::listen(listener, self.sessions.clone());
+                println!("admin socket listening on {}", path);
+                return Ok(Some(socket_file));
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            if self.admin_socket.is_some() {
+                return Err(error::Error::config("admin socket is only supported on unix"));
+            }
         }
+        Ok(None)
     }
 }
 
+#[cfg(unix)]
+type AdminSocket = socketfile::SocketFile;
+#[cfg(not(unix))]
+type AdminSocket = ();
+
+// stream with padding frames added to what's written, if there is padding
+fn padded(stream: transport::BoxStream, padding: &Option<Padding>, max_datagram: usize) -> Result<transport::BoxStream> {
+    Ok(match padding {
+        Some(padding) => Box::new(transport::Padded::new(stream, padding, max_datagram)?),
+        None => stream,
+    })
+}
+
+// stream with datagrams compressed both ways, what reader already read from it goes inside with it
+// so the reader returned starts out empty
+fn compressed(stream: transport::BoxStream, reader: frame::FrameReader, max_datagram: usize, stats: Arc<CompressionStats>) -> (transport::BoxStream, frame::FrameReader) {
+    (Box::new(transport::Compressed::new(stream, reader, max_datagram, stats)), frame::FrameReader::new(max_datagram))
+}
+
+fn notify_ready(on_ready: &Option<ReadyCallback>, status: &str) {
+    println!("{}", status);
+    #[cfg(unix)]
+    systemd::notify_ready(status);
+    if let Some(on_ready) = on_ready {
+        on_ready(status);
+    }
+}
+
+fn watchdog_interval() -> Option<Duration> {
+    #[cfg(unix)]
+    return systemd::watchdog_interval();
+    #[cfg(not(unix))]
+    None
+}
+
+fn notify_watchdog() {
+    #[cfg(unix)]
+    systemd::notify_watchdog();
+}
+
 impl ProxyServerClientHandler {
+    fn tunnel_closed(&self, peer: &str, err: &error::Error) {
+        println!("connection from {} closed: {}", peer, err);
+        if let Some(on_tunnel_closed) = &self.on_tunnel_closed {
+            on_tunnel_closed(peer, err);
+        }
+    }
+
     fn udp_bind(&self) -> Result<UdpSocket> {
-        let mut port = self.udp_low_port;
-        let udp_socket = loop {
-            match UdpSocket::bind((&self.udp_host[..], port)) {
-                Ok(sock) => break sock,
-                Err(_) => {
-                    port += 1;
-                    if port > self.udp_high_port {
-                        panic!("cannot find free port, increase range?");
-                    }
-                }
-            }
-        };
+        // both can be names, resolved afresh for each tunnel, the bind address follows udp_target's family
+        let udp_host = self.udp_host.trim_start_matches('[').trim_end_matches(']');
+        let binds: Vec<IpAddr> = (udp_host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect();
+        let targets = resolve::resolve(&self.resolver, &self.udp_target)?;
+        let (bind_ip, udp_target) = family::pick(&binds, &targets)?;
+
+        let udp_socket = (self.udp_low_port..=self.udp_high_port)
+            .find_map(|port| UdpSocket::bind((bind_ip, port)).ok())
+            .ok_or(error::Error::PortRangeExhausted { low: self.udp_low_port, high: self.udp_high_port })?;
         udp_socket.set_read_timeout(self.socket_timeout)?;
-        udp_socket.connect(&self.udp_target)?;
+        udp_socket.connect(udp_target)?;
         Ok(udp_socket)
     }
+
+    // watches udp_target for the tunnel's sockets, unless it isn't looked up again or isn't used at all
+    fn tunnel(&self, session: Option<Arc<Session>>) -> Tunnel {
+        let upstreams = match (self.re_resolve, &self.endpoint_factory) {
+            (Some(interval), None) => Some(resolve::Upstreams::watch(&self.udp_target, self.resolver.clone(), interval)),
+            _ => None,
+        };
+        Tunnel { upstreams, session }
+    }
+
+    // every feature unless resuming isn't offered, or the stream isn't protected so the client's token could have
+    // been read along
+    fn offered(&self, protected: bool) -> u32 {
+        match self.resume {
+            Some(_) if protected => control::FEATURES,
+            _ => control::FEATURES & !control::RESUME,
+        }
+    }
+
+    // the stream compressed if the client asked for it and we agreed, the tunnel's session keeping count
+    fn compressed(&self, stream: transport::BoxStream, reader: frame::FrameReader, agreed: &control::Hello, tunnel: &Tunnel) -> (transport::BoxStream, frame::FrameReader) {
+        if !agreed.has(control::COMPRESS) {
+            return (stream, reader);
+        }
+        let stats = Arc::new(CompressionStats::default());
+        if let Some(session) = &tunnel.session {
+            session.set_compression(stats.clone());
+        }
+        compressed(stream, reader, self.max_datagram, stats)
+    }
+
+    // a udp socket just bound for tunnel
+    fn register(&self, tunnel: &Tunnel, udp_socket: &UdpSocket) -> Result<()> {
+        if let Some(upstreams) = &tunnel.upstreams {
+            upstreams.add(udp_socket)?;
+        }
+        if let Some(session) = &tunnel.session {
+            session.add_udp_port(udp_socket.local_addr()?.port());
+        }
+        Ok(())
+    }
+}
+
+// what one tunnel's udp sockets are registered with as they are bound
+struct Tunnel {
+    upstreams: Option<Arc<resolve::Upstreams>>,
+    session: Option<Arc<Session>>,
+}
+
+impl Tunnel {
+    fn killed(&self) -> bool {
+        matches!(&self.session, Some(session) if session.killed())
+    }
 }
@@ -1,22 +1,33 @@
-use std::net::TcpStream;
-use super::super::TryClone;
+use super::super::{BoxStream, Stream};
 use std::io::{Read, Write};
 use crate::error::*;
 
 fn err() -> Error {
-    Error::new("Error: compiled without TLS support")
+    Error::config("compiled without TLS support")
 }
 
 pub struct TlsStream;
 
 impl TlsStream {
-    pub fn client(_hostname: Option<&str>, _pinnedpubkey: Option<&str>, _tcp_stream: TcpStream) -> Result<TlsStream> {
+    pub fn server_name(&self) -> Option<String> {
+        None
+    }
+
+    pub fn peer_pubkey(&self) -> Option<String> {
+        None
+    }
+
+    pub fn client(_hostname: Option<&str>, _pinnedpubkey: Option<&str>, _tcp_stream: BoxStream) -> Result<TlsStream> {
         Err(err())
     }
 }
 
-impl TryClone<TlsStream> for TlsStream {
-    fn try_clone(&self) -> Result<TlsStream> {
+impl Stream for TlsStream {
+    fn try_clone(&self) -> Result<BoxStream> {
+        Err(err())
+    }
+
+    fn shutdown(&self) -> Result<()> {
         Err(err())
     }
 }
@@ -43,7 +54,11 @@ impl TlsListener {
     pub fn new(_tls_key: &str, _tls_cert: &str) -> Result<TlsListener> {
         Err(err())
     }
-    pub fn wrap(&self, _tcp_stream: TcpStream) -> Result<TlsStream> {
+    pub fn pinnedpubkey(&self) -> Option<String> {
+        None
+    }
+
+    pub fn wrap(&self, _tcp_stream: BoxStream) -> Result<TlsStream> {
         Err(err())
     }
 }
\ No newline at end of file
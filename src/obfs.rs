@@ -0,0 +1,346 @@
+// obfuscation hides the framing and the wireguard packets inside it from anything in between fingerprinting them.
+// each direction starts with a random salt, everything after it is ChaCha20 keyed from the salt and a secret both
+// ends share: a check value that only decrypts to zeros with the same secret, then records of a header holding
+// the data and padding lengths, the data, and random padding so record sizes don't give packet sizes away.
+// this hides the tunnel, it doesn't authenticate anything, wireguard does that. it fails closed: a wrong secret or a
+// tampered salt or check ends the stream before anything is read from it, a tampered record header ends it with a
+// protocol error or throws the framing inside out of step so it fails soon after, and tampered data comes out as
+// datagrams wireguard drops, nothing gets through that wouldn't have without obfuscation
+
+use std::io;
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20Legacy;
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::frame::write_header;
+use crate::random::{self, Rng};
+
+const SALT: usize = 16;
+const CHECK: usize = 8;
+// data length then padding length, each a big-endian u16
+const RECORD_HEADER: usize = 4;
+// longer writes are split into several records
+const MAX_RECORD: usize = 16 * 1024;
+const READ_SIZE: usize = 64 * 1024;
+/// each record gets up to this many bytes of random padding by default
+pub const DEFAULT_MAX_PADDING: u16 = 255;
+
+/// the secret shared with the other end and how much padding to add, only the secret has to match
+#[derive(Clone)]
+pub struct Obfuscation {
+    secret: Vec<u8>,
+    max_padding: u16,
+}
+
+impl Obfuscation {
+    pub fn new(secret: &str) -> Result<Obfuscation> {
+        if secret.is_empty() {
+            return Err(Error::config("obfuscation secret can't be empty"));
+        }
+        Ok(Obfuscation {
+            secret: secret.as_bytes().to_vec(),
+            max_padding: DEFAULT_MAX_PADDING,
+        })
+    }
+
+    /// each record gets between 0 and this many bytes of random padding, 0 adds none, default 255
+    pub fn max_padding(mut self, max_padding: u16) -> Obfuscation {
+        self.max_padding = max_padding;
+        self
+    }
+
+    pub(crate) fn sealer(&self) -> Result<Sealer> {
+        let mut salt = [0u8; SALT];
+        random::fill(&mut salt)?;
+        Ok(Sealer {
+            cipher: cipher(&self.secret, &salt),
+            rng: Rng::new()?,
+            max_padding: self.max_padding as usize,
+            salt: Some(salt),
+            out: Vec::new(),
+        })
+    }
+
+    pub(crate) fn opener(&self) -> Opener {
+        Opener {
+            secret: self.secret.clone(),
+            cipher: None,
+            state: State::Salt,
+            small: [0u8; SALT],
+            small_len: 0,
+            raw: vec![0u8; READ_SIZE],
+            start: 0,
+            end: 0,
+        }
+    }
+}
+
+// a key of its own for every salt, so the keystream never repeats and the nonce can stay zero
+fn cipher(secret: &[u8], salt: &[u8]) -> ChaCha20Legacy {
+    let key = Sha256::new().chain_update(b"wireguard-proxy obfuscation").chain_update(salt).chain_update(secret).finalize();
+    ChaCha20Legacy::new(&key, &Default::default())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Error::protocol(msg))
+}
+
+/// turns what's written into records, one direction of one stream
+pub(crate) struct Sealer {
+    cipher: ChaCha20Legacy,
+    // for padding and its length
+    rng: Rng,
+    max_padding: usize,
+    // sent ahead of the first record
+    salt: Option<[u8; SALT]>,
+    out: Vec<u8>,
+}
+
+impl Sealer {
+    /// all of data as records, for sealed() to hand out until the next call
+    pub fn seal(&mut self, data: &[u8]) {
+        self.out.clear();
+        if let Some(salt) = self.salt.take() {
+            self.out.extend_from_slice(&salt);
+            self.push_encrypted(&[0u8; CHECK]);
+        }
+        for chunk in data.chunks(MAX_RECORD) {
+            let padding = self.padding();
+            let mut header = [0u8; RECORD_HEADER];
+            write_header(&mut header, chunk.len());
+            write_header(&mut header[2..], padding);
+            self.push_encrypted(&header);
+            self.push_encrypted(chunk);
+            let start = self.out.len();
+            self.out.resize(start + padding, 0);
+            self.rng.fill(&mut self.out[start..]);
+        }
+    }
+
+    pub fn sealed(&self) -> &[u8] {
+        &self.out
+    }
+
+    fn push_encrypted(&mut self, plain: &[u8]) {
+        let start = self.out.len();
+        self.out.extend_from_slice(plain);
+        self.cipher.apply_keystream(&mut self.out[start..]);
+    }
+
+    fn padding(&mut self) -> usize {
+        self.rng.up_to(self.max_padding)
+    }
+}
+
+// where in the stream of records the next byte read belongs
+#[derive(Clone, Copy)]
+enum State {
+    Salt,
+    Check,
+    Header,
+    Data { left: usize, padding: usize },
+    Padding(usize),
+}
+
+/// turns records read back into what was written, one direction of one stream
+pub(crate) struct Opener {
+    secret: Vec<u8>,
+    cipher: Option<ChaCha20Legacy>,
+    state: State,
+    // the salt, check or a record header, collected over as many reads as it takes
+    small: [u8; SALT],
+    small_len: usize,
+    raw: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl Opener {
+    /// free space to read from the stream into, call filled() with how much was
+    pub fn read_mut(&mut self) -> &mut [u8] {
+        // open() only returns 0 once everything read is used up
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+        &mut self.raw[self.end..]
+    }
+
+    pub fn filled(&mut self, len: usize) {
+        self.end += len;
+    }
+
+    /// as much data as is read and fits into buf, 0 means read more
+    pub fn open(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len = 0;
+        while self.start < self.end {
+            let available = self.end - self.start;
+            match self.state {
+                State::Salt | State::Check | State::Header => {
+                    let need = match self.state {
+                        State::Salt => SALT,
+                        State::Check => CHECK,
+                        _ => RECORD_HEADER,
+                    };
+                    let take = available.min(need - self.small_len);
+                    self.small[self.small_len..self.small_len + take].copy_from_slice(&self.raw[self.start..self.start + take]);
+                    self.small_len += take;
+                    self.start += take;
+                    if self.small_len == need {
+                        self.small_len = 0;
+                        self.state = self.next_state(need)?;
+                    }
+                }
+                State::Data { left, padding } => {
+                    if len == buf.len() {
+                        break;
+                    }
+                    let take = available.min(left).min(buf.len() - len);
+                    buf[len..len + take].copy_from_slice(&self.raw[self.start..self.start + take]);
+                    self.cipher().apply_keystream(&mut buf[len..len + take]);
+                    len += take;
+                    self.start += take;
+                    self.state = match left - take {
+                        0 => State::Padding(padding),
+                        left => State::Data { left, padding },
+                    };
+                }
+                State::Padding(left) => {
+                    let take = available.min(left);
+                    self.start += take;
+                    self.state = match left - take {
+                        0 => State::Header,
+                        left => State::Padding(left),
+                    };
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    fn cipher(&mut self) -> &mut ChaCha20Legacy {
+        self.cipher.as_mut().expect("keyed once the salt is read")
+    }
+
+    // what follows the salt, check or record header now in small
+    fn next_state(&mut self, len: usize) -> io::Result<State> {
+        if let State::Salt = self.state {
+            self.cipher = Some(cipher(&self.secret, &self.small));
+            return Ok(State::Check);
+        }
+        let mut small = self.small;
+        self.cipher().apply_keystream(&mut small[..len]);
+        if let State::Check = self.state {
+            if small[..len].iter().any(|b| *b != 0) {
+                return Err(invalid("obfuscation secret doesn't match the other end's".to_owned()));
+            }
+            return Ok(State::Header);
+        }
+        let left = ((small[0] as usize) << 8) + small[1] as usize;
+        let padding = ((small[2] as usize) << 8) + small[3] as usize;
+        if left > MAX_RECORD {
+            return Err(invalid(format!("obfuscated record of {} bytes is longer than {}", left, MAX_RECORD)));
+        }
+        Ok(State::Data { left, padding })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // feeds sealed bytes to the opener a few at a time and reads back into a small buffer, to cross every boundary
+    fn open_all(opener: &mut Opener, mut sealed: &[u8], chunk: usize) -> io::Result<Vec<u8>> {
+        let mut opened = Vec::new();
+        let mut buf = [0u8; 7];
+        loop {
+            let len = opener.open(&mut buf)?;
+            if len > 0 {
+                opened.extend_from_slice(&buf[..len]);
+                continue;
+            }
+            if sealed.is_empty() {
+                return Ok(opened);
+            }
+            let take = sealed.len().min(chunk);
+            opener.read_mut()[..take].copy_from_slice(&sealed[..take]);
+            opener.filled(take);
+            sealed = &sealed[take..];
+        }
+    }
+
+    #[test]
+    fn test_obfuscation() {
+        let obfuscation = Obfuscation::new("correct horse").unwrap();
+        let mut sealer = obfuscation.sealer().unwrap();
+        let datagram = [4u8, 0, 0, 0, 1, 2, 3];
+        sealer.seal(&datagram);
+        let mut sealed = sealer.sealed().to_vec();
+        assert!(!sealed.windows(datagram.len()).any(|window| window == datagram));
+        let big = vec![7u8; MAX_RECORD * 2 + 5];
+        sealer.seal(&big);
+        sealed.extend_from_slice(sealer.sealed());
+        sealer.seal(&datagram);
+        sealed.extend_from_slice(sealer.sealed());
+
+        let mut expected = datagram.to_vec();
+        expected.extend_from_slice(&big);
+        expected.extend_from_slice(&datagram);
+        for chunk in [1, 3, 100, READ_SIZE].iter() {
+            assert_eq!(open_all(&mut obfuscation.opener(), &sealed, *chunk).unwrap(), expected);
+        }
+
+        let wrong = Obfuscation::new("battery staple").unwrap();
+        let e = open_all(&mut wrong.opener(), &sealed, 100).unwrap_err();
+        assert!(matches!(Error::from(e), Error::Protocol(ref msg) if msg.contains("secret")));
+
+        // padding makes the same datagram come out at different sizes
+        let sizes: std::collections::HashSet<_> = (0..20).map(|_| {
+            sealer.seal(&datagram);
+            sealer.sealed().len()
+        }).collect();
+        assert!(sizes.len() > 1);
+        let mut unpadded = Obfuscation::new("correct horse").unwrap().max_padding(0).sealer().unwrap();
+        unpadded.seal(&[]);
+        unpadded.seal(&datagram);
+        assert_eq!(unpadded.sealed().len(), RECORD_HEADER + datagram.len());
+
+        assert!(Obfuscation::new("").is_err());
+    }
+
+    #[test]
+    fn test_tampered() {
+        let obfuscation = Obfuscation::new("correct horse").unwrap().max_padding(0);
+        let mut sealer = obfuscation.sealer().unwrap();
+        let datagram = [0u8, 5, 1, 2, 3, 4, 5];
+        sealer.seal(&datagram);
+        let sealed = sealer.sealed().to_vec();
+        assert_eq!(sealed.len(), SALT + CHECK + RECORD_HEADER + datagram.len());
+
+        for i in 0..sealed.len() * 8 {
+            let mut tampered = sealed.clone();
+            tampered[i / 8] ^= 1 << (i % 8);
+            let opened = open_all(&mut obfuscation.opener(), &tampered, 3);
+            match i / 8 {
+                // the salt keys everything after it, so it and the check can't be touched
+                byte if byte < SALT + CHECK => {
+                    assert!(matches!(opened.map_err(Error::from), Err(Error::Protocol(ref msg)) if msg.contains("secret")), "{}", i);
+                }
+                // a header can only lose data or fail, never make up more than was sent
+                byte if byte < SALT + CHECK + RECORD_HEADER => {
+                    if let Ok(opened) = opened {
+                        assert!(opened.len() <= datagram.len(), "{}", i);
+                    }
+                }
+                // data is flipped right where it was tampered with, for wireguard to drop
+                byte => {
+                    let mut expected = datagram;
+                    expected[byte - SALT - CHECK - RECORD_HEADER] ^= 1 << (i % 8);
+                    assert_eq!(opened.unwrap(), expected, "{}", i);
+                }
+            }
+        }
+    }
+}
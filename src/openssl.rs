@@ -1,50 +1,40 @@
 
-use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode, SslAcceptor, SslFiletype, HandshakeError};
-use std::sync::Arc;
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode, SslAcceptor, SslFiletype, HandshakeError, NameType};
+use openssl::x509::X509Ref;
+use std::sync::{Arc, Mutex};
 use std::cell::UnsafeCell;
-use std::net::TcpStream;
 use std::io::{Read, Write};
 
-use super::super::TryClone;
+use super::super::{BoxStream, Stream};
 
 use crate::error::*;
 
-impl TryClone<TlsStream> for TlsStream {
-    fn try_clone(&self) -> Result<TlsStream> {
-        Ok(self.clone())
-    }
-}
-
 pub struct TlsStream {
-    sess: Arc<UnsafeCell<SslStream<TcpStream>>>,
+    sess: Arc<UnsafeCell<SslStream<BoxStream>>>,
 }
 
 impl TlsStream {
-    fn new(stream: SslStream<TcpStream>) -> TlsStream {
+    // clones share the one session so a thread can read while another writes, see borrow_mut
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn new(stream: SslStream<BoxStream>) -> TlsStream {
         TlsStream {
             sess: Arc::new(UnsafeCell::new(stream))
         }
     }
-    pub fn client(hostname: Option<&str>, pinnedpubkey: Option<&str>, tcp_stream: TcpStream) -> Result<TlsStream> {
+    pub fn client(hostname: Option<&str>, pinnedpubkey: Option<&str>, tcp_stream: BoxStream) -> Result<TlsStream> {
         let mut connector = SslConnector::builder(SslMethod::tls())?.build().configure()?;
         connector.set_use_server_name_indication(hostname.is_some());
         connector.set_verify_hostname(false);
         connector.set_verify(SslVerifyMode::NONE);
-        if pinnedpubkey.is_some() {
-            let pinnedpubkey = pinnedpubkey.unwrap().to_owned();
+        // the verify callback can only say no, this is how we tell a pin mismatch from any other handshake failure
+        let mismatch = Arc::new(Mutex::new(None));
+        if let Some(pinnedpubkey) = pinnedpubkey {
+            let pinnedpubkey = pinnedpubkey.to_owned();
+            let mismatch = mismatch.clone();
             connector.set_verify_callback(SslVerifyMode::PEER, move|_preverify_ok, x509_store_ctx| {
                 //println!("preverify_ok: {}", preverify_ok);
                 let cert = x509_store_ctx.current_cert().expect("could not get TLS cert");
-                let pubkey = cert.public_key().expect("could not get public key from TLS cert");
-                let pubkey = pubkey.public_key_to_der().expect("could not get TLS public key bytes");
-                //println!("spki.len(): {}", pubkey.len());
-                //println!("spki: {:?}", pubkey);
-
-                let mut sha256 = openssl::sha::Sha256::new();
-                sha256.update(&pubkey);
-                let pubkey = sha256.finish();
-
-                let pubkey = ["sha256//", &openssl::base64::encode_block(&pubkey)].join("");
+                let pubkey = pubkey_pin(cert).expect("could not get public key from TLS cert");
                 println!("pubkey from cert: {}", pubkey);
 
                 for key in pinnedpubkey.split(";") {
@@ -54,10 +44,16 @@ impl TlsStream {
                     }
                 }
                 println!("ERROR: pubkey match not found!");
+                *mismatch.lock().unwrap() = Some(pubkey);
                 false
             });
         }
-        let tcp_stream = connector.connect(hostname.unwrap_or(""), tcp_stream)?;
+        let tcp_stream = connector.connect(hostname.unwrap_or(""), tcp_stream).map_err(|e| {
+            match mismatch.lock().unwrap().take() {
+                Some(pubkey) => Error::PinMismatch(pubkey),
+                None => e.into(),
+            }
+        })?;
         Ok(TlsStream::new(tcp_stream))
     }
 }
@@ -74,11 +70,47 @@ impl Clone for TlsStream {
 }
 
 impl TlsStream {
-    pub fn borrow_mut(&self) -> &mut SslStream<TcpStream> {
+    // the tunnel only ever has one clone reading and one writing, which openssl copes with as long as nothing
+    // renegotiates, so handing out the session mutably from a shared clone is how both get at it
+    #[allow(clippy::mut_from_ref)]
+    pub fn borrow_mut(&self) -> &mut SslStream<BoxStream> {
         unsafe {
             &mut *self.sess.get()
         }
     }
+
+    /// the server name the client sent, on the server side
+    pub fn server_name(&self) -> Option<String> {
+        self.borrow_mut().ssl().servername(NameType::HOST_NAME).map(str::to_owned)
+    }
+
+    /// the pin of the server's key, on the client side
+    pub fn peer_pubkey(&self) -> Option<String> {
+        self.borrow_mut().ssl().peer_certificate().and_then(|cert| pubkey_pin(&cert).ok())
+    }
+}
+
+// "sha256//" and the base64 sha256 of cert's public key, the same as curl's --pinnedpubkey
+fn pubkey_pin(cert: &X509Ref) -> Result<String> {
+    let pubkey = cert.public_key()?.public_key_to_der()?;
+    //println!("spki.len(): {}", pubkey.len());
+    //println!("spki: {:?}", pubkey);
+
+    let mut sha256 = openssl::sha::Sha256::new();
+    sha256.update(&pubkey);
+    let pubkey = sha256.finish();
+
+    Ok(["sha256//", &openssl::base64::encode_block(&pubkey)].join(""))
+}
+
+impl Stream for TlsStream {
+    fn try_clone(&self) -> Result<BoxStream> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        self.borrow_mut().get_ref().shutdown()
+    }
 }
 
 impl Read for TlsStream {
@@ -136,19 +168,27 @@ impl TlsListener {
             acceptor
         })
     }
-    pub fn wrap(&self, tcp_stream: TcpStream) -> Result<TlsStream> {
+    /// the pin clients would use for the key this listens with
+    pub fn pinnedpubkey(&self) -> Option<String> {
+        self.acceptor.context().certificate().and_then(|cert| pubkey_pin(cert).ok())
+    }
+
+    pub fn wrap(&self, tcp_stream: BoxStream) -> Result<TlsStream> {
         Ok(TlsStream::new(self.acceptor.accept(tcp_stream)?))
     }
 }
 
 impl From<openssl::error::ErrorStack> for Error {
     fn from(value: openssl::error::ErrorStack) -> Self {
-        Error::new_owned(format!("{}", value))
+        Error::Tls(Box::new(value))
     }
 }
 
-impl From<HandshakeError<std::net::TcpStream>> for Error {
-    fn from(value: HandshakeError<std::net::TcpStream>) -> Self {
-        Error::new(&format!("{}", value))
+impl From<HandshakeError<BoxStream>> for Error {
+    fn from(value: HandshakeError<BoxStream>) -> Self {
+        match value {
+            HandshakeError::SetupFailure(e) => e.into(),
+            HandshakeError::Failure(mid) | HandshakeError::WouldBlock(mid) => Error::Tls(Box::new(mid.into_error())),
+        }
     }
 }
@@ -0,0 +1,187 @@
+// padding hides how much each write to the stream holds, which TLS gives away record by record: a handshake, a
+// keepalive and a data packet each go out as one write of their own telling size. padding is PADDING control
+// frames of zeros after what's written, which the other end reads past like any control frame it has no use for,
+// so only the end sending them is configured
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::control::PADDING;
+use crate::error::{Error, Result};
+use crate::frame::{write_header, CONTROL_HEADER, HEADER};
+use crate::random::Rng;
+
+// the smallest padding frame, one with no payload
+const FRAME: usize = HEADER + CONTROL_HEADER;
+
+/// how to pad, bucket and random can be combined, an end with none of them set adds nothing
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Padding {
+    /// round every write up to a multiple of this many bytes, 0 doesn't
+    pub bucket: usize,
+    /// add up to this many random bytes to every write, on top of bucket, 0 doesn't
+    pub random: usize,
+    /// whenever nothing was written for this long write padding alone, so there is traffic at a constant rate
+    /// whether the tunnel is busy or idle, None never does
+    pub cover: Option<Duration>,
+}
+
+impl Padding {
+    pub fn validate(&self) -> Result<()> {
+        if self.cover == Some(Duration::from_secs(0)) {
+            return Err(Error::config("padding cover must be more than 0, None sends none"));
+        }
+        Ok(())
+    }
+}
+
+/// comma separated bucket:<bytes>, random:<bytes> and cover:<milliseconds>, like bucket:512,cover:1000
+impl FromStr for Padding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Padding> {
+        let mut padding = Padding::default();
+        for policy in s.split(',') {
+            let invalid = || Error::Config(format!("invalid padding {}, must be bucket:<bytes>, random:<bytes> or cover:<milliseconds>", policy));
+            let (name, value) = policy.trim().split_once(':').ok_or_else(invalid)?;
+            let value: usize = value.parse().map_err(|_| invalid())?;
+            match name {
+                "bucket" => padding.bucket = value,
+                "random" => padding.random = value,
+                "cover" if value > 0 => padding.cover = Some(Duration::from_millis(value as u64)),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(padding)
+    }
+}
+
+/// adds padding frames to each write of one stream
+pub(crate) struct Padder {
+    padding: Padding,
+    rng: Rng,
+    // the most one padding frame carries, the other end's max datagram which has to match ours
+    max_payload: usize,
+    out: Vec<u8>,
+}
+
+impl Padder {
+    pub fn new(padding: &Padding, max_datagram: usize) -> Result<Padder> {
+        Ok(Padder {
+            padding: padding.clone(),
+            rng: Rng::new()?,
+            max_payload: max_datagram.max(FRAME),
+            out: Vec::new(),
+        })
+    }
+
+    pub fn cover(&self) -> Option<Duration> {
+        self.padding.cover
+    }
+
+    /// buf followed by its padding, for padded() to hand out until the next call
+    pub fn pad(&mut self, buf: &[u8]) {
+        self.out.clear();
+        self.out.extend_from_slice(buf);
+        let mut len = match self.padding.bucket {
+            0 => 0,
+            bucket => (bucket - buf.len() % bucket) % bucket,
+        };
+        len += self.rng.up_to(self.padding.random);
+        // too little to fit a frame in, so round up to the next bucket or the smallest frame
+        if len > 0 && len < FRAME {
+            len += self.padding.bucket.max(FRAME);
+        }
+        self.push(len);
+    }
+
+    /// padding alone, a bucket of it or the smallest frame, and any random on top
+    pub fn pad_cover(&mut self) {
+        self.out.clear();
+        let len = self.padding.bucket.max(FRAME) + self.rng.up_to(self.padding.random);
+        self.push(len);
+    }
+
+    pub fn padded(&self) -> &[u8] {
+        &self.out
+    }
+
+    // len bytes of padding frames, each no bigger than the other end takes and none too small to be one
+    fn push(&mut self, mut len: usize) {
+        while len > 0 {
+            let frame = match len > FRAME + self.max_payload {
+                true => (FRAME + self.max_payload).min(len - FRAME),
+                false => len,
+            };
+            let start = self.out.len();
+            self.out.resize(start + frame, 0);
+            self.out[start + HEADER] = PADDING;
+            write_header(&mut self.out[start + HEADER + 1..], frame - FRAME);
+            len -= frame;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{FrameReader, DEFAULT_MAX_DATAGRAM};
+
+    // the datagrams in what was padded, checking everything else is padding
+    fn datagrams(padded: &[u8], max_datagram: usize) -> Vec<Vec<u8>> {
+        let mut reader = FrameReader::new(max_datagram);
+        reader.fill(&mut &padded[..]).unwrap();
+        let mut got = Vec::new();
+        loop {
+            let frames: Vec<Vec<u8>> = reader.frames(32).unwrap().iter().map(|frame| frame.to_vec()).collect();
+            let none = frames.is_empty();
+            got.extend(frames);
+            match reader.control().unwrap() {
+                Some((kind, payload)) => assert!(kind == PADDING && payload.iter().all(|b| *b == 0)),
+                None if none => return got,
+                None => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_padding() {
+        assert_eq!("bucket:512, cover:250".parse::<Padding>().unwrap(), Padding { bucket: 512, random: 0, cover: Some(Duration::from_millis(250)) });
+        assert_eq!("random:64".parse::<Padding>().unwrap().random, 64);
+        for bad in ["bucket", "bucket:big", "size:5", "cover:0"].iter() {
+            assert!(bad.parse::<Padding>().is_err(), "{}", bad);
+        }
+
+        // a 32 byte keepalive and a 148 byte handshake, framed, end up the same size
+        let mut padder = Padder::new(&"bucket:256".parse().unwrap(), DEFAULT_MAX_DATAGRAM).unwrap();
+        let keepalive = [&[0u8, 32][..], &[4u8; 32]].concat();
+        let handshake = [&[0u8, 148][..], &[1u8; 148]].concat();
+        padder.pad(&keepalive);
+        assert_eq!(padder.padded().len(), 256);
+        assert_eq!(datagrams(padder.padded(), DEFAULT_MAX_DATAGRAM), vec![vec![4u8; 32]]);
+        padder.pad(&handshake);
+        assert_eq!(padder.padded().len(), 256);
+        // already a multiple needs nothing, 1 short of one needs a whole bucket more to fit a frame
+        padder.pad(&[1u8; 256]);
+        assert_eq!(padder.padded().len(), 256);
+        padder.pad(&[1u8; 255]);
+        assert_eq!(padder.padded().len(), 512);
+        padder.pad_cover();
+        assert_eq!(padder.padded().len(), 256);
+        assert!(datagrams(padder.padded(), DEFAULT_MAX_DATAGRAM).is_empty());
+
+        // more padding than fits in one frame is split across several the other end still takes
+        let mut padder = Padder::new(&"bucket:5000".parse().unwrap(), 100).unwrap();
+        padder.pad(&keepalive);
+        assert_eq!(padder.padded().len(), 5000);
+        assert_eq!(datagrams(padder.padded(), 100), vec![vec![4u8; 32]]);
+
+        let mut padder = Padder::new(&"random:64".parse().unwrap(), DEFAULT_MAX_DATAGRAM).unwrap();
+        let sizes: std::collections::HashSet<_> = (0..50).map(|_| {
+            padder.pad(&keepalive);
+            assert_eq!(datagrams(padder.padded(), DEFAULT_MAX_DATAGRAM), vec![vec![4u8; 32]]);
+            padder.padded().len()
+        }).collect();
+        assert!(sizes.len() > 1 && sizes.iter().all(|len| *len <= keepalive.len() + 64 + FRAME));
+    }
+}
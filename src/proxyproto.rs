@@ -0,0 +1,233 @@
+// the PROXY protocol header HAProxy and load balancers put at the start of a connection to say who the client really
+// is, v1 is a line of text and v2 binary, see https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt
+// a header is only looked for at the very start, before TLS, and whatever follows it carries on as the stream
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// the longest a v1 line can be, \r\n included
+const V1_MAX: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// signature, version and command, family and protocol, then the length of the rest
+const V2_FIXED: usize = 16;
+// read at a time while looking for the end of a header
+const CHUNK: usize = 512;
+/// how long the header may take without a socket_timeout, a load balancer sends it straight away
+pub(crate) const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// whether the server requires a PROXY protocol header on every connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyProtocol {
+    /// connections without one are taken as they are, from the address they came from
+    Optional,
+    /// connections without one are closed
+    Strict,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ProxyProtocol> {
+        match s {
+            "optional" => Ok(ProxyProtocol::Optional),
+            "strict" => Ok(ProxyProtocol::Strict),
+            _ => Err(Error::Config(format!("invalid proxy protocol mode {}, must be optional or strict", s))),
+        }
+    }
+}
+
+/// what's been read from the start of a connection so far
+#[derive(Debug, PartialEq)]
+pub(crate) enum Parsed {
+    /// could still be a header, read more
+    Incomplete,
+    /// doesn't start with a header
+    Absent,
+    /// a header len bytes long, naming the client unless the load balancer connected on its own behalf, like a
+    /// health check, or over something other than tcp
+    Header { len: usize, source: Option<SocketAddr> },
+}
+
+/// collects the start of a connection until it's known whether there is a header and how long it is
+pub(crate) struct HeaderReader {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl HeaderReader {
+    pub fn new() -> HeaderReader {
+        HeaderReader {
+            buf: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// where to read into next, then say how much was with filled()
+    pub fn read_mut(&mut self) -> &mut [u8] {
+        self.buf.resize(self.len + CHUNK, 0);
+        &mut self.buf[self.len..]
+    }
+
+    pub fn filled(&mut self, len: usize) {
+        self.len += len;
+    }
+
+    pub fn parse(&self) -> Result<Parsed> {
+        parse(&self.buf[..self.len])
+    }
+
+    /// whatever was read past the first len bytes, to carry on the stream with
+    pub fn rest(mut self, len: usize) -> Vec<u8> {
+        self.buf.truncate(self.len);
+        self.buf.drain(..len);
+        self.buf
+    }
+}
+
+fn parse(buf: &[u8]) -> Result<Parsed> {
+    if buf[..buf.len().min(V2_SIGNATURE.len())] == V2_SIGNATURE[..buf.len().min(V2_SIGNATURE.len())] {
+        return parse_v2(buf);
+    }
+    if buf[..buf.len().min(V1_PREFIX.len())] == V1_PREFIX[..buf.len().min(V1_PREFIX.len())] {
+        return parse_v1(buf);
+    }
+    Ok(Parsed::Absent)
+}
+
+// "PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\n", or "PROXY UNKNOWN" and anything up to \r\n
+fn parse_v1(buf: &[u8]) -> Result<Parsed> {
+    let end = match buf.windows(2).position(|crlf| crlf == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() >= V1_MAX => return Err(Error::protocol("PROXY protocol v1 header too long")),
+        None => return Ok(Parsed::Incomplete),
+    };
+    let invalid = || Error::protocol(format!("invalid PROXY protocol v1 header {:?}", String::from_utf8_lossy(&buf[..end])));
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| invalid())?;
+    let words: Vec<&str> = line.split(' ').collect();
+    let source = match words.get(1) {
+        Some(&"UNKNOWN") => None,
+        Some(&family) if (family == "TCP4" || family == "TCP6") && words.len() == 6 => {
+            let ip: IpAddr = words[2].parse().map_err(|_| invalid())?;
+            let port: u16 = words[4].parse().map_err(|_| invalid())?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid());
+            }
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(Parsed::Header { len: end + 2, source })
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Parsed> {
+    if buf.len() < V2_FIXED {
+        return Ok(Parsed::Incomplete);
+    }
+    if buf[12] >> 4 != 2 {
+        return Err(Error::protocol(format!("unsupported PROXY protocol version {}", buf[12] >> 4)));
+    }
+    let len = V2_FIXED + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(Parsed::Incomplete);
+    }
+    let addresses = &buf[V2_FIXED..len];
+    let source = match (buf[12] & 0xf, buf[13] >> 4) {
+        // LOCAL, the load balancer itself
+        (0, _) => None,
+        // PROXY over ipv4, source and destination addresses then their ports
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Some(SocketAddr::new(ip.into(), u16::from_be_bytes([addresses[8], addresses[9]])))
+        }
+        // and over ipv6
+        (1, 2) if addresses.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([addresses[32], addresses[33]])))
+        }
+        (1, 1) | (1, 2) => return Err(Error::protocol("PROXY protocol v2 header too short for its addresses")),
+        // unspecified or a unix socket, nothing to go on
+        (1, _) => None,
+        (command, _) => return Err(Error::protocol(format!("unsupported PROXY protocol command {}", command))),
+    };
+    Ok(Parsed::Header { len, source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family << 4 | 1]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn header(len: usize, source: &str) -> Parsed {
+        Parsed::Header { len, source: Some(source.parse().unwrap()) }
+    }
+
+    #[test]
+    fn test_v1() {
+        let line = b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\n";
+        assert_eq!(parse(line).unwrap(), header(line.len(), "203.0.113.7:51234"));
+        let line = b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 443\r\nhello";
+        assert_eq!(parse(line).unwrap(), header(line.len() - 5, "[2001:db8::7]:51234"));
+        assert_eq!(parse(b"PROXY UNKNOWN whatever\r\n").unwrap(), Parsed::Header { len: 24, source: None });
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").unwrap(), Parsed::Header { len: 15, source: None });
+
+        for partial in [&b""[..], b"P", b"PROXY", b"PROXY TCP4 203.0.113.7"].iter() {
+            assert_eq!(parse(partial).unwrap(), Parsed::Incomplete, "{:?}", partial);
+        }
+        for bad in [&b"PROXY TCP4 2001:db8::7 192.0.2.1 51234 443\r\n"[..], b"PROXY TCP4 203.0.113.7 192.0.2.1 51234\r\n",
+                    b"PROXY TCP4 203.0.113.7 192.0.2.1 70000 443\r\n", b"PROXY UDP4 203.0.113.7 192.0.2.1 51234 443\r\n"].iter() {
+            assert!(matches!(parse(bad), Err(Error::Protocol(_))), "{:?}", bad);
+        }
+        let mut long = V1_PREFIX.to_vec();
+        long.resize(V1_MAX, b'x');
+        assert!(matches!(parse(&long), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn test_v2() {
+        let inet = v2(1, 1, &[203, 0, 113, 7, 192, 0, 2, 1, 0xc8, 0x22, 1, 0xbb]);
+        assert_eq!(parse(&inet).unwrap(), header(28, "203.0.113.7:51234"));
+        let mut inet6 = vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7];
+        inet6.extend_from_slice(&[0u8; 16]);
+        inet6.extend_from_slice(&[0xc8, 0x22, 1, 0xbb]);
+        // with a TLV after the addresses
+        inet6.extend_from_slice(&[4, 0, 1, 0]);
+        assert_eq!(parse(&v2(1, 2, &inet6)).unwrap(), header(56, "[2001:db8::7]:51234"));
+        assert_eq!(parse(&v2(0, 0, &[])).unwrap(), Parsed::Header { len: 16, source: None });
+        assert_eq!(parse(&v2(1, 3, &[0u8; 216])).unwrap(), Parsed::Header { len: 232, source: None });
+
+        for len in 0..inet.len() {
+            assert_eq!(parse(&inet[..len]).unwrap(), Parsed::Incomplete, "{}", len);
+        }
+        assert!(matches!(parse(&v2(1, 1, &[203, 0, 113, 7])), Err(Error::Protocol(_))));
+        assert!(matches!(parse(&v2(2, 1, &[])), Err(Error::Protocol(_))));
+        let mut v1 = v2(0, 0, &[]);
+        v1[12] = 0x10;
+        assert!(matches!(parse(&v1), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn test_absent() {
+        // a TLS client hello, a datagram frame and a hello
+        for absent in [&[0x16, 0x03, 0x01, 0x02, 0x00][..], &[0x00, 0x94, 0x01], b"\r\n\r\nGET", b"PROXYX"].iter() {
+            assert_eq!(parse(absent).unwrap(), Parsed::Absent, "{:?}", absent);
+        }
+        let mut reader = HeaderReader::new();
+        let line = b"PROXY UNKNOWN\r\nhello";
+        reader.read_mut()[..line.len()].copy_from_slice(line);
+        reader.filled(line.len());
+        assert_eq!(reader.parse().unwrap(), Parsed::Header { len: 15, source: None });
+        assert_eq!(reader.rest(15), b"hello");
+    }
+}
@@ -0,0 +1,626 @@
+// QUIC carries the tunnel over udp instead of tcp, for networks that let udp to 443 through and nothing else, where
+// it looks like HTTP/3, and so tcp inside wireguard doesn't end up retransmitting over tcp. each tunnel is a QUIC
+// connection of its own holding one bidirectional stream, which carries the same framing, hello and all, as a tcp
+// connection does. quinn-proto does the protocol and a task per udp socket drives it, moving datagrams in and out
+// and bytes between the stream and the QuicStream the tunnel reads and writes. QUIC is always TLS, the client
+// verifies nothing unless a pinnedpubkey is set, like TlsTransport
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::{Bytes, BytesMut};
+use quic_rustls as rustls;
+use quinn_proto::{ApplicationClose, ClientConfig, ConnectionError, ConnectionHandle, DatagramEvent, Dir, Endpoint, EndpointConfig, Event,
+                  ReadError, ServerConfig, StreamEvent, StreamId, TransportConfig, VarInt, WriteError};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::error::{Error, Result};
+use crate::transport::{self, BoxStream, Pins};
+use crate::{TlsClientConfig, TlsInfo, TlsServerConfig};
+
+// what QUIC on 443 usually is
+const ALPN: &[u8] = b"h3";
+// most of the stream either way waiting between the tunnel and its connection
+const BUFFER: usize = 256 * 1024;
+// udp datagrams read before looking at the connections again
+const RECV_BATCH: usize = 64;
+// keeps a quiet tunnel from hitting the idle timeout or losing its NAT mapping
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+/// connects to addr, host:port, and opens the tunnel's stream, with the hostname and pinnedpubkey of tls if any
+pub(crate) async fn connect(addr: &str, tls: Option<&TlsClientConfig>) -> Result<(BoxStream, Option<TlsInfo>)> {
+    let remote = tokio::net::lookup_host(addr).await?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "resolved to no addresses"))?;
+    let local: SocketAddr = match remote {
+        SocketAddr::V4(_) => ([0u8; 4], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+
+    let pins = tls.and_then(|tls| tls.pinnedpubkey.as_deref()).map(Pins::new);
+    let verifier = Arc::new(PinnedpubkeyCertVerifier(pins.clone()));
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| Error::Tls(Box::new(e)))?
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    // like TlsTransport, no hostname or an ip address sends no SNI, rustls still wants a name to check against
+    let sni = tls.and_then(|tls| tls.hostname.clone())
+        .filter(|hostname| matches!(rustls::ServerName::try_from(hostname.as_str()), Ok(rustls::ServerName::DnsName(_))));
+    crypto.enable_sni = sni.is_some();
+    let mut config = ClientConfig::new(Arc::new(crypto));
+    config.transport = Arc::new(transport_config(0));
+
+    let mut endpoint = Endpoint::new(Arc::new(EndpointConfig::default()), None);
+    let (handle, conn) = endpoint.connect(config, remote, sni.as_deref().unwrap_or("dummy.hostname"))
+        .map_err(|e| Error::Config(format!("cannot connect to {} over QUIC: {}", addr, e)))?;
+    let pipe = Arc::new(Mutex::new(Pipe::default()));
+    let (connected_tx, connected_rx) = oneshot::channel();
+    let mut driver = Driver::new(socket, endpoint, None);
+    driver.add(handle, Connection::new(conn, pipe.clone(), Some(connected_tx)));
+    tokio::spawn(driver);
+
+    let pinnedpubkey = match connected_rx.await {
+        Ok(Ok(pinnedpubkey)) => pinnedpubkey,
+        Ok(Err(e)) => return Err(pins.as_ref().and_then(Pins::mismatch).unwrap_or(e)),
+        Err(_) => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "QUIC connection closed").into()),
+    };
+    Ok((Box::new(QuicStream { pipe }), Some(TlsInfo { sni, pinnedpubkey })))
+}
+
+/// the server's udp socket, each tunnel's stream as its client opens it
+pub(crate) struct Listener {
+    accepted: mpsc::Receiver<Accepted>,
+    local_addr: SocketAddr,
+}
+
+// a tunnel's stream, who it came from and what its TLS looked like
+type Accepted = (QuicStream, SocketAddr, TlsInfo);
+
+impl Listener {
+    /// binds addr, ip:port, with the key and cert of tls
+    pub fn bind(addr: &str, tls: &TlsServerConfig) -> Result<Listener> {
+        let (tls_key, tls_cert) = transport::key_and_cert(tls)?;
+        let pinnedpubkey = tls_cert.first().and_then(|cert| transport::pubkey_pin(&cert.0).ok());
+        let tls_cert = tls_cert.into_iter().map(|cert| rustls::Certificate(cert.0)).collect();
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(|e| Error::Tls(Box::new(e)))?
+            .with_no_client_auth()
+            .with_single_cert(tls_cert, rustls::PrivateKey(tls_key.0))
+            .map_err(|err| Error::Config(format!("invalid key or cert: {}", err)))?;
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+        config.transport = Arc::new(transport_config(1));
+
+        let socket = UdpSocket::from_std(std::net::UdpSocket::bind(addr)?)?;
+        let local_addr = socket.local_addr()?;
+        let (accepted_tx, accepted) = mpsc::channel(RECV_BATCH);
+        let endpoint = Endpoint::new(Arc::new(EndpointConfig::default()), Some(Arc::new(config)));
+        tokio::spawn(Driver::new(socket, endpoint, Some((accepted_tx, pinnedpubkey))));
+        Ok(Listener { accepted, local_addr })
+    }
+
+    pub async fn accept(&mut self) -> Result<Accepted> {
+        Ok(self.accepted.recv().await.ok_or_else(|| io::Error::other("QUIC listener stopped"))?)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+// the same both ways apart from how many streams the other end may open, none to the client and one to the server
+fn transport_config(streams: u32) -> TransportConfig {
+    let mut config = TransportConfig::default();
+    config.max_concurrent_bidi_streams(streams.into())
+        .max_concurrent_uni_streams(0u32.into())
+        .keep_alive_interval(Some(KEEP_ALIVE));
+    config
+}
+
+// the same check TlsTransport does, on this rustls version's types
+struct PinnedpubkeyCertVerifier(Option<Pins>);
+
+impl rustls::client::ServerCertVerifier for PinnedpubkeyCertVerifier {
+    fn verify_server_cert(&self,
+                          end_entity: &rustls::Certificate,
+                          _intermediates: &[rustls::Certificate],
+                          _server_name: &rustls::ServerName,
+                          _scts: &mut dyn Iterator<Item = &[u8]>,
+                          _ocsp: &[u8],
+                          _now: SystemTime) -> core::result::Result<rustls::client::ServerCertVerified, rustls::Error> {
+        // without pins verify nothing, subject to MITM
+        if let Some(pins) = &self.0 {
+            pins.check(&end_entity.0).map_err(rustls::Error::General)?;
+        }
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+// one tunnel's stream, between the driver and the QuicStream
+#[derive(Default)]
+struct Pipe {
+    // read from the stream, not yet by the tunnel
+    incoming: Vec<u8>,
+    // written by the tunnel, not yet to the stream
+    outgoing: Vec<u8>,
+    // the other end finished its side of the stream
+    finished: bool,
+    // why the connection is gone, the tunnel gets it as an error from then on
+    closed: Option<String>,
+    // the tunnel is gone, so is the connection
+    dropped: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+    driver: Option<Poke>,
+}
+
+impl Pipe {
+    // the driver has something to do for this connection
+    fn poke(&self) {
+        if let Some(driver) = &self.driver {
+            driver.poke();
+        }
+    }
+
+    fn close(&mut self, why: String) {
+        self.closed.get_or_insert(why);
+        wake(&mut self.reader);
+        wake(&mut self.writer);
+    }
+
+    fn closed(&self) -> Option<io::Error> {
+        self.closed.as_ref().map(|why| io::Error::new(io::ErrorKind::ConnectionAborted, why.clone()))
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+/// a tunnel's stream over QUIC
+pub(crate) struct QuicStream {
+    pipe: Arc<Mutex<Pipe>>,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.pipe.lock().unwrap();
+        if !pipe.incoming.is_empty() {
+            let len = buf.len().min(pipe.incoming.len());
+            buf[..len].copy_from_slice(&pipe.incoming[..len]);
+            pipe.incoming.drain(..len);
+            // room to read more from the stream
+            pipe.poke();
+            return Poll::Ready(Ok(len));
+        }
+        if pipe.finished {
+            return Poll::Ready(Ok(0));
+        }
+        if let Some(e) = pipe.closed() {
+            return Poll::Ready(Err(e));
+        }
+        pipe.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.pipe.lock().unwrap();
+        if let Some(e) = pipe.closed() {
+            return Poll::Ready(Err(e));
+        }
+        let len = buf.len().min(BUFFER - pipe.outgoing.len());
+        if len == 0 && !buf.is_empty() {
+            pipe.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        pipe.outgoing.extend_from_slice(&buf[..len]);
+        pipe.poke();
+        Poll::Ready(Ok(len))
+    }
+
+    // the driver sends what's written as soon as the connection lets it
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for QuicStream {
+    fn drop(&mut self) {
+        let mut pipe = self.pipe.lock().unwrap();
+        pipe.dropped = true;
+        pipe.poke();
+    }
+}
+
+// a QUIC connection and the tunnel it carries
+struct Connection {
+    conn: quinn_proto::Connection,
+    pipe: Arc<Mutex<Pipe>>,
+    // the tunnel's stream, once the client opened it
+    stream: Option<StreamId>,
+    // the client's connect() waits here for the handshake, getting the server's pinnedpubkey
+    connected: Option<oneshot::Sender<Result<Option<String>>>>,
+    // the earliest of its timeouts waiting in the driver's timers
+    scheduled: Option<Instant>,
+}
+
+impl Connection {
+    fn new(conn: quinn_proto::Connection, pipe: Arc<Mutex<Pipe>>, connected: Option<oneshot::Sender<Result<Option<String>>>>) -> Connection {
+        Connection { conn, pipe, stream: None, connected, scheduled: None }
+    }
+
+    // moves what it can between the stream and the pipe
+    fn shuffle(&mut self, now: Instant) {
+        let mut pipe = self.pipe.lock().unwrap();
+        if pipe.dropped && !self.conn.is_closed() {
+            self.conn.close(now, VarInt::from_u32(0), Bytes::new());
+        }
+        let id = match self.stream {
+            Some(id) if pipe.closed.is_none() => id,
+            _ => return,
+        };
+        if !pipe.outgoing.is_empty() {
+            match self.conn.send_stream(id).write(&pipe.outgoing) {
+                Ok(len) => {
+                    pipe.outgoing.drain(..len);
+                    wake(&mut pipe.writer);
+                }
+                // the connection lets us know when there's room again
+                Err(WriteError::Blocked) => {}
+                Err(e) => pipe.close(format!("QUIC stream closed: {}", e)),
+            }
+        }
+        if pipe.incoming.len() >= BUFFER || pipe.finished {
+            return;
+        }
+        let mut stream = self.conn.recv_stream(id);
+        let mut chunks = match stream.read(true) {
+            Ok(chunks) => chunks,
+            Err(_) => return,
+        };
+        let read = pipe.incoming.len();
+        while pipe.incoming.len() < BUFFER {
+            match chunks.next(BUFFER - pipe.incoming.len()) {
+                Ok(Some(chunk)) => pipe.incoming.extend_from_slice(&chunk.bytes),
+                Ok(None) => {
+                    pipe.finished = true;
+                    break;
+                }
+                Err(ReadError::Blocked) => break,
+                Err(ReadError::Reset(code)) => {
+                    pipe.close(format!("QUIC stream reset by the other end with code {}", code));
+                    break;
+                }
+            }
+        }
+        // any flow control credit this gives the other end goes out with the next transmit
+        let _ = chunks.finalize();
+        if pipe.incoming.len() > read || pipe.finished {
+            wake(&mut pipe.reader);
+        }
+    }
+
+    // the tunnel's stream is the first one the client opens, the server hands it to accepted
+    fn event(&mut self, event: Event, accepted: &mut Option<(mpsc::Sender<Accepted>, Option<String>)>) {
+        match event {
+            Event::Connected => {
+                if let Some(connected) = self.connected.take() {
+                    self.stream = self.conn.streams().open(Dir::Bi);
+                    let pinnedpubkey = self.conn.crypto_session().peer_identity()
+                        .and_then(|certs| certs.downcast::<Vec<rustls::Certificate>>().ok())
+                        .and_then(|certs| certs.first().and_then(|cert| transport::pubkey_pin(&cert.0).ok()));
+                    connected.send(Ok(pinnedpubkey)).ok();
+                }
+            }
+            Event::Stream(StreamEvent::Opened { dir: Dir::Bi }) if self.stream.is_none() => {
+                let (accepted, pinnedpubkey) = match accepted {
+                    Some(accepted) => accepted,
+                    None => return,
+                };
+                self.stream = self.conn.streams().accept(Dir::Bi);
+                let sni = self.conn.crypto_session().handshake_data()
+                    .and_then(|data| data.downcast::<quinn_proto::crypto::rustls::HandshakeData>().ok())
+                    .and_then(|data| data.server_name);
+                let stream = QuicStream { pipe: self.pipe.clone() };
+                let tls = TlsInfo { sni, pinnedpubkey: pinnedpubkey.clone() };
+                // the listener not keeping up drops the stream and with it the connection
+                accepted.try_send((stream, self.conn.remote_address(), tls)).ok();
+            }
+            Event::ConnectionLost { reason } => {
+                let mut pipe = self.pipe.lock().unwrap();
+                // the other end closing without an error is the end of the stream, like tcp
+                if let ConnectionError::ApplicationClosed(ApplicationClose { error_code, .. }) = &reason {
+                    pipe.finished |= *error_code == VarInt::from_u32(0);
+                }
+                pipe.close(format!("QUIC connection lost: {}", reason));
+                drop(pipe);
+                if let Some(connected) = self.connected.take() {
+                    connected.send(Err(lost(reason))).ok();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn lost(reason: ConnectionError) -> Error {
+    match reason {
+        ConnectionError::TimedOut => Error::Timeout(io::Error::new(io::ErrorKind::TimedOut, "QUIC handshake timed out")),
+        reason => Error::Tls(Box::new(reason)),
+    }
+}
+
+// wakes the driver for one of its connections, so it only drives the ones with something to do
+#[derive(Clone)]
+struct Poke {
+    handle: ConnectionHandle,
+    woken: Arc<Mutex<Woken>>,
+}
+
+impl Poke {
+    fn poke(&self) {
+        let mut woken = self.woken.lock().unwrap();
+        woken.handles.insert(self.handle);
+        wake(&mut woken.waker);
+    }
+}
+
+#[derive(Default)]
+struct Woken {
+    handles: HashSet<ConnectionHandle>,
+    waker: Option<Waker>,
+}
+
+// everything one udp socket carries, the client's one connection or every client of the server, until the client's
+// connection is closed or forever on the server
+struct Driver {
+    socket: UdpSocket,
+    endpoint: Endpoint,
+    connections: HashMap<ConnectionHandle, Connection>,
+    // connections to drive next, from datagrams for them, their tunnels or their timers
+    due: HashSet<ConnectionHandle>,
+    woken: Arc<Mutex<Woken>>,
+    // when each connection next times out, an entry that isn't its scheduled one is stale
+    timers: BinaryHeap<Reverse<(Instant, ConnectionHandle)>>,
+    timer: tokio::time::Delay,
+    buf: Vec<u8>,
+    // where the server hands each tunnel's stream and the pinnedpubkey of its cert, None on the client
+    accepted: Option<(mpsc::Sender<Accepted>, Option<String>)>,
+}
+
+impl Driver {
+    fn new(socket: UdpSocket, endpoint: Endpoint, accepted: Option<(mpsc::Sender<Accepted>, Option<String>)>) -> Driver {
+        Driver {
+            socket,
+            endpoint,
+            connections: HashMap::new(),
+            due: HashSet::new(),
+            woken: Arc::new(Mutex::new(Woken::default())),
+            timers: BinaryHeap::new(),
+            timer: tokio::time::delay_for(Duration::from_secs(0)),
+            buf: vec![0u8; u16::MAX as usize],
+            accepted,
+        }
+    }
+
+    fn add(&mut self, handle: ConnectionHandle, connection: Connection) {
+        connection.pipe.lock().unwrap().driver = Some(Poke { handle, woken: self.woken.clone() });
+        self.connections.insert(handle, connection);
+        self.due.insert(handle);
+    }
+
+    // whatever udp datagrams are waiting, the connections they're for are due
+    fn recv(&mut self, cx: &mut Context<'_>, now: Instant) {
+        for _ in 0..RECV_BATCH {
+            let (len, remote) = match self.socket.poll_recv_from(cx, &mut self.buf) {
+                Poll::Ready(Ok(datagram)) => datagram,
+                // like any lost datagram, QUIC sorts it out
+                Poll::Ready(Err(_)) => continue,
+                Poll::Pending => break,
+            };
+            match self.endpoint.handle(now, remote, None, None, BytesMut::from(&self.buf[..len])) {
+                Some((handle, DatagramEvent::ConnectionEvent(event))) => {
+                    if let Some(connection) = self.connections.get_mut(&handle) {
+                        connection.conn.handle_event(event);
+                        self.due.insert(handle);
+                    }
+                }
+                Some((handle, DatagramEvent::NewConnection(conn))) => {
+                    self.add(handle, Connection::new(conn, Arc::new(Mutex::new(Pipe::default())), None));
+                }
+                None => {}
+            }
+        }
+    }
+
+    // connections whose timer fired are due
+    fn fired(&mut self, now: Instant) {
+        while let Some(Reverse((timeout, handle))) = self.timers.peek().copied() {
+            if timeout > now {
+                break;
+            }
+            self.timers.pop();
+            if let Some(connection) = self.connections.get_mut(&handle).filter(|connection| connection.scheduled == Some(timeout)) {
+                connection.scheduled = None;
+                self.due.insert(handle);
+            }
+        }
+    }
+
+    // everything the connection has to do now, true if anything happened that it might have more to do after
+    fn drive(&mut self, handle: ConnectionHandle, now: Instant) -> bool {
+        let connection = match self.connections.get_mut(&handle) {
+            Some(connection) => connection,
+            None => return false,
+        };
+        if connection.conn.poll_timeout().is_some_and(|timeout| timeout <= now) {
+            connection.conn.handle_timeout(now);
+        }
+        connection.shuffle(now);
+
+        let mut busy = false;
+        while let Some(event) = connection.conn.poll() {
+            connection.event(event, &mut self.accepted);
+            busy = true;
+        }
+        while let Some(event) = connection.conn.poll_endpoint_events() {
+            if let Some(event) = self.endpoint.handle_event(handle, event) {
+                connection.conn.handle_event(event);
+            }
+            busy = true;
+        }
+        while let Some(transmit) = connection.conn.poll_transmit(now, 1) {
+            // a full socket buffer is one more lost datagram
+            self.socket.try_send_to(&transmit.contents, transmit.destination).ok();
+        }
+        if connection.conn.is_drained() {
+            let connection = self.connections.remove(&handle).expect("still there");
+            connection.pipe.lock().unwrap().close("QUIC connection closed".to_owned());
+            if let Some(connected) = connection.connected {
+                connected.send(Err(io::Error::new(io::ErrorKind::ConnectionAborted, "QUIC connection closed").into())).ok();
+            }
+            return false;
+        }
+        // a timeout moving later fires early and is put back then, only an earlier one needs another timer
+        if let Some(timeout) = connection.conn.poll_timeout() {
+            if connection.scheduled.is_none_or(|scheduled| timeout < scheduled) {
+                connection.scheduled = Some(timeout);
+                self.timers.push(Reverse((timeout, handle)));
+            }
+        }
+        busy
+    }
+}
+
+impl Future for Driver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+        loop {
+            let now = Instant::now();
+            this.recv(cx, now);
+            this.fired(now);
+            this.due.extend(this.woken.lock().unwrap().handles.drain());
+            for handle in std::mem::take(&mut this.due) {
+                if this.drive(handle, now) {
+                    this.due.insert(handle);
+                }
+            }
+            while let Some(transmit) = this.endpoint.poll_transmit() {
+                this.socket.try_send_to(&transmit.contents, transmit.destination).ok();
+            }
+            if this.accepted.is_none() && this.connections.is_empty() {
+                return Poll::Ready(());
+            }
+            if !this.due.is_empty() {
+                continue;
+            }
+            {
+                // under the lock so a tunnel can't poke in between looking and leaving the waker
+                let mut woken = this.woken.lock().unwrap();
+                if !woken.handles.is_empty() {
+                    continue;
+                }
+                woken.waker = Some(cx.waker().clone());
+            }
+            // sleep until the first timer unless something else wakes us first
+            match this.timers.peek() {
+                Some(Reverse((timeout, _))) => {
+                    this.timer.reset(tokio::time::Instant::from_std(*timeout));
+                    if Pin::new(&mut this.timer).poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                }
+                None => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint::ChannelEndpoint;
+    use crate::frame::DEFAULT_MAX_DATAGRAM;
+    use crate::net::TcpUdpPipe;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const PIN: &str = "sha256//BEyQeSjwwUBLXXNuCILHRWyV1gLmY31CdMHNA4VH4dE=";
+
+    #[test]
+    fn test_quic() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut listener = Listener::bind("127.0.0.1:0", &TlsServerConfig::new("ci/cert.key", "ci/cert.pem")).unwrap();
+            let addr = listener.local_addr().to_string();
+            let tls = TlsClientConfig::new().pinnedpubkey(PIN);
+
+            let wrong = TlsClientConfig::new().pinnedpubkey("sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+            assert!(matches!(connect(&addr, Some(&wrong)).await, Err(Error::PinMismatch(ref pubkey)) if pubkey == PIN));
+
+            // two tunnels on the one server socket, with in memory wireguard on both ends
+            let mut wgs = Vec::new();
+            for _ in 0..2 {
+                let (client, info) = connect(&addr, Some(&tls)).await.unwrap();
+                assert_eq!(info.unwrap().pinnedpubkey.as_deref(), Some(PIN));
+                let (client_side, mut client_wg) = ChannelEndpoint::pair(64);
+                tokio::spawn(TcpUdpPipe::new(client, Box::new(client_side), DEFAULT_MAX_DATAGRAM).shuffle());
+                // the server only hears of the stream once something is sent on it
+                client_wg.tx.send(b"hello".to_vec()).await.unwrap();
+                let (server, _, info) = listener.accept().await.unwrap();
+                assert_eq!(info.pinnedpubkey.as_deref(), Some(PIN));
+                let (server_side, mut server_wg) = ChannelEndpoint::pair(64);
+                tokio::spawn(TcpUdpPipe::new(server, Box::new(server_side), DEFAULT_MAX_DATAGRAM).shuffle());
+                assert_eq!(server_wg.rx.recv().await.unwrap(), b"hello");
+                wgs.push((client_wg, server_wg));
+            }
+            for (i, (client_wg, server_wg)) in wgs.iter_mut().enumerate() {
+                let datagrams: Vec<Vec<u8>> = (1..=100u8).map(|x| vec![x ^ i as u8; x as usize * 10]).collect();
+                for datagram in datagrams.iter() {
+                    server_wg.tx.send(datagram.clone()).await.unwrap();
+                    client_wg.tx.send(datagram.clone()).await.unwrap();
+                }
+                for datagram in datagrams.iter() {
+                    assert_eq!(&client_wg.rx.recv().await.unwrap(), datagram);
+                    assert_eq!(&server_wg.rx.recv().await.unwrap(), datagram);
+                }
+            }
+
+            // the client going away closes the connection, which the server reads as the end of the stream
+            let (mut client, _) = connect(&addr, Some(&tls)).await.unwrap();
+            client.write_all(b"bye").await.unwrap();
+            let (mut server, _, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 3];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"bye");
+            drop(client);
+            let closed = tokio::time::timeout(Duration::from_secs(5), server.read(&mut buf)).await;
+            assert_eq!(closed.unwrap().unwrap(), 0);
+        });
+    }
+}
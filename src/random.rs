@@ -0,0 +1,89 @@
+// random bytes for tunnel ids and padding, from getrandom when the obfs feature brings it in, otherwise from
+// /dev/urandom, which leaves builds without it and without unix nothing to draw on
+
+use crate::error::Result;
+
+/// fills buf from the os
+#[cfg(feature = "obfs")]
+pub(crate) fn fill(buf: &mut [u8]) -> Result<()> {
+    getrandom::getrandom(buf).map_err(|e| std::io::Error::other(e.to_string()))?;
+    Ok(())
+}
+
+/// fills buf from the os
+#[cfg(all(not(feature = "obfs"), unix))]
+pub(crate) fn fill(buf: &mut [u8]) -> Result<()> {
+    use std::io::Read;
+    std::fs::File::open("/dev/urandom")?.read_exact(buf)?;
+    Ok(())
+}
+
+/// fills buf from the os
+#[cfg(all(not(feature = "obfs"), not(unix)))]
+pub(crate) fn fill(_buf: &mut [u8]) -> Result<()> {
+    Err(crate::error::Error::config("compiled without obfs support, which random numbers need on this platform"))
+}
+
+/// random numbers and bytes, seeded once from the os rather than asking it every time
+#[cfg(feature = "obfs")]
+pub(crate) struct Rng(chacha20::ChaCha20Legacy);
+
+#[cfg(feature = "obfs")]
+impl Rng {
+    pub fn new() -> Result<Rng> {
+        use chacha20::cipher::KeyIvInit;
+        let mut seed = [0u8; 32];
+        fill(&mut seed)?;
+        Ok(Rng(chacha20::ChaCha20Legacy::new(&seed.into(), &Default::default())))
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        use chacha20::cipher::StreamCipher;
+        buf.iter_mut().for_each(|b| *b = 0);
+        self.0.apply_keystream(buf);
+    }
+}
+
+// without a cipher to stretch a seed, bytes from the os a pool at a time
+#[cfg(not(feature = "obfs"))]
+const POOL: usize = 256;
+
+/// random numbers and bytes, taken from the os a pool at a time rather than asking it every time
+#[cfg(not(feature = "obfs"))]
+pub(crate) struct Rng {
+    pool: [u8; POOL],
+    used: usize,
+}
+
+#[cfg(not(feature = "obfs"))]
+impl Rng {
+    pub fn new() -> Result<Rng> {
+        let mut pool = [0u8; POOL];
+        fill(&mut pool)?;
+        Ok(Rng { pool, used: 0 })
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for b in buf.iter_mut() {
+            if self.used == POOL {
+                // it gave us the first pool, there's no carrying on without randomness if it stops
+                fill(&mut self.pool).expect("cannot read random bytes from the os");
+                self.used = 0;
+            }
+            *b = self.pool[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+impl Rng {
+    /// anything from 0 up to and including max
+    pub fn up_to(&mut self, max: usize) -> usize {
+        if max == 0 {
+            return 0;
+        }
+        let mut n = [0u8; 4];
+        self.fill(&mut n);
+        u32::from_be_bytes(n) as usize % (max + 1)
+    }
+}
@@ -0,0 +1,167 @@
+// udp_target can be a name whose address changes under a running server, so each tunnel's udp sockets
+// can be moved along to wherever it resolves now instead of sending to the stale address forever
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::Resolver;
+
+/// udp_target's addresses from resolver, or the system resolver without one
+pub fn resolve(resolver: &Option<Resolver>, udp_target: &str) -> io::Result<Vec<SocketAddr>> {
+    match resolver {
+        Some(resolver) => resolver(udp_target),
+        None => Ok(udp_target.to_socket_addrs()?.collect()),
+    }
+}
+
+/// the udp sockets of one tunnel connected to udp_target, looked up again every interval until dropped
+pub struct Upstreams {
+    udp_target: String,
+    resolver: Option<Resolver>,
+    sockets: Mutex<Vec<UdpSocket>>,
+}
+
+impl Upstreams {
+    pub fn watch(udp_target: &str, resolver: Option<Resolver>, interval: Duration) -> Arc<Upstreams> {
+        let upstreams = Arc::new(Upstreams {
+            udp_target: udp_target.to_owned(),
+            resolver,
+            sockets: Mutex::new(Vec::new()),
+        });
+        // only a weak reference, so the tunnel's sockets close with it and the thread ends at its next look
+        let weak = Arc::downgrade(&upstreams);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match weak.upgrade() {
+                Some(upstreams) => upstreams.refresh(),
+                None => break,
+            }
+        });
+        upstreams
+    }
+
+    /// keeps a duplicate of socket to connect elsewhere, which moves the original along with it
+    pub fn add(&self, socket: &UdpSocket) -> io::Result<()> {
+        self.sockets.lock().unwrap().push(socket.try_clone()?);
+        Ok(())
+    }
+
+    /// connects each socket to where udp_target resolves now, if that isn't where it already is
+    pub fn refresh(&self) {
+        match resolve(&self.resolver, &self.udp_target) {
+            Ok(targets) => self.move_to(&targets),
+            Err(e) => println!("looking up udp_target {} again failed, staying put: {}", self.udp_target, e),
+        }
+    }
+
+    fn move_to(&self, targets: &[SocketAddr]) {
+        for socket in self.sockets.lock().unwrap().iter() {
+            let (local, current) = match (socket.local_addr(), socket.peer_addr()) {
+                (Ok(local), Ok(current)) => (local, current),
+                _ => continue,
+            };
+            // any of several addresses will do, a bound socket can't change family
+            if targets.contains(&current) {
+                continue;
+            }
+            match targets.iter().find(|target| target.is_ipv4() == local.is_ipv4()) {
+                Some(target) => {
+                    println!("udp_target {} moved from {} to {}, reconnecting {}", self.udp_target, current, target, local);
+                    if let Err(e) = socket.connect(target) {
+                        println!("reconnecting {} to {} failed, staying put: {}", local, target, e);
+                    }
+                }
+                None => println!("udp_target {} now resolves to {:?}, none reachable from {}, staying put", self.udp_target, targets, local),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProxyServerClientHandler;
+
+    // stands in for DNS, answering with whatever the test last set
+    fn resolver(answer: &Arc<Mutex<io::Result<Vec<SocketAddr>>>>) -> Resolver {
+        let answer = answer.clone();
+        Arc::new(move |_: &str| match &*answer.lock().unwrap() {
+            Ok(addrs) => Ok(addrs.clone()),
+            Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+        })
+    }
+
+    fn recv(socket: &UdpSocket) -> Vec<u8> {
+        let mut buf = [0u8; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn test_refresh() {
+        let old = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let new = UdpSocket::bind("127.0.0.1:0").unwrap();
+        new.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let answer = Arc::new(Mutex::new(Ok(vec![old.local_addr().unwrap()])));
+        let handler = ProxyServerClientHandler {
+            udp_target: "wireguard.example.com:51820".to_owned(),
+            udp_host: "127.0.0.1".to_owned(),
+            udp_low_port: 30000,
+            udp_high_port: 40000,
+            socket_timeout: Some(Duration::from_secs(5)),
+            max_datagram: crate::frame::DEFAULT_MAX_DATAGRAM,
+            on_tunnel_closed: None,
+            endpoint_factory: None,
+            re_resolve: None,
+            resolver: Some(resolver(&answer)),
+            resume: None,
+            bonds: Default::default(),
+        };
+        let udp_socket = handler.udp_bind().unwrap();
+        assert_eq!(udp_socket.peer_addr().unwrap(), old.local_addr().unwrap());
+        let upstreams = Upstreams::watch(&handler.udp_target, handler.resolver.clone(), Duration::from_secs(3600));
+        upstreams.add(&udp_socket).unwrap();
+
+        // a failed lookup, another family or an answer still including the current address all stay put
+        *answer.lock().unwrap() = Err(io::Error::other("SERVFAIL"));
+        upstreams.refresh();
+        *answer.lock().unwrap() = Ok(vec!["[::1]:51820".parse().unwrap()]);
+        upstreams.refresh();
+        *answer.lock().unwrap() = Ok(vec![new.local_addr().unwrap(), old.local_addr().unwrap()]);
+        upstreams.refresh();
+        assert_eq!(udp_socket.peer_addr().unwrap(), old.local_addr().unwrap());
+
+        *answer.lock().unwrap() = Ok(vec!["[::1]:51820".parse().unwrap(), new.local_addr().unwrap()]);
+        upstreams.refresh();
+        assert_eq!(udp_socket.peer_addr().unwrap(), new.local_addr().unwrap());
+        udp_socket.send(b"hello").unwrap();
+        assert_eq!(recv(&new), b"hello");
+        // and replies only come from the new address
+        old.send_to(b"stale", udp_socket.local_addr().unwrap()).unwrap();
+        new.send_to(b"reply", udp_socket.local_addr().unwrap()).unwrap();
+        assert_eq!(recv(&udp_socket), b"reply");
+    }
+
+    #[test]
+    fn test_watch() {
+        let old = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let new = UdpSocket::bind("127.0.0.1:0").unwrap();
+        new.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let answer = Arc::new(Mutex::new(Ok(vec![old.local_addr().unwrap()])));
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp_socket.connect(old.local_addr().unwrap()).unwrap();
+        let upstreams = Upstreams::watch("wireguard.example.com:51820", Some(resolver(&answer)), Duration::from_millis(10));
+        upstreams.add(&udp_socket).unwrap();
+
+        *answer.lock().unwrap() = Ok(vec![new.local_addr().unwrap()]);
+        let mut buf = [0u8; 16];
+        let moved = (0..100).any(|_| {
+            udp_socket.send(b"hello").unwrap();
+            new.recv(&mut buf).is_ok()
+        });
+        assert!(moved);
+    }
+}
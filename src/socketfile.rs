@@ -0,0 +1,132 @@
+// unix socket files the server creates, made with their final permissions from the start and removed again when
+// the server stops, whether by returning or by SIGTERM or SIGINT
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Once;
+
+use crate::error::Result;
+
+// paths the signal handler unlinks, set before it's installed and never freed as it could be using them
+static PATHS: [AtomicPtr<libc::c_char>; 4] = [
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+];
+static HANDLER: Once = Once::new();
+
+/// a socket file removed when dropped, as long as it's still the one that was bound
+pub(crate) struct SocketFile {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+    slot: Option<usize>,
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            PATHS[slot].store(ptr::null_mut(), Ordering::SeqCst);
+        }
+        match std::fs::symlink_metadata(&self.path) {
+            Ok(metadata) if metadata.dev() == self.dev && metadata.ino() == self.ino => {
+                std::fs::remove_file(&self.path).ok();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// binds a unix socket at path, with mode from the moment it appears there when given, so nothing can connect
+/// while it still has whatever the umask allows
+pub(crate) fn bind(path: &str, mode: Option<u32>) -> Result<(UnixListener, SocketFile)> {
+    let listener = match mode {
+        Some(mode) => bind_private(Path::new(path), mode)?,
+        None => UnixListener::bind(path)?,
+    };
+    let metadata = std::fs::symlink_metadata(path)?;
+    let socket_file = SocketFile {
+        path: PathBuf::from(path),
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+        slot: remove_on_signal(path),
+    };
+    Ok((listener, socket_file))
+}
+
+// bound inside a directory only we can get into, given mode, then moved into place in one step
+fn bind_private(path: &Path, mode: u32) -> Result<UnixListener> {
+    let name = path.file_name().ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let private = dir.join(name);
+    let bound = UnixListener::bind(&private).and_then(|listener| {
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&private, path)?;
+        Ok(listener)
+    });
+    std::fs::remove_dir_all(&dir).ok();
+    Ok(bound?)
+}
+
+// registers path with the handler, installing it the first time unless something else already handles the
+// signals, the slot to clear when the file is removed another way
+fn remove_on_signal(path: &str) -> Option<usize> {
+    let path = CString::new(Path::new(path).as_os_str().as_bytes()).ok()?.into_raw();
+    let slot = PATHS.iter().position(|slot| slot.compare_exchange(ptr::null_mut(), path, Ordering::SeqCst, Ordering::SeqCst).is_ok());
+    HANDLER.call_once(|| {
+        for signal in [libc::SIGTERM, libc::SIGINT].iter() {
+            unsafe {
+                let mut current: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(*signal, ptr::null(), &mut current) == 0 && current.sa_sigaction == libc::SIG_DFL {
+                    libc::signal(*signal, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+                }
+            }
+        }
+    });
+    slot
+}
+
+// only async signal safe calls in here, then the signal does what it would have without the handler
+extern "C" fn on_signal(signal: libc::c_int) {
+    for slot in PATHS.iter() {
+        let path = slot.load(Ordering::SeqCst);
+        if !path.is_null() {
+            unsafe { libc::unlink(path); }
+        }
+    }
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_bind() {
+        let path = std::env::temp_dir().join(format!("wgp-socketfile-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let (listener, socket_file) = bind(path, Some(0o600)).unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        // nothing left behind next to it
+        let dir = Path::new(path).with_file_name(format!(".{}.{}", Path::new(path).file_name().unwrap().to_string_lossy(), std::process::id()));
+        assert!(!dir.exists());
+        // still listening after the move
+        UnixStream::connect(path).unwrap();
+        listener.accept().unwrap();
+
+        drop(socket_file);
+        assert!(!Path::new(path).exists());
+    }
+}
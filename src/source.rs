@@ -0,0 +1,213 @@
+// which local addresses the client takes wireguard packets from, and following wireguard to a new source
+// port when it restarts, instead of connecting to the first source and ignoring everything else
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::error::{Error, Result};
+
+/// an address range like 10.0.0.0/8 or fd00::/8, a bare ip is just that address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    ip: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(ip: IpAddr, prefix: u8) -> Result<Cidr> {
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(Error::Config(format!("prefix /{} too long for {}", prefix, ip)));
+        }
+        Ok(Cidr { ip, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.ip, unmap(*ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => masked(u32::from(net) as u128, 32, self.prefix) == masked(u32::from(ip) as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => masked(u128::from(net), 128, self.prefix) == masked(u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+// keeps the top prefix bits of a bits wide address
+fn masked(ip: u128, bits: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        return 0;
+    }
+    ip >> (bits - prefix)
+}
+
+// an ipv4 client on a dual stack socket shows up as ::ffff:a.b.c.d
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => IpAddr::V4(Ipv4Addr::from(((hi as u32) << 16) | lo as u32)),
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let invalid = || Error::Config(format!("invalid cidr {}", s));
+        let mut parts = s.trim().splitn(2, '/');
+        let ip: IpAddr = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None if ip.is_ipv4() => 32,
+            None => 128,
+        };
+        Cidr::new(ip, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix)
+    }
+}
+
+/// where the client accepts wireguard packets from, empty allows any source
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AllowedSources(pub Vec<Cidr>);
+
+impl AllowedSources {
+    pub fn loopback() -> AllowedSources {
+        AllowedSources(vec![
+            Cidr { ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), prefix: 8 },
+            Cidr { ip: IpAddr::V6(Ipv6Addr::LOCALHOST), prefix: 128 },
+        ])
+    }
+
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        self.0.is_empty() || self.0.iter().any(|cidr| cidr.contains(&addr.ip()))
+    }
+}
+
+/// comma separated cidrs, or loopback for 127.0.0.0/8 and ::1
+impl FromStr for AllowedSources {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<AllowedSources> {
+        let mut cidrs = Vec::new();
+        for cidr in s.split(',') {
+            match cidr.trim() {
+                "loopback" => cidrs.extend(AllowedSources::loopback().0),
+                cidr => cidrs.push(cidr.parse()?),
+            }
+        }
+        Ok(AllowedSources(cidrs))
+    }
+}
+
+/// where replies to the local wireguard go, following it when it shows up from somewhere new
+pub struct ReplyTo {
+    addr: Mutex<SocketAddr>,
+    allowed: AllowedSources,
+    // only log a rejected source once in a row, not for every packet
+    rejected: Mutex<Option<SocketAddr>>,
+}
+
+impl ReplyTo {
+    pub fn new(addr: SocketAddr, allowed: AllowedSources) -> ReplyTo {
+        ReplyTo {
+            addr: Mutex::new(addr),
+            allowed,
+            rejected: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self) -> SocketAddr {
+        *self.addr.lock().unwrap()
+    }
+
+    /// whether a datagram from src should be carried, replies go to src from now on if so
+    pub fn accept(&self, src: SocketAddr) -> bool {
+        if !allowed(&self.allowed, src, &mut self.rejected.lock().unwrap()) {
+            return false;
+        }
+        let mut addr = self.addr.lock().unwrap();
+        if *addr != src {
+            println!("wireguard moved from {} to {}, replying there now", *addr, src);
+            *addr = src;
+        }
+        true
+    }
+}
+
+/// whether src is allowed, logging it the first time in a row it isn't
+pub fn allowed(allowed: &AllowedSources, src: SocketAddr, rejected: &mut Option<SocketAddr>) -> bool {
+    if allowed.allows(&src) {
+        return true;
+    }
+    if *rejected != Some(src) {
+        println!("dropping datagram from {}, not an allowed source", src);
+        *rejected = Some(src);
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(&"10.1.200.3".parse().unwrap()));
+        assert!(!net.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(!net.contains(&"::1".parse().unwrap()));
+        assert!(net.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+        assert_eq!(net.to_string(), "10.1.0.0/16");
+
+        let net: Cidr = "fd00::/8".parse().unwrap();
+        assert!(net.contains(&"fd12::1".parse().unwrap()));
+        assert!(!net.contains(&"fe80::1".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&"192.168.1.1".parse().unwrap()));
+        let one: Cidr = "192.168.1.1".parse().unwrap();
+        assert!(one.contains(&"192.168.1.1".parse().unwrap()));
+        assert!(!one.contains(&"192.168.1.2".parse().unwrap()));
+
+        for bad in ["10.0.0.0/33", "::/129", "nope", "10.0.0.0/x", ""].iter() {
+            assert!(matches!(bad.parse::<Cidr>(), Err(Error::Config(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_allowed_sources() {
+        assert!(AllowedSources::default().allows(&addr("192.168.1.1:5")));
+
+        let loopback: AllowedSources = "loopback".parse().unwrap();
+        assert!(loopback.allows(&addr("127.0.0.1:51820")));
+        assert!(loopback.allows(&addr("127.3.2.1:51820")));
+        assert!(loopback.allows(&addr("[::1]:51820")));
+        assert!(!loopback.allows(&addr("192.168.1.1:51820")));
+
+        let list: AllowedSources = "loopback, 192.168.1.0/24".parse().unwrap();
+        assert!(list.allows(&addr("192.168.1.7:1")));
+        assert!(!list.allows(&addr("192.168.2.7:1")));
+    }
+
+    #[test]
+    fn test_reply_to() {
+        let reply_to = ReplyTo::new(addr("127.0.0.1:1000"), AllowedSources::loopback());
+        assert!(reply_to.accept(addr("127.0.0.1:1000")));
+        assert!(reply_to.accept(addr("127.0.0.1:2000")));
+        assert_eq!(reply_to.get(), addr("127.0.0.1:2000"));
+        assert!(!reply_to.accept(addr("10.0.0.1:3000")));
+        assert_eq!(reply_to.get(), addr("127.0.0.1:2000"));
+    }
+}
@@ -0,0 +1,61 @@
+// where a client is at, for something like a tray icon to show, reported through on_status as it changes
+// and optionally written to a file as one line of JSON for anything that can't take a callback
+
+use crate::admin::{json_opt, json_str};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    /// opening the tcp or unix connection to target
+    Connecting { target: String },
+    /// connected, now TLS (or whatever the transport does) is being set up
+    TlsHandshaking { target: String },
+    /// connected, waiting for the local wireguard's first packet to know where to send replies
+    WaitingForWireGuard,
+    /// the tunnel is carrying packets, server_pubkey is the "sha256//" pin of the server's TLS key
+    Connected { target: String, server_pubkey: Option<String> },
+    /// the tunnel failed, run() starts over once reconnect has passed, attempt counts since last connected
+    Reconnecting { attempt: u32 },
+    /// why the tunnel failed, followed by Reconnecting if run() is going to try again
+    Failed { error: String },
+}
+
+impl Status {
+    pub fn json(&self) -> String {
+        match self {
+            Status::Connecting { target } => format!(r#"{{"status":"connecting","target":{}}}"#, json_str(target)),
+            Status::TlsHandshaking { target } => format!(r#"{{"status":"tls_handshaking","target":{}}}"#, json_str(target)),
+            Status::WaitingForWireGuard => r#"{"status":"waiting_for_wireguard"}"#.to_owned(),
+            Status::Connected { target, server_pubkey } => {
+                format!(r#"{{"status":"connected","target":{},"server_pubkey":{}}}"#, json_str(target), json_opt(server_pubkey))
+            }
+            Status::Reconnecting { attempt } => format!(r#"{{"status":"reconnecting","attempt":{}}}"#, attempt),
+            Status::Failed { error } => format!(r#"{{"status":"failed","error":{}}}"#, json_str(error)),
+        }
+    }
+}
+
+/// replaces the file at path with status, through a rename so a reader never sees half of it
+pub fn write_file(path: &str, status: &Status) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, status.json() + "\n")?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let connected = Status::Connected { target: "gw.example.com:443".to_owned(), server_pubkey: Some("sha256//abc=".to_owned()) };
+        assert_eq!(connected.json(), r#"{"status":"connected","target":"gw.example.com:443","server_pubkey":"sha256//abc="}"#);
+        assert_eq!(Status::Failed { error: "said \"no\"".to_owned() }.json(), r#"{"status":"failed","error":"said \"no\""}"#);
+
+        let path = std::env::temp_dir().join(format!("wgp-status-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_file(path, &Status::WaitingForWireGuard).unwrap();
+        write_file(path, &Status::Reconnecting { attempt: 3 }).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "{\"status\":\"reconnecting\",\"attempt\":3}\n");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+// where the tunnel delivers datagrams, normally a udp socket wireguard talks to, but a library
+// user running wireguard in process can hand packets over directly with no loopback udp hop
+
+use std::io;
+use std::net::UdpSocket;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::source::ReplyTo;
+
+/// receiving half of an endpoint, datagrams headed into the tunnel, blocks on its own thread
+pub trait DatagramRx: Send {
+    /// blocks for the next datagram and returns its length, one bigger than buf is truncated like udp would
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+    /// like recv but giving up with None once timeout passes with nothing received, so the thread can check its
+    /// tunnel is still there, the default for endpoints that can't wait any less just blocks
+    fn recv_timeout(&mut self, buf: &mut [u8], _timeout: Duration) -> Result<Option<usize>> {
+        self.recv(buf).map(Some)
+    }
+
+    /// grabs more datagrams that are already waiting without blocking, filling in their lengths and
+    /// returning how many, the default of 0 just means one per recv
+    fn try_recv_many(&mut self, _bufs: &mut [&mut [u8]], _lens: &mut [usize]) -> usize {
+        0
+    }
+}
+
+/// sending half of an endpoint, datagrams coming out of the tunnel
+pub trait DatagramTx: Send {
+    fn send(&mut self, buf: &[u8]) -> Result<()>;
+
+    /// sends at least one of bufs, blocking if need be, returning how many went
+    fn send_many(&mut self, bufs: &[&[u8]]) -> Result<usize> {
+        for buf in bufs {
+            self.send(buf)?;
+        }
+        Ok(bufs.len())
+    }
+}
+
+/// anything that sends and receives whole datagrams, split so each direction gets its own thread
+pub trait DatagramEndpoint: Send {
+    fn split(self: Box<Self>) -> Result<(Box<dyn DatagramRx>, Box<dyn DatagramTx>)>;
+}
+
+pub type BoxEndpoint = Box<dyn DatagramEndpoint>;
+
+/// a connected udp socket, batching with recvmmsg/sendmmsg on linux
+impl DatagramEndpoint for UdpSocket {
+    fn split(self: Box<Self>) -> Result<(Box<dyn DatagramRx>, Box<dyn DatagramTx>)> {
+        Ok((Box::new(self.try_clone()?), self))
+    }
+}
+
+impl DatagramRx for UdpSocket {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(UdpSocket::recv(self, buf)?)
+    }
+
+    fn recv_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        if !readable(self, timeout) {
+            return Ok(None);
+        }
+        self.recv(buf).map(Some)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_recv_many(&mut self, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> usize {
+        use std::os::unix::io::AsRawFd;
+        // WouldBlock just means nothing else is waiting, anything else the next recv will report
+        crate::batch::sys::recvmmsg(self.as_raw_fd(), bufs, lens).unwrap_or(0)
+    }
+}
+
+impl DatagramTx for UdpSocket {
+    fn send(&mut self, buf: &[u8]) -> Result<()> {
+        UdpSocket::send(self, buf)?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_many(&mut self, bufs: &[&[u8]]) -> Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        Ok(crate::batch::sys::sendmmsg(self.as_raw_fd(), bufs, 0)?)
+    }
+}
+
+/// the client's udp socket, left unconnected so wireguard can move to a new source port, replies follow it
+pub(crate) struct LocalUdp {
+    socket: UdpSocket,
+    reply_to: Arc<ReplyTo>,
+}
+
+impl LocalUdp {
+    pub fn new(socket: UdpSocket, reply_to: Arc<ReplyTo>) -> LocalUdp {
+        LocalUdp { socket, reply_to }
+    }
+}
+
+impl DatagramEndpoint for LocalUdp {
+    fn split(self: Box<Self>) -> Result<(Box<dyn DatagramRx>, Box<dyn DatagramTx>)> {
+        let rx = LocalUdp {
+            socket: self.socket.try_clone()?,
+            reply_to: self.reply_to.clone(),
+        };
+        Ok((Box::new(rx), self))
+    }
+}
+
+impl DatagramRx for LocalUdp {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let (len, src_addr) = self.socket.recv_from(buf)?;
+            if self.reply_to.accept(src_addr) {
+                return Ok(len);
+            }
+        }
+    }
+
+    fn recv_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        loop {
+            if !readable(&self.socket, timeout) {
+                return Ok(None);
+            }
+            let (len, src_addr) = self.socket.recv_from(buf)?;
+            if self.reply_to.accept(src_addr) {
+                return Ok(Some(len));
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_recv_many(&mut self, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> usize {
+        use std::os::unix::io::AsRawFd;
+        let mut addrs = [None; crate::batch::BATCH];
+        let count = crate::batch::sys::recvmmsg_from(self.socket.as_raw_fd(), bufs, lens, &mut addrs).unwrap_or(0);
+        for (len, addr) in lens.iter_mut().zip(addrs[..count].iter()) {
+            if !addr.is_some_and(|addr| self.reply_to.accept(addr)) {
+                // empty datagrams are dropped when packed
+                *len = 0;
+            }
+        }
+        count
+    }
+}
+
+impl DatagramTx for LocalUdp {
+    fn send(&mut self, buf: &[u8]) -> Result<()> {
+        self.socket.send_to(buf, self.reply_to.get())?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_many(&mut self, bufs: &[&[u8]]) -> Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        Ok(crate::batch::sys::sendmmsg_to(self.socket.as_raw_fd(), bufs, Some(&self.reply_to.get()), 0)?)
+    }
+}
+
+/// in memory datagrams, for running wireguard in the same process or in tests
+pub struct ChannelEndpoint {
+    pub tx: mpsc::SyncSender<Vec<u8>>,
+    pub rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl ChannelEndpoint {
+    /// two endpoints back to back, what is sent on one is received on the other, give one to the
+    /// proxy and use the other like wireguard's udp socket, each direction holds up to capacity datagrams
+    pub fn pair(capacity: usize) -> (ChannelEndpoint, ChannelEndpoint) {
+        let (a_tx, a_rx) = mpsc::sync_channel(capacity);
+        let (b_tx, b_rx) = mpsc::sync_channel(capacity);
+        (ChannelEndpoint { tx: a_tx, rx: b_rx }, ChannelEndpoint { tx: b_tx, rx: a_rx })
+    }
+}
+
+impl DatagramEndpoint for ChannelEndpoint {
+    fn split(self: Box<Self>) -> Result<(Box<dyn DatagramRx>, Box<dyn DatagramTx>)> {
+        Ok((Box::new(self.rx), Box::new(self.tx)))
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "datagram channel closed")
+}
+
+// copies as much as fits, like udp truncating into a short buffer
+fn copy_datagram(datagram: &[u8], buf: &mut [u8]) -> usize {
+    let len = datagram.len().min(buf.len());
+    buf[..len].copy_from_slice(&datagram[..len]);
+    len
+}
+
+impl DatagramRx for mpsc::Receiver<Vec<u8>> {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let datagram = mpsc::Receiver::recv(self).map_err(|_| closed())?;
+        Ok(copy_datagram(&datagram, buf))
+    }
+
+    fn recv_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        match mpsc::Receiver::recv_timeout(self, timeout) {
+            Ok(datagram) => Ok(Some(copy_datagram(&datagram, buf))),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(closed().into()),
+        }
+    }
+
+    fn try_recv_many(&mut self, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> usize {
+        let mut count = 0;
+        for (buf, len) in bufs.iter_mut().zip(lens.iter_mut()) {
+            match self.try_recv() {
+                Ok(datagram) => *len = copy_datagram(&datagram, buf),
+                Err(_) => break,
+            }
+            count += 1;
+        }
+        count
+    }
+}
+
+impl DatagramTx for mpsc::SyncSender<Vec<u8>> {
+    fn send(&mut self, buf: &[u8]) -> Result<()> {
+        mpsc::SyncSender::send(self, buf.to_vec()).map_err(|_| closed())?;
+        Ok(())
+    }
+}
+
+/// waits up to timeout for socket to have something to read or accept, false if it timed out, errors are left for
+/// whatever reads it next to report
+#[cfg(unix)]
+pub(crate) fn readable<T: std::os::unix::io::AsRawFd>(socket: &T, timeout: Duration) -> bool {
+    let mut poll = libc::pollfd { fd: socket.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    unsafe { libc::poll(&mut poll, 1, timeout) != 0 }
+}
+
+// nothing to wait with, so the next read just blocks
+#[cfg(not(unix))]
+pub(crate) fn readable<T>(_socket: &T, _timeout: Duration) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::DEFAULT_MAX_DATAGRAM;
+    use crate::net::syncmod::TcpUdpPipe;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_readable() {
+        let (a, b) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        assert!(!readable(&b, Duration::from_millis(10)));
+        a.send(b"ping").unwrap();
+        assert!(readable(&b, Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_channel_pipe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        // a tunnel with in memory wireguard on both ends, no udp anywhere
+        let (client_side, client_wg) = ChannelEndpoint::pair(64);
+        let (server_side, server_wg) = ChannelEndpoint::pair(64);
+        thread::spawn(move || TcpUdpPipe::new(Box::new(client), Box::new(client_side), DEFAULT_MAX_DATAGRAM).shuffle());
+        thread::spawn(move || TcpUdpPipe::new(Box::new(server), Box::new(server_side), DEFAULT_MAX_DATAGRAM).shuffle());
+
+        let datagrams: Vec<Vec<u8>> = (1..=100u8).map(|x| vec![x; x as usize]).collect();
+        for datagram in datagrams.iter() {
+            client_wg.tx.send(datagram.clone()).unwrap();
+        }
+        for datagram in datagrams.iter() {
+            assert_eq!(&server_wg.rx.recv().unwrap(), datagram);
+        }
+
+        server_wg.tx.send(b"reply".to_vec()).unwrap();
+        assert_eq!(client_wg.rx.recv().unwrap(), b"reply");
+    }
+
+    // once the tunnel closes nothing is left waiting on the udp socket, so its port can be bound again
+    #[test]
+    fn test_pipe_releases_udp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp_socket.local_addr().unwrap();
+        let pipe = thread::spawn(move || TcpUdpPipe::new(Box::new(server), Box::new(udp_socket), DEFAULT_MAX_DATAGRAM).shuffle());
+
+        drop(client);
+        assert!(pipe.join().unwrap().is_err());
+        UdpSocket::bind(addr).unwrap();
+    }
+
+    // a whole tunnel, server and client, over a unix socket with in memory wireguard on both ends
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_tunnel() {
+        use crate::transport::Plain;
+        use crate::{ProxyClientBuilder, ProxyServerBuilder};
+        use std::os::unix::fs::PermissionsExt;
+        use std::sync::Mutex;
+
+        let path = std::env::temp_dir().join(format!("wgp-unix-tunnel-sync-{}", std::process::id()));
+        let (client_side, client_wg) = ChannelEndpoint::pair(64);
+        let (server_side, server_wg) = ChannelEndpoint::pair(64);
+        let server_side = Mutex::new(Some(server_side));
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let ready_tx = Mutex::new(ready_tx);
+        let server = ProxyServerBuilder::new()
+            .unix_host(&path, Some(0o600))
+            .endpoint_factory(move || Ok(Box::new(server_side.lock().unwrap().take().expect("only one tunnel")) as BoxEndpoint))
+            .on_ready(move |_| { ready_tx.lock().unwrap().send(()).ok(); })
+            .build()
+            .unwrap();
+        let client = ProxyClientBuilder::new().unix_target(&path).build().unwrap();
+
+        thread::spawn(move || server.start());
+        ready_rx.recv().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        thread::spawn(move || client.start_with_endpoint(&Plain, Box::new(client_side)));
+
+        client_wg.tx.send(b"hello".to_vec()).unwrap();
+        assert_eq!(server_wg.rx.recv().unwrap(), b"hello");
+        server_wg.tx.send(b"reply".to_vec()).unwrap();
+        assert_eq!(client_wg.rx.recv().unwrap(), b"reply");
+        // the server never stops to remove it itself
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,208 +1,856 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
 use std::thread;
-use crate::error::Result;
+use std::time::{Duration, Instant};
+use crate::batch::{UdpBatch, BATCH};
+use crate::bond::Stripes;
+use crate::endpoint::{BoxEndpoint, DatagramRx, DatagramTx, LocalUdp};
+use crate::control::{self, Hello, PeerFrames, Peers};
+use crate::{eyeballs, failover};
+use crate::frame::FrameReader;
+use crate::source::{self, ReplyTo};
+use crate::error::{Error, Result};
+use crate::transport::{Acceptor, BoxStream, Counted, Plain, Socket, TlsAcceptor, TlsTransport, Transport};
 use crate::*;
 
-use std::net::TcpListener;
-use std::io::{Write, Read};
+use std::io::Write;
 
-#[cfg(any(feature = "tls", feature = "openssl_vendored"))]
-#[path = ""]
-mod tls {
-    pub mod openssl;
-    pub use super::tls::openssl::{TlsStream, TlsListener};
-}
-
-#[cfg(not(any(feature = "tls", feature = "openssl_vendored")))]
-#[path = ""]
-mod tls {
-    pub mod notls;
-    pub use super::tls::notls::{TlsStream, TlsListener};
-}
+// how often a thread waiting on udp checks whether its tunnel is still there, so it lets go of the socket once it isn't
+const RECV_POLL: Duration = Duration::from_millis(250);
 
-use tls::{TlsStream, TlsListener};
-
-pub struct TcpUdpPipe<T: Write + Read + TryClone<T> + Send + 'static> {
-    buf: [u8; 2050], // 2048 + 2 for len
-    tcp_stream: T,
-    udp_socket: UdpSocket,
+pub struct TcpUdpPipe {
+    tcp_stream: BoxStream,
+    endpoint: BoxEndpoint,
+    frame_reader: FrameReader,
+    max_datagram: usize,
 }
 
-impl<T: Write + Read + TryClone<T> + Send + 'static> TcpUdpPipe<T> {
-    pub fn new(tcp_stream: T, udp_socket: UdpSocket) -> TcpUdpPipe<T> {
+impl TcpUdpPipe {
+    pub fn new(tcp_stream: BoxStream, endpoint: BoxEndpoint, max_datagram: usize) -> TcpUdpPipe {
         TcpUdpPipe {
             tcp_stream,
-            udp_socket,
-            buf: [0u8; 2050],
+            endpoint,
+            frame_reader: FrameReader::new(max_datagram),
+            max_datagram,
         }
     }
 
-    pub fn try_clone(&self) -> Result<TcpUdpPipe<T>> {
-        Ok(TcpUdpPipe::new(
-            self.tcp_stream.try_clone()?,
-            self.udp_socket.try_clone()?,
-        ))
+    /// carry on with frames already read from tcp_stream
+    pub fn with_reader(mut self, frame_reader: FrameReader) -> TcpUdpPipe {
+        self.frame_reader = frame_reader;
+        self
     }
 
-    pub fn shuffle_after_first_udp(&mut self) -> Result<usize> {
-        let (len, src_addr) = self.udp_socket.recv_from(&mut self.buf[2..])?;
+    pub fn shuffle(self) -> Result<usize> {
+        let mut tcp_rd = self.tcp_stream;
+        let mut tcp_wr = tcp_rd.try_clone()?;
+        let (mut udp_rd, mut udp_wr) = self.endpoint.split()?;
+        let max_datagram = self.max_datagram;
+        let closed = Arc::new(AtomicBool::new(false));
+        let udp_closed = closed.clone();
+        let udp_thread = thread::spawn(move || {
+            let mut batch = UdpBatch::new(max_datagram);
+            let closed = || udp_closed.load(Ordering::SeqCst);
+            while udp_to_tcp(&mut udp_rd, &mut tcp_wr, &mut batch, &closed).is_ok() {}
+            // wakes up tcp_to_udp below so the whole tunnel closes
+            tcp_wr.shutdown().ok();
+        });
 
-        println!("first packet from {}, connecting to that", src_addr);
-        self.udp_socket.connect(src_addr)?;
+        let mut reader = self.frame_reader;
+        let err = loop {
+            if let Err(e) = tcp_to_udp(&mut tcp_rd, &mut udp_wr, &mut reader) {
+                break e;
+            }
+        };
+        // and this wakes up the thread above, once it next writes or polls, and it's gone before the endpoint is
+        // used again, the client's udp socket by the next tunnel after failing over
+        closed.store(true, Ordering::SeqCst);
+        tcp_rd.shutdown().ok();
+        udp_thread.join().ok();
+        Err(err)
+    }
+}
 
-        self.send_udp(len)?;
+// the client doesn't know where wireguard is until it sends something, so wait for that from an
+// allowed source, send it on and return where it came from to reply to
+fn first_udp(udp_socket: &UdpSocket, tcp_wr: &mut BoxStream, allowed: &AllowedSources, max_datagram: usize) -> Result<SocketAddr> {
+    let mut batch = UdpBatch::new(max_datagram);
+    let mut rejected = None;
+    let (len, src_addr) = loop {
+        let (len, src_addr) = udp_socket.recv_from(batch.first_mut())?;
+        if source::allowed(allowed, src_addr, &mut rejected) {
+            break (len, src_addr);
+        }
+    };
 
-        self.shuffle()
+    println!("first packet from {}, replying there", src_addr);
+
+    batch.set_first(len);
+    tcp_wr.write_all(batch.pack())?;
+    Ok(src_addr)
+}
+
+fn udp_to_tcp(udp_rd: &mut Box<dyn DatagramRx>, tcp_wr: &mut BoxStream, batch: &mut UdpBatch, closed: &dyn Fn() -> bool) -> Result<()> {
+    udp_recv(udp_rd, batch, closed)?;
+    Ok(tcp_wr.write_all(batch.pack())?)
+    // todo: do this? self.tcp_stream.flush()
+}
+
+fn udp_recv(udp_rd: &mut Box<dyn DatagramRx>, batch: &mut UdpBatch, closed: &dyn Fn() -> bool) -> Result<()> {
+    // block for the first one until the tunnel is closed, then grab whatever else is waiting
+    let len = loop {
+        match udp_rd.recv_timeout(batch.first_mut(), RECV_POLL)? {
+            Some(len) => break len,
+            None if closed() => return Err(tunnel_closed()),
+            None => {}
+        }
+    };
+    batch.set_first(len);
+    batch.recv_more(|bufs, lens| udp_rd.try_recv_many(bufs, lens));
+    Ok(())
+}
+
+fn tunnel_closed() -> Error {
+    io::Error::new(io::ErrorKind::NotConnected, "tunnel closed").into()
+}
+
+fn tcp_to_udp(tcp_rd: &mut BoxStream, udp_wr: &mut Box<dyn DatagramTx>, reader: &mut FrameReader) -> Result<usize> {
+    // nothing is done with control frames in a plain tunnel, a newer client may still send them
+    while reader.control()?.is_some() {}
+    let frames = reader.frames(BATCH)?;
+    if frames.is_empty() {
+        reader.fill(tcp_rd)?;
+        return Ok(0);
     }
 
-    pub fn udp_to_tcp(&mut self) -> Result<()> {
-        let len = self.udp_socket.recv(&mut self.buf[2..])?;
-        self.send_udp(len)
+    let mut sent = 0;
+    while sent < frames.len() {
+        sent += udp_wr.send_many(&frames[sent..])?;
     }
+    Ok(frames.len())
+}
 
-    fn send_udp(&mut self, len: usize) -> Result<()> {
-        #[cfg(feature = "verbose")]
-        println!("udp got len: {}", len);
+// says hello asking for features, an old server never answers so this relies on socket_timeout, or sends a datagram
+fn hello(tcp_stream: &mut BoxStream, hello: &Hello, max_datagram: usize) -> Result<(FrameReader, Hello)> {
+    tcp_stream.write_all(&hello.frame())?;
+    let mut reader = FrameReader::new(max_datagram);
+    // the server's padding can get here ahead of its answer
+    let answer = loop {
+        if let Some(answer) = control::answer(&mut reader, hello.features)? {
+            break answer;
+        }
+        match reader.fill(tcp_stream) {
+            Err(Error::Timeout(_)) => return Err(control::unsupported(hello.features)),
+            result => result?,
+        }
+    };
+    Ok((reader, control::answered(hello.features, answer)?))
+}
 
-        self.buf[0] = ((len >> 8) & 0xFF) as u8;
-        self.buf[1] = (len & 0xFF) as u8;
+// connected streams, several when bonded, and the Connected status to report once the tunnel carries packets
+type Connection = (Vec<Link>, Status);
+// a connected stream and what was read past the hello if there was one
+type Link = (BoxStream, Option<FrameReader>);
+
+/// one endpoint carried over several streams of a bond, datagrams read from any of them go to it and what it
+/// sends back goes out over each stream in turn
+pub(crate) struct Bond {
+    udp_wr: Mutex<Box<dyn DatagramTx>>,
+    // each stream is locked on its own to write to it, so a slow one doesn't hold up links coming and going
+    links: Mutex<Stripes<Arc<Mutex<BoxStream>>>>,
+    // keeps udp_target watched for as long as the bond lasts
+    _upstreams: Option<Arc<resolve::Upstreams>>,
+}
+
+impl Bond {
+    pub fn new(endpoint: BoxEndpoint, upstreams: Option<Arc<resolve::Upstreams>>, max_datagram: usize) -> Result<Arc<Bond>> {
+        let (mut udp_rd, udp_wr) = endpoint.split()?;
+        let bond = Arc::new(Bond {
+            udp_wr: Mutex::new(udp_wr),
+            links: Mutex::new(Stripes::default()),
+            _upstreams: upstreams,
+        });
+        // only holds on to the bond while sending, the thread ends soon after it's gone
+        let weak = Arc::downgrade(&bond);
+        thread::spawn(move || {
+            let mut batch = UdpBatch::new(max_datagram);
+            let closed = || weak.strong_count() == 0;
+            loop {
+                let result = udp_recv(&mut udp_rd, &mut batch, &closed);
+                let bond = match weak.upgrade() {
+                    Some(bond) => bond,
+                    None => break,
+                };
+                match result {
+                    Ok(()) => bond.send(batch.pack()),
+                    // like one stream's tunnel, the endpoint failing takes every stream down with it
+                    Err(_) => break bond.shutdown(),
+                }
+            }
+        });
+        Ok(bond)
+    }
 
-        Ok(self.tcp_stream.write_all(&self.buf[..len + 2])?)
-        // todo: do this? self.tcp_stream.flush()
+    /// carries stream until it fails, reader holds what was already read from it
+    pub fn link(&self, stream: BoxStream, mut reader: FrameReader) -> Result<usize> {
+        let mut tcp_rd = stream;
+        let id = self.links.lock().unwrap().add(Arc::new(Mutex::new(tcp_rd.try_clone()?)))?;
+        let err = loop {
+            if let Err(e) = self.tcp_to_udp(&mut tcp_rd, &mut reader) {
+                break e;
+            }
+        };
+        self.links.lock().unwrap().remove(id);
+        tcp_rd.shutdown().ok();
+        Err(err)
     }
 
-    pub fn tcp_to_udp(&mut self) -> Result<usize> {
-        self.tcp_stream.read_exact(&mut self.buf[..2])?;
-        let len = ((self.buf[0] as usize) << 8) + self.buf[1] as usize;
-        #[cfg(feature = "verbose")]
-        println!("tcp expecting len: {}", len);
-        self.tcp_stream.read_exact(&mut self.buf[..len])?;
-        #[cfg(feature = "verbose")]
-        println!("tcp got len: {}", len);
-        Ok(self.udp_socket.send(&self.buf[..len])?)
+    /// every link at once, until the last one fails
+    pub fn run(&self, links: Vec<(BoxStream, FrameReader)>) -> Result<usize> {
+        thread::scope(|scope| {
+            let links: Vec<_> = links.into_iter().map(|(stream, reader)| scope.spawn(move || self.link(stream, reader))).collect();
+            let mut result = Ok(0);
+            for link in links {
+                result = link.join().expect("bond link panicked");
+            }
+            result
+        })
+    }
 
-        //let sent = udp_socket.send_to(&buf[..len], &self.udp_target)?;
-        //assert_eq!(sent, len);
+    // over the next stream that takes them, each tried once and dropped if it fails, once none are left the
+    // datagrams are lost
+    fn send(&self, frames: &[u8]) {
+        let tries = self.links.lock().unwrap().len();
+        for _ in 0..tries {
+            let (id, link) = match self.links.lock().unwrap().next() {
+                Some((id, link)) => (id, link.clone()),
+                None => return,
+            };
+            let mut link = link.lock().unwrap();
+            if link.write_all(frames).is_ok() {
+                return;
+            }
+            link.shutdown().ok();
+            self.links.lock().unwrap().remove(id);
+        }
     }
 
-    pub fn shuffle(&mut self) -> Result<usize> {
-        let mut udp_pipe_clone = self.try_clone()?;
-        thread::spawn(move || loop {
-            udp_pipe_clone
-                .udp_to_tcp()
-                .expect("cannot write to tcp_clone");
-        });
+    fn shutdown(&self) {
+        let links: Vec<_> = self.links.lock().unwrap().drain().collect();
+        for link in links {
+            link.lock().unwrap().shutdown().ok();
+        }
+    }
 
-        loop {
-            self.tcp_to_udp()?;
+    fn tcp_to_udp(&self, tcp_rd: &mut BoxStream, reader: &mut FrameReader) -> Result<()> {
+        while reader.control()?.is_some() {}
+        let frames = reader.frames(BATCH)?;
+        if frames.is_empty() {
+            return reader.fill(tcp_rd);
+        }
+        let mut udp_wr = self.udp_wr.lock().unwrap();
+        let mut sent = 0;
+        while sent < frames.len() {
+            sent += udp_wr.send_many(&frames[sent..])?;
         }
+        Ok(())
     }
 }
 
-pub trait TryClone<T> {
-    fn try_clone(&self) -> Result<T>;
+// several local wireguards share the tunnel, each source address is a peer and PEER frames say whose datagrams follow
+fn shuffle_peers(tcp_stream: BoxStream, udp_socket: UdpSocket, mut reader: FrameReader, allowed: AllowedSources, max_datagram: usize) -> Result<usize> {
+    let mut tcp_rd = tcp_stream;
+    let mut tcp_wr = tcp_rd.try_clone()?;
+    let udp_rd = udp_socket.try_clone()?;
+    let peers = Arc::new(Mutex::new(Peers::new(allowed)));
+
+    let local_peers = peers.clone();
+    let closed = Arc::new(AtomicBool::new(false));
+    let local_closed = closed.clone();
+    let local_thread = thread::spawn(move || {
+        let mut buf = vec![0u8; max_datagram + 1];
+        let mut frames = PeerFrames::new(max_datagram);
+        while local_to_tcp(&udp_rd, &mut tcp_wr, &local_peers, &mut buf, &mut frames, &local_closed).is_ok() {}
+        // wakes up tcp_to_local below so the whole tunnel closes
+        tcp_wr.shutdown().ok();
+    });
+
+    let mut current = None;
+    let err = loop {
+        if let Err(e) = tcp_to_local(&mut tcp_rd, &udp_socket, &mut reader, &peers, &mut current) {
+            break e;
+        }
+    };
+    // like one peer's tunnel, the thread above is gone before the next tunnel takes over the socket
+    closed.store(true, Ordering::SeqCst);
+    tcp_rd.shutdown().ok();
+    local_thread.join().ok();
+    Err(err)
 }
 
-impl TryClone<UdpSocket> for UdpSocket {
-    fn try_clone(&self) -> Result<UdpSocket> {
-        Ok(self.try_clone()?)
+fn local_to_tcp(udp_rd: &UdpSocket, tcp_wr: &mut BoxStream, peers: &Mutex<Peers>, buf: &mut [u8], frames: &mut PeerFrames, closed: &AtomicBool) -> Result<()> {
+    let max_datagram = buf.len() - 1;
+    while !crate::endpoint::readable(udp_rd, RECV_POLL) {
+        if closed.load(Ordering::SeqCst) {
+            return Err(tunnel_closed());
+        }
     }
+    let (len, src_addr) = udp_rd.recv_from(buf)?;
+    if len > max_datagram {
+        println!("dropping truncated udp datagram, larger than max datagram size {}", max_datagram);
+        return Ok(());
+    }
+    let id = match peers.lock().unwrap().id(src_addr) {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    frames.push(id, &buf[..len])?;
+    let result = tcp_wr.write_all(frames.as_bytes());
+    frames.clear();
+    Ok(result?)
 }
 
-impl TryClone<TcpStream> for TcpStream {
-    fn try_clone(&self) -> Result<TcpStream> {
-        Ok(self.try_clone()?)
+fn tcp_to_local(tcp_rd: &mut BoxStream, udp_wr: &UdpSocket, reader: &mut FrameReader, peers: &Mutex<Peers>, current: &mut Option<u16>) -> Result<()> {
+    match control::peer_frames(reader, current)? {
+        Some((id, frames)) => {
+            let addr = peers.lock().unwrap().addr(id)
+                .ok_or_else(|| Error::protocol(format!("datagram for unknown local peer {}", id)))?;
+            for frame in frames {
+                udp_wr.send_to(frame, addr)?;
+            }
+            Ok(())
+        }
+        None => reader.fill(tcp_rd),
     }
 }
 
 impl ProxyClient {
 
     pub fn start(&self) -> Result<usize> {
-        let tcp_stream = self.tcp_connect()?;
+        self.start_with(&Plain)
+    }
+
+    pub fn start_tls(&self, hostname: Option<&str>, pinnedpubkey: Option<&str>) -> Result<usize> {
+        let config = TlsClientConfig {
+            hostname: hostname.map(str::to_owned),
+            pinnedpubkey: pinnedpubkey.map(str::to_owned),
+        };
+        self.start_with(&TlsTransport::new(&config)?)
+    }
+
+    /// connects and runs the tunnel over whatever transport wraps the connection
+    pub fn start_with(&self, transport: &dyn Transport) -> Result<usize> {
+        let (links, connected_status) = self.connect_target(&self.tcp_target, transport, true)?;
 
         let udp_socket = self.udp_connect()?;
+        notify_ready(&self.on_ready, &format!("{}, listening on {}", self.connected(&self.tcp_target, transport), &self.udp_host));
+        spawn_watchdog();
 
-        // we want to wait for first udp packet from client first, to set the target to respond to
-        TcpUdpPipe::new(tcp_stream, udp_socket).shuffle_after_first_udp()
+        self.tunnel(links, udp_socket, &mut None, connected_status)
     }
 
-    pub fn start_tls(&self, hostname: Option<&str>, pinnedpubkey: Option<&str>) -> Result<usize> {
-        let tcp_stream = self.tcp_connect()?;
+    /// like start_with() but exchanging datagrams with endpoint instead of listening on udp_host
+    pub fn start_with_endpoint(&self, transport: &dyn Transport, endpoint: BoxEndpoint) -> Result<usize> {
+        let (stream, connected_status) = self.connect_transport(&self.tcp_target, transport, true)?;
+        self.report(connected_status);
+
+        notify_ready(&self.on_ready, &self.connected(&self.tcp_target, transport));
+        spawn_watchdog();
 
-        let tcp_stream = TlsStream::client(hostname, pinnedpubkey, tcp_stream)?;
+        TcpUdpPipe::new(stream, endpoint, self.max_datagram).shuffle()
+    }
+
+    /// run() with fallback_targets, connects to the first target in target_order that can be, and while on
+    /// a fallback tries the ones ahead of it every fail_back, moving the tunnel over once one connects
+    pub fn start_failover(&self) -> Result<usize> {
+        let targets = self.targets()?;
+        let (order, mut pos, mut connection) = self.connect_first(&targets)?;
 
         let udp_socket = self.udp_connect()?;
+        spawn_watchdog();
+
+        // where the local wireguard is, so a tunnel after failing back doesn't wait for it to send first
+        let mut reply_to = None;
+        loop {
+            let (tcp_target, transport) = &targets[order[pos]];
+            notify_ready(&self.on_ready, &format!("{}, listening on {}", self.connected(tcp_target, transport.as_ref()), &self.udp_host));
+
+            let (links, connected_status) = connection;
+            let udp = udp_socket.try_clone()?;
+            let fail_back = match self.fail_back {
+                Some(fail_back) if pos > 0 => fail_back,
+                _ => return self.tunnel(links, udp, &mut reply_to, connected_status),
+            };
+
+            // the probe ends the tunnel by shutting its streams down, the tunnel ending stops the probe
+            let current = links.iter().map(|(stream, _)| stream.try_clone()).collect::<Result<Vec<_>>>()?;
+            let (stop, stopped) = mpsc::channel::<()>();
+            let candidates = &order[..pos];
+            let targets = &targets;
+            let (result, better) = thread::scope(|scope| {
+                let probe = scope.spawn(move || self.probe(targets, candidates, fail_back, stopped, current));
+                let result = self.tunnel(links, udp, &mut reply_to, connected_status);
+                drop(stop);
+                (result, probe.join().expect("fail back probe panicked"))
+            });
+            match better {
+                Some((better, better_connection)) => {
+                    println!("{} is reachable again, failing back to it", targets[order[better]].0);
+                    pos = better;
+                    connection = better_connection;
+                }
+                None => return result,
+            }
+        }
+    }
+
+    // the targets in target_order and the position in it of the first one that connects
+    fn connect_first(&self, targets: &Targets) -> Result<(Vec<usize>, usize, Connection)> {
+        let order = match self.target_order {
+            TargetOrder::Listed => (0..targets.len()).collect(),
+            TargetOrder::Latency => {
+                let latencies: Vec<_> = targets.iter().map(|(tcp_target, _)| {
+                    let start = Instant::now();
+                    self.connect(tcp_target).ok().map(|_| start.elapsed())
+                }).collect();
+                failover::by_latency(&latencies)
+            }
+        };
+        let mut last_err = None;
+        for (pos, i) in order.iter().enumerate() {
+            let (tcp_target, transport) = &targets[*i];
+            match self.connect_target(tcp_target, transport.as_ref(), true) {
+                Ok(connection) => return Ok((order, pos, connection)),
+                Err(e) => {
+                    println!("could not connect to {}: {}", tcp_target, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.expect("there is always tcp_target"))
+    }
+
+    // every interval until stopped tries candidates in order, shutting current down once one connects
+    // and returning its position
+    fn probe(&self, targets: &Targets, candidates: &[usize], interval: Duration, stopped: mpsc::Receiver<()>, current: Vec<BoxStream>) -> Option<(usize, Connection)> {
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            for (pos, i) in candidates.iter().enumerate() {
+                let (tcp_target, transport) = &targets[*i];
+                if let Ok(connection) = self.connect_target(tcp_target, transport.as_ref(), false) {
+                    current.iter().for_each(|stream| { stream.shutdown().ok(); });
+                    return Some((pos, connection));
+                }
+            }
+        }
+        None
+    }
+
+    // the tunnel over already connected streams, reply_to is where the local wireguard was last seen
+    fn tunnel(&self, links: Vec<Link>, udp_socket: UdpSocket, reply_to: &mut Option<Arc<ReplyTo>>, connected: Status) -> Result<usize> {
+        let mut links: Vec<_> = links.into_iter()
+            .map(|(stream, reader)| (stream, reader.unwrap_or_else(|| FrameReader::new(self.max_datagram))))
+            .collect();
+        if self.multi_peer {
+            let (stream, reader) = links.pop().expect("multi peer tunnels aren't bonded");
+            self.report(connected);
+            return shuffle_peers(stream, udp_socket, reader, self.allowed_sources.clone(), self.max_datagram);
+        }
+        let reply_to = match reply_to {
+            Some(reply_to) => reply_to.clone(),
+            None => {
+                // we want to wait for first udp packet from client first, to set the target to respond to
+                self.report(Status::WaitingForWireGuard);
+                let src_addr = first_udp(&udp_socket, &mut links[0].0, &self.allowed_sources, self.max_datagram)?;
+                reply_to.insert(Arc::new(ReplyTo::new(src_addr, self.allowed_sources.clone()))).clone()
+            }
+        };
+        self.report(connected);
+        let endpoint = Box::new(LocalUdp::new(udp_socket, reply_to));
+        if links.len() > 1 {
+            return Bond::new(endpoint, None, self.max_datagram)?.run(links);
+        }
+        let (stream, reader) = links.pop().expect("a tunnel has a stream");
+        TcpUdpPipe::new(stream, endpoint, self.max_datagram).with_reader(reader).shuffle()
+    }
+
+    // connected, wrapped by transport and said hello if need be, reporting how it goes unless just probing,
+    // and when bonding the rest of the bond's streams connected the same way without reporting
+    fn connect_target(&self, tcp_target: &str, transport: &dyn Transport, report: bool) -> Result<Connection> {
+        let hello = self.hello(transport)?;
+        let (stream, connected) = self.connect_transport(tcp_target, transport, report)?;
+        let hello = match hello {
+            Some(hello) => hello,
+            None => return Ok((vec![(stream, None)], connected)),
+        };
+        let stats = Arc::new(CompressionStats::default());
+        let mut links = vec![self.say_hello(stream, &hello, &stats)?];
+        for _ in 1..self.bond {
+            let (stream, _) = self.connect_transport(tcp_target, transport, false)?;
+            links.push(self.say_hello(stream, &hello, &stats)?);
+        }
+        Ok((links, connected))
+    }
 
-        // we want to wait for first udp packet from client first, to set the target to respond to
-        TcpUdpPipe::new(tcp_stream, udp_socket).shuffle_after_first_udp()
+    fn say_hello(&self, mut stream: BoxStream, hello: &Hello, stats: &Arc<CompressionStats>) -> Result<Link> {
+        let (reader, agreed) = self::hello(&mut stream, hello, self.max_datagram)?;
+        let (stream, reader) = self.compressed(stream, reader, &agreed, stats);
+        Ok((stream, Some(reader)))
+    }
+
+    fn connect_transport(&self, tcp_target: &str, transport: &dyn Transport, report: bool) -> Result<(BoxStream, Status)> {
+        if report {
+            self.report(Status::Connecting { target: tcp_target.to_owned() });
+        }
+        let stream = self.connect(tcp_target)?;
+        if report && transport.name().is_some() {
+            self.report(Status::TlsHandshaking { target: tcp_target.to_owned() });
+        }
+        let (stream, tls) = transport.connect_info(stream)?;
+        let stream = padded(stream, &self.padding, self.max_datagram)?;
+        let server_pubkey = tls.and_then(|tls| tls.pinnedpubkey);
+        Ok((stream, Status::Connected { target: tcp_target.to_owned(), server_pubkey }))
+    }
+
+    fn connect(&self, tcp_target: &str) -> Result<BoxStream> {
+        if quic_addr(tcp_target).is_some() {
+            return Err(Error::config("quic: targets need the async build with the quic feature"));
+        }
+        if self.http2.is_some() {
+            return Err(Error::config("http2 needs the async build with the http2 feature"));
+        }
+        #[cfg(unix)]
+        {
+            if let Some(path) = unix_path(tcp_target) {
+                return Ok(Box::new(self.unix_connect(path)?));
+            }
+        }
+
+        let tcp_stream = self.tcp_connect(tcp_target)?;
+        tcp_stream.set_read_timeout(self.socket_timeout)?;
+        Ok(Box::new(tcp_stream))
+    }
+
+    // resolves tcp_target afresh every time and tries its addresses in happy eyeballs order, one at a time
+    // as std can't race them, each getting an even share of what's left of connect_timeout
+    fn tcp_connect(&self, tcp_target: &str) -> Result<TcpStream> {
+        let addrs = eyeballs::interleave(tcp_target.to_socket_addrs()?.collect());
+        let deadline = self.connect_timeout.map(|connect_timeout| Instant::now() + connect_timeout);
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "resolved to no addresses");
+        for (i, addr) in addrs.iter().enumerate() {
+            let result = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.as_millis() == 0 {
+                        last_err = io::Error::new(io::ErrorKind::TimedOut, format!("connecting to {} took over {:?}", tcp_target, self.connect_timeout.unwrap()));
+                        break;
+                    }
+                    TcpStream::connect_timeout(addr, left / (addrs.len() - i) as u32)
+                }
+                None => TcpStream::connect(addr),
+            };
+            match result {
+                Ok(tcp_stream) => return Ok(tcp_stream),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err.into())
     }
 }
 
 impl ProxyServer {
 
     pub fn start(&self) -> Result<()> {
-        let listener = TcpListener::bind(&self.tcp_host)?;
-        println!("Listening for connections on {}", &self.tcp_host);
-
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let client_handler = self.client_handler.clone();
-                    client_handler.set_tcp_options(&stream).expect("cannot set tcp options");
-
-                    thread::spawn(move || {
-                        client_handler
-                            .handle_client(stream)
-                            .expect("error handling connection")
-                    });
+        self.start_with(Arc::new(Plain))
+    }
+
+    pub fn start_tls(&self, tls_key: &str, tls_cert: &str) -> Result<()> {
+        let acceptor = TlsAcceptor::new(&TlsServerConfig::new(tls_key, tls_cert))?;
+        self.start_with(Arc::new(acceptor))
+    }
+
+    /// listens and runs a tunnel for each connection, after the acceptor wraps it
+    pub fn start_with(&self, acceptor: Arc<dyn Acceptor>) -> Result<()> {
+        let connections = match acceptor.name() {
+            Some(name) => format!("{} connections", name),
+            None => "connections".to_owned(),
+        };
+        let _admin = self.admin_listen()?;
+
+        if quic_addr(&self.tcp_host).is_some() {
+            return Err(Error::config("quic: needs the async build with the quic feature"));
+        }
+        if self.http2.is_some() {
+            return Err(Error::config("http2 needs the async build with the http2 feature"));
+        }
+        self.check_proxy_protocol()?;
+
+        #[cfg(unix)]
+        {
+            if let Some(path) = unix_path(&self.tcp_host) {
+                let (listener, _socket_file) = self.unix_listener(path)?;
+                notify_ready(&self.on_ready, &format!("Listening for {} on unix:{}", connections, path));
+
+                let watchdog = watchdog_interval();
+                loop {
+                    watchdog_until_accept(&listener, watchdog);
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            if let Err(e) = stream.set_read_timeout(self.client_handler.socket_timeout) {
+                                println!("Unable to set unix socket options: {}", e);
+                                continue;
+                            }
+                            self.spawn_client(acceptor.clone(), stream, format!("unix:{}", path), None);
+                        }
+                        Err(e) => {
+                            println!("Unable to connect: {}", e);
+                        }
+                    }
+                }
+            }
+        }
+
+        let listener = self.tcp_listener()?;
+        notify_ready(&self.on_ready, &format!("Listening for {} on {}", connections, listener.local_addr()?));
+
+        let watchdog = watchdog_interval();
+        loop {
+            watchdog_until_accept(&listener, watchdog);
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    if let Err(e) = self.client_handler.set_tcp_options(&stream) {
+                        println!("Unable to set tcp options: {}", e);
+                        continue;
+                    }
+                    self.spawn_client(acceptor.clone(), stream, addr.to_string(), Some(addr));
                 }
                 Err(e) => {
                     println!("Unable to connect: {}", e);
                 }
             }
         }
-        Ok(())
     }
 
-    pub fn start_tls(&self, tls_key: &str, tls_cert: &str) -> Result<()> {
-        let tls_listener = Arc::new(TlsListener::new(tls_key, tls_cert)?);
-
-        let listener = TcpListener::bind(&self.tcp_host)?;
-        println!("Listening for TLS connections on {}", &self.tcp_host);
-
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let client_handler = self.client_handler.clone();
-                    client_handler.set_tcp_options(&stream).expect("cannot set tcp options");
-
-                    let tls_listener = tls_listener.clone();
-                    thread::spawn(move || {
-                        let stream = tls_listener.wrap(stream).expect("cannot wrap with tls");
-                        client_handler
-                            .handle_client_tls(stream)
-                            .expect("error handling connection")
-                    });
-                }
-                Err(e) => {
-                    println!("Unable to connect: {}", e);
+    fn spawn_client<S: Socket>(&self, acceptor: Arc<dyn Acceptor>, stream: S, peer: String, addr: Option<SocketAddr>) {
+        let spawner = Spawner {
+            sessions: self.sessions.clone(),
+            client_handler: self.client_handler.clone(),
+            padding: self.padding.clone(),
+        };
+        match self.proxy_mode(addr) {
+            Ok(Some(mode)) => spawner.spawn_proxied(mode, acceptor, stream, peer),
+            Ok(None) => spawner.spawn(acceptor, Box::new(stream), peer),
+            Err(e) => self.client_handler.tunnel_closed(&peer, &e),
+        }
+    }
+}
+
+// what spawning a tunnel takes, so a connection's thread can spawn it once its PROXY protocol header is read
+struct Spawner {
+    sessions: Arc<Sessions>,
+    client_handler: Arc<ProxyServerClientHandler>,
+    padding: Option<Padding>,
+}
+
+impl Spawner {
+    // a failing connection only takes down its own tunnel, the listener carries on
+    fn spawn(&self, acceptor: Arc<dyn Acceptor>, stream: BoxStream, peer: String) {
+        if !self.sessions.accepting() {
+            println!("not accepting new tunnels, closing connection from {}", peer);
+            return;
+        }
+        let client_handler = self.client_handler.clone();
+        let padding = self.padding.clone();
+        // killing shuts down the underlying socket, which wakes every thread blocked on the tunnel
+        let raw = match stream.try_clone() {
+            Ok(raw) => raw,
+            Err(e) => return client_handler.tunnel_closed(&peer, &e),
+        };
+        let open = self.sessions.open(&peer, Box::new(move || { raw.shutdown().ok(); }));
+        thread::spawn(move || {
+            let session = open.session.clone();
+            let result = acceptor.accept_info(stream).and_then(|(stream, tls)| {
+                if let Some(tls) = tls {
+                    session.set_tls(tls);
                 }
+                let stream = padded(stream, &padding, client_handler.max_datagram)?;
+                let stream = Box::new(Counted::new(stream, session.bytes.clone()));
+                client_handler.handle_tunnel(stream, Some(session.clone()), acceptor.protected())
+            });
+            let result = match result {
+                Err(_) if session.killed() => Err(Error::Killed),
+                result => result,
+            };
+            if let Err(e) = result {
+                client_handler.tunnel_closed(&peer, &e);
             }
+            drop(open);
+        });
+    }
+
+    // the header is read on a thread of its own so a slow one doesn't hold up the listener, the client it names is
+    // the tunnel's peer from then on
+    fn spawn_proxied<S: Socket>(self, mode: ProxyProtocol, acceptor: Arc<dyn Acceptor>, stream: S, peer: String) {
+        let socket_timeout = self.client_handler.socket_timeout;
+        let timeout = socket_timeout.unwrap_or(proxyproto::HEADER_TIMEOUT);
+        thread::spawn(move || match transport::proxied(stream, mode, timeout, socket_timeout) {
+            Ok((stream, source)) => self.spawn(acceptor, stream, source.map_or(peer, |source| source.to_string())),
+            Err(e) => self.client_handler.tunnel_closed(&peer, &e),
+        });
+    }
+}
+
+// the client's tunnel has no loop of its own to ping from, so it's pinged as long as the process runs
+fn spawn_watchdog() {
+    if let Some(interval) = watchdog_interval() {
+        thread::spawn(move || loop {
+            notify_watchdog();
+            thread::sleep(interval);
+        });
+    }
+}
+
+// with the watchdog on, pings it until listener has a connection waiting, so the pings come from the accept loop
+// itself and stop if it ever gets stuck
+#[cfg(unix)]
+fn watchdog_until_accept<L: std::os::unix::io::AsRawFd>(listener: &L, watchdog: Option<Duration>) {
+    if let Some(interval) = watchdog {
+        notify_watchdog();
+        while !crate::endpoint::readable(listener, interval) {
+            notify_watchdog();
         }
-        Ok(())
     }
 }
 
+#[cfg(not(unix))]
+fn watchdog_until_accept<L>(_listener: &L, _watchdog: Option<Duration>) {}
+
 impl ProxyServerClientHandler {
 
     pub fn set_tcp_options(&self, tcp_stream: &TcpStream) -> Result<()> {
         Ok(tcp_stream.set_read_timeout(self.socket_timeout)?)
     }
 
-    pub fn handle_client(&self, tcp_stream: TcpStream) -> Result<usize> {
-        TcpUdpPipe::new(tcp_stream, self.udp_bind()?).shuffle()
+    /// doesn't offer resuming, as there's no telling whether tcp_stream is protected
+    pub fn handle_client(&self, tcp_stream: BoxStream) -> Result<usize> {
+        self.handle_tunnel(tcp_stream, None, false)
+    }
+
+    fn handle_tunnel(&self, mut tcp_stream: BoxStream, session: Option<Arc<Session>>, protected: bool) -> Result<usize> {
+        // nothing is bound until the client sends something, a client wanting more than plain datagrams says hello first
+        let mut reader = FrameReader::new(self.max_datagram);
+        while !reader.ready()? {
+            reader.fill(&mut tcp_stream)?;
+        }
+        let tunnel = self.tunnel(session);
+        if let Some(hello) = control::hello(&mut reader)? {
+            let agreed = control::agree(&hello, self.offered(protected));
+            tcp_stream.write_all(&agreed.frame())?;
+            let (compressed, rest) = self.compressed(tcp_stream, reader, &agreed, &tunnel);
+            tcp_stream = compressed;
+            reader = rest;
+            if agreed.has(control::MULTI_PEER) {
+                return self.shuffle_peers(tcp_stream, reader, &tunnel);
+            }
+            // the first stream of a bond makes its endpoint, the rest and any resuming the tunnel join it
+            if let Some(id) = agreed.id {
+                let bond = self.bonds.join(&id, || Bond::new(self.endpoint(&tunnel)?, tunnel.upstreams.clone(), self.max_datagram))?;
+                let result = bond.link(tcp_stream, reader);
+                if agreed.has(control::RESUME) {
+                    self.linger(bond, &tunnel);
+                }
+                return result;
+            }
+        }
+        TcpUdpPipe::new(tcp_stream, self.endpoint(&tunnel)?, self.max_datagram).with_reader(reader).shuffle()
+    }
+
+    // keeps a tunnel that resumes for the grace period after a stream of it is gone, unless it was killed
+    fn linger(&self, bond: Arc<Bond>, tunnel: &Tunnel) {
+        if let Some(grace) = self.resume.filter(|_| !tunnel.killed()) {
+            thread::spawn(move || {
+                thread::sleep(grace);
+                drop(bond);
+            });
+        }
+    }
+
+    fn endpoint(&self, tunnel: &Tunnel) -> Result<BoxEndpoint> {
+        Ok(match &self.endpoint_factory {
+            Some(endpoint_factory) => endpoint_factory()?,
+            None => {
+                let udp_socket = self.udp_bind()?;
+                self.register(tunnel, &udp_socket)?;
+                Box::new(udp_socket)
+            }
+        })
+    }
+
+    // one upstream endpoint for each of the client's local peers, made when its first datagram shows up,
+    // each gets a thread writing to the shared stream
+    fn shuffle_peers(&self, tcp_stream: BoxStream, mut reader: FrameReader, tunnel: &Tunnel) -> Result<usize> {
+        let mut tcp_rd = tcp_stream;
+        let tcp_wr = Arc::new(Mutex::new((tcp_rd.try_clone()?, PeerFrames::new(self.max_datagram))));
+        let mut upstream: HashMap<u16, Box<dyn DatagramTx>> = HashMap::new();
+        let mut current = None;
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let err = loop {
+            let result = match control::peer_frames(&mut reader, &mut current) {
+                Ok(Some((id, frames))) => self.tcp_to_upstream(&mut upstream, &tcp_wr, &closed, id, &frames, tunnel),
+                Ok(None) => reader.fill(&mut tcp_rd),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                break e;
+            }
+        };
+        // wakes up the upstream threads, once they next write or poll
+        closed.store(true, Ordering::SeqCst);
+        tcp_rd.shutdown().ok();
+        Err(err)
+    }
+
+    fn tcp_to_upstream(&self, upstream: &mut HashMap<u16, Box<dyn DatagramTx>>, tcp_wr: &Arc<Mutex<(BoxStream, PeerFrames)>>, closed: &Arc<AtomicBool>, id: u16, frames: &[&[u8]], tunnel: &Tunnel) -> Result<()> {
+        let udp_wr = match upstream.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.spawn_upstream(id, tcp_wr.clone(), closed.clone(), tunnel)?),
+        };
+        let mut sent = 0;
+        while sent < frames.len() {
+            sent += udp_wr.send_many(&frames[sent..])?;
+        }
+        Ok(())
     }
 
-    pub fn handle_client_tls(&self, tcp_stream: TlsStream) -> Result<usize> {
-        TcpUdpPipe::new(tcp_stream, self.udp_bind()?).shuffle()
+    fn spawn_upstream(&self, id: u16, tcp_wr: Arc<Mutex<(BoxStream, PeerFrames)>>, closed: Arc<AtomicBool>, tunnel: &Tunnel) -> Result<Box<dyn DatagramTx>> {
+        let (mut udp_rd, udp_wr) = self.endpoint(tunnel)?.split()?;
+        let max_datagram = self.max_datagram;
+        thread::spawn(move || {
+            let mut buf = vec![0u8; max_datagram + 1];
+            loop {
+                let len = match udp_rd.recv_timeout(&mut buf, RECV_POLL) {
+                    Ok(Some(len)) => len,
+                    Ok(None) if !closed.load(Ordering::SeqCst) => continue,
+                    _ => break,
+                };
+                if len > max_datagram {
+                    println!("dropping truncated udp datagram, larger than max datagram size {}", max_datagram);
+                    continue;
+                }
+                let mut tcp_wr = tcp_wr.lock().unwrap();
+                let (stream, frames) = &mut *tcp_wr;
+                let result = frames.push(id, &buf[..len]).and_then(|_| Ok(stream.write_all(frames.as_bytes())?));
+                frames.clear();
+                if result.is_err() {
+                    break;
+                }
+            }
+            // an upstream failing takes the tunnel down with it, like it would with one peer
+            tcp_wr.lock().unwrap().0.shutdown().ok();
+        });
+        Ok(udp_wr)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,612 @@
+// what the tunnel runs over, a Transport wraps each stream the client connects and an Acceptor
+// each stream the server accepts, so a library user can swap in their own layer instead of TLS
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use crate::compress::{Compressor, Decompressor};
+use crate::error::{Error, Result};
+use crate::frame::FrameReader;
+use crate::obfs::{Opener, Sealer};
+use crate::padding::Padder;
+use crate::proxyproto::{HeaderReader, Parsed, ProxyProtocol};
+use crate::{CompressionStats, Counters, Obfuscation, Padding, TlsClientConfig, TlsInfo, TlsServerConfig};
+
+#[cfg(any(feature = "tls", feature = "openssl_vendored"))]
+#[path = ""]
+mod tls {
+    pub mod openssl;
+    pub use super::tls::openssl::{TlsStream, TlsListener};
+}
+
+#[cfg(not(any(feature = "tls", feature = "openssl_vendored")))]
+#[path = ""]
+mod tls {
+    pub mod notls;
+    pub use super::tls::notls::{TlsStream, TlsListener};
+}
+
+use tls::{TlsStream, TlsListener};
+
+/// anything the tunnel can run over, each direction blocks on its own thread with its own clone
+pub trait Stream: Read + Write + Send + 'static {
+    fn try_clone(&self) -> Result<BoxStream>;
+
+    /// closes both directions, so a thread blocked on another clone wakes up
+    fn shutdown(&self) -> Result<()>;
+}
+
+pub type BoxStream = Box<dyn Stream>;
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> Result<BoxStream> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        Ok(TcpStream::shutdown(self, std::net::Shutdown::Both)?)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn try_clone(&self) -> Result<BoxStream> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        Ok(UnixStream::shutdown(self, std::net::Shutdown::Both)?)
+    }
+}
+
+/// a freshly accepted tcp or unix stream, before anything wraps it
+pub(crate) trait Socket: Stream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+}
+
+impl Socket for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(TcpStream::set_read_timeout(self, timeout)?)
+    }
+}
+
+#[cfg(unix)]
+impl Socket for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(UnixStream::set_read_timeout(self, timeout)?)
+    }
+}
+
+/// client side, wraps a freshly connected tcp or unix stream
+pub trait Transport: Send + Sync {
+    fn connect(&self, stream: BoxStream) -> Result<BoxStream>;
+
+    /// connect() along with what the server's TLS looked like, None for anything that isn't TLS
+    fn connect_info(&self, stream: BoxStream) -> Result<(BoxStream, Option<TlsInfo>)> {
+        Ok((self.connect(stream)?, None))
+    }
+
+    /// shows up in log messages like "Connected with TLS", None for nothing special
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    /// whether what's sent over it can't be read or changed on the way, resuming is only asked for when it is
+    fn protected(&self) -> bool {
+        false
+    }
+}
+
+/// server side, wraps a freshly accepted tcp or unix stream
+pub trait Acceptor: Send + Sync {
+    fn accept(&self, stream: BoxStream) -> Result<BoxStream>;
+
+    /// accept() along with what the admin socket shows about TLS, None for anything that isn't TLS
+    fn accept_info(&self, stream: BoxStream) -> Result<(BoxStream, Option<TlsInfo>)> {
+        Ok((self.accept(stream)?, None))
+    }
+
+    /// shows up in log messages like "Listening for TLS connections", None for nothing special
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    /// whether what's sent over it can't be read or changed on the way, resuming is only offered when it is
+    fn protected(&self) -> bool {
+        false
+    }
+}
+
+/// the stream as is
+pub struct Plain;
+
+impl Transport for Plain {
+    fn connect(&self, stream: BoxStream) -> Result<BoxStream> {
+        Ok(stream)
+    }
+}
+
+impl Acceptor for Plain {
+    fn accept(&self, stream: BoxStream) -> Result<BoxStream> {
+        Ok(stream)
+    }
+}
+
+/// TLS with OpenSSL, verifies nothing unless a pinnedpubkey is set
+pub struct TlsTransport {
+    config: TlsClientConfig,
+}
+
+impl TlsTransport {
+    pub fn new(config: &TlsClientConfig) -> Result<TlsTransport> {
+        Ok(TlsTransport {
+            config: config.clone(),
+        })
+    }
+}
+
+impl Transport for TlsTransport {
+    fn connect(&self, stream: BoxStream) -> Result<BoxStream> {
+        Ok(self.connect_info(stream)?.0)
+    }
+
+    fn connect_info(&self, stream: BoxStream) -> Result<(BoxStream, Option<TlsInfo>)> {
+        let stream = TlsStream::client(self.config.hostname.as_deref(), self.config.pinnedpubkey.as_deref(), stream)?;
+        let tls = TlsInfo {
+            sni: self.config.hostname.clone(),
+            pinnedpubkey: stream.peer_pubkey(),
+        };
+        Ok((Box::new(stream), Some(tls)))
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("TLS")
+    }
+
+    fn protected(&self) -> bool {
+        // without a pin it verifies nothing, so anyone in the middle could read along
+        self.config.pinnedpubkey.is_some()
+    }
+}
+
+/// TLS with OpenSSL, key and cert are read once up front
+pub struct TlsAcceptor {
+    listener: TlsListener,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: &TlsServerConfig) -> Result<TlsAcceptor> {
+        Ok(TlsAcceptor {
+            listener: TlsListener::new(&config.key, &config.cert)?,
+        })
+    }
+}
+
+impl Acceptor for TlsAcceptor {
+    fn accept(&self, stream: BoxStream) -> Result<BoxStream> {
+        Ok(Box::new(self.listener.wrap(stream)?))
+    }
+
+    fn accept_info(&self, stream: BoxStream) -> Result<(BoxStream, Option<TlsInfo>)> {
+        let stream = self.listener.wrap(stream)?;
+        let tls = TlsInfo {
+            sni: stream.server_name(),
+            pinnedpubkey: self.listener.pinnedpubkey(),
+        };
+        Ok((Box::new(stream), Some(tls)))
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("TLS")
+    }
+
+    fn protected(&self) -> bool {
+        true
+    }
+}
+
+impl Transport for Obfuscation {
+    fn connect(&self, stream: BoxStream) -> Result<BoxStream> {
+        Ok(Box::new(Obfuscated::new(stream, self)?))
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("obfs")
+    }
+
+    fn protected(&self) -> bool {
+        true
+    }
+}
+
+impl Acceptor for Obfuscation {
+    fn accept(&self, stream: BoxStream) -> Result<BoxStream> {
+        Ok(Box::new(Obfuscated::new(stream, self)?))
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("obfs")
+    }
+
+    fn protected(&self) -> bool {
+        true
+    }
+}
+
+/// stream with everything going through it obfuscated, clones share the state of each direction
+/// as only one thread ever reads and one writes
+struct Obfuscated {
+    stream: BoxStream,
+    opener: Arc<Mutex<Opener>>,
+    sealer: Arc<Mutex<Sealer>>,
+}
+
+impl Obfuscated {
+    fn new(stream: BoxStream, obfuscation: &Obfuscation) -> Result<Obfuscated> {
+        Ok(Obfuscated {
+            stream,
+            opener: Arc::new(Mutex::new(obfuscation.opener())),
+            sealer: Arc::new(Mutex::new(obfuscation.sealer()?)),
+        })
+    }
+}
+
+impl Stream for Obfuscated {
+    fn try_clone(&self) -> Result<BoxStream> {
+        Ok(Box::new(Obfuscated {
+            stream: self.stream.try_clone()?,
+            opener: self.opener.clone(),
+            sealer: self.sealer.clone(),
+        }))
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        self.stream.shutdown()
+    }
+}
+
+impl Read for Obfuscated {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut opener = self.opener.lock().unwrap();
+        loop {
+            let len = opener.open(buf)?;
+            if len > 0 {
+                return Ok(len);
+            }
+            let len = self.stream.read(opener.read_mut())?;
+            if len == 0 {
+                return Ok(0);
+            }
+            opener.filled(len);
+        }
+    }
+}
+
+impl Write for Obfuscated {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut sealer = self.sealer.lock().unwrap();
+        sealer.seal(buf);
+        self.stream.write_all(sealer.sealed())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// pads every write with padding frames, clones share the one writer so cover traffic sent from
+/// a thread of its own never lands in the middle of a write
+pub(crate) struct Padded {
+    stream: BoxStream,
+    writer: Arc<Mutex<PadWriter>>,
+}
+
+struct PadWriter {
+    stream: BoxStream,
+    padder: Padder,
+    // since cover traffic last looked
+    wrote: bool,
+}
+
+impl Padded {
+    pub fn new(stream: BoxStream, padding: &Padding, max_datagram: usize) -> Result<Padded> {
+        let padder = Padder::new(padding, max_datagram)?;
+        let cover = padder.cover();
+        let writer = Arc::new(Mutex::new(PadWriter {
+            stream: stream.try_clone()?,
+            padder,
+            wrote: false,
+        }));
+        if let Some(cover) = cover {
+            // only a weak reference, so the thread ends at its next look once the stream is dropped
+            let weak = Arc::downgrade(&writer);
+            thread::spawn(move || loop {
+                thread::sleep(cover);
+                let writer = match weak.upgrade() {
+                    Some(writer) => writer,
+                    None => break,
+                };
+                let mut writer = writer.lock().unwrap();
+                let writer = &mut *writer;
+                if !writer.wrote {
+                    writer.padder.pad_cover();
+                    if writer.stream.write_all(writer.padder.padded()).is_err() {
+                        break;
+                    }
+                }
+                writer.wrote = false;
+            });
+        }
+        Ok(Padded { stream, writer })
+    }
+}
+
+impl Stream for Padded {
+    fn try_clone(&self) -> Result<BoxStream> {
+        Ok(Box::new(Padded {
+            stream: self.stream.try_clone()?,
+            writer: self.writer.clone(),
+        }))
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        self.stream.shutdown()
+    }
+}
+
+impl Read for Padded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Padded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut writer = self.writer.lock().unwrap();
+        let writer = &mut *writer;
+        writer.padder.pad(buf);
+        writer.stream.write_all(writer.padder.padded())?;
+        writer.wrote = true;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().stream.flush()
+    }
+}
+
+/// compresses the datagram frames written and decompresses those read, see compress.rs
+pub(crate) struct Compressed {
+    stream: BoxStream,
+    decompressor: Arc<Mutex<Decompressor>>,
+    compressor: Arc<Mutex<Compressor>>,
+}
+
+impl Compressed {
+    /// reader holds what was already read from stream
+    pub fn new(stream: BoxStream, reader: FrameReader, max_datagram: usize, stats: Arc<CompressionStats>) -> Compressed {
+        Compressed {
+            stream,
+            decompressor: Arc::new(Mutex::new(Decompressor::new(reader, max_datagram, stats.clone()))),
+            compressor: Arc::new(Mutex::new(Compressor::new(stats))),
+        }
+    }
+}
+
+impl Stream for Compressed {
+    fn try_clone(&self) -> Result<BoxStream> {
+        Ok(Box::new(Compressed {
+            stream: self.stream.try_clone()?,
+            decompressor: self.decompressor.clone(),
+            compressor: self.compressor.clone(),
+        }))
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        self.stream.shutdown()
+    }
+}
+
+impl Read for Compressed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut decompressor = self.decompressor.lock().unwrap();
+        loop {
+            let len = decompressor.open(buf)?;
+            if len > 0 {
+                return Ok(len);
+            }
+            let len = self.stream.read(decompressor.read_mut())?;
+            if len == 0 {
+                return Ok(0);
+            }
+            decompressor.filled(len);
+        }
+    }
+}
+
+impl Write for Compressed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut compressor = self.compressor.lock().unwrap();
+        compressor.compress(buf);
+        self.stream.write_all(compressor.compressed())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// counts what goes through stream into counters, clones count into the same ones
+pub(crate) struct Counted {
+    stream: BoxStream,
+    counters: Arc<Counters>,
+}
+
+impl Counted {
+    pub fn new(stream: BoxStream, counters: Arc<Counters>) -> Counted {
+        Counted { stream, counters }
+    }
+}
+
+impl Stream for Counted {
+    fn try_clone(&self) -> Result<BoxStream> {
+        Ok(Box::new(Counted::new(self.stream.try_clone()?, self.counters.clone())))
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        self.stream.shutdown()
+    }
+}
+
+impl Read for Counted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stream.read(buf)?;
+        self.counters.add_rx(len);
+        Ok(len)
+    }
+}
+
+impl Write for Counted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.stream.write(buf)?;
+        self.counters.add_tx(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// stream with what was read off its start to look for a PROXY protocol header put back in front, clones share it
+/// as only one thread ever reads
+pub(crate) struct Rewound {
+    stream: BoxStream,
+    rewound: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Stream for Rewound {
+    fn try_clone(&self) -> Result<BoxStream> {
+        Ok(Box::new(Rewound { stream: self.stream.try_clone()?, rewound: self.rewound.clone() }))
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        self.stream.shutdown()
+    }
+}
+
+impl Read for Rewound {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut rewound = self.rewound.lock().unwrap();
+        if rewound.is_empty() {
+            drop(rewound);
+            return self.stream.read(buf);
+        }
+        let len = buf.len().min(rewound.len());
+        buf[..len].copy_from_slice(&rewound[..len]);
+        rewound.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for Rewound {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// reads a PROXY protocol header off the start of stream, returning the client it names if any and the stream
+/// carrying on after it, or after nothing when there's no header and that's allowed, failing if it all takes over
+/// timeout, then reads wait socket_timeout again
+pub(crate) fn proxied<S: Socket>(mut stream: S, mode: ProxyProtocol, timeout: Duration, socket_timeout: Option<Duration>) -> Result<(BoxStream, Option<SocketAddr>)> {
+    // one for the whole header, so a peer sending a byte at a time can't keep the thread much longer
+    let deadline = Instant::now() + timeout;
+    let mut reader = HeaderReader::new();
+    let (len, source) = loop {
+        match reader.parse()? {
+            Parsed::Incomplete => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, format!("no PROXY protocol header within {:?}", timeout)).into());
+                }
+                stream.set_read_timeout(Some(left))?;
+                let len = stream.read(reader.read_mut())?;
+                if len == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                reader.filled(len);
+            }
+            Parsed::Absent if mode == ProxyProtocol::Strict => return Err(Error::protocol("no PROXY protocol header")),
+            Parsed::Absent => break (0, None),
+            Parsed::Header { len, source } => break (len, source),
+        }
+    };
+    stream.set_read_timeout(socket_timeout)?;
+    let rewound = Arc::new(Mutex::new(reader.rest(len)));
+    Ok((Box::new(Rewound { stream: Box::new(stream), rewound }), source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (client, listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn test_proxied() {
+        let (mut client, server) = pair();
+        client.write_all(b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\nhello").unwrap();
+        let (mut server, source) = proxied(server, ProxyProtocol::Strict, Duration::from_secs(5), None).unwrap();
+        assert_eq!(source, Some("203.0.113.7:51234".parse().unwrap()));
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // a peer that says nothing doesn't hold on to the thread, and the socket's own timeout is back afterwards
+        let (_client, server) = pair();
+        let result = proxied(server.try_clone().unwrap(), ProxyProtocol::Optional, Duration::from_millis(50), None);
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        let (mut client, server) = pair();
+        client.write_all(b"hello").unwrap();
+        proxied(server.try_clone().unwrap(), ProxyProtocol::Optional, Duration::from_millis(50), None).unwrap();
+        assert_eq!(server.read_timeout().unwrap(), None);
+
+        // nor does one sending a byte at a time
+        let (mut client, server) = pair();
+        let trickle = thread::spawn(move || {
+            for byte in b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443".iter() {
+                if client.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let start = Instant::now();
+        let result = proxied(server, ProxyProtocol::Optional, Duration::from_millis(100), None);
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        trickle.join().unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+// minimal implementations of sd_listen_fds(3) and sd_notify(3), these are simple enough
+// protocols that linking to libsystemd isn't worth it
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// what systemd passed in the environment, read once and never changed, as changing the environment of a running
+// multithreaded process isn't safe
+struct SystemdEnv {
+    listen_pid: Option<String>,
+    listen_fds: Option<String>,
+    notify_socket: Option<OsString>,
+    watchdog_pid: Option<String>,
+    watchdog_usec: Option<String>,
+}
+
+static ENV: OnceLock<SystemdEnv> = OnceLock::new();
+static LISTEN_FDS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+fn systemd_env() -> &'static SystemdEnv {
+    ENV.get_or_init(|| SystemdEnv {
+        listen_pid: env::var("LISTEN_PID").ok(),
+        listen_fds: env::var("LISTEN_FDS").ok(),
+        notify_socket: env::var_os("NOTIFY_SOCKET"),
+        watchdog_pid: env::var("WATCHDOG_PID").ok(),
+        watchdog_usec: env::var("WATCHDOG_USEC").ok(),
+    })
+}
+
+// true if pid is set and matches ours, systemd sets these so children don't act on them
+fn for_us(pid: Option<&str>, our_pid: u32) -> bool {
+    pid.and_then(|pid| pid.parse::<u32>().ok()) == Some(our_pid)
+}
+
+fn parse_listen_fds(pid: Option<&str>, fds: Option<&str>, our_pid: u32) -> Vec<RawFd> {
+    let fds = match fds.and_then(|fds| fds.parse::<RawFd>().ok()) {
+        Some(fds) if for_us(pid, our_pid) && fds > 0 => fds,
+        _ => 0,
+    };
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds).collect()
+}
+
+/// file descriptors passed in by socket activation, these are only returned the first time this is called
+pub fn listen_fds() -> Vec<RawFd> {
+    if LISTEN_FDS_TAKEN.swap(true, Ordering::SeqCst) {
+        return Vec::new();
+    }
+    let env = systemd_env();
+    parse_listen_fds(env.listen_pid.as_deref(), env.listen_fds.as_deref(), std::process::id())
+}
+
+/// send state to the service manager, returns Ok(false) if NOTIFY_SOCKET isn't set
+pub fn notify(state: &str) -> std::io::Result<bool> {
+    match &systemd_env().notify_socket {
+        Some(notify_socket) => notify_to(notify_socket, state).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+fn notify_to(notify_socket: &OsStr, state: &str) -> std::io::Result<()> {
+    let notify_socket = notify_socket.to_string_lossy();
+    let socket = UnixDatagram::unbound()?;
+    if notify_socket.starts_with('@') {
+        // abstract namespace socket, linux only
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(&notify_socket.as_bytes()[1..])?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        return Err(std::io::Error::other("abstract NOTIFY_SOCKET unsupported on this platform"));
+    } else {
+        socket.send_to(state.as_bytes(), &*notify_socket)?;
+    }
+    Ok(())
+}
+
+// half of WATCHDOG_USEC as recommended by sd_watchdog_enabled(3), WATCHDOG_PID is optional but if it's there it
+// has to be us
+fn parse_watchdog(pid: Option<&str>, usec: Option<&str>, our_pid: u32) -> Option<Duration> {
+    if pid.is_some() && !for_us(pid, our_pid) {
+        return None;
+    }
+    match usec.and_then(|usec| usec.parse::<u64>().ok()) {
+        Some(usec) if usec > 0 => Some(Duration::from_micros(usec / 2)),
+        _ => None,
+    }
+}
+
+/// how often we should send WATCHDOG=1, None if the watchdog isn't enabled
+pub fn watchdog_interval() -> Option<Duration> {
+    let env = systemd_env();
+    parse_watchdog(env.watchdog_pid.as_deref(), env.watchdog_usec.as_deref(), std::process::id())
+}
+
+/// tell the service manager we are listening, ignoring any errors because there isn't anything to do about them
+pub fn notify_ready(status: &str) {
+    if let Err(e) = notify(&format!("READY=1\nSTATUS={}", status)) {
+        println!("error notifying systemd: {}", e);
+    }
+}
+
+/// ping the watchdog, this should only be called from the event loop it's supposed to prove is alive
+pub fn notify_watchdog() {
+    if let Err(e) = notify("WATCHDOG=1") {
+        println!("error notifying systemd watchdog: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        let path = env::temp_dir().join(format!("wgp-notify-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        notify_to(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+        assert!(notify_to(path.as_os_str(), "READY=1").is_err());
+    }
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(parse_listen_fds(Some("42"), Some("2"), 42), vec![3, 4]);
+        // meant for our parent, or not from systemd at all
+        assert!(parse_listen_fds(Some("41"), Some("2"), 42).is_empty());
+        assert!(parse_listen_fds(None, Some("2"), 42).is_empty());
+        assert!(parse_listen_fds(Some("42"), None, 42).is_empty());
+        assert!(parse_listen_fds(Some("42"), Some("-1"), 42).is_empty());
+        assert!(parse_listen_fds(Some("42"), Some("two"), 42).is_empty());
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(parse_watchdog(None, Some("30000000"), 42), Some(Duration::from_secs(15)));
+        assert_eq!(parse_watchdog(Some("42"), Some("30000000"), 42), Some(Duration::from_secs(15)));
+        assert_eq!(parse_watchdog(Some("41"), Some("30000000"), 42), None);
+        assert_eq!(parse_watchdog(None, Some("0"), 42), None);
+        assert_eq!(parse_watchdog(None, Some("soon"), 42), None);
+        assert_eq!(parse_watchdog(None, None, 42), None);
+    }
+}